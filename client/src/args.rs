@@ -1,30 +1,1087 @@
+use crate::auth::Credentials;
 use crate::config::Config;
+use crate::config_file::{ConfigFile, ConfigFormat};
+use crate::encoding::{Compression, GZIP_UNAVAILABLE};
+use crate::hashing::{Algorithm, HashFormat};
+use crate::http_client::{TlsVersion, TLS_UNAVAILABLE};
+use crate::proxy::{parse_proxy_url, proxy_from_env, Socks5Proxy};
+use crate::resolve::AddressOrder;
+use crate::trailer::TrailerChecksum;
+use crate::url::{Scheme, Url};
 use std::env;
 use std::error::Error;
 use std::time::Duration;
 
 pub fn parse_args() -> Result<Config, Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+    let config = parse_args_from(env::args())?;
+    if config.manifest.is_some() {
+        return Ok(config);
+    }
+    apply_env_proxy(config, |name| env::var(name).ok())
+}
 
-    let mut config = Config::default();
+/// Falls back to the proxy environment variables unless `--proxy`, `--no-proxy` or `--socks5`
+/// was given. The proxy is picked for the config's own URL, so with `--manifest` it is left to
+/// `run_manifest`, which applies it to every entry's URL in turn.
+pub fn apply_env_proxy<F>(mut config: Config, env: F) -> Result<Config, Box<dyn Error>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if config.no_proxy || config.proxy.is_some() || config.socks5.is_some() {
+        return Ok(config);
+    }
+    if let Some(proxy) = proxy_from_env(config.scheme, &config.host, env) {
+        config.proxy = Some(
+            parse_proxy_url(&proxy).map_err(|e| format!("Invalid proxy in environment: {}", e))?,
+        );
+    }
+    Ok(config)
+}
 
+/// Puts the options of a `--config` file before the command-line arguments, each marked with
+/// whether it came from the file. A flag given on the command line replaces every value the file
+/// sets for it, so repeatable flags such as `--hash` are overridden rather than added to.
+fn expand_config_file(args: Vec<String>) -> Result<Vec<(bool, String)>, Box<dyn Error>> {
+    let mut path = None;
+    let mut format = None;
     for arg in &args {
-        if let Some(val) = arg.strip_prefix("--host=") {
+        if let Some(val) = arg.strip_prefix("--config=") {
+            path = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--config-format=") {
+            format = Some(ConfigFormat::parse(val)?);
+        }
+    }
+    let Some(path) = path else {
+        if format.is_some() {
+            return Err("--config-format only applies with --config=<PATH>".into());
+        }
+        return Ok(args.into_iter().map(|arg| (false, arg)).collect());
+    };
+    let mut expanded: Vec<(bool, String)> = ConfigFile::load(&path, format)?
+        .to_args()?
+        .into_iter()
+        .filter(|file_arg| !args.iter().any(|arg| flag_name(arg) == flag_name(file_arg)))
+        .map(|arg| (true, arg))
+        .collect();
+    expanded.extend(args.into_iter().map(|arg| (false, arg)));
+    Ok(expanded)
+}
+
+/// The flag part of `--flag=value`.
+fn flag_name(arg: &str) -> &str {
+    arg.split_once('=').map_or(arg, |(name, _)| name)
+}
+
+/// Refuses control characters in a value sent verbatim as a request header, where a CR or LF
+/// would end the header and let the value inject others.
+fn header_value<'a>(option: &str, value: &'a str) -> Result<&'a str, Box<dyn Error>> {
+    if value.chars().any(char::is_control) {
+        return Err(format!("{} must not contain control characters", option).into());
+    }
+    Ok(value)
+}
+
+fn parse_args_from<I: IntoIterator<Item = String>>(args: I) -> Result<Config, Box<dyn Error>> {
+    let mut config = Config::default();
+    let mut body_only = false;
+
+    for (from_file, arg) in expand_config_file(args.into_iter().collect())? {
+        if let Some(val) = arg.strip_prefix("--url=") {
+            let url = Url::parse(val)?;
+            config.scheme = url.scheme;
+            config.host = url.host;
+            config.port = url.port;
+            config.path = url.path;
+        } else if let Some(val) = arg.strip_prefix("--host=") {
             config.host = val.to_string();
         } else if let Some(val) = arg.strip_prefix("--port=") {
             config.port = val.parse::<u16>()?;
         } else if let Some(val) = arg.strip_prefix("--hash=") {
-            config.expected_hash = val.to_string();
+            match val.split_once(':') {
+                Some((prefix, hex)) => {
+                    let algorithm = Algorithm::parse(prefix)
+                        .map_err(|e| format!("Invalid --hash prefix: {}", e))?;
+                    config
+                        .prefixed_hashes
+                        .push((algorithm, hex.to_ascii_lowercase()));
+                }
+                None => config.expected_hash = val.to_string(),
+            }
+        } else if let Some(val) = arg.strip_prefix("--algo=") {
+            config.algorithm = Some(Algorithm::parse(val)?);
+        } else if arg == "--checksum-algorithm-auto" {
+            config.checksum_algorithm_auto = true;
+        } else if let Some(val) = arg.strip_prefix("--hash-format=") {
+            config.hash_format = HashFormat::parse(val)?;
         } else if let Some(val) = arg.strip_prefix("--connect-timeout=") {
             config.connect_timeout = Duration::from_secs(val.parse::<u64>()?);
         } else if let Some(val) = arg.strip_prefix("--read-write-timeout=") {
             config.read_write_timeout = Duration::from_secs(val.parse::<u64>()?);
+        } else if let Some(val) = arg.strip_prefix("--request-timeout=") {
+            config.request_timeout = Some(Duration::from_secs(val.parse::<u64>()?));
+        } else if let Some(val) = arg.strip_prefix("--stall-timeout=") {
+            config.stall_timeout = match val.parse::<u64>()? {
+                0 => return Err("--stall-timeout must be at least 1 second".into()),
+                seconds => Some(Duration::from_secs(seconds)),
+            };
+        } else if let Some(val) = arg.strip_prefix("--tls-handshake-timeout=") {
+            config.tls_handshake_timeout = Duration::from_secs(val.parse::<u64>()?);
+        } else if let Some(val) = arg.strip_prefix("--tls-servername=") {
+            config.tls_server_name = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--min-tls-version=") {
+            config.min_tls_version = TlsVersion::parse(val)?;
+        } else if let Some(val) = arg.strip_prefix("--warn-cert-expiry-days=") {
+            config.warn_cert_expiry_days = Some(val.parse::<u64>()?);
+        } else if let Some(val) = arg.strip_prefix("--bind-port=") {
+            config.bind_port = Some(val.parse::<u16>()?);
+        } else if let Some(val) = arg.strip_prefix("--recv-buffer=") {
+            match val.parse::<usize>()? {
+                0 => return Err("--recv-buffer must be a positive number of bytes".into()),
+                size => config.recv_buffer = Some(size),
+            }
+        } else if let Some(val) = arg.strip_prefix("--address-order=") {
+            config.address_order = AddressOrder::parse(val)?;
+        } else if let Some(val) = arg.strip_prefix("--dns-cache-size=") {
+            match val.parse::<usize>()? {
+                0 => return Err("--dns-cache-size must be at least 1".into()),
+                size => config.dns_cache_size = Some(size),
+            }
+        } else if let Some(val) = arg.strip_prefix("--dns-ttl=") {
+            config.dns_ttl = Some(Duration::from_secs(val.parse::<u64>()?));
+        } else if let Some(val) = arg.strip_prefix("--dns-timeout=") {
+            config.dns_timeout = match val.parse::<u64>()? {
+                0 => return Err("--dns-timeout must be at least 1 second".into()),
+                seconds => Some(Duration::from_secs(seconds)),
+            };
+        } else if arg == "--verify-chunk-sha" {
+            config.verify_chunk_sha = true;
+        } else if let Some(val) = arg.strip_prefix("--range-hash-url-template=") {
+            if !val.contains("{start}") || !val.contains("{end}") {
+                return Err(format!(
+                    "--range-hash-url-template must contain {{start}} and {{end}}: '{}'",
+                    val
+                )
+                .into());
+            }
+            Url::parse(val).map_err(|e| format!("Invalid --range-hash-url-template: {}", e))?;
+            config.range_hash_url_template = Some(val.to_string());
+        } else if arg == "--normalize-newlines" {
+            config.normalize_newlines = true;
+        } else if arg == "--normalize-output" {
+            config.normalize_output = true;
+        } else if let Some(val) = arg.strip_prefix("--referer=") {
+            Url::parse(header_value("--referer", val)?)
+                .map_err(|e| format!("Invalid --referer: {}", e))?;
+            config.referer = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--user=") {
+            config.credentials = Some(Credentials::parse_user(header_value("--user", val)?)?);
+        } else if let Some(val) = arg.strip_prefix("--bearer-token=") {
+            if val.is_empty() {
+                return Err("--bearer-token needs a token".into());
+            }
+            let token = header_value("--bearer-token", val)?;
+            config.credentials = Some(Credentials::Bearer(token.to_string()));
+        } else if arg == "--allow-plaintext-credentials" {
+            config.allow_plaintext_credentials = true;
+        } else if arg == "--single-request" {
+            config.single_request = true;
+        } else if let Some(val) = arg.strip_prefix("--suffix=") {
+            config.suffix = match val.parse::<usize>()? {
+                0 => return Err("--suffix must be at least 1 byte".into()),
+                length => Some(length),
+            };
+        } else if let Some(val) = arg.strip_prefix("--start-offset=") {
+            config.start_offset = Some(
+                val.parse::<usize>()
+                    .map_err(|_| format!("Invalid --start-offset '{}'", val))?,
+            );
+        } else if arg == "--head-first" {
+            config.head_first = true;
+        } else if let Some(val) = arg.strip_prefix("--small-threshold=") {
+            config.small_threshold = Some(val.parse::<u64>()?);
+        } else if let Some(val) = arg.strip_prefix("--head-retries=") {
+            config.head_retries = Some(val.parse::<u32>()?);
+        } else if let Some(val) = arg.strip_prefix("--compression=") {
+            config.compression = Compression::parse(val)?;
+        } else if arg == "--strict-close" {
+            config.strict_close = true;
+        } else if arg == "--response-cache" {
+            config.response_cache = true;
+        } else if arg == "--keep-alive" || arg == "--shared-connection" {
+            config.keep_alive = true;
+        } else if arg == "--connection-per-range" {
+            // The last of the two toggles wins, so the command line can override a config file.
+            config.keep_alive = false;
+        } else if let Some(val) = arg.strip_prefix("--keepalive-idle-timeout=") {
+            config.keepalive_idle_timeout = Some(Duration::from_secs(val.parse::<u64>()?));
+        } else if let Some(val) = arg.strip_prefix("--recycle-after=") {
+            match val.parse::<usize>()? {
+                0 => return Err("--recycle-after must be at least 1".into()),
+                ranges => config.recycle_after = Some(ranges),
+            }
+        } else if let Some(val) = arg.strip_prefix("--output=") {
+            config.output = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--output-mode=") {
+            config.output_mode = match u32::from_str_radix(val.trim_start_matches("0o"), 8) {
+                Ok(mode) if mode <= 0o7777 => Some(mode),
+                _ => {
+                    return Err(format!(
+                        "--output-mode must be an octal mode such as 0600, got '{}'",
+                        val
+                    )
+                    .into())
+                }
+            };
+        } else if arg == "--no-verify" {
+            config.no_verify = true;
+        } else if arg == "--quiet-on-success" {
+            config.quiet_on_success = true;
+        } else if arg == "--body-only" {
+            body_only = true;
+        } else if arg == "--resume" {
+            config.resume = true;
+        } else if let Some(val) = arg.strip_prefix("--expected-prefix-hash=") {
+            config.expected_prefix_hash = Some(val.to_ascii_lowercase());
+        } else if let Some(val) = arg.strip_prefix("--output-template=") {
+            config.output_template = Some(val.to_string());
+        } else if arg == "--tee-stdout" {
+            config.tee_stdout = true;
+        } else if arg == "--decompress-gzip" {
+            config.decompress_gzip = true;
+        } else if let Some(val) = arg.strip_prefix("--verify-inner-hash=") {
+            config.verify_inner_hash = Some(val.to_ascii_lowercase());
+        } else if let Some(val) = arg.strip_prefix("--trailer-checksum=") {
+            config.trailer_checksum = Some(TrailerChecksum::parse(val)?);
+        } else if arg == "--fsync" {
+            config.fsync = true;
+        } else if arg == "--fail-on-empty" {
+            config.fail_on_empty = true;
+        } else if arg == "--fail-on-html" {
+            config.fail_on_html = true;
+        } else if let Some(val) = arg.strip_prefix("--success-status=") {
+            config.success_statuses = val
+                .split(',')
+                .map(|status| match status.trim().parse::<u16>() {
+                    Ok(status @ 100..=599) => Ok(status),
+                    _ => Err(format!("Invalid --success-status '{}'", status.trim())),
+                })
+                .collect::<Result<_, _>>()?;
+        } else if arg == "--stop-on-hash-match" {
+            config.stop_on_hash_match = true;
+        } else if let Some(val) = arg.strip_prefix("--pieces-file=") {
+            config.pieces_file = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--piece-length=") {
+            config.piece_length = match val.parse::<u64>()? {
+                0 => return Err("--piece-length must be at least 1".into()),
+                length => Some(length),
+            };
+        } else if let Some(val) = arg.strip_prefix("--retry-rate=") {
+            match val.parse::<f64>()? {
+                rate if rate > 0.0 && rate.is_finite() => config.retry_rate = Some(rate),
+                _ => {
+                    return Err(
+                        "--retry-rate must be a positive number of retries per second".into(),
+                    )
+                }
+            }
+        } else if let Some(val) = arg.strip_prefix("--retry-log=") {
+            config.retry_log = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--max-retries=") {
+            config.max_retries = Some(val.parse::<u32>()?);
+        } else if arg == "--no-reset-retries-on-progress" {
+            config.reset_retries_on_progress = false;
+        } else if let Some(val) = arg.strip_prefix("--connect-rate=") {
+            match val.parse::<f64>()? {
+                rate if rate > 0.0 && rate.is_finite() => config.connect_rate = Some(rate),
+                _ => {
+                    return Err(
+                        "--connect-rate must be a positive number of connections per second".into(),
+                    )
+                }
+            }
+        } else if let Some(val) = arg.strip_prefix("--inter-range-delay-ms=") {
+            config.inter_range_delay = Some(Duration::from_millis(val.parse::<u64>()?));
+        } else if let Some(val) = arg.strip_prefix("--max-redirects=") {
+            config.max_redirects = val.parse::<usize>()?;
+        } else if let Some(val) = arg.strip_prefix("--allow-redirect-host=") {
+            config.allow_redirect_hosts.push(val.trim().to_string());
+        } else if let Some(val) = arg.strip_prefix("--max-headers=") {
+            config.max_headers = match val.parse::<usize>()? {
+                0 => return Err("--max-headers must be at least 1".into()),
+                limit => limit,
+            };
+        } else if let Some(val) = arg.strip_prefix("--dump-raw=") {
+            config.dump_raw = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--max-size=") {
+            config.max_size = Some(val.parse::<usize>()?);
+        } else if let Some(val) = arg.strip_prefix("--hexdump-on-failure=") {
+            config.hexdump_on_failure = Some(val.parse::<usize>()?);
+        } else if let Some(val) = arg.strip_prefix("--diff-against=") {
+            config.diff_against = Some(val.to_string());
+        } else if arg == "--hash-only" {
+            config.hash_only = true;
+        } else if let Some(val) = arg.strip_prefix("--proxy=") {
+            config.proxy = Some(parse_proxy_url(val)?);
+        } else if let Some(val) = arg.strip_prefix("--manifest=") {
+            config.manifest = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--sources-file=") {
+            config.sources_file = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--report=") {
+            config.report = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--notify-url=") {
+            config.notify_url =
+                Some(Url::parse(val).map_err(|e| format!("Invalid --notify-url: {}", e))?);
+        } else if let Some(val) = arg.strip_prefix("--metrics-file=") {
+            config.metrics_file = Some(val.to_string());
+        } else if let Some(val) = arg.strip_prefix("--verify-only=") {
+            config.verify_only = Some(val.to_string());
+        } else if arg == "--probe-ranges" {
+            config.probe_ranges = true;
+        } else if arg == "--compare-remote" {
+            config.compare_remote = true;
+        } else if arg == "--verbose" {
+            config.verbose = true;
+        } else if arg == "--print-config" {
+            config.print_config = true;
+        } else if let Some(val) = arg.strip_prefix("--socks5=") {
+            config.socks5 = Some(Socks5Proxy::parse(val)?);
+        } else if arg == "--no-proxy" {
+            config.no_proxy = true;
+        } else if let Some(val) = arg.strip_prefix("--no-proxy-hosts=") {
+            config.no_proxy_hosts.extend(
+                val.split(',')
+                    .map(|host| host.trim().to_string())
+                    .filter(|host| !host.is_empty()),
+            );
+        } else if from_file {
+            return Err(format!(
+                "Unknown option '{}' in the --config file",
+                flag_name(&arg).trim_start_matches("--")
+            )
+            .into());
         }
     }
+    if config.no_proxy {
+        config.proxy = None;
+    }
+    if body_only {
+        if config.output.as_deref().is_some_and(|output| output != "-") || config.tee_stdout {
+            return Err("--body-only writes the body to stdout; drop --output/--tee-stdout".into());
+        }
+        config.output = Some("-".to_string());
+        config.no_verify = true;
+        config.quiet_on_success = true;
+    }
+    let expected_digests = config.expected_digests()?;
+    config.expected_inner_digest()?;
 
-    if config.expected_hash.is_empty() {
+    if expected_digests.is_empty()
+        && config.verify_inner_hash.is_none()
+        && config.trailer_checksum.is_none()
+        && config.manifest.is_none()
+        && config.sources_file.is_none()
+        && !config.no_verify
+        && !config.probe_ranges
+    {
         Err("Expected hash (--hash=<HASH>) is required".into())
+    } else if config.sources_file.is_some()
+        && (!expected_digests.is_empty()
+            || config.no_verify
+            || config.single_request
+            || config.head_first
+            || config.resume
+            || config.suffix.is_some()
+            || config.start_offset.is_some()
+            || config.hash_only
+            || config.pieces_file.is_some()
+            || config.stop_on_hash_match
+            || config.normalize_newlines
+            || config.compression == Compression::On
+            || config.manifest.is_some()
+            || config.verify_only.is_some()
+            || config.probe_ranges)
+    {
+        Err("--sources-file names the URLs and the hash itself and fetches byte ranges from each source, so it cannot be combined with --hash, --no-verify, --single-request, --head-first, --resume, --suffix, --start-offset, --hash-only, --pieces-file, --stop-on-hash-match, --normalize-newlines, --compression=on, --manifest, --verify-only or --probe-ranges".into())
+    } else if config.range_hash_url_template.is_some()
+        && (config.single_request
+            || config.suffix.is_some()
+            || config.hash_only
+            || config.sources_file.is_some()
+            || config.manifest.is_some()
+            || config.verify_only.is_some()
+            || config.probe_ranges)
+    {
+        Err("--range-hash-url-template checks the ranges of a single ranged download and cannot be combined with --single-request, --suffix, --hash-only, --sources-file, --manifest, --verify-only or --probe-ranges".into())
+    } else if config.start_offset.is_some()
+        && (config.resume
+            || config.single_request
+            || config.head_first
+            || config.suffix.is_some()
+            || config.hash_only
+            || config.pieces_file.is_some()
+            || config.trailer_checksum.is_some()
+            || config.manifest.is_some()
+            || config.verify_only.is_some()
+            || config.probe_ranges)
+    {
+        Err("--start-offset downloads from a byte range of its own and cannot be combined with --resume, --single-request, --head-first, --suffix, --hash-only, --pieces-file, --trailer-checksum, --manifest, --verify-only or --probe-ranges".into())
+    } else if config.no_verify && (config.output_template.is_some() || config.manifest.is_some()) {
+        Err("--no-verify cannot be combined with --output-template or --manifest".into())
+    } else if config.report.is_some() && config.manifest.is_none() {
+        Err("--report records manifest entries and requires --manifest=<PATH>".into())
+    } else if config.manifest.is_some() && (config.output.is_some() || config.tee_stdout) {
+        Err("--manifest saves entries with --output-template, not --output/--tee-stdout".into())
+    } else if config.verify_only.is_some()
+        && (config.no_verify
+            || config.manifest.is_some()
+            || config.output.is_some()
+            || config.output_template.is_some()
+            || config.resume)
+    {
+        Err("--verify-only checks an existing file and cannot be combined with --no-verify, --manifest, --output, --output-template or --resume".into())
+    } else if config.compare_remote && config.verify_only.is_none() {
+        Err("--compare-remote only applies with --verify-only=<PATH>".into())
+    } else if config.notify_url.is_some()
+        && (config.manifest.is_some() || config.verify_only.is_some() || config.probe_ranges)
+    {
+        Err("--notify-url reports a single download and cannot be combined with --manifest, --verify-only or --probe-ranges".into())
+    } else if config.metrics_file.is_some()
+        && (config.manifest.is_some() || config.verify_only.is_some() || config.probe_ranges)
+    {
+        Err("--metrics-file describes a single download and cannot be combined with --manifest, --verify-only or --probe-ranges".into())
+    } else if config.diff_against.is_some()
+        && (config.manifest.is_some() || config.verify_only.is_some() || config.probe_ranges)
+    {
+        Err("--diff-against compares a single download and cannot be combined with --manifest, --verify-only or --probe-ranges".into())
+    } else if config.credentials.is_some()
+        && (config.manifest.is_some() || config.sources_file.is_some())
+    {
+        Err("--user and --bearer-token authenticate to the --url host and cannot be combined with --manifest or --sources-file".into())
+    } else if config.sends_plaintext_credentials() && !config.allow_plaintext_credentials {
+        Err(format!(
+            "Refusing to send --user/--bearer-token credentials in the clear to http://{}; use an https:// --url or pass --allow-plaintext-credentials",
+            config.host
+        )
+        .into())
+    } else if (config.scheme == Scheme::Https
+        || config
+            .notify_url
+            .as_ref()
+            .is_some_and(|url| url.scheme == Scheme::Https))
+        && !cfg!(feature = "tls")
+    {
+        Err(TLS_UNAVAILABLE.into())
+    } else if config.tls_server_name.is_some() && config.scheme != Scheme::Https {
+        Err("--tls-servername only applies to an https:// --url".into())
+    } else if config.warn_cert_expiry_days.is_some() && config.scheme != Scheme::Https {
+        Err("--warn-cert-expiry-days only applies to an https:// --url".into())
+    } else if config.socks5.is_some() && (config.proxy.is_some() || config.no_proxy) {
+        Err("--socks5 cannot be combined with --proxy or --no-proxy".into())
+    } else if config.output.is_some() && config.output_template.is_some() {
+        Err("--output and --output-template cannot be combined".into())
+    } else if config.normalize_output && !config.normalize_newlines {
+        Err("--normalize-output requires --normalize-newlines".into())
+    } else if config.strict_close && config.keep_alive {
+        Err("--strict-close checks that the server closes every connection and cannot be combined with --keep-alive".into())
+    } else if config.recycle_after.is_some() && !config.keep_alive {
+        Err("--recycle-after only applies with --keep-alive".into())
+    } else if config.resume && matches!(config.output.as_deref(), None | Some("-")) {
+        Err("--resume requires --output=<PATH> pointing at the partial file".into())
+    } else if config.head_first && (config.single_request || config.resume) {
+        Err("--head-first picks the download mode itself and cannot be combined with --single-request or --resume".into())
+    } else if config.small_threshold.is_some() && !config.head_first {
+        Err("--small-threshold only applies with --head-first".into())
+    } else if config.head_retries.is_some() && !config.head_first {
+        Err("--head-retries only applies with --head-first".into())
+    } else if config.inter_range_delay.is_some() && config.single_request {
+        Err("--inter-range-delay-ms paces range requests and cannot be combined with --single-request".into())
+    } else if config.stop_on_hash_match
+        && (config.no_verify || config.single_request || config.normalize_newlines)
+    {
+        Err("--stop-on-hash-match hashes the raw ranges as they arrive and cannot be combined with --no-verify, --single-request or --normalize-newlines".into())
+    } else if config.suffix.is_some()
+        && (config.single_request
+            || config.head_first
+            || config.resume
+            || config.pieces_file.is_some()
+            || config.stop_on_hash_match
+            || config.compression == Compression::On
+            || config.manifest.is_some()
+            || config.verify_only.is_some()
+            || config.probe_ranges)
+    {
+        Err("--suffix fetches the last bytes with one range request and cannot be combined with --single-request, --head-first, --resume, --pieces-file, --stop-on-hash-match, --compression=on, --manifest, --verify-only or --probe-ranges".into())
+    } else if config.hash_only
+        && (config.no_verify
+            || config.output.is_some()
+            || config.output_template.is_some()
+            || config.single_request
+            || config.head_first
+            || config.resume
+            || config.pieces_file.is_some()
+            || config.stop_on_hash_match
+            || config.suffix.is_some()
+            || config.normalize_newlines
+            || config.compression == Compression::On
+            || config.diff_against.is_some()
+            || config.hexdump_on_failure.is_some()
+            || config.verify_inner_hash.is_some()
+            || config.manifest.is_some()
+            || config.verify_only.is_some()
+            || config.probe_ranges)
+    {
+        Err("--hash-only hashes ranges as they arrive and keeps no data, so it cannot be combined with --no-verify, --output, --output-template, --single-request, --head-first, --resume, --pieces-file, --stop-on-hash-match, --suffix, --normalize-newlines, --compression=on, --diff-against, --hexdump-on-failure, --verify-inner-hash, --manifest, --verify-only or --probe-ranges".into())
+    } else if config.pieces_file.is_some() != config.piece_length.is_some() {
+        Err("--pieces-file and --piece-length must be given together".into())
+    } else if !config.reset_retries_on_progress && config.max_retries.is_none() {
+        Err("--no-reset-retries-on-progress only changes how --max-retries counts; give --max-retries too".into())
+    } else if config.pieces_file.is_some()
+        && (config.single_request
+            || config.head_first
+            || config.stop_on_hash_match
+            || config.manifest.is_some())
+    {
+        Err("--pieces-file re-fetches bad pieces with range requests and cannot be combined with --single-request, --head-first, --stop-on-hash-match or --manifest".into())
+    } else if config.resume && config.single_request {
+        Err(
+            "--resume continues with a range request and cannot be combined with --single-request"
+                .into(),
+        )
+    } else if config.expected_prefix_hash.is_some() && !config.resume {
+        Err("--expected-prefix-hash only applies with --resume".into())
+    } else if config.max_size.is_some() && config.dump_raw.is_none() {
+        Err("--max-size caps the --dump-raw file and requires --dump-raw=<PATH>".into())
+    } else if config.decompress_gzip && config.output.is_none() {
+        Err("--decompress-gzip only applies to what is written to --output".into())
+    } else if config.decompress_gzip && config.resume {
+        Err("--resume continues the raw bytes and cannot be combined with --decompress-gzip".into())
+    } else if config.verify_inner_hash.is_some()
+        && (config.no_verify || config.manifest.is_some() || config.verify_only.is_some())
+    {
+        Err(
+            "--verify-inner-hash cannot be combined with --no-verify, --manifest or --verify-only"
+                .into(),
+        )
+    } else if config.trailer_checksum.is_some()
+        && (config.no_verify
+            || config.hash_only
+            || config.manifest.is_some()
+            || config.verify_only.is_some())
+    {
+        Err("--trailer-checksum verifies the downloaded bytes and cannot be combined with --no-verify, --hash-only, --manifest or --verify-only".into())
+    } else if !cfg!(feature = "gzip")
+        && (config.decompress_gzip
+            || config.verify_inner_hash.is_some()
+            || config.compression == Compression::On)
+    {
+        Err(GZIP_UNAVAILABLE.into())
+    } else if config.output_mode.is_some() && matches!(config.output.as_deref(), None | Some("-")) {
+        Err("--output-mode requires --output=<PATH> pointing at a file".into())
+    } else if config.tee_stdout && matches!(config.output.as_deref(), None | Some("-")) {
+        Err("--tee-stdout requires --output=<PATH> pointing at a file".into())
     } else {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::REDACTED;
+
+    fn parse(args: &[&str]) -> Result<Config, Box<dyn Error>> {
+        parse_args_from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_url_sets_host_port_and_path() {
+        let config =
+            parse(&["client", "--hash=abc", "--url=http://example.com:9000/file"]).unwrap();
+        assert_eq!(config.scheme, Scheme::Http);
+        assert_eq!(config.host, "example.com");
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.path, "/file");
+    }
+
+    #[test]
+    fn test_plaintext_credentials_are_refused_unless_allowed() {
+        let url = "--url=http://example.com/file";
+        let error = parse(&["client", "--hash=abc", url, "--user=me:s3cret"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("--allow-plaintext-credentials"));
+        assert!(parse(&["client", "--hash=abc", url, "--bearer-token=t0k3n"]).is_err());
+
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            url,
+            "--bearer-token=t0k3n",
+            "--allow-plaintext-credentials",
+        ])
+        .unwrap();
+        assert!(config.sends_plaintext_credentials());
+        assert_eq!(
+            config.redacted().credentials,
+            Some(Credentials::Bearer(REDACTED.to_string()))
+        );
+        assert!(!parse(&["client", "--hash=abc", url])
+            .unwrap()
+            .sends_plaintext_credentials());
+    }
+
+    #[test]
+    fn test_credentials_with_control_characters_are_rejected() {
+        let url = "--url=https://example.com/file";
+        for credentials in [
+            "--bearer-token=t0k3n\r\nX-Injected: 1",
+            "--user=me:s3cret\nX-Injected: 1",
+        ] {
+            let error = parse(&["client", "--hash=abc", url, credentials])
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.contains("must not contain control characters"),
+                "{}",
+                error
+            );
+            assert!(!error.contains("s3cret") && !error.contains("t0k3n"));
+        }
+    }
+
+    #[test]
+    fn test_referer_must_be_a_url() {
+        let config =
+            parse(&["client", "--hash=abc", "--referer=https://example.com/page"]).unwrap();
+        assert_eq!(config.referer.as_deref(), Some("https://example.com/page"));
+        assert!(parse(&["client", "--hash=abc", "--referer=not a url"]).is_err());
+        let error = parse(&[
+            "client",
+            "--hash=abc",
+            "--referer=http://example.com/\r\nX-Injected: 1",
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("control characters"));
+        assert!(parse(&["client", "--hash=abc"]).unwrap().referer.is_none());
+    }
+
+    #[test]
+    fn test_tee_stdout_requires_file_output() {
+        assert!(parse(&["client", "--hash=abc", "--tee-stdout"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--output=-", "--tee-stdout"]).is_err());
+        let config = parse(&["client", "--hash=abc", "--output=out.bin", "--tee-stdout"]).unwrap();
+        assert!(config.body_to_stdout());
+    }
+
+    #[test]
+    fn test_no_proxy_flag_overrides_explicit_and_env_proxy() {
+        let env = |name: &str| (name == "http_proxy").then(|| "http://env-proxy:3128".to_string());
+
+        let config = parse(&["client", "--hash=abc"]).unwrap();
+        let config = apply_env_proxy(config, env).unwrap();
+        assert_eq!(config.proxy.unwrap().host, "env-proxy");
+
+        let config = parse(&["client", "--hash=abc", "--proxy=http://cli-proxy:8080"]).unwrap();
+        let config = apply_env_proxy(config, env).unwrap();
+        assert_eq!(config.proxy.unwrap().host, "cli-proxy");
+
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--proxy=cli-proxy:8080",
+            "--no-proxy",
+        ])
+        .unwrap();
+        let config = apply_env_proxy(config, env).unwrap();
+        assert!(config.proxy.is_none());
+    }
+
+    #[test]
+    fn test_body_only_bundles_stdout_no_verify_and_quiet() {
+        let config = parse(&["client", "--body-only"]).unwrap();
+        assert_eq!(config.output.as_deref(), Some("-"));
+        assert!(config.no_verify);
+        assert!(config.quiet_on_success);
+        assert!(parse(&["client", "--body-only", "--output=file.bin"]).is_err());
+        assert!(parse(&["client", "--no-verify", "--output-template={hash}"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_only_needs_hash_and_excludes_downloading_flags() {
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--verify-only=file.bin",
+            "--compare-remote",
+        ])
+        .unwrap();
+        assert_eq!(config.verify_only.as_deref(), Some("file.bin"));
+        assert!(config.compare_remote);
+        assert!(parse(&["client", "--verify-only=file.bin"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--verify-only=a", "--output=b"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--compare-remote"]).is_err());
+    }
+
+    #[test]
+    fn test_compression_mode_and_single_request() {
+        let config = parse(&["client", "--hash=abc", "--single-request"]).unwrap();
+        assert!(config.single_request);
+        assert_eq!(config.compression, Compression::Auto);
+        let config = parse(&["client", "--hash=abc", "--compression=off"]).unwrap();
+        assert_eq!(config.compression, Compression::Off);
+        assert!(parse(&["client", "--hash=abc", "--compression=zstd"]).is_err());
+        assert!(parse(&[
+            "client",
+            "--hash=abc",
+            "--single-request",
+            "--resume",
+            "--output=file.bin"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_resume_requires_output_file() {
+        assert!(parse(&["client", "--hash=abc", "--resume"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--resume", "--output=-"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--expected-prefix-hash=ab"]).is_err());
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--output=part.bin",
+            "--resume",
+            "--expected-prefix-hash=AB",
+        ])
+        .unwrap();
+        assert_eq!(config.expected_prefix_hash.as_deref(), Some("ab"));
+    }
+
+    #[test]
+    fn test_max_size_requires_dump_raw() {
+        assert!(parse(&["client", "--hash=abc", "--max-size=100"]).is_err());
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--dump-raw=raw.bin",
+            "--max-size=100",
+        ])
+        .unwrap();
+        assert_eq!(config.dump_raw.as_deref(), Some("raw.bin"));
+        assert_eq!(config.max_size, Some(100));
+    }
+
+    #[test]
+    fn test_hash_format() {
+        let config = parse(&["client", "--hash=abc"]).unwrap();
+        assert_eq!(config.hash_format, HashFormat::Hex);
+        let config = parse(&["client", "--hash=abc", "--hash-format=sri"]).unwrap();
+        assert_eq!(config.hash_format, HashFormat::Sri);
+        assert!(parse(&["client", "--hash=abc", "--hash-format=hex8"]).is_err());
+    }
+
+    #[test]
+    fn test_checksum_algorithm_auto() {
+        let sha1 = format!("--hash={}", "a".repeat(40));
+        let config = parse(&["client", &sha1, "--checksum-algorithm-auto"]).unwrap();
+        assert_eq!(
+            config.algorithm_for(&config.expected_hash).unwrap(),
+            Algorithm::Sha1
+        );
+        assert!(parse(&["client", "--hash=abc", "--checksum-algorithm-auto"]).is_err());
+
+        let explicit = parse(&[
+            "client",
+            "--hash=abc",
+            "--checksum-algorithm-auto",
+            "--algo=sha512",
+        ])
+        .unwrap();
+        assert_eq!(
+            explicit.algorithm_for(&explicit.expected_hash).unwrap(),
+            Algorithm::Sha512
+        );
+        assert!(parse(&["client", "--hash=abc", "--algo=md5"]).is_err());
+    }
+
+    #[test]
+    fn test_prefixed_hashes_accumulate() {
+        let config = parse(&["client", "--hash=sha256:AAAA", "--hash=sha512:bbbb"]).unwrap();
+        assert_eq!(
+            config.expected_digests().unwrap(),
+            vec![
+                (Algorithm::Sha256, "aaaa".to_string()),
+                (Algorithm::Sha512, "bbbb".to_string())
+            ]
+        );
+        assert!(parse(&["client", "--hash=md5:abcd"]).is_err());
+    }
+
+    #[test]
+    fn test_manifest_replaces_hash_requirement() {
+        assert!(parse(&["client"]).is_err());
+        let config = parse(&["client", "--manifest=list.txt"]).unwrap();
+        assert_eq!(config.manifest.as_deref(), Some("list.txt"));
+        assert!(parse(&["client", "--manifest=list.txt", "--output=x.bin"]).is_err());
+    }
+
+    #[test]
+    fn test_no_proxy_hosts_list() {
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--no-proxy-hosts=a.internal, b.internal",
+        ])
+        .unwrap();
+        assert_eq!(config.no_proxy_hosts, ["a.internal", "b.internal"]);
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn test_https_url_without_tls_feature_errors() {
+        let result = parse(&["client", "--hash=abc", "--url=https://example.com/file"]);
+        let error_msg = result.err().unwrap().to_string();
+        assert!(error_msg.contains("HTTPS requested but TLS support not compiled in"));
+        assert!(error_msg.contains("enable the `tls` feature"));
+    }
+
+    #[test]
+    fn test_verify_inner_hash_replaces_hash_requirement() {
+        let result = parse(&["client", "--verify-inner-hash=ABC"]);
+        if cfg!(feature = "gzip") {
+            assert_eq!(result.unwrap().verify_inner_hash.as_deref(), Some("abc"));
+        } else {
+            assert!(result
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("gzip support not compiled in"));
+        }
+        assert!(parse(&["client", "--verify-inner-hash=abc", "--no-verify"]).is_err());
+    }
+
+    #[test]
+    fn test_equivalent_toml_and_json_configs_agree() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("client-config-{}.toml", std::process::id()));
+        let json_path = dir.join(format!("client-config-{}.json", std::process::id()));
+        std::fs::write(
+            &toml_path,
+            "url = \"http://example.com:9000/data.bin\"\nhash = \"ABC\"\nkeep-alive = true\n\
+             connect_timeout = 3\nretry-rate = 2.5\nallow-redirect-host = [\"a.test\", \"b.test\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &json_path,
+            r#"{"url": "http://example.com:9000/data.bin", "hash": "ABC", "keep-alive": true,
+                "connect_timeout": 3, "retry-rate": 2.5, "allow-redirect-host": ["a.test", "b.test"]}"#,
+        )
+        .unwrap();
+        let toml_arg = format!("--config={}", toml_path.display());
+        let json_arg = format!("--config={}", json_path.display());
+
+        let from_toml = parse(&["client", &toml_arg]);
+        let from_json = parse(&["client", &json_arg]);
+        let overridden = parse(&[
+            "client",
+            &json_arg,
+            "--port=9100",
+            "--allow-redirect-host=c.test",
+        ]);
+        let forced = parse(&["client", &json_arg, "--config-format=toml"]);
+        std::fs::remove_file(&toml_path).unwrap();
+        std::fs::remove_file(&json_path).unwrap();
+
+        let from_toml = from_toml.unwrap();
+        assert_eq!(from_toml, from_json.unwrap());
+        assert_eq!(from_toml.host, "example.com");
+        assert!(from_toml.keep_alive);
+        assert_eq!(from_toml.connect_timeout, Duration::from_secs(3));
+        assert_eq!(from_toml.allow_redirect_hosts, ["a.test", "b.test"]);
+        let overridden = overridden.unwrap();
+        assert_eq!(overridden.port, 9100);
+        assert_eq!(overridden.allow_redirect_hosts, ["c.test"]);
+        assert!(forced.is_err());
+        assert!(parse(&["client", "--hash=abc", "--config-format=json"]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_config_file_key_is_rejected() {
+        let path = std::env::temp_dir().join(format!("client-typo-{}.toml", std::process::id()));
+        std::fs::write(&path, "hash = \"abc\"\nkeep_alvie = true\n").unwrap();
+        let result = parse(&["client", &format!("--config={}", path.display())]);
+        std::fs::remove_file(&path).unwrap();
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Unknown option 'keep-alvie'"), "{}", error);
+    }
+
+    #[test]
+    fn test_print_config_redacts_secrets() {
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--url=http://example.com/data.bin?api_key=k3y&part=2",
+            "--notify-url=http://hooks.test/done?token=t0ken",
+            "--socks5=alice:s3cret@proxy.test:1080",
+            "--range-hash-url-template=http://hashes.test/{start}-{end}?access_token=r4nge",
+            "--print-config",
+        ])
+        .unwrap();
+        assert!(config.print_config);
+        let printed = format!("{:#?}", config.redacted());
+        for secret in ["k3y", "t0ken", "s3cret", "r4nge"] {
+            assert!(!printed.contains(secret), "{} leaked", secret);
+        }
+        assert!(printed.contains("/data.bin?api_key=<redacted>&part=2"));
+        assert!(printed.contains("/{start}-{end}?access_token=<redacted>"));
+        assert!(printed.contains("alice"));
+        assert_eq!(config.socks5.unwrap().credentials.unwrap().1, "s3cret");
+    }
+
+    #[test]
+    fn test_pieces_file_needs_a_piece_length() {
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--pieces-file=file.pieces",
+            "--piece-length=262144",
+        ])
+        .unwrap();
+        assert_eq!(config.piece_length, Some(262144));
+        assert!(parse(&["client", "--hash=abc", "--pieces-file=file.pieces"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--piece-length=0"]).is_err());
+    }
+
+    #[test]
+    fn test_output_mode_is_octal() {
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--output=key.pem",
+            "--output-mode=0600",
+        ]);
+        assert_eq!(config.unwrap().output_mode, Some(0o600));
+        assert!(parse(&[
+            "client",
+            "--hash=abc",
+            "--output=key.pem",
+            "--output-mode=0690"
+        ])
+        .is_err());
+        assert!(parse(&["client", "--hash=abc", "--output-mode=600"]).is_err());
+    }
+
+    #[test]
+    fn test_connection_strategy_toggles() {
+        assert!(!parse(&["client", "--hash=abc"]).unwrap().keep_alive);
+        assert!(
+            parse(&["client", "--hash=abc", "--shared-connection"])
+                .unwrap()
+                .keep_alive
+        );
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--shared-connection",
+            "--connection-per-range",
+        ]);
+        assert!(!config.unwrap().keep_alive);
+    }
+
+    #[test]
+    fn test_success_status_list() {
+        let config = parse(&["client", "--hash=abc"]).unwrap();
+        assert_eq!(config.success_statuses, [200, 206]);
+        let config = parse(&["client", "--hash=abc", "--success-status=200, 206,203"]).unwrap();
+        assert_eq!(config.success_statuses, [200, 206, 203]);
+        assert!(parse(&["client", "--hash=abc", "--success-status=200,ok"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--success-status=600"]).is_err());
+    }
+
+    #[test]
+    fn test_retry_reset_toggle_needs_max_retries() {
+        let config = parse(&["client", "--hash=abc", "--max-retries=3"]).unwrap();
+        assert_eq!(config.max_retries, Some(3));
+        assert!(config.reset_retries_on_progress);
+        let config = parse(&[
+            "client",
+            "--hash=abc",
+            "--max-retries=3",
+            "--no-reset-retries-on-progress",
+        ])
+        .unwrap();
+        assert!(!config.reset_retries_on_progress);
+        assert!(parse(&["client", "--hash=abc", "--no-reset-retries-on-progress"]).is_err());
+    }
+
+    #[test]
+    fn test_range_hash_url_template_needs_both_placeholders() {
+        let template = "--range-hash-url-template=http://host/hash?range={start}-{end}";
+        let config = parse(&["client", "--hash=abc", template]).unwrap();
+        assert_eq!(
+            config.range_hash_url_template.as_deref(),
+            Some("http://host/hash?range={start}-{end}")
+        );
+        assert!(parse(&[
+            "client",
+            "--hash=abc",
+            "--range-hash-url-template=http://host/hash?from={start}"
+        ])
+        .is_err());
+        assert!(parse(&["client", "--hash=abc", template, "--single-request"]).is_err());
+    }
+
+    #[test]
+    fn test_sources_file_replaces_hash_and_url() {
+        let config = parse(&["client", "--sources-file=mirrors.txt"]).unwrap();
+        assert_eq!(config.sources_file.as_deref(), Some("mirrors.txt"));
+        assert!(parse(&["client", "--sources-file=mirrors.txt", "--hash=abc"]).is_err());
+        assert!(parse(&["client", "--sources-file=mirrors.txt", "--single-request"]).is_err());
+    }
+
+    #[test]
+    fn test_trailer_checksum_replaces_hash_requirement() {
+        let config = parse(&["client", "--trailer-checksum=crc32:4"]).unwrap();
+        assert_eq!(config.trailer_checksum.unwrap().length, 4);
+        assert!(parse(&["client", "--trailer-checksum=crc32:4", "--no-verify"]).is_err());
+    }
+
+    #[test]
+    fn test_hash_only_keeps_no_data() {
+        assert!(
+            parse(&["client", "--hash=abc", "--hash-only"])
+                .unwrap()
+                .hash_only
+        );
+        assert!(parse(&["client", "--hash=abc", "--hash-only", "--output=out.bin"]).is_err());
+        assert!(parse(&["client", "--hash-only", "--no-verify"]).is_err());
+    }
+
+    #[test]
+    fn test_start_offset_excludes_resume() {
+        let config = parse(&["client", "--hash=abc", "--start-offset=4096"]).unwrap();
+        assert_eq!(config.start_offset, Some(4096));
+        assert!(parse(&["client", "--hash=abc", "--start-offset=-1"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--start-offset=4096", "--resume"]).is_err());
+    }
+
+    #[test]
+    fn test_suffix_is_its_own_mode() {
+        assert_eq!(
+            parse(&["client", "--hash=abc", "--suffix=512"])
+                .unwrap()
+                .suffix,
+            Some(512)
+        );
+        assert!(parse(&["client", "--hash=abc", "--suffix=0"]).is_err());
+        assert!(parse(&["client", "--hash=abc", "--suffix=512", "--single-request"]).is_err());
+    }
+
+    #[test]
+    fn test_diff_against_needs_a_single_download() {
+        let config = parse(&["client", "--no-verify", "--diff-against=good.bin"]).unwrap();
+        assert_eq!(config.diff_against.as_deref(), Some("good.bin"));
+        assert!(parse(&["client", "--manifest=list.txt", "--diff-against=good.bin"]).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::error::Error;
+
+use crate::base64;
+use crate::config::REDACTED;
+
+/// Credentials sent in an `Authorization` header: `--user` for Basic auth or `--bearer-token`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Parses `--user=<USER>:<PASSWORD>`; the password may itself contain colons.
+    pub fn parse_user(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.split_once(':') {
+            Some((user, password)) if !user.is_empty() => Ok(Credentials::Basic {
+                user: user.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(format!("--user must be <USER>:<PASSWORD>, got '{}'", value).into()),
+        }
+    }
+
+    /// The `Authorization` header value.
+    pub fn header_value(&self) -> String {
+        match self {
+            Credentials::Basic { user, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", user, password).as_bytes())
+            ),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+        }
+    }
+
+    /// The same credentials with the password or token replaced by `REDACTED`.
+    pub fn redacted(&self) -> Credentials {
+        match self {
+            Credentials::Basic { user, .. } => Credentials::Basic {
+                user: user.clone(),
+                password: REDACTED.to_string(),
+            },
+            Credentials::Bearer(_) => Credentials::Bearer(REDACTED.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_and_bearer_header_values() {
+        let basic = Credentials::parse_user("Aladdin:open:sesame").unwrap();
+        assert_eq!(basic.header_value(), "Basic QWxhZGRpbjpvcGVuOnNlc2FtZQ==");
+        assert_eq!(
+            Credentials::Bearer("t0k3n".to_string()).header_value(),
+            "Bearer t0k3n"
+        );
+        assert!(Credentials::parse_user("no-password").is_err());
+        assert!(Credentials::parse_user(":password").is_err());
+        assert_eq!(
+            basic.redacted(),
+            Credentials::Basic {
+                user: "Aladdin".to_string(),
+                password: REDACTED.to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::args::apply_env_proxy;
+use crate::config::Config;
+use crate::download::{address_cache, build_client, download_with_client};
+use crate::dump::open_raw_dump;
+use crate::hex;
+use crate::http_client::HttpClient;
+use crate::output::{save_with_template, write_atomically, Diagnostics};
+use crate::pool::{ConnectionPool, PoolStats};
+use crate::resolve::ResolveStats;
+use crate::retry::RetryBudget;
+use crate::url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub expected_hash: String,
+    pub url: Url,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub verified: usize,
+    /// Entries that failed; only non-zero with `--report`, which keeps going past failures.
+    pub failed: usize,
+    pub connections: PoolStats,
+    pub addresses: ResolveStats,
+}
+
+/// How one manifest entry went, as written to the `--report` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryReport {
+    pub url: String,
+    pub expected_hash: String,
+    /// The digest of the downloaded data; `None` when the download itself failed.
+    pub computed_hash: Option<String>,
+    /// `verified`, `hash mismatch`, or the error that stopped the entry.
+    pub status: String,
+    pub passed: bool,
+}
+
+/// Parses a manifest of `<sha256-hex> <url>` lines; blank lines and `#` comments are skipped.
+pub fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(hash), Some(url), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(format!(
+                "Manifest line {}: expected '<hash> <url>', got '{}'",
+                index + 1,
+                line
+            )
+            .into());
+        };
+        let url = Url::parse(url).map_err(|e| format!("Manifest line {}: {}", index + 1, e))?;
+        entries.push(ManifestEntry {
+            expected_hash: hash.to_ascii_lowercase(),
+            url,
+        });
+    }
+    Ok(entries)
+}
+
+/// The config for one manifest entry: its URL and hash, with the proxy from the environment
+/// picked for that URL, since entries may be on other hosts or schemes or covered by `NO_PROXY`.
+fn entry_config<F>(config: &Config, entry: &ManifestEntry, env: F) -> Result<Config, Box<dyn Error>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let entry_config = Config {
+        scheme: entry.url.scheme,
+        host: entry.url.host.clone(),
+        port: entry.url.port,
+        path: entry.url.path.clone(),
+        expected_hash: entry.expected_hash.clone(),
+        ..config.clone()
+    };
+    apply_env_proxy(entry_config, env)
+}
+
+/// Downloads and verifies every manifest entry in order, stopping at the first failure. With
+/// `--report`, failed entries are recorded instead and the run continues; the report lists every
+/// entry once the run is over.
+///
+/// All entries share one connection pool and address cache, so entries on the same host reuse
+/// resolved addresses (and, with `--keep-alive`, connections). `--retry-rate` and
+/// `--connect-rate` cap the whole run, not each entry.
+pub fn run_manifest(
+    config: &Config,
+    entries: &[ManifestEntry],
+) -> Result<BatchSummary, Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    let pool = Arc::new(ConnectionPool::with_idle_timeout(
+        config.keepalive_idle_timeout,
+    ));
+    let addresses = Arc::new(address_cache(config));
+    let retry_budget = config
+        .retry_rate
+        .map(|rate| Arc::new(RetryBudget::new(rate)));
+    let connect_rate = config
+        .connect_rate
+        .map(|rate| Arc::new(RetryBudget::new(rate)));
+    let raw_dump = open_raw_dump(config)?;
+    let mut reports = Vec::with_capacity(entries.len());
+
+    for (index, entry) in entries.iter().enumerate() {
+        let entry_config = entry_config(config, entry, |name| env::var(name).ok())?;
+        diag.println(format_args!(
+            "\n[{}/{}] {}",
+            index + 1,
+            entries.len(),
+            entry.url
+        ));
+        let client = build_client(&entry_config)
+            .with_pool(Arc::clone(&pool))
+            .with_address_cache(Arc::clone(&addresses))
+            .with_retry_budget(retry_budget.clone())
+            .with_connect_rate(connect_rate.clone())
+            .with_raw_dump(raw_dump.clone());
+        let mut report = EntryReport {
+            url: entry.url.to_string(),
+            expected_hash: entry.expected_hash.clone(),
+            computed_hash: None,
+            status: String::new(),
+            passed: true,
+        };
+        if let Err(e) = verify_entry(config, &entry_config, &client, entry, &mut report) {
+            report.passed = false;
+            if config.report.is_none() {
+                return Err(format!("Entry {} ({}): {}", index + 1, entry.url, e).into());
+            }
+            eprintln!("Entry {} ({}) failed: {}", index + 1, entry.url, e);
+            if report.status.is_empty() {
+                report.status = e.to_string();
+            }
+        }
+        reports.push(report);
+    }
+
+    if let Some(path) = &config.report {
+        write_atomically(
+            Path::new(path),
+            format_report(&reports).as_bytes(),
+            config.fsync,
+        )
+        .map_err(|e| format!("Failed to write report {}: {}", path, e))?;
+        diag.println(format_args!("Wrote report to {}", path));
+    }
+    let failed = reports.iter().filter(|report| !report.passed).count();
+    Ok(BatchSummary {
+        verified: reports.len() - failed,
+        failed,
+        connections: pool.stats(),
+        addresses: addresses.stats(),
+    })
+}
+
+/// Downloads one entry, checks its hash and saves it, filling in `report` along the way.
+fn verify_entry(
+    config: &Config,
+    entry_config: &Config,
+    client: &HttpClient,
+    entry: &ManifestEntry,
+    report: &mut EntryReport,
+) -> Result<(), Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    let algorithm = config.algorithm_for(&entry.expected_hash)?;
+    let download = download_with_client(entry_config, client, algorithm.new_hasher())?;
+
+    let digest_hex = hex::encode(&download.digest);
+    report.computed_hash = Some(digest_hex.clone());
+    let data = download.data.as_deref().unwrap_or_default();
+    if digest_hex != entry.expected_hash {
+        if let Some(limit) = config.hexdump_on_failure {
+            let shown = &data[..limit.min(data.len())];
+            eprint!("First {} bytes:\n{}", shown.len(), hex::hexdump(shown));
+        }
+        if let Some(warning) = download.html_warning() {
+            eprintln!("{}", warning);
+        }
+        report.status = "hash mismatch".to_string();
+        return Err(format!(
+            "hash mismatch, expected {} but downloaded data hashes to {}",
+            entry.expected_hash, digest_hex
+        )
+        .into());
+    }
+    diag.println(format_args!("Verified {}: {}", algorithm, digest_hex));
+    if let Some(template) = &config.output_template {
+        let path = save_with_template(template, &entry.url.path, &digest_hex, data, config.fsync)?;
+        diag.println(format_args!("Saved verified data to {}", path));
+    }
+    report.status = "verified".to_string();
+    Ok(())
+}
+
+/// Renders the `--report` CSV: a header row, then one row per entry in manifest order.
+pub fn format_report(reports: &[EntryReport]) -> String {
+    let mut csv = String::from("url,expected_hash,computed_hash,status,result\n");
+    for report in reports {
+        let fields = [
+            report.url.as_str(),
+            report.expected_hash.as_str(),
+            report.computed_hash.as_deref().unwrap_or(""),
+            report.status.as_str(),
+            if report.passed { "pass" } else { "fail" },
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, spawn_server};
+    use sha2::{Digest, Sha256};
+
+    /// Entries on 127.0.0.1 are fetched directly whatever proxy the environment sets.
+    fn test_config() -> Config {
+        Config {
+            no_proxy: true,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let text = "# mirror list\n\
+                    AAAA http://example.com/a.bin\n\
+                    \n\
+                    bbbb   https://example.com:8443/b.bin\n";
+        let entries = parse_manifest(text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].expected_hash, "aaaa");
+        assert_eq!(entries[0].url.path, "/a.bin");
+        assert_eq!(entries[1].url.port, 8443);
+
+        assert!(parse_manifest("aaaa").is_err());
+        assert!(parse_manifest("aaaa http://example.com/ extra").is_err());
+        assert!(parse_manifest("aaaa ftp://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_env_proxy_is_picked_for_each_entry_url() {
+        let env = |name: &str| match name {
+            "http_proxy" => Some("http://plain-proxy:3128".to_string()),
+            "https_proxy" => Some("http://tls-proxy:3128".to_string()),
+            "no_proxy" => Some("internal.example".to_string()),
+            _ => None,
+        };
+        let entries = parse_manifest(
+            "aaaa http://example.com/a
+\
+             bbbb https://example.com/b
+\
+             cccc http://internal.example/c
+",
+        )
+        .unwrap();
+        let proxy_host = |entry| {
+            entry_config(&Config::default(), entry, env)
+                .unwrap()
+                .proxy
+                .map(|proxy| proxy.host)
+        };
+
+        assert_eq!(proxy_host(&entries[0]).as_deref(), Some("plain-proxy"));
+        assert_eq!(proxy_host(&entries[1]).as_deref(), Some("tls-proxy"));
+        assert_eq!(proxy_host(&entries[2]), None);
+        let explicit = Config {
+            no_proxy: true,
+            ..Config::default()
+        };
+        assert!(entry_config(&explicit, &entries[0], env)
+            .unwrap()
+            .proxy
+            .is_none());
+    }
+
+    #[test]
+    fn test_entries_on_one_host_share_a_resolved_address() {
+        let (port, server) = spawn_server(vec![
+            response("200 OK", "", b"first"),
+            response("200 OK", "", b"second"),
+        ]);
+        let manifest = format!(
+            "{} http://127.0.0.1:{port}/a\n{} http://127.0.0.1:{port}/b\n",
+            hex::encode(&Sha256::digest(b"first")),
+            hex::encode(&Sha256::digest(b"second")),
+        );
+        let entries = parse_manifest(&manifest).unwrap();
+
+        let summary = run_manifest(&test_config(), &entries).unwrap();
+
+        assert_eq!(summary.verified, 2);
+        assert_eq!(
+            summary.addresses,
+            ResolveStats {
+                lookups: 2,
+                resolutions: 1
+            }
+        );
+        assert_eq!(summary.connections.opened, 2);
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /a HTTP/1.1"));
+        assert!(requests[1].starts_with("GET /b HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_batch_stops_at_hash_mismatch() {
+        let (port, server) = spawn_server(vec![response("200 OK", "", b"tampered")]);
+        let manifest = format!(
+            "{} http://127.0.0.1:{}/a\n{} http://127.0.0.1:{}/b\n",
+            hex::encode(&Sha256::digest(b"original")),
+            port,
+            hex::encode(&Sha256::digest(b"other")),
+            port
+        );
+        let entries = parse_manifest(&manifest).unwrap();
+
+        let error_msg = run_manifest(&test_config(), &entries)
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.starts_with("Entry 1"));
+        assert!(error_msg.contains("hash mismatch"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_report_records_every_entry_and_continues_past_failures() {
+        let (port, server) = spawn_server(vec![
+            response("200 OK", "", b"tampered"),
+            response("404 Not Found", "", b""),
+            response("200 OK", "", b"third"),
+        ]);
+        let manifest = format!(
+            "{} http://127.0.0.1:{port}/a\n{} http://127.0.0.1:{port}/b\n{} http://127.0.0.1:{port}/c\n",
+            hex::encode(&Sha256::digest(b"original")),
+            hex::encode(&Sha256::digest(b"missing")),
+            hex::encode(&Sha256::digest(b"third")),
+        );
+        let entries = parse_manifest(&manifest).unwrap();
+        let path = std::env::temp_dir().join(format!("client-report-{}.csv", std::process::id()));
+        let config = Config {
+            report: Some(path.to_string_lossy().into_owned()),
+            ..test_config()
+        };
+
+        let summary = run_manifest(&config, &entries).unwrap();
+        server.join().unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((summary.verified, summary.failed), (1, 2));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "url,expected_hash,computed_hash,status,result");
+        assert_eq!(
+            lines[1],
+            format!(
+                "http://127.0.0.1:{}/a,{},{},hash mismatch,fail",
+                port,
+                entries[0].expected_hash,
+                hex::encode(&Sha256::digest(b"tampered"))
+            )
+        );
+        assert_eq!(
+            lines[2],
+            format!(
+                "http://127.0.0.1:{}/b,{},,Server returned non-successful status: 404,fail",
+                port, entries[1].expected_hash
+            )
+        );
+        assert_eq!(
+            lines[3],
+            format!(
+                "http://127.0.0.1:{}/c,{},{},verified,pass",
+                port, entries[2].expected_hash, entries[2].expected_hash
+            )
+        );
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_csv_field_quotes_separators() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::http_client::HttpResponse;
+
+/// `--response-cache`: successful answers kept for the rest of the run, keyed by URL and the
+/// whole request head (method, range and every header), so an identical request is answered
+/// without going back to the server.
+///
+/// Clients share one cache through an `Arc`, like the connection pool. Every cached body stays
+/// in memory until the cache is cleared, so this only suits runs that repeat requests.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, HttpResponse>>,
+}
+
+impl ResponseCache {
+    pub fn get(&self, key: &str) -> Option<HttpResponse> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, response: &HttpResponse) {
+        self.entries.lock().unwrap().insert(key, response.clone());
+    }
+
+    /// Forgets every answer, e.g. after one of them failed verification.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
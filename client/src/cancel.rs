@@ -0,0 +1,72 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The error a download stops with once its token is cancelled.
+pub const CANCELLED: &str = "Download cancelled";
+
+/// Lets another thread stop a download while it is pausing, e.g. for `--inter-range-delay-ms`,
+/// or reading a long body. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Marks the token cancelled and wakes every thread sleeping on it.
+    pub fn cancel(&self) {
+        let (cancelled, wakeup) = &*self.state;
+        *cancelled.lock().unwrap() = true;
+        wakeup.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Sleeps for `duration` unless cancelled first; returns `false` when cancelled.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let (cancelled, wakeup) = &*self.state;
+        let mut cancelled = cancelled.lock().unwrap();
+        while !*cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            cancelled = wakeup.wait_timeout(cancelled, deadline - now).unwrap().0;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_sleep_runs_its_course_without_cancel() {
+        let started = Instant::now();
+        assert!(CancelToken::new().sleep(Duration::from_millis(50)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_cancel_wakes_a_sleeping_thread() {
+        let token = CancelToken::new();
+        let sleeper = {
+            let token = token.clone();
+            thread::spawn(move || token.sleep(Duration::from_secs(10)))
+        };
+        let started = Instant::now();
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+        assert!(!sleeper.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(token.is_cancelled());
+    }
+}
@@ -1,21 +1,333 @@
+use crate::auth::Credentials;
+use crate::download::RangeCallback;
+use crate::encoding::Compression;
+use crate::hashing::{Algorithm, HashFormat};
+use crate::http_client::{TlsVersion, DEFAULT_MAX_HEADERS};
+use crate::proxy::Socks5Proxy;
+use crate::resolve::AddressOrder;
+use crate::retry::RetryPolicy;
+use crate::sources::SourceList;
+use crate::trailer::TrailerChecksum;
+use crate::url::{Scheme, Url};
+use std::error::Error;
 use std::time::Duration;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
+    pub scheme: Scheme,
     pub host: String,
     pub port: u16,
+    pub path: String,
     pub expected_hash: String,
+    pub prefixed_hashes: Vec<(Algorithm, String)>,
+    pub algorithm: Option<Algorithm>,
+    pub checksum_algorithm_auto: bool,
+    pub hash_format: HashFormat,
     pub connect_timeout: Duration,
     pub read_write_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+    pub stall_timeout: Option<Duration>,
+    pub tls_handshake_timeout: Duration,
+    pub tls_server_name: Option<String>,
+    pub min_tls_version: TlsVersion,
+    pub warn_cert_expiry_days: Option<u64>,
+    pub bind_port: Option<u16>,
+    pub recv_buffer: Option<usize>,
+    pub address_order: AddressOrder,
+    pub dns_cache_size: Option<usize>,
+    pub dns_ttl: Option<Duration>,
+    pub dns_timeout: Option<Duration>,
+    pub verify_chunk_sha: bool,
+    /// `--range-hash-url-template`: where to fetch each range's expected hash, with `{start}`
+    /// and `{end}` (inclusive) filled in.
+    pub range_hash_url_template: Option<String>,
+    pub normalize_newlines: bool,
+    pub normalize_output: bool,
+    pub referer: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub allow_plaintext_credentials: bool,
+    pub single_request: bool,
+    pub suffix: Option<usize>,
+    /// `--start-offset`: the byte to start downloading from, for callers that keep the earlier
+    /// bytes themselves. The hash then covers only the bytes from here on.
+    pub start_offset: Option<usize>,
+    pub head_first: bool,
+    pub small_threshold: Option<u64>,
+    pub head_retries: Option<u32>,
+    pub compression: Compression,
+    pub keep_alive: bool,
+    pub strict_close: bool,
+    pub response_cache: bool,
+    pub keepalive_idle_timeout: Option<Duration>,
+    pub recycle_after: Option<usize>,
+    pub output: Option<String>,
+    pub output_mode: Option<u32>,
+    pub no_verify: bool,
+    pub quiet_on_success: bool,
+    /// Library-only: print nothing, not even warnings. `Downloader::new` sets it.
+    pub silent: bool,
+    pub resume: bool,
+    pub expected_prefix_hash: Option<String>,
+    pub tee_stdout: bool,
+    pub decompress_gzip: bool,
+    pub verify_inner_hash: Option<String>,
+    pub trailer_checksum: Option<TrailerChecksum>,
+    pub fsync: bool,
+    pub output_template: Option<String>,
+    pub fail_on_empty: bool,
+    pub fail_on_html: bool,
+    /// Statuses whose body is accepted (`--success-status`); any other non-redirect is fatal.
+    pub success_statuses: Vec<u16>,
+    pub stop_on_hash_match: bool,
+    pub pieces_file: Option<String>,
+    pub piece_length: Option<u64>,
+    pub retry_rate: Option<f64>,
+    pub connect_rate: Option<f64>,
+    /// Library-only: classifies failed requests; `None` uses `RetryPolicy::default()`.
+    pub retry_policy: Option<RetryPolicy>,
+    pub retry_log: Option<String>,
+    /// Network-error retries allowed before giving up; `None` leaves it to the retry policy.
+    pub max_retries: Option<u32>,
+    pub reset_retries_on_progress: bool,
+    /// Library-only: receives each range as the download accepts it.
+    pub on_range_complete: Option<RangeCallback>,
+    pub inter_range_delay: Option<Duration>,
+    pub max_redirects: usize,
+    pub allow_redirect_hosts: Vec<String>,
+    pub max_headers: usize,
+    pub dump_raw: Option<String>,
+    pub max_size: Option<usize>,
+    pub hexdump_on_failure: Option<usize>,
+    pub diff_against: Option<String>,
+    pub hash_only: bool,
+    pub proxy: Option<Url>,
+    pub no_proxy: bool,
+    pub no_proxy_hosts: Vec<String>,
+    pub socks5: Option<Socks5Proxy>,
+    pub manifest: Option<String>,
+    pub sources_file: Option<String>,
+    /// The mirrors to fetch segments from, read from `--sources-file` (or set by library users).
+    pub sources: Option<SourceList>,
+    pub report: Option<String>,
+    pub notify_url: Option<Url>,
+    pub metrics_file: Option<String>,
+    pub verify_only: Option<String>,
+    pub probe_ranges: bool,
+    pub compare_remote: bool,
+    pub verbose: bool,
+    pub print_config: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            scheme: Scheme::Http,
             host: "127.0.0.1".to_string(),
             port: 8080,
+            path: "/".to_string(),
             expected_hash: String::new(), // обязательное поле, нет умолчания
+            prefixed_hashes: Vec::new(),
+            algorithm: None,
+            checksum_algorithm_auto: false,
+            hash_format: HashFormat::Hex,
             connect_timeout: Duration::from_secs(5),
             read_write_timeout: Duration::from_secs(15),
+            request_timeout: None,
+            stall_timeout: None,
+            tls_handshake_timeout: Duration::from_secs(10),
+            tls_server_name: None,
+            min_tls_version: TlsVersion::default(),
+            warn_cert_expiry_days: None,
+            bind_port: None,
+            recv_buffer: None,
+            address_order: AddressOrder::Resolver,
+            dns_cache_size: None,
+            dns_ttl: None,
+            dns_timeout: None,
+            verify_chunk_sha: false,
+            range_hash_url_template: None,
+            normalize_newlines: false,
+            normalize_output: false,
+            referer: None,
+            credentials: None,
+            allow_plaintext_credentials: false,
+            single_request: false,
+            suffix: None,
+            start_offset: None,
+            head_first: false,
+            small_threshold: None,
+            head_retries: None,
+            compression: Compression::Auto,
+            keep_alive: false,
+            strict_close: false,
+            response_cache: false,
+            keepalive_idle_timeout: None,
+            recycle_after: None,
+            output: None,
+            output_mode: None,
+            no_verify: false,
+            quiet_on_success: false,
+            silent: false,
+            resume: false,
+            expected_prefix_hash: None,
+            tee_stdout: false,
+            decompress_gzip: false,
+            verify_inner_hash: None,
+            trailer_checksum: None,
+            fsync: false,
+            output_template: None,
+            fail_on_empty: false,
+            fail_on_html: false,
+            success_statuses: vec![200, 206],
+            stop_on_hash_match: false,
+            pieces_file: None,
+            piece_length: None,
+            retry_rate: None,
+            connect_rate: None,
+            retry_policy: None,
+            retry_log: None,
+            max_retries: None,
+            reset_retries_on_progress: true,
+            on_range_complete: None,
+            inter_range_delay: None,
+            max_redirects: 5,
+            allow_redirect_hosts: Vec::new(),
+            max_headers: DEFAULT_MAX_HEADERS,
+            dump_raw: None,
+            max_size: None,
+            hexdump_on_failure: None,
+            diff_against: None,
+            hash_only: false,
+            proxy: None,
+            no_proxy: false,
+            no_proxy_hosts: Vec::new(),
+            socks5: None,
+            manifest: None,
+            sources_file: None,
+            sources: None,
+            report: None,
+            notify_url: None,
+            metrics_file: None,
+            verify_only: None,
+            probe_ranges: false,
+            compare_remote: false,
+            verbose: false,
+            print_config: false,
+        }
+    }
+}
+
+/// Shown by `--print-config` in place of a secret.
+pub const REDACTED: &str = "<redacted>";
+
+/// Query parameters whose values `--print-config` hides, matched as substrings of the name.
+const SECRET_PARAMS: [&str; 7] = [
+    "auth",
+    "credential",
+    "key",
+    "password",
+    "secret",
+    "sig",
+    "token",
+];
+
+/// Replaces the value of every secret-looking query parameter in `path` with `REDACTED`.
+fn redact_query(path: &str) -> String {
+    let Some((base, query)) = path.split_once('?') else {
+        return path.to_string();
+    };
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _))
+                if SECRET_PARAMS
+                    .iter()
+                    .any(|secret| name.to_ascii_lowercase().contains(secret)) =>
+            {
+                format!("{}={}", name, REDACTED)
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, params.join("&"))
+}
+
+impl Config {
+    /// A copy safe to print with `--print-config`: the `--user` password, `--bearer-token`, SOCKS5
+    /// password and secret-looking query parameters of the URLs are replaced with `REDACTED`.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        config.path = redact_query(&config.path);
+        config.referer = config.referer.as_deref().map(redact_query);
+        config.range_hash_url_template =
+            config.range_hash_url_template.as_deref().map(redact_query);
+        config.credentials = config.credentials.as_ref().map(Credentials::redacted);
+        if let Some(url) = &mut config.notify_url {
+            url.path = redact_query(&url.path);
+        }
+        if let Some(url) = &mut config.proxy {
+            url.path = redact_query(&url.path);
+        }
+        if let Some((_, password)) = config
+            .socks5
+            .as_mut()
+            .and_then(|proxy| proxy.credentials.as_mut())
+        {
+            *password = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Whether `--user`/`--bearer-token` credentials would cross the network unencrypted.
+    pub fn sends_plaintext_credentials(&self) -> bool {
+        self.credentials.is_some() && self.scheme == Scheme::Http
+    }
+
+    pub fn body_to_stdout(&self) -> bool {
+        self.output.as_deref() == Some("-") || self.tee_stdout
+    }
+
+    /// Whether a redirect may lead to `host`: always for the original host, otherwise only when
+    /// no `--allow-redirect-host` list was given or the host is on it.
+    pub fn redirect_allowed(&self, host: &str) -> bool {
+        self.allow_redirect_hosts.is_empty()
+            || host.eq_ignore_ascii_case(&self.host)
+            || self
+                .allow_redirect_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// Every digest the download must match: the plain `--hash` (if any) with its algorithm,
+    /// followed by each `--hash=<algo>:<hex>`.
+    pub fn expected_digests(&self) -> Result<Vec<(Algorithm, String)>, Box<dyn Error>> {
+        let mut digests = Vec::new();
+        if !self.expected_hash.is_empty() {
+            digests.push((
+                self.algorithm_for(&self.expected_hash)?,
+                self.expected_hash.clone(),
+            ));
+        }
+        digests.extend(self.prefixed_hashes.iter().cloned());
+        Ok(digests)
+    }
+
+    /// The `--verify-inner-hash` digest of the gunzipped data, with its algorithm chosen like
+    /// `expected_hash`'s.
+    pub fn expected_inner_digest(&self) -> Result<Option<(Algorithm, String)>, Box<dyn Error>> {
+        match &self.verify_inner_hash {
+            Some(hash) => Ok(Some((self.algorithm_for(hash)?, hash.clone()))),
+            None => Ok(None),
+        }
+    }
+
+    /// The algorithm that verifies `expected_hash`: `--algo` if given, inferred from the hash
+    /// length with `--checksum-algorithm-auto`, SHA-256 otherwise.
+    pub fn algorithm_for(&self, expected_hash: &str) -> Result<Algorithm, Box<dyn Error>> {
+        match self.algorithm {
+            Some(algorithm) => Ok(algorithm),
+            None if self.checksum_algorithm_auto => Algorithm::from_hex_len(expected_hash),
+            None => Ok(Algorithm::Sha256),
         }
     }
 }
@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The syntax of a `--config` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Parses a `--config-format` value: `toml` or `json`.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value.to_ascii_lowercase().as_str() {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!("Unknown config format {:?} (use toml or json)", value).into()),
+        }
+    }
+
+    /// Picks the format from a `.toml` or `.json` extension.
+    pub fn from_path(path: &str) -> Result<Self, Box<dyn Error>> {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Ok(ConfigFormat::Toml),
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(ConfigFormat::Json),
+            _ => Err(format!(
+                "Cannot tell the format of {} from its extension; pass --config-format=toml|json",
+                path
+            )
+            .into()),
+        }
+    }
+}
+
+/// One option of a config file. Keys are the command-line flags without the leading `--`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Switch(bool),
+    Integer(i64),
+    Number(f64),
+    Text(String),
+    /// A repeatable flag such as `hash` or `allow-redirect-host`.
+    List(Vec<String>),
+}
+
+/// A `--config` file, deserialized the same way from TOML and JSON.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct ConfigFile {
+    pub options: BTreeMap<String, OptionValue>,
+}
+
+impl ConfigFile {
+    pub fn parse(text: &str, format: ConfigFormat) -> Result<Self, Box<dyn Error>> {
+        match format {
+            ConfigFormat::Toml => Ok(toml::from_str(text)?),
+            ConfigFormat::Json => Ok(serde_json::from_str(text)?),
+        }
+    }
+
+    /// Reads `path`, in `format` or else the format its extension names.
+    pub fn load(path: &str, format: Option<ConfigFormat>) -> Result<Self, Box<dyn Error>> {
+        let format = match format {
+            Some(format) => format,
+            None => ConfigFormat::from_path(path)?,
+        };
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        Self::parse(&text, format).map_err(|e| format!("Invalid config {}: {}", path, e).into())
+    }
+
+    /// The options as command-line flags, so they go through the same parsing and validation.
+    /// `true` switches become bare flags, `false` ones are left out and lists repeat the flag.
+    /// Underscores in keys are read as dashes.
+    pub fn to_args(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut args = Vec::new();
+        for (key, value) in &self.options {
+            let flag = format!("--{}", key.replace('_', "-"));
+            if flag == "--config" || flag == "--config-format" {
+                return Err(format!("{} cannot be set from a config file", flag).into());
+            }
+            match value {
+                OptionValue::Switch(true) => args.push(flag),
+                OptionValue::Switch(false) => {}
+                OptionValue::Integer(n) => args.push(format!("{}={}", flag, n)),
+                OptionValue::Number(n) => args.push(format!("{}={}", flag, n)),
+                OptionValue::Text(text) => args.push(format!("{}={}", flag, text)),
+                OptionValue::List(items) => {
+                    args.extend(items.iter().map(|item| format!("{}={}", flag, item)))
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_extension_or_flag() {
+        assert_eq!(
+            ConfigFormat::from_path("client.TOML").unwrap(),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path("/etc/client.json").unwrap(),
+            ConfigFormat::Json
+        );
+        assert!(ConfigFormat::from_path("client.conf").is_err());
+        assert_eq!(ConfigFormat::parse("JSON").unwrap(), ConfigFormat::Json);
+        assert!(ConfigFormat::parse("yaml").is_err());
+    }
+
+    #[test]
+    fn test_options_become_flags() {
+        let file = ConfigFile::parse(
+            r#"{"keep_alive": true, "no-verify": false, "retry-rate": 2.5, "hash": ["a", "b"]}"#,
+            ConfigFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(
+            file.to_args().unwrap(),
+            ["--hash=a", "--hash=b", "--keep-alive", "--retry-rate=2.5"]
+        );
+        let nested = ConfigFile::parse(r#"{"config": "other.json"}"#, ConfigFormat::Json);
+        assert!(nested.unwrap().to_args().is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+
+/// How many differing regions `--diff-against` lists; the rest are only counted.
+pub const MAX_REPORTED_REGIONS: usize = 10;
+
+/// A run of consecutive differing bytes, `[start, end)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffRegion {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl DiffRegion {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Where the downloaded bytes differ from a local reference. Bytes past the end of the shorter
+/// side count as differing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffReport {
+    pub downloaded_len: u64,
+    pub reference_len: u64,
+    pub differing_bytes: u64,
+    pub region_count: u64,
+    /// The first `MAX_REPORTED_REGIONS` regions, in order.
+    pub regions: Vec<DiffRegion>,
+}
+
+impl DiffReport {
+    pub fn identical(&self) -> bool {
+        self.differing_bytes == 0
+    }
+
+    fn record(&mut self, region: DiffRegion, max_regions: usize) {
+        self.differing_bytes += region.len();
+        self.region_count += 1;
+        if self.regions.len() < max_regions {
+            self.regions.push(region);
+        }
+    }
+}
+
+/// Compares `downloaded` with the `--diff-against` file, reading it in chunks.
+pub fn diff_against(downloaded: &[u8], path: &str) -> Result<DiffReport, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    diff_reader(downloaded, file, MAX_REPORTED_REGIONS)
+        .map_err(|e| format!("Cannot read {}: {}", path, e).into())
+}
+
+/// Streams `reference` against `downloaded`, merging adjacent differing bytes into regions and
+/// keeping the first `max_regions` of them.
+pub fn diff_reader<R: Read>(
+    downloaded: &[u8],
+    mut reference: R,
+    max_regions: usize,
+) -> io::Result<DiffReport> {
+    let mut report = DiffReport {
+        downloaded_len: downloaded.len() as u64,
+        reference_len: 0,
+        differing_bytes: 0,
+        region_count: 0,
+        regions: Vec::new(),
+    };
+    let mut open: Option<u64> = None;
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = match reference.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for (i, &expected) in buffer[..n].iter().enumerate() {
+            let offset = report.reference_len + i as u64;
+            let differs = downloaded.get(offset as usize) != Some(&expected);
+            match (differs, open) {
+                (true, None) => open = Some(offset),
+                (false, Some(start)) => {
+                    report.record(DiffRegion { start, end: offset }, max_regions);
+                    open = None;
+                }
+                _ => {}
+            }
+        }
+        report.reference_len += n as u64;
+    }
+    let compared = report.reference_len;
+    let end = report.downloaded_len.max(report.reference_len);
+    match open {
+        Some(start) => report.record(DiffRegion { start, end }, max_regions),
+        None if end > compared => report.record(
+            DiffRegion {
+                start: compared,
+                end,
+            },
+            max_regions,
+        ),
+        None => {}
+    }
+    Ok(report)
+}
+
+/// The lines printed for `--diff-against`, e.g. `2 differing bytes in 1 region` followed by
+/// one `  0x...-0x... (N bytes)` line per listed region.
+pub fn format_diff_report(report: &DiffReport) -> String {
+    let mut text = format!(
+        "Downloaded {} bytes, reference {} bytes: ",
+        report.downloaded_len, report.reference_len
+    );
+    if report.identical() {
+        text.push_str("identical\n");
+        return text;
+    }
+    text.push_str(&format!(
+        "{} differing bytes in {} region{}\n",
+        report.differing_bytes,
+        report.region_count,
+        if report.region_count == 1 { "" } else { "s" }
+    ));
+    for region in &report.regions {
+        text.push_str(&format!(
+            "  0x{:08x}-0x{:08x} ({} bytes)\n",
+            region.start,
+            region.end,
+            region.len()
+        ));
+    }
+    let hidden = report.region_count - report.regions.len() as u64;
+    if hidden > 0 {
+        text.push_str(&format!("  ... and {} more\n", hidden));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most `step` bytes per read, so regions straddle read boundaries.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_known_diff_report() {
+        let reference: Vec<u8> = (0..32).collect();
+        let mut downloaded = reference.clone();
+        downloaded[3] = 0xff;
+        downloaded[7..10].fill(0);
+        downloaded.truncate(30);
+
+        let report = diff_reader(
+            &downloaded,
+            Trickle {
+                data: &reference,
+                step: 8,
+            },
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            report.regions,
+            [
+                DiffRegion { start: 3, end: 4 },
+                DiffRegion { start: 7, end: 10 }
+            ]
+        );
+        assert_eq!(report.region_count, 3);
+        assert_eq!(report.differing_bytes, 6);
+        assert_eq!(
+            format_diff_report(&report),
+            "Downloaded 30 bytes, reference 32 bytes: 6 differing bytes in 3 regions\n\
+             \x20 0x00000003-0x00000004 (1 bytes)\n\
+             \x20 0x00000007-0x0000000a (3 bytes)\n\
+             \x20 ... and 1 more\n"
+        );
+    }
+
+    #[test]
+    fn test_identical_and_longer_download() {
+        let report = diff_reader(b"abc", &b"abc"[..], MAX_REPORTED_REGIONS).unwrap();
+        assert!(report.identical());
+        assert!(format_diff_report(&report).ends_with("identical\n"));
+
+        let report = diff_reader(b"abcdef", &b"abX"[..], MAX_REPORTED_REGIONS).unwrap();
+        assert_eq!(report.regions, [DiffRegion { start: 2, end: 6 }]);
+    }
+}
@@ -0,0 +1,2927 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth::Credentials;
+use crate::cache::ResponseCache;
+use crate::cancel::CANCELLED;
+use crate::config::Config;
+use crate::dump::open_raw_dump;
+use crate::encoding::{decoding_reader, is_identity, Compression};
+use crate::hashing::{Algorithm, Hasher, RunningDigest, VerifyingReader};
+use crate::hex;
+use crate::histogram::LatencyHistogram;
+use crate::http_client::{HttpClient, HttpResponse};
+use crate::output::{create_output_file, discard_output_file, finish_output_file, Diagnostics};
+use crate::pieces::{BadPiece, PieceList, PieceVerifier};
+use crate::pool::ConnectionPool;
+use crate::progress::Progress;
+use crate::resolve::AddressCache;
+use crate::retry::{
+    append_retry_log, is_retryable, RetryBudget, RetryCount, RetryDecision, RetryEvent,
+};
+use crate::sources::download_from_sources;
+use crate::url::Url;
+
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// Default `--head-retries`: a couple of quick retries before giving up on the preflight.
+const DEFAULT_HEAD_RETRIES: u32 = 2;
+
+/// Default `--small-threshold`: below this a single request beats the range bookkeeping.
+const DEFAULT_SMALL_THRESHOLD: u64 = 1024 * 1024;
+
+type RangeSink = dyn Fn(u64, &[u8]) + Send + Sync;
+
+/// Library-only: called with the offset and bytes of every range the download accepts, so
+/// callers can store ranges wherever they like (e.g. an object store). The bytes are as the
+/// server sent them, before any `Content-Encoding` is decoded. After a piece is refetched or the
+/// download restarts, ranges at offsets already reported are reported again and replace them.
+#[derive(Clone)]
+pub struct RangeCallback(Arc<RangeSink>);
+
+impl RangeCallback {
+    pub fn new<F>(on_range_complete: F) -> Self
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        RangeCallback(Arc::new(on_range_complete))
+    }
+
+    pub fn on_range_complete(&self, offset: u64, bytes: &[u8]) {
+        (self.0)(offset, bytes)
+    }
+}
+
+impl fmt::Debug for RangeCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RangeCallback(..)")
+    }
+}
+
+/// Callbacks compare equal only when they are the same closure.
+impl PartialEq for RangeCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// What a finished download produced, plus how it went.
+pub struct DownloadResult {
+    /// The body; `None` when it was written straight to `--output` rather than kept in memory.
+    pub data: Option<Vec<u8>>,
+    /// Body length after decoding (and newline normalization with `--normalize-output`).
+    pub bytes: u64,
+    /// Requests that had to be repeated after a network error or a cut-off single request.
+    pub retries: u32,
+    pub elapsed: Duration,
+    /// The hasher's digest of the body, fed each range as it arrived and reset when the download
+    /// restarted or a piece was refetched. A body with a `Content-Encoding` (or newlines to
+    /// normalize) is hashed again once complete, after decoding.
+    pub digest: Vec<u8>,
+    /// The `Content-Type` the server sent for the body, if any.
+    pub content_type: Option<String>,
+    /// With `--pieces-file`, the pieces that failed verification and were fetched again.
+    pub failed_pieces: Vec<usize>,
+    /// How long each request took, successful or not; summarized with `--verbose`.
+    pub range_times: LatencyHistogram,
+}
+
+impl DownloadResult {
+    /// A warning for a hash mismatch on an HTML response, which is most likely an error or login
+    /// page served in place of the file.
+    pub fn html_warning(&self) -> Option<String> {
+        let content_type = self.content_type.as_deref().filter(|t| is_html(t))?;
+        Some(format!(
+            "Warning: the server sent Content-Type: {}, so it most likely returned an error page rather than the file.",
+            content_type
+        ))
+    }
+}
+
+/// A download that failed, with the retries it made and the bytes it had received by then, so
+/// `--metrics-file` and notifications report how far it got.
+#[derive(Debug)]
+pub struct DownloadError {
+    pub retries: u32,
+    pub bytes: u64,
+    pub source: Box<dyn Error>,
+}
+
+impl DownloadError {
+    fn boxed(source: Box<dyn Error>, retries: &RetryCount, bytes: u64) -> Box<dyn Error> {
+        Box::new(DownloadError {
+            retries: retries.total(),
+            bytes,
+            source,
+        })
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.source()
+    }
+}
+
+/// Headers of the successful responses that describe the assembled body.
+#[derive(Debug, Default)]
+struct BodyHeaders {
+    content_encoding: Option<String>,
+    content_type: Option<String>,
+}
+
+/// Whether a `Content-Type` value names an HTML document, ignoring parameters like `charset`.
+pub fn is_html(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case("text/html")
+        || media_type.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+/// Downloads the configured resource and digests it with the given hasher.
+pub fn download_file(
+    config: &Config,
+    hasher: Box<dyn Hasher>,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    if let Some(sources) = &config.sources {
+        return download_from_sources(config, sources, hasher);
+    }
+    let planned;
+    let config = if config.head_first {
+        planned = plan_with_head(config);
+        &planned
+    } else {
+        config
+    };
+    let client = build_client(config).with_raw_dump(open_raw_dump(config)?);
+    download_with_client(config, &client, hasher)
+}
+
+/// For `--head-first`: sends a `HEAD` and switches to `--single-request` when the resource is
+/// small or the server does not advertise byte ranges. A failed `HEAD` is retried up to
+/// `--head-retries` times, separately from the download's own retries; if it keeps failing the
+/// download falls back to range requests.
+fn plan_with_head(config: &Config) -> Config {
+    let diag = Diagnostics::for_config(config);
+    let threshold = config.small_threshold.unwrap_or(DEFAULT_SMALL_THRESHOLD);
+    let head_retries = config.head_retries.unwrap_or(DEFAULT_HEAD_RETRIES);
+    let client = build_client(config);
+    let mut head = client.head();
+    for attempt in 1..=head_retries {
+        match &head {
+            Err(e) if is_retryable(e.as_ref()) => {}
+            Ok(response) if response.status >= 500 => {}
+            _ => break,
+        }
+        diag.println(format_args!(
+            "HEAD failed; retrying ({} of {} HEAD retries).",
+            attempt, head_retries
+        ));
+        std::thread::sleep(Duration::from_millis(100));
+        head = client.head();
+    }
+    let single_request = match head {
+        Ok(response) if response.status == 200 => {
+            let single = prefers_single_request(&response, threshold);
+            diag.println(format_args!(
+                "HEAD: Content-Length {}, Accept-Ranges {}; using {}.",
+                response
+                    .resource_length()
+                    .map_or_else(|| "unknown".to_string(), |length| length.to_string()),
+                response.header("Accept-Ranges").unwrap_or("not sent"),
+                if single {
+                    "a single request"
+                } else {
+                    "range requests"
+                }
+            ));
+            single
+        }
+        Ok(response) => {
+            diag.println(format_args!(
+                "HEAD returned status {}; using range requests.",
+                response.status
+            ));
+            false
+        }
+        Err(e) => {
+            diag.println(format_args!("HEAD failed ({}); using range requests.", e));
+            false
+        }
+    };
+    Config {
+        single_request,
+        ..config.clone()
+    }
+}
+
+/// Whether a `HEAD` response calls for one plain `GET`: the body is known to be smaller than
+/// `threshold`, or the server does not say it serves byte ranges.
+fn prefers_single_request(head: &HttpResponse, threshold: u64) -> bool {
+    let small = head
+        .resource_length()
+        .is_some_and(|length| length < threshold);
+    let ranges = head
+        .header("Accept-Ranges")
+        .is_some_and(|units| units.trim().eq_ignore_ascii_case("bytes"));
+    small || !ranges
+}
+
+pub fn build_client(config: &Config) -> HttpClient {
+    HttpClient::new(
+        config.host.clone(),
+        config.port,
+        config.connect_timeout,
+        config.read_write_timeout,
+    )
+    .with_scheme(config.scheme)
+    .with_request_timeout(config.request_timeout)
+    .with_stall_timeout(config.stall_timeout)
+    .with_tls_handshake_timeout(config.tls_handshake_timeout)
+    .with_tls_server_name(config.tls_server_name.clone())
+    .with_min_tls_version(config.min_tls_version)
+    .with_warn_cert_expiry_days(config.warn_cert_expiry_days)
+    .with_bind_port(config.bind_port)
+    .with_recv_buffer(config.recv_buffer)
+    .with_path(config.path.clone())
+    .with_chunk_sha_verification(config.verify_chunk_sha)
+    .with_max_headers(config.max_headers)
+    .with_referer(config.referer.clone())
+    .with_authorization(config.credentials.as_ref().map(Credentials::header_value))
+    .with_gzip(config.compression.accepts_gzip(config.single_request))
+    .with_probe_gzip(config.compression.probe_accepts_gzip())
+    .with_keep_alive(config.keep_alive)
+    .with_strict_close(config.strict_close)
+    .with_recycle_after(config.recycle_after)
+    .with_pool(Arc::new(ConnectionPool::with_idle_timeout(
+        config.keepalive_idle_timeout,
+    )))
+    .with_address_cache(Arc::new(address_cache(config)))
+    .with_response_cache(
+        config
+            .response_cache
+            .then(|| Arc::new(ResponseCache::default())),
+    )
+    .with_retry_budget(
+        config
+            .retry_rate
+            .map(|rate| Arc::new(RetryBudget::new(rate))),
+    )
+    .with_connect_rate(
+        config
+            .connect_rate
+            .map(|rate| Arc::new(RetryBudget::new(rate))),
+    )
+    .with_proxy(config.proxy.clone())
+    .with_proxy_bypass(config.no_proxy_hosts.clone())
+    .with_socks5(config.socks5.clone())
+    .with_verbose(config.verbose.then(|| Diagnostics::for_config(config)))
+    .with_quiet(config.silent)
+}
+
+/// The address cache configured by `--address-order`, `--dns-cache-size` and `--dns-ttl`.
+pub fn address_cache(config: &Config) -> AddressCache {
+    AddressCache::with_order(config.address_order)
+        .with_capacity(config.dns_cache_size)
+        .with_ttl(config.dns_ttl)
+        .with_timeout(config.dns_timeout)
+}
+
+/// Runs the download loop with a prepared client, e.g. one sharing a pool with other downloads.
+pub fn download_with_client(
+    config: &Config,
+    client: &HttpClient,
+    hasher: Box<dyn Hasher>,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    if let Some(length) = config.suffix {
+        return download_suffix(config, client, hasher, length);
+    }
+    let mut retries = RetryCount::default();
+    let hasher = match download_streaming(config, client, hasher, &mut retries)? {
+        Streamed::Complete(download) => return Ok(*download),
+        Streamed::Buffer(hasher) => hasher,
+    };
+    let mut data = resume_prefix(config, &Diagnostics::for_config(config))?;
+    download_buffered(config, client, hasher, &mut data, &mut retries)
+        .map_err(|e| DownloadError::boxed(e, &retries, data.len() as u64))
+}
+
+/// The main loop: requests ranges from the end of `data` (which holds any resumed prefix) until
+/// the body is complete, feeding each accepted range to the hasher as it arrives. `data` and
+/// `retries` are the caller's so a failure can report how far the download got.
+fn download_buffered(
+    config: &Config,
+    client: &HttpClient,
+    mut hasher: Box<dyn Hasher>,
+    data: &mut Vec<u8>,
+    retries: &mut RetryCount,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    hasher.update(data);
+    let mut expected_total: Option<u64> = None;
+    let mut body_headers = BodyHeaders::default();
+    let server_address = format!("{}:{}", config.host, config.port);
+    let mut current_url = Url {
+        scheme: config.scheme,
+        host: config.host.clone(),
+        port: config.port,
+        path: config.path.clone(),
+    };
+    let mut running = running_digests(config, data)?;
+    let mut pieces = match (&config.pieces_file, config.piece_length) {
+        (Some(path), Some(length)) => Some(PieceVerifier::new(PieceList::load(path, length)?)),
+        _ => None,
+    };
+    let mut resume_etag = resume_etag(config).filter(|_| !data.is_empty());
+    let mut saved_etag = resume_etag.clone();
+    // Replaces `client` after a redirect, and while resuming sends `If-Range` with the ETag the
+    // partial file was downloaded under.
+    let mut replacement_client = resume_etag
+        .as_ref()
+        .map(|etag| client.clone().with_if_range(Some(etag.clone())));
+    let mut redirects = 0;
+    let mut pause_before_next = false;
+    let mut range_times = LatencyHistogram::default();
+    // `--start-offset`: `data` holds the bytes from here on, and ranges are requested past it.
+    let offset = config.start_offset.unwrap_or(0);
+    let mut progress = Progress::new((offset + data.len()) as u64);
+    let started = Instant::now();
+
+    diag.println(format_args!(
+        "Starting download from {} using std::net HttpClient...",
+        server_address
+    ));
+
+    loop {
+        let start_byte = offset + data.len();
+        let range_header_info = format!("bytes={}-", start_byte);
+
+        let active_client = replacement_client.as_ref().unwrap_or(client);
+        // Only a request that follows a successful range waits; retries never do.
+        if let Some(delay) = config.inter_range_delay.filter(|_| pause_before_next) {
+            pause_between_ranges(active_client, delay)?;
+        }
+        pause_before_next = false;
+        let requested = Instant::now();
+        let result = if config.single_request {
+            diag.print(format_args!("Requesting the whole resource -> "));
+            active_client.fetch_whole()
+        } else {
+            diag.print(format_args!("Requesting range: {} -> ", range_header_info));
+            active_client.fetch_range(start_byte)
+        };
+        range_times.record(requested.elapsed());
+        match result {
+            Ok(response) => {
+                let status = response.status;
+                let content_range = response.content_range();
+                if let Some(total) = content_range.and_then(|range| range.total) {
+                    expected_total = Some(total);
+                }
+                let declared_empty = response
+                    .header("Content-Length")
+                    .is_none_or(|length| length == "0");
+                let declared_length = response
+                    .header("Content-Length")
+                    .and_then(|length| length.parse::<usize>().ok());
+                let location = response.header("Location").map(str::to_string);
+                let etag = response.header("ETag").map(str::to_string);
+                if let Some(encoding) = response.header("Content-Encoding") {
+                    body_headers.content_encoding = Some(encoding.to_string());
+                }
+                if let Some(content_type) = response.header("Content-Type") {
+                    body_headers.content_type = Some(content_type.to_string());
+                }
+                let received_chunk = response.body;
+                diag.println(format_args!(
+                    "{}",
+                    describe_attempt(
+                        status,
+                        received_chunk.len(),
+                        response.peer.filter(|_| config.verbose)
+                    )
+                ));
+
+                if config.success_statuses.contains(&status) {
+                    if status == 200 && offset > 0 {
+                        return Err(format!(
+                            "Server ignored the range request (status 200); cannot start at --start-offset={}",
+                            offset
+                        )
+                        .into());
+                    }
+                    if let Some(resumed) = &resume_etag {
+                        // A 200 means `If-Range` failed: the server's copy is no longer the one
+                        // the partial file came from.
+                        if status == 200 || etag.as_ref().is_some_and(|etag| etag != resumed) {
+                            diag.println(format_args!(
+                                "ETag changed from {} to {} since the partial download; discarding {} bytes and restarting from byte 0.",
+                                resumed,
+                                etag.as_deref().unwrap_or("none"),
+                                data.len()
+                            ));
+                            data.clear();
+                            expected_total = None;
+                            body_headers = BodyHeaders::default();
+                            hasher.reset();
+                            running = running_digests(config, data)?;
+                            if let Some(verifier) = &mut pieces {
+                                verifier.restart();
+                            }
+                            progress.restart();
+                            resume_etag = None;
+                            replacement_client = Some(client.clone().with_target(&current_url));
+                            continue;
+                        }
+                    }
+                    if let Some(etag) = etag.filter(|etag| saved_etag.as_ref() != Some(etag)) {
+                        save_resume_etag(config, &etag)?;
+                        saved_etag = Some(etag);
+                    }
+                    reject_unwanted_body(
+                        config,
+                        status,
+                        start_byte as u64,
+                        received_chunk.is_empty() && declared_empty,
+                        body_headers.content_type.as_deref(),
+                    )?;
+
+                    // Without ranges there is nothing to continue from: a short body is refetched.
+                    if config.single_request {
+                        if let Some(declared) =
+                            declared_length.filter(|&declared| received_chunk.len() < declared)
+                        {
+                            let e = format!(
+                                "Short read: received {} of {} declared bytes",
+                                received_chunk.len(),
+                                declared
+                            );
+                            wait_to_retry(
+                                config,
+                                active_client,
+                                e.into(),
+                                retries,
+                                Some(0),
+                                "the whole resource",
+                            )?;
+                            retries.record();
+                            continue;
+                        }
+                        report_range(config, 0, &received_chunk);
+                        hasher.update(&received_chunk);
+                        return finish_download(
+                            config,
+                            received_chunk,
+                            &body_headers,
+                            hasher,
+                            retries.total(),
+                            range_times,
+                            started,
+                        );
+                    }
+
+                    // Without a Content-Range, the Content-Length of a whole body is the file size.
+                    if status == 200 && start_byte == 0 && content_range.is_none() {
+                        if let Some(length) = declared_length {
+                            expected_total = Some(length as u64);
+                        }
+                    }
+                    if status == 206 {
+                        if let Some(range) = content_range.filter(|r| r.first != start_byte as u64)
+                        {
+                            return Err(format!(
+                                "Server answered with bytes starting at {} for a range starting at {}",
+                                range.first, start_byte
+                            )
+                            .into());
+                        }
+                    }
+                    if let Some(template) = config
+                        .range_hash_url_template
+                        .as_deref()
+                        .filter(|_| !received_chunk.is_empty())
+                    {
+                        if let Err(e) = check_range_hash(
+                            config,
+                            active_client,
+                            template,
+                            start_byte,
+                            &received_chunk,
+                        ) {
+                            active_client.clear_response_cache();
+                            wait_to_retry(
+                                config,
+                                active_client,
+                                e,
+                                retries,
+                                Some(start_byte as u64),
+                                &format!("range {}", range_header_info),
+                            )?;
+                            retries.record();
+                            continue;
+                        }
+                    }
+                    let chunk_start = data.len();
+                    data.extend_from_slice(&received_chunk);
+                    hasher.update(&received_chunk);
+                    pause_before_next = true;
+
+                    let mut received_chunk = received_chunk;
+                    if let Some(verifier) = &mut pieces {
+                        if repair_pieces(
+                            config,
+                            active_client,
+                            verifier,
+                            data,
+                            offset,
+                            false,
+                            retries,
+                        )? {
+                            // The hashes were fed the bad bytes: start them over.
+                            hasher.reset();
+                            hasher.update(data);
+                            running = running_digests(config, &data[..chunk_start])?;
+                            received_chunk = data[chunk_start..].to_vec();
+                        }
+                    }
+                    if !received_chunk.is_empty() {
+                        retries.progressed();
+                        report_range(config, start_byte as u64, &received_chunk);
+                        if progress.due() {
+                            diag.println(format_args!(
+                                "Progress: {}",
+                                progress.snapshot(
+                                    start_byte as u64 + received_chunk.len() as u64,
+                                    expected_total
+                                )
+                            ));
+                        }
+                    }
+
+                    if !running.is_empty() {
+                        for (digest, _) in &mut running {
+                            digest.update(&received_chunk);
+                        }
+                        if running.iter().all(|(digest, expected)| {
+                            digest.current_hex().eq_ignore_ascii_case(expected)
+                        }) {
+                            diag.println(format_args!(
+                                "The {} bytes received so far match the expected hash; stopping (--stop-on-hash-match).",
+                                data.len()
+                            ));
+                            return finish_download(
+                                config,
+                                std::mem::take(data),
+                                &body_headers,
+                                hasher,
+                                retries.total(),
+                                range_times,
+                                started,
+                            );
+                        }
+                    }
+
+                    // A short read keeps what arrived; the next request asks only for the rest.
+                    if let Some(declared) =
+                        declared_length.filter(|&declared| received_chunk.len() < declared)
+                    {
+                        diag.println(format_args!(
+                            "Short read: received {} of {} declared bytes, requesting the remaining {} from byte {}.",
+                            received_chunk.len(),
+                            declared,
+                            declared - received_chunk.len(),
+                            data.len()
+                        ));
+                    }
+
+                    // Once the total size is known the download ends with its last byte; otherwise
+                    // an empty `206` past the end marks completion.
+                    let received_all = !received_chunk.is_empty()
+                        && expected_total
+                            .is_some_and(|total| (offset + data.len()) as u64 >= total);
+                    let empty_past_end =
+                        status == 206 && received_chunk.is_empty() && start_byte > 0;
+                    if received_all {
+                        diag.println(format_args!(
+                            "Received all {} bytes of the file.",
+                            offset + data.len()
+                        ));
+                    } else if empty_past_end {
+                        diag.println(format_args!("Received status 206 and 0 bytes for range starting at {}, assuming download complete.", start_byte));
+                    }
+                    if received_all || empty_past_end {
+                        check_total_length(offset + data.len(), expected_total)?;
+                        if let Some(verifier) = &mut pieces {
+                            if repair_pieces(
+                                config,
+                                active_client,
+                                verifier,
+                                data,
+                                offset,
+                                true,
+                                retries,
+                            )? {
+                                hasher.reset();
+                                hasher.update(data);
+                            }
+                        }
+                        let mut download = finish_download(
+                            config,
+                            std::mem::take(data),
+                            &body_headers,
+                            hasher,
+                            retries.total(),
+                            range_times,
+                            started,
+                        )?;
+                        if let Some(pieces) = &pieces {
+                            download.failed_pieces = pieces.failed_pieces();
+                        }
+                        return Ok(download);
+                    }
+                } else if REDIRECT_STATUSES.contains(&status) {
+                    let location = location.ok_or_else(|| {
+                        format!(
+                            "Server returned redirect {} without a Location header",
+                            status
+                        )
+                    })?;
+                    redirects += 1;
+                    if redirects > config.max_redirects {
+                        return Err(format!(
+                            "Too many redirects: exceeded --max-redirects={}",
+                            config.max_redirects
+                        )
+                        .into());
+                    }
+                    // The redirect target is a different resource, so start over from byte 0.
+                    let target = current_url.join(&location)?;
+                    if !config.redirect_allowed(&target.host) {
+                        return Err(format!(
+                            "Redirect to {} refused: host '{}' is not in --allow-redirect-host",
+                            target, target.host
+                        )
+                        .into());
+                    }
+                    current_url = target;
+                    diag.println(format_args!(
+                        "Redirected to {}, restarting download from byte {} (discarding {} bytes).",
+                        current_url,
+                        offset,
+                        data.len()
+                    ));
+                    data.clear();
+                    expected_total = None;
+                    body_headers = BodyHeaders::default();
+                    hasher.reset();
+                    running = running_digests(config, data)?;
+                    if let Some(verifier) = &mut pieces {
+                        verifier.restart();
+                    }
+                    progress = Progress::new(offset as u64);
+                    replacement_client = Some(client.clone().with_target(&current_url));
+                } else {
+                    return Err(format!("Server returned non-successful status: {}", status).into());
+                }
+            }
+
+            Err(e) => {
+                wait_to_retry(
+                    config,
+                    active_client,
+                    e,
+                    retries,
+                    Some(start_byte as u64),
+                    &format!("range {}", range_header_info),
+                )?;
+                retries.record();
+            }
+        }
+    }
+}
+
+/// For `--range-hash-url-template`: the URL of the hash of bytes `start..=end`.
+fn range_hash_url(template: &str, start: usize, end: usize) -> String {
+    template
+        .replace("{start}", &start.to_string())
+        .replace("{end}", &end.to_string())
+}
+
+/// Fetches the hash the server publishes for the range that starts at `start` and compares it
+/// with the bytes received. A mismatch is retryable, so the range is requested again.
+fn check_range_hash(
+    config: &Config,
+    client: &HttpClient,
+    template: &str,
+    start: usize,
+    bytes: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let end = start + bytes.len() - 1;
+    let url = Url::parse(&range_hash_url(template, start, end))?;
+    let response = client
+        .clone()
+        .with_target(&url)
+        .with_if_range(None)
+        .with_gzip(false)
+        .fetch_whole()?;
+    if response.status != 200 {
+        return Err(format!(
+            "Range hash endpoint {} returned status {}",
+            url, response.status
+        )
+        .into());
+    }
+    // Accept a bare digest as well as `sha256sum`-style `<digest>  <name>` output.
+    let body = String::from_utf8_lossy(&response.body);
+    let expected = body.split_whitespace().next().unwrap_or_default();
+    let algorithm = config.algorithm.unwrap_or(Algorithm::Sha256);
+    let mut hasher = algorithm.new_hasher();
+    hasher.update(bytes);
+    let actual = hex::encode(&hasher.finalize());
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Range hash mismatch for bytes {}-{}: {} expected {}, got {}",
+            start, end, url, expected, actual
+        )
+        .into())
+    }
+}
+
+/// Asks the retry policy about a failed request: waits and returns `Ok` when `what` should be
+/// requested again, or returns the error as fatal.
+pub fn wait_to_retry(
+    config: &Config,
+    client: &HttpClient,
+    error: Box<dyn Error>,
+    retries: &RetryCount,
+    offset: Option<u64>,
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    let policy = config.retry_policy.clone().unwrap_or_default();
+    match policy.decide(error.as_ref(), retries.total()) {
+        RetryDecision::RetryAfter(_)
+            if config
+                .max_retries
+                .is_some_and(|max| retries.counted(config.reset_retries_on_progress) >= max) =>
+        {
+            Err(format!(
+                "Retry limit of {} reached (--max-retries): {}",
+                config.max_retries.unwrap_or_default(),
+                error
+            )
+            .into())
+        }
+        RetryDecision::RetryAfter(delay) => {
+            let diag = Diagnostics::for_config(config);
+            diag.warn(format_args!(
+                "\nRetryable error: {}. Retrying {}...",
+                error, what
+            ));
+            if let Some(path) = &config.retry_log {
+                let event = RetryEvent::new(offset, error.as_ref(), retries.total() + 1, delay);
+                if let Err(e) = append_retry_log(path, &event) {
+                    diag.warn(format_args!(
+                        "Warning: failed to write --retry-log {}: {}",
+                        path, e
+                    ));
+                }
+            }
+            std::thread::sleep(delay);
+            if let Some(budget) = client.retry_budget() {
+                budget.acquire();
+            }
+            Ok(())
+        }
+        RetryDecision::Stop => Err(format!("Fatal download error: {}", error).into()),
+    }
+}
+
+/// For `--suffix`: fetches and digests only the last `length` bytes with one suffix range
+/// request, repeated on network errors like any other request.
+fn download_suffix(
+    config: &Config,
+    client: &HttpClient,
+    mut hasher: Box<dyn Hasher>,
+    length: usize,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    let started = Instant::now();
+    let mut retries = RetryCount::default();
+    let mut range_times = LatencyHistogram::default();
+    loop {
+        diag.print(format_args!("Requesting the last {} bytes -> ", length));
+        let requested = Instant::now();
+        let result = client.fetch_suffix(length);
+        range_times.record(requested.elapsed());
+        match result {
+            Ok((range, body)) => {
+                diag.println(format_args!(
+                    "Received bytes {}-{} of {}",
+                    range.first,
+                    range.last,
+                    range
+                        .total
+                        .map_or_else(|| "an unknown length".to_string(), |t| t.to_string())
+                ));
+                report_range(config, range.first, &body);
+                hasher.update(&body);
+                return finish_download(
+                    config,
+                    body,
+                    &BodyHeaders::default(),
+                    hasher,
+                    retries.total(),
+                    range_times,
+                    started,
+                );
+            }
+            Err(e) => {
+                wait_to_retry(
+                    config,
+                    client,
+                    e,
+                    &retries,
+                    None,
+                    &format!("the last {} bytes", length),
+                )?;
+                retries.record();
+            }
+        }
+    }
+}
+
+/// Hands an accepted range to the `on_range_complete` callback, if there is one.
+fn report_range(config: &Config, offset: u64, bytes: &[u8]) {
+    if let Some(callback) = &config.on_range_complete {
+        callback.on_range_complete(offset, bytes);
+    }
+}
+
+/// The option that needs the whole body in memory, if any: decoding, normalizing, piece or
+/// trailer checks, resuming, comparing, ... Without one, `--output` is written range by range.
+fn buffering_option(config: &Config) -> Option<&'static str> {
+    [
+        (config.resume, "--resume"),
+        (config.tee_stdout, "--tee-stdout"),
+        (config.single_request, "--single-request"),
+        (config.start_offset.is_some(), "--start-offset"),
+        (config.compression == Compression::On, "--compression=on"),
+        (config.decompress_gzip, "--decompress-gzip"),
+        (config.normalize_newlines, "--normalize-newlines"),
+        (config.pieces_file.is_some(), "--pieces-file"),
+        (config.stop_on_hash_match, "--stop-on-hash-match"),
+        (config.trailer_checksum.is_some(), "--trailer-checksum"),
+        (config.verify_inner_hash.is_some(), "--verify-inner-hash"),
+        (config.diff_against.is_some(), "--diff-against"),
+        (config.hexdump_on_failure.is_some(), "--hexdump-on-failure"),
+    ]
+    .into_iter()
+    .find_map(|(needed, option)| needed.then_some(option))
+}
+
+/// What `download_streaming` made of a download.
+enum Streamed {
+    Complete(Box<DownloadResult>),
+    /// The body has to be kept in memory after all; the hasher comes back reset.
+    Buffer(Box<dyn Hasher>),
+}
+
+/// For `--hash-only` and an `--output` file: downloads without keeping the body in memory,
+/// writing each range to `<output>.part` as it arrives and renaming that over the output once
+/// the download is complete. Hands the hasher back when the body has to be buffered instead,
+/// because of an option that needs all of it or a `Content-Encoding` nobody asked for.
+fn download_streaming(
+    config: &Config,
+    client: &HttpClient,
+    hasher: Box<dyn Hasher>,
+    retries: &mut RetryCount,
+) -> Result<Streamed, Box<dyn Error>> {
+    let mut hashed = 0;
+    if config.hash_only {
+        return stream_ranges(config, client, hasher, None, retries, &mut hashed)
+            .map_err(|e| DownloadError::boxed(e, retries, hashed));
+    }
+    let Some(path) = config.output.as_deref().filter(|&path| path != "-") else {
+        return Ok(Streamed::Buffer(hasher));
+    };
+    if let Some(option) = buffering_option(config) {
+        Diagnostics::for_config(config).println(format_args!(
+            "{} needs the whole body, so it is kept in memory and written to {} once complete.",
+            option, path
+        ));
+        return Ok(Streamed::Buffer(hasher));
+    }
+    let mut file = create_output_file(config, path)
+        .map_err(|e| format!("Cannot create --output {}: {}", path, e))?;
+    match stream_ranges(
+        config,
+        client,
+        hasher,
+        Some(&mut file),
+        retries,
+        &mut hashed,
+    ) {
+        Ok(Streamed::Complete(download)) => {
+            finish_output_file(file, path, config.fsync)
+                .map_err(|e| format!("Cannot write --output {}: {}", path, e))?;
+            Ok(Streamed::Complete(download))
+        }
+        Ok(buffer) => {
+            discard_output_file(file, path);
+            Ok(buffer)
+        }
+        Err(e) => {
+            discard_output_file(file, path);
+            Err(DownloadError::boxed(e, retries, hashed))
+        }
+    }
+}
+
+/// The loop of `download_streaming`: requests ranges like the main loop but feeds each response
+/// straight to the hasher (and `sink`, if any) and drops it, so only the current response is
+/// ever held in memory. A redirect starts the sink and the hasher over. `retries` and `hashed`
+/// are the caller's so a failure can report how far the download got.
+fn stream_ranges(
+    config: &Config,
+    client: &HttpClient,
+    mut hasher: Box<dyn Hasher>,
+    mut sink: Option<&mut File>,
+    retries: &mut RetryCount,
+    hashed: &mut u64,
+) -> Result<Streamed, Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    let started = Instant::now();
+    let mut current_url = Url {
+        scheme: config.scheme,
+        host: config.host.clone(),
+        port: config.port,
+        path: config.path.clone(),
+    };
+    let mut redirected_client: Option<HttpClient> = None;
+    let mut expected_total: Option<u64> = None;
+    let mut content_type = None;
+    let mut redirects = 0;
+    let mut pause_before_next = false;
+    let mut range_times = LatencyHistogram::default();
+    loop {
+        let active_client = redirected_client.as_ref().unwrap_or(client);
+        if let Some(delay) = config.inter_range_delay.filter(|_| pause_before_next) {
+            pause_between_ranges(active_client, delay)?;
+        }
+        pause_before_next = false;
+        diag.print(format_args!(
+            "{} range: bytes={}- -> ",
+            if sink.is_some() { "Saving" } else { "Hashing" },
+            *hashed
+        ));
+        let requested = Instant::now();
+        let result = active_client.fetch_range(*hashed as usize);
+        range_times.record(requested.elapsed());
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                wait_to_retry(
+                    config,
+                    active_client,
+                    e,
+                    retries,
+                    Some(*hashed),
+                    &format!("range bytes={}-", *hashed),
+                )?;
+                retries.record();
+                continue;
+            }
+        };
+        diag.println(format_args!(
+            "{}",
+            describe_attempt(
+                response.status,
+                response.body.len(),
+                response.peer.filter(|_| config.verbose)
+            )
+        ));
+        if REDIRECT_STATUSES.contains(&response.status) {
+            let location = response.header("Location").ok_or_else(|| {
+                format!(
+                    "Server returned redirect {} without a Location header",
+                    response.status
+                )
+            })?;
+            redirects += 1;
+            if redirects > config.max_redirects {
+                return Err(format!(
+                    "Too many redirects: exceeded --max-redirects={}",
+                    config.max_redirects
+                )
+                .into());
+            }
+            let target = current_url.join(location)?;
+            if !config.redirect_allowed(&target.host) {
+                return Err(format!(
+                    "Redirect to {} refused: host '{}' is not in --allow-redirect-host",
+                    target, target.host
+                )
+                .into());
+            }
+            current_url = target;
+            if *hashed > 0 {
+                diag.println(format_args!(
+                    "Redirected to {}, restarting download from byte 0 (discarding {} bytes).",
+                    current_url, *hashed
+                ));
+                if let Some(sink) = sink.as_mut() {
+                    sink.set_len(0)?;
+                    sink.seek(SeekFrom::Start(0))?;
+                }
+                hasher.reset();
+                *hashed = 0;
+                expected_total = None;
+                content_type = None;
+            }
+            redirected_client = Some(client.clone().with_target(&current_url));
+            continue;
+        }
+        if !config.success_statuses.contains(&response.status) {
+            return Err(
+                format!("Server returned non-successful status: {}", response.status).into(),
+            );
+        }
+        if let Some(encoding) = response
+            .header("Content-Encoding")
+            .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+        {
+            diag.println(format_args!(
+                "Server sent Content-Encoding: {}, which cannot be decoded range by range; downloading again into memory.",
+                encoding
+            ));
+            hasher.reset();
+            return Ok(Streamed::Buffer(hasher));
+        }
+        if let Some(range) = response.content_range() {
+            if range.first != *hashed {
+                return Err(format!(
+                    "Server answered with bytes starting at {} for a range starting at {}",
+                    range.first, *hashed
+                )
+                .into());
+            }
+            if range.total.is_some() {
+                expected_total = range.total;
+            }
+        }
+        if let Some(value) = response.header("Content-Type") {
+            content_type = Some(value.to_string());
+        }
+        reject_unwanted_body(
+            config,
+            response.status,
+            *hashed,
+            response.body.is_empty()
+                && response
+                    .header("Content-Length")
+                    .is_none_or(|length| length == "0"),
+            content_type.as_deref(),
+        )?;
+        if response.status == 200 && *hashed == 0 && response.content_range().is_none() {
+            if let Some(length) = response
+                .header("Content-Length")
+                .and_then(|length| length.parse::<u64>().ok())
+            {
+                expected_total = Some(length);
+            }
+        }
+        if let Some(template) = config
+            .range_hash_url_template
+            .as_deref()
+            .filter(|_| !response.body.is_empty())
+        {
+            if let Err(e) = check_range_hash(
+                config,
+                active_client,
+                template,
+                *hashed as usize,
+                &response.body,
+            ) {
+                active_client.clear_response_cache();
+                wait_to_retry(
+                    config,
+                    active_client,
+                    e,
+                    retries,
+                    Some(*hashed),
+                    &format!("range bytes={}-", *hashed),
+                )?;
+                retries.record();
+                continue;
+            }
+        }
+        if !response.body.is_empty() {
+            retries.progressed();
+            pause_before_next = true;
+            if let Some(sink) = sink.as_mut() {
+                sink.write_all(&response.body)?;
+            }
+            report_range(config, *hashed, &response.body);
+        }
+        hasher.update(&response.body);
+        *hashed += response.body.len() as u64;
+        let received_all =
+            !response.body.is_empty() && expected_total.is_some_and(|total| *hashed >= total);
+        if received_all || (response.status == 206 && response.body.is_empty() && *hashed > 0) {
+            check_total_length(*hashed as usize, expected_total)?;
+            return Ok(Streamed::Complete(Box::new(DownloadResult {
+                data: None,
+                bytes: *hashed,
+                retries: retries.total(),
+                elapsed: started.elapsed(),
+                digest: hasher.finalize(),
+                content_type,
+                failed_pieces: Vec::new(),
+                range_times,
+            })));
+        }
+    }
+}
+
+/// `--fail-on-empty` and `--fail-on-html`: rejects a successful response that cannot be the
+/// file. `empty` means the body and its declared length are both empty.
+fn reject_unwanted_body(
+    config: &Config,
+    status: u16,
+    start_byte: u64,
+    empty: bool,
+    content_type: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if config.fail_on_empty && start_byte == 0 && empty {
+        return Err(format!(
+            "Server returned status {} with an empty body for a download starting at byte 0 (--fail-on-empty)",
+            status
+        )
+        .into());
+    }
+    if config.fail_on_html {
+        if let Some(content_type) = content_type.filter(|t| is_html(t)) {
+            return Err(format!(
+                "Server returned an HTML page (Content-Type: {}) instead of the file (--fail-on-html)",
+                content_type
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the `--pieces-file` pieces `data` completes, fetching each bad one again with a
+/// bounded range and writing it over the bad bytes; the bytes after it are kept. `data` starts
+/// at byte `offset` of the file. Returns whether any piece was replaced.
+fn repair_pieces(
+    config: &Config,
+    client: &HttpClient,
+    verifier: &mut PieceVerifier,
+    data: &mut [u8],
+    offset: usize,
+    finished: bool,
+    retries: &mut RetryCount,
+) -> Result<bool, Box<dyn Error>> {
+    let mut repaired = false;
+    while let Some(bad) = verifier.check(data, finished)? {
+        refetch_piece(
+            config,
+            client,
+            &mut data[bad.start..bad.end],
+            offset,
+            bad,
+            retries,
+        )?;
+        repaired = true;
+    }
+    Ok(repaired)
+}
+
+/// Fetches the bytes of `bad` again into `piece`, retrying failed requests like any other. The
+/// response cache is cleared first, so the bad bytes are not served again.
+fn refetch_piece(
+    config: &Config,
+    client: &HttpClient,
+    piece: &mut [u8],
+    offset: usize,
+    bad: BadPiece,
+    retries: &mut RetryCount,
+) -> Result<(), Box<dyn Error>> {
+    let (first, last) = (offset + bad.start, offset + bad.end - 1);
+    let mut error: Box<dyn Error> = format!(
+        "Piece {} (bytes {}-{}) failed verification (attempt {})",
+        bad.index,
+        bad.start,
+        bad.end - 1,
+        bad.attempts
+    )
+    .into();
+    loop {
+        client.clear_response_cache();
+        let what = format!("piece {} (bytes {}-{})", bad.index, first, last);
+        wait_to_retry(config, client, error, retries, Some(first as u64), &what)?;
+        retries.record();
+        error = match client.fetch_ranges(&[(first, last)]) {
+            Ok(mut parts) if parts.len() == 1 => {
+                let (range, body) = parts.remove(0);
+                if range.first != first as u64 {
+                    return Err(format!(
+                        "Server answered with bytes starting at {} for a range starting at {}",
+                        range.first, first
+                    )
+                    .into());
+                }
+                if let Some(bytes) = body.get(..piece.len()) {
+                    piece.copy_from_slice(bytes);
+                    return Ok(());
+                }
+                format!(
+                    "Short read: received {} of {} bytes of piece {}",
+                    body.len(),
+                    piece.len(),
+                    bad.index
+                )
+                .into()
+            }
+            Ok(parts) => {
+                return Err(format!("Server answered one range with {} parts", parts.len()).into())
+            }
+            Err(e) => e,
+        };
+    }
+}
+
+/// For `--stop-on-hash-match`: a running digest per expected hash, already fed `prefix` (the
+/// resumed bytes). Empty without the flag.
+fn running_digests(
+    config: &Config,
+    prefix: &[u8],
+) -> Result<Vec<(RunningDigest, String)>, Box<dyn Error>> {
+    if !config.stop_on_hash_match {
+        return Ok(Vec::new());
+    }
+    Ok(config
+        .expected_digests()?
+        .into_iter()
+        .map(|(algorithm, expected)| {
+            let mut digest = RunningDigest::new(algorithm);
+            digest.update(prefix);
+            (digest, expected)
+        })
+        .collect())
+}
+
+/// Waits `--inter-range-delay-ms` before the next range; the client's cancel token cuts the
+/// wait short and aborts the download.
+fn pause_between_ranges(client: &HttpClient, delay: Duration) -> Result<(), Box<dyn Error>> {
+    match client.cancel_token() {
+        Some(token) if !token.sleep(delay) => Err(CANCELLED.into()),
+        Some(_) => Ok(()),
+        None => {
+            std::thread::sleep(delay);
+            Ok(())
+        }
+    }
+}
+
+/// The per-attempt log line; with `--verbose` it names the address that answered, which
+/// singles out a bad backend behind round-robin DNS.
+fn describe_attempt(status: u16, received: usize, peer: Option<SocketAddr>) -> String {
+    let mut line = format!("Status: {}, Received: {} bytes", status, received);
+    if let Some(peer) = peer {
+        line.push_str(&format!(" from {}", peer));
+    }
+    line
+}
+
+/// Completes a download whose hasher was fed `data` as it arrived. A body with a
+/// `Content-Encoding`, or with `--normalize-newlines`, is hashed again after decoding and
+/// normalizing, since the digest covers the content rather than the bytes on the wire. The
+/// decoder feeds the hasher as it goes, so with `--hash-only` the decoded body is never held.
+fn finish_download(
+    config: &Config,
+    data: Vec<u8>,
+    body_headers: &BodyHeaders,
+    mut hasher: Box<dyn Hasher>,
+    retries: u32,
+    range_times: LatencyHistogram,
+    started: Instant,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    let encoding = body_headers.content_encoding.as_deref();
+    let decode_failed = |e: io::Error| {
+        format!(
+            "Failed to decode {} body: {}",
+            encoding.unwrap_or_default(),
+            e
+        )
+    };
+    let (bytes, data, digest) = if is_identity(encoding) && !config.normalize_newlines {
+        (data.len() as u64, Some(data), hasher.finalize())
+    } else if config.normalize_newlines {
+        hasher.reset();
+        let mut decoded = Vec::new();
+        decoding_reader(encoding, data.as_slice())?
+            .read_to_end(&mut decoded)
+            .map_err(decode_failed)?;
+        let normalized = crlf_to_lf(&decoded);
+        hasher.update(&normalized);
+        let data = if config.normalize_output {
+            normalized
+        } else {
+            decoded
+        };
+        (data.len() as u64, Some(data), hasher.finalize())
+    } else {
+        hasher.reset();
+        let mut reader = VerifyingReader::new(decoding_reader(encoding, data.as_slice())?, hasher);
+        let decoded = if config.hash_only {
+            io::copy(&mut reader, &mut io::sink()).map(|_| None)
+        } else {
+            let mut decoded = Vec::new();
+            reader.read_to_end(&mut decoded).map(|_| Some(decoded))
+        }
+        .map_err(decode_failed)?;
+        let bytes = reader.bytes_read();
+        let digest = reader
+            .into_digest()
+            .ok_or("Decoding stopped before the end of the body")?;
+        (bytes, decoded, digest)
+    };
+    Ok(DownloadResult {
+        bytes,
+        data,
+        retries,
+        elapsed: started.elapsed(),
+        digest,
+        content_type: body_headers.content_type.clone(),
+        failed_pieces: Vec::new(),
+        range_times,
+    })
+}
+
+/// Loads the partial `--output` file for `--resume`. With `--expected-prefix-hash`, a partial
+/// file that fails the check is discarded so the download restarts instead of extending garbage.
+fn resume_prefix(config: &Config, diag: &Diagnostics) -> Result<Vec<u8>, Box<dyn Error>> {
+    let Some(path) = config.output.as_deref().filter(|_| config.resume) else {
+        return Ok(Vec::new());
+    };
+    let prefix = match fs::read(path) {
+        Ok(prefix) => prefix,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read partial file {}: {}", path, e).into()),
+    };
+    if let Some(expected) = &config.expected_prefix_hash {
+        let mut hasher = config.algorithm_for(expected)?.new_hasher();
+        hasher.update(&prefix);
+        let actual = hex::encode(&hasher.finalize());
+        if actual != *expected {
+            diag.println(format_args!(
+                "Partial file {} ({} bytes) does not match --expected-prefix-hash (got {}); restarting from byte 0.",
+                path,
+                prefix.len(),
+                actual
+            ));
+            return Ok(Vec::new());
+        }
+    }
+    diag.println(format_args!(
+        "Resuming {} from byte {}.",
+        path,
+        prefix.len()
+    ));
+    Ok(prefix)
+}
+
+/// The `--resume` state file beside `--output` that records the ETag of the partial data.
+fn etag_sidecar_path(output: &str) -> String {
+    format!("{}.etag", output)
+}
+
+/// The ETag recorded for the partial `--output` file, if `--resume` saved one earlier.
+fn resume_etag(config: &Config) -> Option<String> {
+    let output = config.output.as_deref().filter(|_| config.resume)?;
+    let etag = fs::read_to_string(etag_sidecar_path(output)).ok()?;
+    Some(etag.trim().to_string()).filter(|etag| !etag.is_empty())
+}
+
+/// Records the ETag of the data being downloaded, so a later `--resume` can tell whether the
+/// server's copy changed in between.
+fn save_resume_etag(config: &Config, etag: &str) -> Result<(), Box<dyn Error>> {
+    let Some(output) = config.output.as_deref().filter(|_| config.resume) else {
+        return Ok(());
+    };
+    let path = etag_sidecar_path(output);
+    fs::write(&path, format!("{}\n", etag))
+        .map_err(|e| format!("Failed to write resume state {}: {}", path, e).into())
+}
+
+/// Converts CRLF line endings to LF for `--normalize-newlines`; lone CRs are kept.
+fn crlf_to_lf(data: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(data.len());
+    for (index, &byte) in data.iter().enumerate() {
+        if byte == b'\r' && data.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+    normalized
+}
+
+/// Compares the assembled length with the `Content-Range` total the server advertised, so
+/// truncation or overrun is reported as such rather than as a hash mismatch.
+fn check_total_length(length: usize, expected_total: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let Some(total) = expected_total else {
+        return Ok(());
+    };
+    let length = length as u64;
+    if length < total {
+        Err(format!(
+            "Downloaded length {} is shorter than the Content-Range total {} ({} bytes missing)",
+            length,
+            total,
+            total - length
+        )
+        .into())
+    } else if length > total {
+        Err(format!(
+            "Downloaded length {} exceeds the Content-Range total {} ({} extra bytes)",
+            length,
+            total,
+            length - total
+        )
+        .into())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::retry::RetryPolicy;
+    use crate::test_support::{response, spawn_keep_alive_server, spawn_server};
+    use sha2::{Digest, Sha256};
+
+    fn test_config(port: u16) -> Config {
+        Config {
+            port,
+            expected_hash: "unused".to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_download_retries_chunk_with_mismatched_sha256() {
+        let body = b"hello world";
+        let good_sha = format!(
+            "X-Content-SHA256: {}\r\n",
+            hex::encode(&Sha256::digest(body))
+        );
+        let bad_sha = format!(
+            "X-Content-SHA256: {}\r\n",
+            hex::encode(&Sha256::digest(b"something else"))
+        );
+        let (port, server) = spawn_server(vec![
+            response("200 OK", &bad_sha, body),
+            response("200 OK", &good_sha, body),
+        ]);
+        let config = Config {
+            verify_chunk_sha: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), body);
+        assert_eq!(download.digest, Sha256::digest(body).to_vec());
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("Range: bytes=0-\r\n"));
+        assert!(requests[1].contains("Range: bytes=0-\r\n"));
+    }
+
+    #[test]
+    fn test_chunk_sha256_is_not_checked_after_a_short_read() {
+        let sha = |bytes: &[u8]| {
+            format!(
+                "X-Content-SHA256: {}\r\n",
+                hex::encode(&Sha256::digest(bytes))
+            )
+        };
+        // The first response declares all 11 bytes and their hash but is cut off after 5.
+        let mut truncated = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n{}\r\n",
+            sha(b"hello world")
+        )
+        .into_bytes();
+        truncated.extend_from_slice(b"hello");
+        let (port, server) = spawn_server(vec![
+            truncated,
+            response(
+                "206 Partial Content",
+                &format!("Content-Range: bytes 5-10/11\r\n{}", sha(b" world")),
+                b" world",
+            ),
+        ]);
+        let config = Config {
+            verify_chunk_sha: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello world");
+        assert_eq!(download.retries, 0);
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("Range: bytes=5-\r\n"));
+    }
+
+    #[test]
+    fn test_range_hash_mismatch_refetches_the_range() {
+        let body = b"hello world";
+        let good = hex::encode(&Sha256::digest(body));
+        let bad = hex::encode(&Sha256::digest(b"something else"));
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", body),
+            response("200 OK", "", bad.as_bytes()),
+            response("206 Partial Content", "", body),
+            response("200 OK", "", format!("{}  -\n", good).as_bytes()),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            range_hash_url_template: Some(format!(
+                "http://127.0.0.1:{}/hash?range={{start}}-{{end}}",
+                port
+            )),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), body);
+        assert_eq!(download.retries, 1);
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].contains("Range: bytes=0-\r\n"));
+        assert!(requests[1].starts_with("GET /hash?range=0-10 HTTP/1.1\r\n"));
+        assert!(!requests[1].contains("Range:"));
+        assert!(requests[2].contains("Range: bytes=0-\r\n"));
+        assert!(requests[3].starts_with("GET /hash?range=0-10 HTTP/1.1\r\n"));
+        assert!(requests[4].contains("Range: bytes=11-\r\n"));
+    }
+
+    #[test]
+    fn test_html_content_type_is_recognized() {
+        assert!(is_html("text/html"));
+        assert!(is_html("Text/HTML; charset=utf-8"));
+        assert!(is_html("application/xhtml+xml"));
+        assert!(!is_html("application/octet-stream"));
+        assert!(!is_html("text/plain"));
+    }
+
+    #[test]
+    fn test_html_response_warns_on_mismatch_and_fails_with_fail_on_html() {
+        let page = b"<html>Not Found</html>";
+        let (port, server) = spawn_server(vec![response(
+            "200 OK",
+            "Content-Type: text/html; charset=utf-8\r\n",
+            page,
+        )]);
+        let download = download_file(&test_config(port), Box::new(Sha256::new())).unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            download.content_type.as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert!(download.html_warning().unwrap().contains("error page"));
+
+        let (port, server) = spawn_server(vec![response(
+            "200 OK",
+            "Content-Type: text/html\r\n",
+            page,
+        )]);
+        let config = Config {
+            fail_on_html: true,
+            ..test_config(port)
+        };
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("--fail-on-html"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fail_on_empty_rejects_empty_body_at_start() {
+        let (port, server) = spawn_server(vec![response("200 OK", "", b"")]);
+        let config = Config {
+            fail_on_empty: true,
+            ..test_config(port)
+        };
+
+        let error_msg = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.contains("empty body"));
+        assert!(error_msg.contains("--fail-on-empty"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_success_status_changes_which_responses_succeed() {
+        let responses = || {
+            vec![
+                response("203 Non-Authoritative Information", "", b"hello"),
+                response("206 Partial Content", "", b""),
+            ]
+        };
+        let (port, server) = spawn_server(responses()[..1].to_vec());
+        let error = download_file(&test_config(port), Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Server returned non-successful status: 203"
+        );
+        server.join().unwrap();
+
+        let (port, server) = spawn_server(responses());
+        let config = Config {
+            success_statuses: vec![200, 206, 203],
+            ..test_config(port)
+        };
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+        assert_eq!(download.data.unwrap(), b"hello");
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        let (port, server) = spawn_server(responses());
+        let config = Config {
+            success_statuses: vec![203],
+            ..test_config(port)
+        };
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Server returned non-successful status: 206"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_retries_resets_on_progress_unless_disabled() {
+        let responses = || {
+            vec![
+                response("206 Partial Content", "", b"ab"),
+                Vec::new(),
+                response("206 Partial Content", "", b"cd"),
+                Vec::new(),
+                response("206 Partial Content", "", b""),
+            ]
+        };
+        let (port, server) = spawn_server(responses());
+        let config = Config {
+            max_retries: Some(1),
+            ..test_config(port)
+        };
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+        assert_eq!(download.data.unwrap(), b"abcd");
+        assert_eq!(download.retries, 2);
+        server.join().unwrap();
+
+        let (port, server) = spawn_server(responses()[..4].to_vec());
+        let config = Config {
+            max_retries: Some(1),
+            reset_retries_on_progress: false,
+            ..test_config(port)
+        };
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Retry limit of 1 reached (--max-retries): "));
+        server.join().unwrap();
+    }
+
+    /// Toy digest: total length followed by the wrapping sum of all bytes.
+    struct ByteSumHasher {
+        length: u64,
+        sum: u8,
+    }
+
+    impl Hasher for ByteSumHasher {
+        fn update(&mut self, data: &[u8]) {
+            self.length += data.len() as u64;
+            self.sum = data.iter().fold(self.sum, |sum, &b| sum.wrapping_add(b));
+        }
+
+        fn finalize(self: Box<Self>) -> Vec<u8> {
+            let mut digest = self.length.to_be_bytes().to_vec();
+            digest.push(self.sum);
+            digest
+        }
+
+        fn reset(&mut self) {
+            self.length = 0;
+            self.sum = 0;
+        }
+    }
+
+    #[test]
+    fn test_download_uses_custom_hasher() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"\x01\x02"),
+            response("206 Partial Content", "", b"\x03"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let hasher = Box::new(ByteSumHasher { length: 0, sum: 0 });
+
+        let download = download_file(&test_config(port), hasher).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"\x01\x02\x03");
+        assert_eq!(download.digest, [0, 0, 0, 0, 0, 0, 0, 3, 6]);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    /// Digest: the length of every non-empty update, in order.
+    struct UpdateSizesHasher(Vec<u8>);
+
+    impl Hasher for UpdateSizesHasher {
+        fn update(&mut self, data: &[u8]) {
+            if !data.is_empty() {
+                self.0.push(data.len() as u8);
+            }
+        }
+
+        fn finalize(self: Box<Self>) -> Vec<u8> {
+            self.0
+        }
+
+        fn reset(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    #[test]
+    fn test_buffered_download_hashes_each_range_as_it_arrives() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello "),
+            response("206 Partial Content", "", b"world"),
+            response("206 Partial Content", "", b""),
+        ]);
+
+        let download =
+            download_file(&test_config(port), Box::new(UpdateSizesHasher(Vec::new()))).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(download.data.unwrap(), b"hello world");
+        assert_eq!(download.digest, [6, 5]);
+    }
+
+    #[test]
+    fn test_check_total_length() {
+        assert!(check_total_length(10, None).is_ok());
+        assert!(check_total_length(10, Some(10)).is_ok());
+        let short = check_total_length(7, Some(10)).err().unwrap().to_string();
+        assert!(short.contains("shorter than the Content-Range total 10 (3 bytes missing)"));
+        let long = check_total_length(12, Some(10)).err().unwrap().to_string();
+        assert!(long.contains("exceeds the Content-Range total 10 (2 extra bytes)"));
+    }
+
+    #[test]
+    fn test_download_fails_when_shorter_than_content_range_total() {
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-9/10\r\n",
+                b"only",
+            ),
+            response("206 Partial Content", "", b""),
+        ]);
+
+        let error_msg = download_file(&test_config(port), Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(
+            error_msg.contains("Downloaded length 4 is shorter than the Content-Range total 10")
+        );
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_download_fails_when_longer_than_content_range_total() {
+        let (port, server) = spawn_server(vec![response(
+            "206 Partial Content",
+            "Content-Range: bytes 0-2/3\r\n",
+            b"overrun",
+        )]);
+
+        let error_msg = download_file(&test_config(port), Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.contains("Downloaded length 7 exceeds the Content-Range total 3"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_mid_download_redirect_restarts_from_new_location() {
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-7/100\r\n",
+                b"old-data",
+            ),
+            response("302 Found", "Location: /new\r\n", b""),
+            response("200 OK", "", b"fresh"),
+        ]);
+
+        let download = download_file(&test_config(port), Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"fresh");
+        assert_eq!(download.digest, Sha256::digest(b"fresh").to_vec());
+        let requests = server.join().unwrap();
+        assert!(requests[1].starts_with("GET / HTTP/1.1\r\n"));
+        assert!(requests[1].contains("Range: bytes=8-\r\n"));
+        assert!(requests[2].starts_with("GET /new HTTP/1.1\r\n"));
+        assert!(requests[2].contains("Range: bytes=0-\r\n"));
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn test_redirects_beyond_max_redirects_fail() {
+        let (port, server) = spawn_server(vec![
+            response("301 Moved Permanently", "Location: /a\r\n", b""),
+            response("307 Temporary Redirect", "Location: /b\r\n", b""),
+        ]);
+        let config = Config {
+            max_redirects: 1,
+            ..test_config(port)
+        };
+
+        let error_msg = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.contains("--max-redirects=1"));
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_redirect_to_allowed_host_is_followed() {
+        let (port, server) = spawn_server(vec![response("200 OK", "", b"unused")]);
+        let (redirect_port, redirector) = spawn_server(vec![response(
+            "302 Found",
+            &format!("Location: http://localhost:{}/mirror\r\n", port),
+            b"",
+        )]);
+        let config = Config {
+            allow_redirect_hosts: vec!["localhost".to_string()],
+            ..test_config(redirect_port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"unused");
+        redirector.join().unwrap();
+        assert!(server.join().unwrap()[0].starts_with("GET /mirror HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_redirect_to_unlisted_host_is_refused() {
+        let (port, server) = spawn_server(vec![response(
+            "302 Found",
+            "Location: http://evil.example/payload\r\n",
+            b"",
+        )]);
+        let config = Config {
+            allow_redirect_hosts: vec!["mirror.example".to_string()],
+            ..test_config(port)
+        };
+
+        let error_msg = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.contains("host 'evil.example' is not in --allow-redirect-host"));
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    fn partial_file(name: &str, content: &[u8]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("client-{}-{}.part", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_download_result_reports_bytes_retries_and_digest() {
+        let (port, server) = spawn_server(vec![Vec::new(), response("200 OK", "", b"hello")]);
+
+        let download = download_file(&test_config(port), Box::new(Sha256::new())).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(download.data.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(download.bytes, 5);
+        assert_eq!(download.retries, 1);
+        assert!(download.elapsed >= Duration::from_millis(100));
+        assert_eq!(download.digest, Sha256::digest(b"hello").to_vec());
+    }
+
+    fn head_response(headers: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            reason: "OK".to_string(),
+            headers: headers
+                .lines()
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+            request_bytes: 0,
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn test_head_first_picks_single_request_for_small_or_unrangeable_resources() {
+        let small = head_response("Content-Length: 100\nAccept-Ranges: bytes");
+        let large = head_response("Content-Length: 5000000\nAccept-Ranges: bytes");
+        let no_ranges = head_response("Content-Length: 5000000");
+        // A gzip-accepting probe gets the compressed length, which says nothing of the file.
+        let compressed =
+            head_response("Content-Length: 5000000\nContent-Encoding: gzip\nAccept-Ranges: bytes");
+        assert_eq!(compressed.resource_length(), None);
+        assert!(!prefers_single_request(&compressed, 6_000_000));
+        assert!(prefers_single_request(&small, DEFAULT_SMALL_THRESHOLD));
+        assert!(!prefers_single_request(&large, DEFAULT_SMALL_THRESHOLD));
+        assert!(prefers_single_request(&no_ranges, DEFAULT_SMALL_THRESHOLD));
+    }
+
+    #[test]
+    fn test_head_first_small_resource_downloads_without_range() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nAccept-Ranges: bytes\r\n\r\n".to_vec();
+        let (port, server) = spawn_server(vec![head, response("200 OK", "", b"hello")]);
+        let config = Config {
+            head_first: true,
+            small_threshold: Some(1024),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("HEAD "));
+        assert!(requests[1].starts_with("GET "));
+        assert!(!requests[1].contains("Range:"));
+    }
+
+    #[test]
+    fn test_head_first_large_resource_uses_ranges() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nAccept-Ranges: bytes\r\n\r\n".to_vec();
+        let (port, server) = spawn_server(vec![head, response("200 OK", "", b"hello")]);
+        let config = Config {
+            head_first: true,
+            small_threshold: Some(4),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello");
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("Range: bytes=0-\r\n"));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_head_probe_accepts_gzip_while_ranges_ask_for_identity() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nAccept-Ranges: bytes\r\n\r\n".to_vec();
+        let (port, server) = spawn_server(vec![
+            head,
+            response("206 Partial Content", "", b"hello"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            head_first: true,
+            small_threshold: Some(4),
+            ..test_config(port)
+        };
+
+        download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("HEAD "));
+        assert!(requests[0].contains("Accept-Encoding: gzip\r\n"));
+        assert!(requests[1].contains("Range: bytes=0-\r\n"));
+        assert!(requests[1].contains("Accept-Encoding: identity\r\n"));
+    }
+
+    #[test]
+    fn test_head_failures_use_their_own_budget_then_fall_back_to_ranges() {
+        let (port, server) = spawn_server(vec![
+            Vec::new(),
+            Vec::new(),
+            response("200 OK", "", b"hello"),
+        ]);
+        let config = Config {
+            head_first: true,
+            head_retries: Some(1),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello");
+        assert_eq!(download.retries, 0);
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("HEAD "));
+        assert!(requests[1].starts_with("HEAD "));
+        assert!(requests[2].contains("Range: bytes=0-\r\n"));
+    }
+
+    #[test]
+    fn test_attempt_log_names_the_address_used() {
+        let peer: SocketAddr = "10.0.0.7:8080".parse().unwrap();
+        assert_eq!(
+            describe_attempt(206, 42, Some(peer)),
+            "Status: 206, Received: 42 bytes from 10.0.0.7:8080"
+        );
+        assert_eq!(
+            describe_attempt(206, 42, None),
+            "Status: 206, Received: 42 bytes"
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_single_request_gunzips_a_compressed_body() {
+        let gzipped = crate::test_support::gzip(b"hello world");
+        // The first response declares the full length but is cut off after 4 bytes.
+        let mut truncated = response("200 OK", "Content-Encoding: gzip\r\n", &gzipped);
+        truncated.truncate(truncated.len() - (gzipped.len() - 4));
+        let (port, server) = spawn_server(vec![
+            truncated,
+            response("200 OK", "Content-Encoding: gzip\r\n", &gzipped),
+        ]);
+        let config = Config {
+            single_request: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello world");
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("Range:"));
+        assert!(requests[0].contains("Accept-Encoding: gzip\r\n"));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_chunked_gzip_body_is_dechunked_before_gunzipping() {
+        let gzipped = crate::test_support::gzip(b"chunked and compressed");
+        let (first, second) = gzipped.split_at(gzipped.len() / 2);
+        let mut chunked =
+            b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_vec();
+        for chunk in [first, second] {
+            chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+        let (port, server) = spawn_server(vec![chunked]);
+        let config = Config {
+            single_request: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"chunked and compressed");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_content_encoding_fails_the_download() {
+        let (port, server) = spawn_server(vec![response(
+            "200 OK",
+            "Content-Encoding: br\r\n",
+            b"compressed",
+        )]);
+        let config = Config {
+            single_request: true,
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+
+        server.join().unwrap();
+        assert!(error
+            .to_string()
+            .contains("Unsupported Content-Encoding: br"));
+    }
+
+    #[test]
+    fn test_resume_continues_after_matching_prefix() {
+        let path = partial_file("resume-good", b"hello ");
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            resume: true,
+            expected_prefix_hash: Some(hex::encode(&Sha256::digest(b"hello "))),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(download.data.unwrap(), b"hello world");
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+        assert!(server.join().unwrap()[0].contains("Range: bytes=6-\r\n"));
+    }
+
+    #[test]
+    fn test_resume_restarts_when_the_etag_changed() {
+        let path = partial_file("resume-etag", b"hello ");
+        let sidecar = etag_sidecar_path(&path);
+        fs::write(&sidecar, "\"v1\"\n").unwrap();
+        let (port, server) = spawn_server(vec![
+            response("200 OK", "ETag: \"v2\"\r\n", b"HELLO WORLD"),
+            response("200 OK", "ETag: \"v2\"\r\n", b"HELLO WORLD"),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            resume: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let saved = fs::read_to_string(&sidecar).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sidecar).unwrap();
+        assert_eq!(download.data.unwrap(), b"HELLO WORLD");
+        assert_eq!(saved, "\"v2\"\n");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=6-\r\nIf-Range: \"v1\"\r\n"));
+        assert!(requests[1].contains("Range: bytes=0-\r\n"));
+        assert!(!requests[1].contains("If-Range"));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn test_resume_restarts_when_prefix_hash_mismatches() {
+        let path = partial_file("resume-bad", b"garbage");
+        let (port, server) = spawn_server(vec![response("200 OK", "", b"hello world")]);
+        let config = Config {
+            output: Some(path.clone()),
+            resume: true,
+            expected_prefix_hash: Some(hex::encode(&Sha256::digest(b"hello "))),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(download.data.unwrap(), b"hello world");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=0-\r\n"));
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn test_crlf_to_lf() {
+        assert_eq!(crlf_to_lf(b"a\r\nb\nc\rd\r\n"), b"a\nb\nc\rd\n");
+    }
+
+    #[test]
+    fn test_normalize_newlines_affects_only_the_hash() {
+        let (port, server) =
+            spawn_server(vec![response("200 OK", "", b"line one\r\nline two\r\n")]);
+        let config = Config {
+            normalize_newlines: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(download.data.unwrap(), b"line one\r\nline two\r\n");
+        assert_eq!(
+            download.digest,
+            Sha256::digest(b"line one\nline two\n").to_vec()
+        );
+    }
+
+    #[test]
+    fn test_normalize_output_also_rewrites_saved_bytes() {
+        let (port, server) =
+            spawn_server(vec![response("200 OK", "", b"line one\r\nline two\r\n")]);
+        let config = Config {
+            normalize_newlines: true,
+            normalize_output: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(download.data.unwrap(), b"line one\nline two\n");
+        assert_eq!(
+            download.digest,
+            Sha256::digest(b"line one\nline two\n").to_vec()
+        );
+    }
+
+    #[test]
+    fn test_short_read_requests_only_the_remainder() {
+        let (port, server) = spawn_server(vec![
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 10\r\n\r\nabcd".to_vec(),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 4-9/10\r\n",
+                b"efghij",
+            ),
+        ]);
+
+        let download = download_file(&test_config(port), Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"abcdefghij");
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=0-\r\n"));
+        assert!(requests[1].contains("Range: bytes=4-\r\n"));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn test_misaligned_content_range_is_rejected() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"abcd"),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-9/10\r\n",
+                b"abcdefghij",
+            ),
+        ]);
+
+        let error_msg = download_file(&test_config(port), Box::new(Sha256::new()))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error_msg.contains("bytes starting at 0 for a range starting at 4"));
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_only_the_bad_piece_is_fetched_again() {
+        let pieces: String = [&b"abcd"[..], b"efgh", b"ij"]
+            .iter()
+            .map(|piece| hex::encode(&Sha256::digest(piece)) + "\n")
+            .collect();
+        let path = partial_file("pieces", pieces.as_bytes());
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-9/10\r\n",
+                b"abcdeXghij",
+            ),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 4-7/10\r\n",
+                b"efgh",
+            ),
+        ]);
+        let config = Config {
+            pieces_file: Some(path.clone()),
+            piece_length: Some(4),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(download.data.unwrap(), b"abcdefghij");
+        assert_eq!(download.digest, Sha256::digest(b"abcdefghij").to_vec());
+        assert_eq!(download.failed_pieces, [1]);
+        assert_eq!(download.retries, 1);
+        let requests = server.join().unwrap();
+        // The verified bytes after the bad piece are kept: nothing past it is asked for again.
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("Range: bytes=4-7\r\n"));
+    }
+
+    #[test]
+    fn test_bad_piece_refetches_are_logged_and_count_toward_max_retries() {
+        let pieces = hex::encode(&Sha256::digest(b"abcd")) + "\n";
+        let path = partial_file("pieces-limit", pieces.as_bytes());
+        let log = std::env::temp_dir()
+            .join(format!("client-piece-retry-log-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = fs::remove_file(&log);
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"abcX"),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-3/4\r\n",
+                b"abcX",
+            ),
+        ]);
+        let config = Config {
+            pieces_file: Some(path.clone()),
+            piece_length: Some(4),
+            max_retries: Some(1),
+            retry_log: Some(log.clone()),
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        server.join().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        let text = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Retry limit of 1 reached (--max-retries): Piece 0"));
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains("offset=0 kind=other attempt=1"));
+        assert!(text.contains("Piece 0 (bytes 0-3) failed verification (attempt 1)"));
+    }
+
+    #[test]
+    fn test_bad_final_piece_is_not_served_from_the_response_cache() {
+        let pieces = hex::encode(&Sha256::digest(b"abc")) + "\n";
+        let path = partial_file("final-piece", pieces.as_bytes());
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"abX"),
+            response("206 Partial Content", "", b""),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-2/3\r\n",
+                b"abc",
+            ),
+        ]);
+        let config = Config {
+            pieces_file: Some(path.clone()),
+            piece_length: Some(4),
+            response_cache: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(download.data.unwrap(), b"abc");
+        assert_eq!(download.failed_pieces, [0]);
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].contains("Range: bytes=0-2\r\n"));
+    }
+
+    #[test]
+    fn test_start_offset_sets_the_first_range() {
+        let (port, server) = spawn_server(vec![response(
+            "206 Partial Content",
+            "Content-Range: bytes 6-10/11\r\n",
+            b"world",
+        )]);
+        let config = Config {
+            start_offset: Some(6),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"world");
+        assert_eq!(download.digest, Sha256::digest(b"world").to_vec());
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("Range: bytes=6-\r\n"));
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn test_suffix_downloads_and_hashes_only_the_tail() {
+        let (port, server) = spawn_server(vec![response(
+            "206 Partial Content",
+            "Content-Range: bytes 6-10/11\r\n",
+            b"world",
+        )]);
+        let config = Config {
+            suffix: Some(5),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"world");
+        assert_eq!(download.digest, Sha256::digest(b"world").to_vec());
+        assert!(server.join().unwrap()[0].contains("Range: bytes=-5\r\n"));
+    }
+
+    #[test]
+    fn test_hash_only_digests_without_keeping_the_data() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello "),
+            Vec::new(),
+            response("206 Partial Content", "", b"world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            hash_only: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(download.data.is_none());
+        assert_eq!(download.bytes, 11);
+        assert_eq!(download.retries, 1);
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+        assert!(requests[1].contains("Range: bytes=6-\r\n"));
+        assert!(requests[2].contains("Range: bytes=6-\r\n"));
+    }
+
+    #[test]
+    fn test_streaming_stops_at_the_content_range_total() {
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-5/11\r\n",
+                b"hello ",
+            ),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 6-10/11\r\n",
+                b"world",
+            ),
+        ]);
+        let config = Config {
+            hash_only: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.bytes, 11);
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_output_file_is_written_range_by_range() {
+        let path = partial_file("streamed", b"stale contents");
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello "),
+            Vec::new(),
+            response("206 Partial Content", "", b"world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(download.data.is_none());
+        assert_eq!(saved, b"hello world");
+        assert_eq!(download.bytes, 11);
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+        let requests = server.join().unwrap();
+        assert!(requests[2].contains("Range: bytes=6-\r\n"));
+        // Options that need the whole body keep it in memory.
+        assert_eq!(
+            buffering_option(&Config {
+                decompress_gzip: true,
+                ..config.clone()
+            }),
+            Some("--decompress-gzip")
+        );
+    }
+
+    fn part_exists(path: &str) -> bool {
+        std::path::Path::new(&format!("{}.part", path)).exists()
+    }
+
+    #[test]
+    fn test_streamed_output_replaces_the_file_only_once_complete() {
+        let path = partial_file("replaced", b"previous contents");
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello "),
+            response(
+                "206 Partial Content",
+                "Content-Type: text/html\r\n",
+                b"<html>",
+            ),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            fail_on_html: true,
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+
+        server.join().unwrap();
+        assert!(error.to_string().contains("--fail-on-html"));
+        assert_eq!(fs::read(&path).unwrap(), b"previous contents");
+        assert!(!part_exists(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_output_starts_over_after_a_redirect() {
+        let path = partial_file("redirected", b"");
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-7/100\r\n",
+                b"old-data",
+            ),
+            response("302 Found", "Location: /new\r\n", b""),
+            response("200 OK", "", b"fresh"),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(download.data.is_none());
+        assert_eq!(saved, b"fresh");
+        assert_eq!(download.digest, Sha256::digest(b"fresh").to_vec());
+        let requests = server.join().unwrap();
+        assert!(requests[2].starts_with("GET /new HTTP/1.1\r\n"));
+        assert!(requests[2].contains("Range: bytes=0-\r\n"));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_streamed_output_falls_back_to_buffering_for_an_unrequested_encoding() {
+        let path = partial_file("encoded", b"previous contents");
+        let archive = crate::test_support::gzip(b"plain text");
+        let (port, server) = spawn_server(vec![
+            response("200 OK", "Content-Encoding: gzip\r\n", &archive),
+            response("200 OK", "Content-Encoding: gzip\r\n", &archive),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(server.join().unwrap().len(), 2);
+        assert_eq!(download.data.unwrap(), b"plain text");
+        assert_eq!(download.digest, Sha256::digest(b"plain text").to_vec());
+        // The buffered body is written by the caller, as for any buffered download.
+        assert_eq!(fs::read(&path).unwrap(), b"previous contents");
+        assert!(!part_exists(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_output_checks_range_hashes() {
+        let path = partial_file("range-hashes", b"");
+        let body = b"hello world";
+        let good = hex::encode(&Sha256::digest(body));
+        let bad = hex::encode(&Sha256::digest(b"something else"));
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", body),
+            response("200 OK", "", bad.as_bytes()),
+            response("206 Partial Content", "", body),
+            response("200 OK", "", good.as_bytes()),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            output: Some(path.clone()),
+            range_hash_url_template: Some(format!(
+                "http://127.0.0.1:{}/hash?range={{start}}-{{end}}",
+                port
+            )),
+            fail_on_empty: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(download.data.is_none());
+        assert_eq!(saved, body);
+        assert_eq!(download.retries, 1);
+        assert_eq!(server.join().unwrap().len(), 5);
+    }
+
+    fn connections_for(keep_alive: bool) -> usize {
+        let (port, server) = spawn_keep_alive_server(vec![
+            response("206 Partial Content", "", b"one "),
+            response("206 Partial Content", "", b"two "),
+            response("206 Partial Content", "", b"three"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            keep_alive,
+            ..test_config(port)
+        };
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+        assert_eq!(download.data.unwrap(), b"one two three");
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_connection_per_range_and_shared_connection() {
+        assert_eq!(connections_for(false), 4);
+        assert_eq!(connections_for(true), 1);
+    }
+
+    #[test]
+    fn test_retry_log_records_each_retry() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello"),
+            Vec::new(),
+            Vec::new(),
+            response("206 Partial Content", "", b""),
+        ]);
+        let log = std::env::temp_dir()
+            .join(format!("client-retry-log-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = fs::remove_file(&log);
+        let config = Config {
+            retry_log: Some(log.clone()),
+            ..test_config(port)
+        };
+
+        download_file(&config, Box::new(Sha256::new())).unwrap();
+        server.join().unwrap();
+
+        let text = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, attempt) in lines.iter().zip(1..) {
+            assert!(
+                line.contains(&format!(
+                    " offset=5 kind=other attempt={} backoff_ms=100 error=",
+                    attempt
+                )),
+                "{}",
+                line
+            );
+            assert!(line.contains("Connection closed before status line"));
+        }
+    }
+
+    #[test]
+    fn test_single_request_short_reads_count_toward_max_retries() {
+        let truncated = || b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello".to_vec();
+        let (port, server) = spawn_server(vec![truncated(), truncated(), truncated()]);
+        let log = std::env::temp_dir()
+            .join(format!("client-short-read-log-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = fs::remove_file(&log);
+        let config = Config {
+            single_request: true,
+            max_retries: Some(2),
+            retry_log: Some(log.clone()),
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        server.join().unwrap();
+
+        assert!(error
+            .to_string()
+            .starts_with("Retry limit of 2 reached (--max-retries): Short read"));
+        let text = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.contains("offset=0 kind=other attempt=1"));
+        assert!(text.contains("Short read: received 5 of 10 declared bytes"));
+    }
+
+    #[test]
+    fn test_failed_download_reports_its_retries_and_bytes() {
+        let (port, server) = spawn_server(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-4/10\r\n",
+                b"hello",
+            ),
+            Vec::new(),
+            Vec::new(),
+        ]);
+        let config = Config {
+            max_retries: Some(1),
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+        server.join().unwrap();
+
+        let failed = error.downcast_ref::<DownloadError>().unwrap();
+        assert_eq!((failed.retries, failed.bytes), (1, 5));
+        assert!(error
+            .to_string()
+            .starts_with("Retry limit of 1 reached (--max-retries)"));
+    }
+
+    #[test]
+    fn test_range_callback_receives_every_range() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello "),
+            response("206 Partial Content", "", b"range "),
+            response("206 Partial Content", "", b"world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let ranges = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback = {
+            let ranges = Arc::clone(&ranges);
+            RangeCallback::new(move |offset, bytes| {
+                ranges.lock().unwrap().push((offset, bytes.to_vec()));
+            })
+        };
+        let config = Config {
+            on_range_complete: Some(callback),
+            ..test_config(port)
+        };
+
+        download_file(&config, Box::new(Sha256::new())).unwrap();
+        server.join().unwrap();
+
+        let ranges = ranges.lock().unwrap();
+        assert_eq!(
+            ranges.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(),
+            [0, 6, 12]
+        );
+        let mut file = Vec::new();
+        for (offset, bytes) in ranges.iter() {
+            file.truncate(*offset as usize);
+            file.extend_from_slice(bytes);
+        }
+        assert_eq!(file, b"hello range world");
+    }
+
+    #[test]
+    fn test_custom_retry_policy_can_stop_on_a_retryable_error() {
+        // Nothing listens on the port once the listener is dropped: connection refused.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let calls = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let policy = {
+            let calls = Arc::clone(&calls);
+            RetryPolicy::new(move |error, retries| {
+                calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                assert!(is_retryable(error));
+                if retries < 2 {
+                    RetryDecision::RetryAfter(Duration::from_millis(1))
+                } else {
+                    RetryDecision::Stop
+                }
+            })
+        };
+        let config = Config {
+            retry_policy: Some(policy),
+            ..test_config(port)
+        };
+
+        let error = download_file(&config, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+
+        assert!(error.to_string().starts_with("Fatal download error"));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_chunk_sha256_mismatch_is_retryable() {
+        let error: Box<dyn Error> = "Chunk SHA-256 mismatch: declared abc".into();
+        assert!(is_retryable(error.as_ref()));
+        let error: Box<dyn Error> = "Server returned non-successful status: 404".into();
+        assert!(!is_retryable(error.as_ref()));
+    }
+
+    #[test]
+    fn test_inter_range_delay_applies_between_successful_ranges_only() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello"),
+            response("206 Partial Content", "", b" world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            inter_range_delay: Some(Duration::from_secs(1)),
+            ..test_config(port)
+        };
+
+        let started = Instant::now();
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+        let elapsed = started.elapsed();
+
+        server.join().unwrap();
+        assert_eq!(download.data.unwrap(), b"hello world");
+        // Two pauses: before the second and the third request, none before the first. A third
+        // pause would take the run past 3s, which leaves a loaded machine most of a second.
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_stop_on_hash_match_stops_once_the_data_matches() {
+        // Without the flag the download would go on to " extra" and the final empty range.
+        let (port, _server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello"),
+            response("206 Partial Content", "", b" world"),
+            response("206 Partial Content", "", b" extra"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let config = Config {
+            expected_hash: hex::encode(&Sha256::digest(b"hello world")),
+            stop_on_hash_match: true,
+            ..test_config(port)
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+
+        assert_eq!(download.data.unwrap(), b"hello world");
+        assert_eq!(download.digest, Sha256::digest(b"hello world").to_vec());
+    }
+
+    #[test]
+    fn test_cancel_token_aborts_the_inter_range_delay() {
+        let (port, server) = spawn_server(vec![response("206 Partial Content", "", b"hello")]);
+        let config = Config {
+            inter_range_delay: Some(Duration::from_secs(30)),
+            ..test_config(port)
+        };
+        let token = CancelToken::new();
+        let client = build_client(&config).with_cancel_token(Some(token.clone()));
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+
+        let started = Instant::now();
+        let error = download_with_client(&config, &client, Box::new(Sha256::new()))
+            .err()
+            .unwrap();
+
+        canceller.join().unwrap();
+        server.join().unwrap();
+        assert!(error.to_string().contains("cancelled"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
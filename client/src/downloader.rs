@@ -0,0 +1,171 @@
+use std::error::Error;
+
+use crate::config::Config;
+use crate::download::{download_file, DownloadResult, RangeCallback};
+use crate::hashing::{check_digests, Algorithm, DigestCheck, MultiHasher};
+use crate::retry::RetryPolicy;
+use crate::url::Url;
+
+/// The library entry point for embedding the client: a range-by-range download of one URL,
+/// retried on network errors and checked against the expected digests.
+///
+/// It wraps a `Config`, so every command-line option is also available through `config_mut`.
+/// Unlike the command line it prints nothing unless asked to with `with_diagnostics`.
+///
+/// ```no_run
+/// use client::downloader::Downloader;
+/// use client::hashing::Algorithm;
+///
+/// let verified = Downloader::new("http://127.0.0.1:8080/")?
+///     .with_hash(Algorithm::Sha256, "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")
+///     .download_verified()?;
+/// println!("{} bytes", verified.download.bytes);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    config: Config,
+}
+
+/// A download together with the digest checks it passed.
+pub struct VerifiedDownload {
+    pub download: DownloadResult,
+    pub checks: Vec<DigestCheck>,
+}
+
+impl Downloader {
+    /// A silent downloader for `url` with the default options and no expected hash yet.
+    pub fn new(url: &str) -> Result<Self, Box<dyn Error>> {
+        let config = Config {
+            silent: true,
+            ..Config::default()
+        };
+        Ok(Downloader::from_config(config).with_url(&Url::parse(url)?))
+    }
+
+    /// A downloader for a configuration built elsewhere, e.g. by `args::parse_args`, which
+    /// prints progress and warnings as that configuration says.
+    pub fn from_config(config: Config) -> Self {
+        Downloader { config }
+    }
+
+    pub fn with_url(mut self, url: &Url) -> Self {
+        self.config.scheme = url.scheme;
+        self.config.host = url.host.clone();
+        self.config.port = url.port;
+        self.config.path = url.path.clone();
+        self
+    }
+
+    /// Adds a digest the data must match; may be called once per algorithm.
+    pub fn with_hash(mut self, algorithm: Algorithm, hex: &str) -> Self {
+        self.config
+            .prefixed_hashes
+            .push((algorithm, hex.to_ascii_lowercase()));
+        self
+    }
+
+    /// Hands every accepted range to `callback`, e.g. to store it somewhere other than memory.
+    pub fn with_range_callback(mut self, callback: RangeCallback) -> Self {
+        self.config.on_range_complete = Some(callback);
+        self
+    }
+
+    /// Prints progress, retries and warnings like the command line does.
+    pub fn with_diagnostics(mut self) -> Self {
+        self.config.silent = false;
+        self
+    }
+
+    /// Decides which errors are retried and after how long, in place of the default policy.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry_policy = Some(policy);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Downloads the data, digesting it with every expected algorithm, and leaves the checks to
+    /// the caller (see `hashing::check_digests`).
+    pub fn download(&self) -> Result<DownloadResult, Box<dyn Error>> {
+        let algorithms: Vec<_> = self
+            .config
+            .expected_digests()?
+            .iter()
+            .map(|(algorithm, _)| *algorithm)
+            .collect();
+        download_file(&self.config, Box::new(MultiHasher::new(&algorithms)))
+    }
+
+    /// Downloads the data and fails unless it matches every expected digest.
+    pub fn download_verified(&self) -> Result<VerifiedDownload, Box<dyn Error>> {
+        let expected = self.config.expected_digests()?;
+        if expected.is_empty() {
+            return Err("No expected hash to verify against (see Downloader::with_hash)".into());
+        }
+        let download = self.download()?;
+        let checks = check_digests(&expected, &download.digest);
+        if let Some(check) = checks.iter().find(|check| !check.matches()) {
+            return Err(format!(
+                "Hashes DO NOT match: expected {} {}, got {}",
+                check.algorithm, check.expected, check.actual
+            )
+            .into());
+        }
+        Ok(VerifiedDownload { download, checks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+    use crate::test_support::{response, spawn_server};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_download_verified_checks_the_expected_hash() {
+        let (port, server) = spawn_server(vec![
+            response("206 Partial Content", "", b"hello world"),
+            response("206 Partial Content", "", b""),
+            response("206 Partial Content", "", b"hello world"),
+            response("206 Partial Content", "", b""),
+        ]);
+        let url = format!("http://127.0.0.1:{}/file", port);
+        let good = hex::encode(&Sha256::digest(b"hello world"));
+
+        let verified = Downloader::new(&url)
+            .unwrap()
+            .with_hash(Algorithm::Sha256, &good.to_ascii_uppercase())
+            .download_verified()
+            .unwrap();
+        assert_eq!(verified.download.data.unwrap(), b"hello world");
+        assert_eq!(verified.checks[0].actual, good);
+
+        let error = Downloader::new(&url)
+            .unwrap()
+            .with_hash(Algorithm::Sha256, &"0".repeat(64))
+            .download_verified()
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("DO NOT match"));
+        assert!(Downloader::new(&url).unwrap().download_verified().is_err());
+        assert!(Downloader::new(&url).unwrap().config().silent);
+        assert!(
+            !Downloader::new(&url)
+                .unwrap()
+                .with_diagnostics()
+                .config()
+                .silent
+        );
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /file HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+
+/// Records the raw bytes received from the server (`--dump-raw`), up to an optional cap
+/// (`--max-size`), so a glitched response can be inspected exactly as it arrived.
+pub struct RawDump {
+    state: Mutex<DumpState>,
+}
+
+struct DumpState {
+    sink: Box<dyn Write + Send>,
+    remaining: Option<usize>,
+}
+
+impl RawDump {
+    pub fn new(sink: Box<dyn Write + Send>, max_size: Option<usize>) -> Self {
+        RawDump {
+            state: Mutex::new(DumpState {
+                sink,
+                remaining: max_size,
+            }),
+        }
+    }
+
+    pub fn record(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let take = match state.remaining {
+            Some(remaining) => remaining.min(bytes.len()),
+            None => bytes.len(),
+        };
+        if take == 0 {
+            return Ok(());
+        }
+        state.sink.write_all(&bytes[..take])?;
+        state.sink.flush()?;
+        if let Some(remaining) = state.remaining.as_mut() {
+            *remaining -= take;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for RawDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawDump").finish_non_exhaustive()
+    }
+}
+
+/// Opens the `--dump-raw` file, if one was requested.
+pub fn open_raw_dump(config: &Config) -> io::Result<Option<Arc<RawDump>>> {
+    config
+        .dump_raw
+        .as_ref()
+        .map(|path| {
+            let file = File::create(path)?;
+            Ok(Arc::new(RawDump::new(Box::new(file), config.max_size)))
+        })
+        .transpose()
+}
+
+/// Passes reads through, copying every received byte into the dump.
+pub struct TeeReader<'a, R> {
+    inner: R,
+    dump: Option<&'a RawDump>,
+}
+
+impl<'a, R: Read> TeeReader<'a, R> {
+    pub fn new(inner: R, dump: Option<&'a RawDump>) -> Self {
+        TeeReader { inner, dump }
+    }
+}
+
+impl<R: Read> Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(dump) = self.dump {
+            dump.record(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_dump_stops_at_max_size() {
+        let path = std::env::temp_dir().join(format!("client-dump-cap-{}.bin", std::process::id()));
+        let dump = RawDump::new(Box::new(File::create(&path).unwrap()), Some(10));
+        let mut reader = TeeReader::new(&b"HTTP/1.1 200 OK\r\n\r\nbody"[..], Some(&dump));
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+
+        assert_eq!(received, b"HTTP/1.1 200 OK\r\n\r\nbody");
+        assert_eq!(fs::read(&path).unwrap(), b"HTTP/1.1 2");
+        fs::remove_file(&path).unwrap();
+    }
+}
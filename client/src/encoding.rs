@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "gzip")]
+use flate2::read::GzDecoder;
+
+use crate::hashing::{Algorithm, DigestCheck, Hasher};
+use crate::hex;
+
+pub const GZIP_UNAVAILABLE: &str = "gzip support not compiled in (enable the `gzip` feature)";
+
+/// When to ask the server for a compressed body. Byte ranges of a compressed body are offsets
+/// into the compressed stream, so by default only single-request downloads accept compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Accept gzip with `--single-request`, identity for ranged downloads.
+    #[default]
+    Auto,
+    /// Always request identity encoding.
+    Off,
+    /// Always accept gzip, even for ranged downloads.
+    On,
+}
+
+impl Compression {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "auto" => Ok(Compression::Auto),
+            "off" => Ok(Compression::Off),
+            "on" => Ok(Compression::On),
+            _ => Err(format!(
+                "Invalid --compression '{}' (expected auto, off or on)",
+                value
+            )
+            .into()),
+        }
+    }
+
+    /// Whether requests advertise gzip for the given download mode.
+    pub fn accepts_gzip(self, single_request: bool) -> bool {
+        cfg!(feature = "gzip")
+            && match self {
+                Compression::Auto => single_request,
+                Compression::Off => false,
+                Compression::On => true,
+            }
+    }
+
+    /// Whether `HEAD` probes advertise gzip: always unless compression is off, since they
+    /// have no ranged body to keep in identity encoding.
+    pub fn probe_accepts_gzip(self) -> bool {
+        cfg!(feature = "gzip") && self != Compression::Off
+    }
+}
+
+/// Wraps `source` in a reader that undoes `content_encoding` as it is read, so a compressed
+/// body can be hashed or written chunk by chunk without inflating all of it first.
+pub fn decoding_reader<'a, R: Read + 'a>(
+    content_encoding: Option<&str>,
+    source: R,
+) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    if is_gzip(content_encoding)? {
+        Ok(gunzip_reader(source)?)
+    } else {
+        Ok(Box::new(source))
+    }
+}
+
+/// Whether the body is sent as is: no `Content-Encoding`, or `identity`.
+pub fn is_identity(content_encoding: Option<&str>) -> bool {
+    content_encoding
+        .map(str::trim)
+        .is_none_or(|encoding| encoding.is_empty() || encoding.eq_ignore_ascii_case("identity"))
+}
+
+/// Whether `content_encoding` is gzip; identity (or none) is `false` and anything else an error.
+fn is_gzip(content_encoding: Option<&str>) -> Result<bool, Box<dyn Error>> {
+    match content_encoding.map(str::trim) {
+        None | Some("") => Ok(false),
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => Ok(false),
+        Some(encoding)
+            if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("x-gzip") =>
+        {
+            Ok(true)
+        }
+        Some(encoding) => Err(format!("Unsupported Content-Encoding: {}", encoding).into()),
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip_reader<'a, R: Read + 'a>(source: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(GzDecoder::new(source)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip_reader<'a, R: Read + 'a>(_source: R) -> io::Result<Box<dyn Read + 'a>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, GZIP_UNAVAILABLE))
+}
+
+/// Streams the gunzipped content of `data` into `sink` through a fixed-size buffer, so only the
+/// compressed input and the decoder's window are held in memory.
+pub fn gunzip_to<W: Write>(data: &[u8], sink: &mut W) -> io::Result<u64> {
+    io::copy(&mut gunzip_reader(data)?, sink)
+}
+
+/// Adapts a `Hasher` so decompressed bytes can be hashed as they are produced.
+struct HashingWriter(Box<dyn Hasher>);
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Checks `--verify-inner-hash`: hashes the gunzipped content of `data` without buffering it.
+pub fn check_inner_digest(
+    data: &[u8],
+    algorithm: Algorithm,
+    expected: &str,
+) -> Result<DigestCheck, Box<dyn Error>> {
+    let mut writer = HashingWriter(algorithm.new_hasher());
+    gunzip_to(data, &mut writer).map_err(|e| format!("Failed to gunzip the data: {}", e))?;
+    Ok(DigestCheck {
+        algorithm,
+        expected: expected.to_string(),
+        actual: hex::encode(&writer.0.finalize()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_accepts_gzip_only_for_single_request() {
+        let gzip = cfg!(feature = "gzip");
+        assert_eq!(Compression::Auto.accepts_gzip(true), gzip);
+        assert!(!Compression::Auto.accepts_gzip(false));
+        assert!(!Compression::Off.accepts_gzip(true));
+        assert_eq!(Compression::On.accepts_gzip(false), gzip);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_inner_digest_hashes_the_decompressed_content() {
+        // SHA-256 of "hello".
+        let expected = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let archive = crate::test_support::gzip(b"hello");
+        let check = check_inner_digest(&archive, Algorithm::Sha256, expected).unwrap();
+        assert!(check.matches());
+        assert!(check_inner_digest(b"hello", Algorithm::Sha256, expected).is_err());
+    }
+}
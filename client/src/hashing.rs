@@ -0,0 +1,447 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::{base64, hex};
+
+/// Incremental digest used to verify downloaded bytes.
+///
+/// The built-in algorithms implement it, and library users can plug in their own.
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+    /// Forgets everything fed so far, for a download that restarts from byte 0.
+    fn reset(&mut self);
+}
+
+macro_rules! impl_hasher_for_digest {
+    ($($digest:ty),*) => {$(
+        impl Hasher for $digest {
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(self, data);
+            }
+
+            fn finalize(self: Box<Self>) -> Vec<u8> {
+                Digest::finalize(*self).to_vec()
+            }
+
+            fn reset(&mut self) {
+                Digest::reset(self);
+            }
+        }
+    )*};
+}
+
+impl_hasher_for_digest!(Sha1, Sha256, Sha512);
+
+/// The built-in digest algorithms selectable with `--algo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            _ => Err(format!(
+                "Unknown hash algorithm '{}' (expected sha1, sha256 or sha512)",
+                name
+            )
+            .into()),
+        }
+    }
+
+    /// Infers the algorithm from the length of a hex-encoded digest.
+    pub fn from_hex_len(expected_hash: &str) -> Result<Self, Box<dyn Error>> {
+        match expected_hash.len() {
+            40 => Ok(Algorithm::Sha1),
+            64 => Ok(Algorithm::Sha256),
+            128 => Ok(Algorithm::Sha512),
+            len => Err(format!(
+                "Cannot infer the hash algorithm from a {}-character hash; pass --algo explicitly",
+                len
+            )
+            .into()),
+        }
+    }
+
+    /// Digest length in bytes.
+    pub fn output_len(self) -> usize {
+        match self {
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+
+    pub fn new_hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Sha1 => Box::new(Sha1::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Algorithm::Sha1 => "SHA-1",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha512 => "SHA-512",
+        })
+    }
+}
+
+/// How `--hash-format` prints a computed digest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashFormat {
+    /// Lowercase hex, as `sha256sum` prints it.
+    #[default]
+    Hex,
+    /// Uppercase hex.
+    UpperHex,
+    /// Standard base64 with padding.
+    Base64,
+    /// Subresource Integrity: `sha256-<base64>`.
+    Sri,
+}
+
+impl HashFormat {
+    /// Parses `hex`, `HEX`, `base64` or `sri`; the case of `hex` picks the case of the digits.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "hex" => Ok(HashFormat::Hex),
+            "HEX" => Ok(HashFormat::UpperHex),
+            "base64" => Ok(HashFormat::Base64),
+            "sri" => Ok(HashFormat::Sri),
+            _ => Err(format!(
+                "Invalid --hash-format '{}' (expected hex, HEX, base64 or sri)",
+                value
+            )
+            .into()),
+        }
+    }
+
+    pub fn render(self, algorithm: Algorithm, digest: &[u8]) -> String {
+        match self {
+            HashFormat::Hex => hex::encode(digest),
+            HashFormat::UpperHex => hex::encode(digest).to_ascii_uppercase(),
+            HashFormat::Base64 => base64::encode(digest),
+            HashFormat::Sri => {
+                let name = match algorithm {
+                    Algorithm::Sha1 => "sha1",
+                    Algorithm::Sha256 => "sha256",
+                    Algorithm::Sha512 => "sha512",
+                };
+                format!("{}-{}", name, base64::encode(digest))
+            }
+        }
+    }
+}
+
+/// A built-in digest that can be read after any update without ending it, for
+/// `--stop-on-hash-match`. Each read finalizes a clone, so it costs a copy of the state.
+#[derive(Clone)]
+pub enum RunningDigest {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl RunningDigest {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha1 => RunningDigest::Sha1(Sha1::new()),
+            Algorithm::Sha256 => RunningDigest::Sha256(Sha256::new()),
+            Algorithm::Sha512 => RunningDigest::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            RunningDigest::Sha1(digest) => Digest::update(digest, data),
+            RunningDigest::Sha256(digest) => Digest::update(digest, data),
+            RunningDigest::Sha512(digest) => Digest::update(digest, data),
+        }
+    }
+
+    /// Hex digest of everything fed so far.
+    pub fn current_hex(&self) -> String {
+        match self.clone() {
+            RunningDigest::Sha1(digest) => hex::encode(&digest.finalize()),
+            RunningDigest::Sha256(digest) => hex::encode(&digest.finalize()),
+            RunningDigest::Sha512(digest) => hex::encode(&digest.finalize()),
+        }
+    }
+}
+
+/// Feeds the same bytes to several algorithms; the digest is theirs concatenated in order.
+pub struct MultiHasher {
+    hashers: Vec<Box<dyn Hasher>>,
+}
+
+impl MultiHasher {
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        MultiHasher {
+            hashers: algorithms
+                .iter()
+                .map(|algorithm| algorithm.new_hasher())
+                .collect(),
+        }
+    }
+}
+
+impl Hasher for MultiHasher {
+    fn update(&mut self, data: &[u8]) {
+        for hasher in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.hashers
+            .into_iter()
+            .flat_map(|hasher| hasher.finalize())
+            .collect()
+    }
+
+    fn reset(&mut self) {
+        for hasher in &mut self.hashers {
+            hasher.reset();
+        }
+    }
+}
+
+/// Passes reads through from `inner` while feeding every byte to a hasher, so verification fits
+/// into any `Read` pipeline (`io::copy` to a file, a decoder, ...).
+pub struct VerifyingReader<R> {
+    inner: R,
+    hasher: Box<dyn Hasher>,
+    bytes: u64,
+    eof: bool,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(inner: R, hasher: Box<dyn Hasher>) -> Self {
+        VerifyingReader {
+            inner,
+            hasher,
+            bytes: 0,
+            eof: false,
+        }
+    }
+
+    /// Bytes read through so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The digest of everything read, or `None` if the reader has not reported EOF yet, since
+    /// a digest of part of the stream would silently verify the wrong thing.
+    pub fn into_digest(self) -> Option<Vec<u8>> {
+        self.eof.then(|| self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.eof = true;
+        }
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestCheck {
+    pub algorithm: Algorithm,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl DigestCheck {
+    pub fn matches(&self) -> bool {
+        self.actual == self.expected
+    }
+
+    /// The computed digest printed in `format`.
+    pub fn actual_as(&self, format: HashFormat) -> String {
+        match hex::decode(&self.actual) {
+            Some(digest) => format.render(self.algorithm, &digest),
+            None => self.actual.clone(),
+        }
+    }
+}
+
+/// Splits a `MultiHasher` digest back into its parts and pairs each with the expected value.
+pub fn check_digests(expected: &[(Algorithm, String)], combined: &[u8]) -> Vec<DigestCheck> {
+    let mut rest = combined;
+    expected
+        .iter()
+        .map(|(algorithm, expected_hex)| {
+            let (digest, tail) = rest.split_at(algorithm.output_len().min(rest.len()));
+            rest = tail;
+            DigestCheck {
+                algorithm: *algorithm,
+                expected: expected_hex.clone(),
+                actual: hex::encode(digest),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_through_trait_object() {
+        let mut hasher: Box<dyn Hasher> = Box::new(Sha256::new());
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(
+            hex::encode(&hasher.finalize()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_verifying_reader_digests_what_passes_through() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = VerifyingReader::new(&data[..], Box::new(Sha256::new()));
+        let mut copy = Vec::new();
+        let mut buf = [0u8; 1000];
+        let n = reader.read(&mut buf).unwrap();
+        copy.extend_from_slice(&buf[..n]);
+        io::copy(&mut reader, &mut copy).unwrap();
+
+        assert_eq!(copy, data);
+        assert_eq!(reader.bytes_read(), data.len() as u64);
+        assert_eq!(
+            reader.into_digest().unwrap(),
+            Sha256::digest(&data).to_vec()
+        );
+
+        let mut unfinished = VerifyingReader::new(&data[..], Box::new(Sha256::new()));
+        unfinished.read_exact(&mut buf).unwrap();
+        assert_eq!(unfinished.into_digest(), None);
+    }
+
+    #[test]
+    fn test_running_digest_can_be_read_between_updates() {
+        let mut running = RunningDigest::new(Algorithm::Sha256);
+        running.update(b"a");
+        assert_eq!(
+            running.current_hex(),
+            "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"
+        );
+        running.update(b"bc");
+        assert_eq!(
+            running.current_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_algorithm_inferred_from_hash_length() {
+        for algorithm in [Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha512] {
+            let digest = hex::encode(&algorithm.new_hasher().finalize());
+            assert_eq!(Algorithm::from_hex_len(&digest).unwrap(), algorithm);
+        }
+        assert_eq!(
+            Algorithm::from_hex_len(&"a".repeat(40)).unwrap(),
+            Algorithm::Sha1
+        );
+        assert_eq!(
+            Algorithm::from_hex_len(&"a".repeat(64)).unwrap(),
+            Algorithm::Sha256
+        );
+        assert_eq!(
+            Algorithm::from_hex_len(&"a".repeat(128)).unwrap(),
+            Algorithm::Sha512
+        );
+    }
+
+    #[test]
+    fn test_unknown_hash_length_asks_for_algo() {
+        let error = Algorithm::from_hex_len(&"a".repeat(32)).err().unwrap();
+        assert!(error.to_string().contains("--algo"));
+    }
+
+    fn expected_for(data: &[u8]) -> Vec<(Algorithm, String)> {
+        [Algorithm::Sha256, Algorithm::Sha512]
+            .into_iter()
+            .map(|algorithm| {
+                let mut hasher = algorithm.new_hasher();
+                hasher.update(data);
+                (algorithm, hex::encode(&hasher.finalize()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_formats_render_a_known_digest() {
+        let check = check_digests(
+            &[(Algorithm::Sha256, String::new())],
+            &Sha256::digest(b"hello"),
+        )
+        .remove(0);
+        assert_eq!(
+            check.actual_as(HashFormat::Hex),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            check.actual_as(HashFormat::UpperHex),
+            "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824"
+        );
+        assert_eq!(
+            check.actual_as(HashFormat::Base64),
+            "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(
+            check.actual_as(HashFormat::Sri),
+            "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(HashFormat::parse("HEX").unwrap(), HashFormat::UpperHex);
+        assert!(HashFormat::parse("Hex").is_err());
+    }
+
+    #[test]
+    fn test_dual_digest_verification_succeeds() {
+        let expected = expected_for(b"payload");
+        let mut hasher: Box<dyn Hasher> =
+            Box::new(MultiHasher::new(&[Algorithm::Sha256, Algorithm::Sha512]));
+        hasher.update(b"pay");
+        hasher.update(b"load");
+
+        let checks = check_digests(&expected, &hasher.finalize());
+
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().all(DigestCheck::matches));
+    }
+
+    #[test]
+    fn test_single_wrong_digest_fails_verification() {
+        let mut expected = expected_for(b"payload");
+        expected[1].1 = expected_for(b"tampered")[1].1.clone();
+        let mut hasher: Box<dyn Hasher> =
+            Box::new(MultiHasher::new(&[Algorithm::Sha256, Algorithm::Sha512]));
+        hasher.update(b"payload");
+
+        let checks = check_digests(&expected, &hasher.finalize());
+
+        assert!(checks[0].matches());
+        assert!(!checks[1].matches());
+    }
+}
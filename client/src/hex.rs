@@ -10,6 +10,52 @@ pub fn encode(bytes: &[u8]) -> String {
     result
 }
 
+/// Decodes a hex string of either case; `None` if it has an odd length or a non-hex digit.
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Formats `bytes` like `hexdump -C`: offset, sixteen hex bytes in two groups of eight,
+/// the printable ASCII column, and a final line with the total length.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for (line_index, line) in bytes.chunks(16).enumerate() {
+        let digits = encode(line);
+        result.push_str(&format!("{:08x}  ", line_index * 16));
+        for column in 0..16 {
+            match digits.get(column * 2..column * 2 + 2) {
+                Some(pair) => {
+                    result.push_str(pair);
+                    result.push(' ');
+                }
+                None => result.push_str("   "),
+            }
+            if column == 7 {
+                result.push(' ');
+            }
+        }
+        result.push_str(" |");
+        result.extend(line.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        result.push_str("|\n");
+    }
+    if !bytes.is_empty() {
+        result.push_str(&format!("{:08x}\n", bytes.len()));
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -34,4 +80,25 @@ mod tests {
             "123456789abcdef0"
         );
     }
+
+    #[test]
+    fn test_decode_round_trips_and_rejects_bad_digits() {
+        assert_eq!(decode("DEADbeef"), Some(vec![0xDE, 0xAD, 0xBE, 0xEF]));
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("+f"), None);
+    }
+
+    #[test]
+    fn test_hexdump_layout() {
+        let dump = hexdump(b"<html>\n<title>404 Not Found");
+        assert_eq!(
+            dump,
+            "00000000  3c 68 74 6d 6c 3e 0a 3c  74 69 74 6c 65 3e 34 30  |<html>.<title>40|\n\
+             00000010  34 20 4e 6f 74 20 46 6f  75 6e 64                 |4 Not Found|\n\
+             0000001b\n"
+        );
+        assert_eq!(hexdump(&[]), "");
+    }
 }
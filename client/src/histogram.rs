@@ -0,0 +1,114 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in milliseconds; slower requests land in a final
+/// overflow bucket.
+const BUCKET_BOUNDS_MS: [u64; 15] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 60_000,
+];
+
+/// Per-request durations in fixed buckets, so a long download keeps a constant-size record.
+/// Minimum and maximum are exact; percentiles are the upper bound of the bucket they fall in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+/// The `--verbose` summary of a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let millis = duration.as_millis();
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| millis <= bound as u128)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.min = Some(self.min.map_or(duration, |min| min.min(duration)));
+        self.max = Some(self.max.map_or(duration, |max| max.max(duration)));
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The duration `percent`% of requests took at most, rounded up to a bucket bound but
+    /// never past the slowest request. `None` when nothing was recorded.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let (min, max) = (self.min?, self.max?);
+        let rank = ((percent / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match BUCKET_BOUNDS_MS.get(bucket) {
+                    Some(&bound) => Duration::from_millis(bound).clamp(min, max),
+                    None => max,
+                });
+            }
+        }
+        Some(max)
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        Some(LatencySummary {
+            count: self.count,
+            min: self.min?,
+            p50: self.percentile(50.0)?,
+            p90: self.percentile(90.0)?,
+            max: self.max?,
+        })
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests: min {:.2?}, p50 {:.2?}, p90 {:.2?}, max {:.2?}",
+            self.count, self.min, self.p50, self.p90, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles_from_synthetic_durations() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.summary(), None);
+        for millis in [3, 4, 4, 8, 9, 15, 40, 45, 180, 75_000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+
+        let summary = histogram.summary().unwrap();
+        assert_eq!(summary.count, 10);
+        assert_eq!(summary.min, Duration::from_millis(3));
+        // Ranks 5 and 9 fall in the 5-10 ms and 100-200 ms buckets.
+        assert_eq!(summary.p50, Duration::from_millis(10));
+        assert_eq!(summary.p90, Duration::from_millis(200));
+        assert_eq!(summary.max, Duration::from_millis(75_000));
+        // The overflow bucket reports the slowest request, and bounds never undercut the minimum.
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_millis(75_000))
+        );
+        let mut single = LatencyHistogram::default();
+        single.record(Duration::from_micros(2_500));
+        assert_eq!(single.percentile(50.0), Some(Duration::from_micros(2_500)));
+    }
+}
@@ -1,14 +1,252 @@
+use crate::cache::ResponseCache;
+use crate::cancel::{CancelToken, CANCELLED};
+use crate::dump::{RawDump, TeeReader};
+use crate::hex;
+use crate::multipart::{self, RangePart};
+use crate::output::Diagnostics;
+use crate::pool::{Connection, ConnectionPool, Stream};
+use crate::proxy::{self, Socks5Proxy};
+use crate::resolve::AddressCache;
+use crate::retry::RetryBudget;
+use crate::url::{Scheme, Url};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::error::Error;
+use std::fmt;
 use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+const CHUNK_SHA256_HEADER: &str = "X-Content-SHA256";
+
+/// Default `--max-headers`: plenty for real servers, small enough to stop a header flood early.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// Bounds for `--recv-buffer`: below a few KiB TCP stalls, and `setsockopt` takes a C `int`.
+const MIN_RECV_BUFFER: usize = 4 * 1024;
+const MAX_RECV_BUFFER: usize = i32::MAX as usize;
+
+/// Body reads (of up to 8 KiB each) between checks of the cancel token.
+const CANCEL_CHECK_INTERVAL: usize = 16;
+
+const RESPONSE_DESYNC: &str = "Response desync on keep-alive connection";
+
+pub const TLS_UNAVAILABLE: &str =
+    "HTTPS requested but TLS support not compiled in (enable the `tls` feature)";
+
+/// The oldest TLS version `--min-tls-version` lets a handshake settle on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!(
+                "Invalid --min-tls-version '{}' (expected 1.2 or 1.3)",
+                value
+            )
+            .into()),
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls12 => "TLS 1.2",
+            TlsVersion::Tls13 => "TLS 1.3",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// The status line's reason phrase, empty when the server sent none.
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Bytes written for the request line and headers that produced this response.
+    pub request_bytes: usize,
+    /// The socket address the request went to (the proxy's, when one is used).
+    pub peer: Option<SocketAddr>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn content_range(&self) -> Option<ContentRange> {
+        self.header("Content-Range").and_then(ContentRange::parse)
+    }
+
+    /// The `Content-Length` as the size of the resource itself; `None` when the response is
+    /// content-encoded, e.g. a `HEAD` probe that accepted gzip, since the length is then that of
+    /// the encoded form.
+    pub fn resource_length(&self) -> Option<u64> {
+        if self
+            .header("Content-Encoding")
+            .is_some_and(|encoding| !encoding.trim().eq_ignore_ascii_case("identity"))
+        {
+            return None;
+        }
+        self.header("Content-Length")?.trim().parse().ok()
+    }
+}
+
+/// A parsed `Content-Range: bytes <first>-<last>/<total>` header; `total` is `None` for `*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub first: u64,
+    pub last: u64,
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    /// Parses `bytes <first>-<last>/<total>`; a range whose last byte comes before its first is
+    /// rejected like any other malformed value.
+    pub fn parse(value: &str) -> Option<ContentRange> {
+        let spec = value.trim().strip_prefix("bytes")?.trim_start();
+        let (range, total) = spec.split_once('/')?;
+        let (first, last) = range.trim().split_once('-')?;
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        let range = ContentRange {
+            first: first.trim().parse().ok()?,
+            last: last.trim().parse().ok()?,
+            total,
+        };
+        (range.first <= range.last).then_some(range)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request<'a> {
+    /// `GET` with `Range: bytes=<start>-`.
+    Range(usize),
+    /// `GET` with several inclusive ranges in one `Range` header.
+    Ranges(&'a [(usize, usize)]),
+    /// `GET` with a verbatim `Range: bytes=<spec>`, valid or not.
+    Spec(&'a str),
+    /// `GET` of the last bytes, with `Range: bytes=-<length>`.
+    Suffix(usize),
+    /// `GET` of the whole resource, for `--single-request`.
+    Whole,
+    Head,
+    /// `POST` of a body with the given `Content-Type`.
+    Post(&'a str, &'a [u8]),
+}
+
+impl Request<'_> {
+    /// The method and `Range` spec of this request, from which `perform_with` builds its
+    /// `ResponseCache` key; `None` for a `POST`, which is never cached.
+    fn cache_key(&self) -> Option<(Method, Option<String>)> {
+        Some(match *self {
+            Request::Range(start_byte) => (Method::Get, Some(range_spec(&[(start_byte, None)]))),
+            Request::Ranges(ranges) => {
+                let ranges: Vec<_> = ranges
+                    .iter()
+                    .map(|&(start, end)| (start, Some(end)))
+                    .collect();
+                (Method::Get, Some(range_spec(&ranges)))
+            }
+            Request::Spec(spec) => (Method::Get, Some(spec.to_string())),
+            Request::Suffix(length) => (Method::Get, Some(format!("-{}", length))),
+            Request::Whole => (Method::Get, None),
+            Request::Head => (Method::Head, None),
+            Request::Post(..) => return None,
+        })
+    }
+}
+
+/// The socket timeouts for one request; the client's own unless a call overrides them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timeouts {
+    connect: Duration,
+    read_write: Duration,
+}
+
+#[derive(Debug, Clone)]
 pub struct HttpClient {
     host: String,
     port: u16,
+    scheme: Scheme,
+    path: String,
     connect_timeout: Duration,
     read_write_timeout: Duration,
+    request_timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    bind_port: Option<u16>,
+    recv_buffer: Option<usize>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    tls_handshake_timeout: Duration,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    tls_server_name: Option<String>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    min_tls_version: TlsVersion,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    warn_cert_expiry_days: Option<u64>,
+    /// Set once the expiry warning has been printed, so each connection does not repeat it.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    cert_expiry_warned: Arc<AtomicBool>,
+    verify_chunk_sha: bool,
+    max_headers: usize,
+    referer: Option<String>,
+    authorization: Option<String>,
+    if_range: Option<String>,
+    accept_gzip: bool,
+    probe_gzip: bool,
+    keep_alive: bool,
+    strict_close: bool,
+    recycle_after: Option<usize>,
+    pool: Arc<ConnectionPool>,
+    addresses: Arc<AddressCache>,
+    response_cache: Option<Arc<ResponseCache>>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    connect_rate: Option<Arc<RetryBudget>>,
+    cancel: Option<CancelToken>,
+    proxy: Option<Url>,
+    proxy_bypass_hosts: Vec<String>,
+    socks5: Option<Socks5Proxy>,
+    raw_dump: Option<Arc<RawDump>>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    verbose: Option<Diagnostics>,
+    quiet: bool,
 }
 
 impl HttpClient {
@@ -21,323 +259,2757 @@ impl HttpClient {
         HttpClient {
             host,
             port,
-            connect_timeout,
-            read_write_timeout,
+            scheme: Scheme::Http,
+            path: "/".to_string(),
+            connect_timeout,
+            read_write_timeout,
+            request_timeout: None,
+            stall_timeout: None,
+            bind_port: None,
+            recv_buffer: None,
+            tls_handshake_timeout: Duration::from_secs(10),
+            tls_server_name: None,
+            min_tls_version: TlsVersion::default(),
+            warn_cert_expiry_days: None,
+            cert_expiry_warned: Arc::new(AtomicBool::new(false)),
+            verify_chunk_sha: false,
+            max_headers: DEFAULT_MAX_HEADERS,
+            referer: None,
+            authorization: None,
+            if_range: None,
+            accept_gzip: false,
+            probe_gzip: false,
+            keep_alive: false,
+            strict_close: false,
+            recycle_after: None,
+            pool: Arc::default(),
+            addresses: Arc::default(),
+            response_cache: None,
+            retry_budget: None,
+            connect_rate: None,
+            cancel: None,
+            proxy: None,
+            proxy_bypass_hosts: Vec::new(),
+            socks5: None,
+            raw_dump: None,
+            verbose: None,
+            quiet: false,
+        }
+    }
+
+    pub fn with_scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn with_path(mut self, path: String) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Points the client at another URL, e.g. a redirect target, keeping all other settings.
+    pub fn with_target(mut self, url: &Url) -> Self {
+        // Credentials are for the original server: never hand them to another host or send
+        // them over plain HTTP after starting on HTTPS.
+        if !url.host.eq_ignore_ascii_case(&self.host)
+            || (self.scheme == Scheme::Https && url.scheme == Scheme::Http)
+        {
+            self.authorization = None;
+        }
+        self.scheme = url.scheme;
+        self.host = url.host.clone();
+        self.port = url.port;
+        self.path = url.path.clone();
+        self
+    }
+
+    /// Bounds one whole range request (connect, send, status, headers and body).
+    pub fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Fails a request once no byte at all has arrived for `timeout`, however long it has run.
+    /// Read timeouts shorter than this are waited out instead of ending the response early.
+    pub fn with_stall_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Connects from this local port (on the wildcard address of the target's family).
+    pub fn with_bind_port(mut self, port: Option<u16>) -> Self {
+        self.bind_port = port;
+        self
+    }
+
+    /// Sets `SO_RCVBUF` on new connections, for links with a high bandwidth-delay product.
+    pub fn with_recv_buffer(mut self, bytes: Option<usize>) -> Self {
+        self.recv_buffer = bytes;
+        self
+    }
+
+    pub fn with_tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.tls_handshake_timeout = timeout;
+        self
+    }
+
+    /// Sends `name` as the TLS SNI and validates the certificate against it instead of the
+    /// host (`--tls-servername`), e.g. to check a certificate deployed on one backend.
+    pub fn with_tls_server_name(mut self, name: Option<String>) -> Self {
+        self.tls_server_name = name;
+        self
+    }
+
+    /// Refuses handshakes that would settle on a TLS version older than `version`.
+    pub fn with_min_tls_version(mut self, version: TlsVersion) -> Self {
+        self.min_tls_version = version;
+        self
+    }
+
+    /// Warns (once per client) when the server's certificate expires within `days` days.
+    pub fn with_warn_cert_expiry_days(mut self, days: Option<u64>) -> Self {
+        self.warn_cert_expiry_days = days;
+        self
+    }
+
+    /// The name for SNI and certificate validation: the override, or else the logical host,
+    /// never the address actually connected to.
+    #[cfg(feature = "tls")]
+    fn tls_server_name(&self) -> &str {
+        self.tls_server_name.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Verifies each response body against its `X-Content-SHA256` header, if the server sends one.
+    pub fn with_chunk_sha_verification(mut self, enabled: bool) -> Self {
+        self.verify_chunk_sha = enabled;
+        self
+    }
+
+    /// Aborts a response with more than `limit` header lines.
+    pub fn with_max_headers(mut self, limit: usize) -> Self {
+        self.max_headers = limit;
+        self
+    }
+
+    pub fn with_referer(mut self, referer: Option<String>) -> Self {
+        self.referer = referer;
+        self
+    }
+
+    /// Sends this `Authorization` header value, e.g. from `--user` or `--bearer-token`.
+    pub fn with_authorization(mut self, authorization: Option<String>) -> Self {
+        self.authorization = authorization;
+        self
+    }
+
+    /// Sends `If-Range: <etag>` with range requests, so a server whose copy has changed answers
+    /// with the whole new body (`200`) instead of a range of it.
+    pub fn with_if_range(mut self, etag: Option<String>) -> Self {
+        self.if_range = etag;
+        self
+    }
+
+    /// Advertises `Accept-Encoding: gzip` instead of `identity`. Callers decode the body.
+    pub fn with_gzip(mut self, accept_gzip: bool) -> Self {
+        self.accept_gzip = accept_gzip;
+        self
+    }
+
+    /// Advertises gzip on `HEAD` probes even when the download itself asks for `identity`:
+    /// a probe carries no body whose offsets could shift, only metadata and error pages.
+    pub fn with_probe_gzip(mut self, probe_gzip: bool) -> Self {
+        self.probe_gzip = probe_gzip;
+        self
+    }
+
+    /// Reuses one connection across range requests instead of opening a new one each time.
+    pub fn with_keep_alive(mut self, enabled: bool) -> Self {
+        self.keep_alive = enabled;
+        self
+    }
+
+    /// Closes a keep-alive connection after it has served `ranges` requests instead of reusing it.
+    pub fn with_recycle_after(mut self, ranges: Option<usize>) -> Self {
+        self.recycle_after = ranges;
+        self
+    }
+
+    /// Frames bodies by `Content-Length` even without keep-alive and then requires the server to
+    /// close the connection, rejecting a response after which it stays open (`--strict-close`).
+    pub fn with_strict_close(mut self, enabled: bool) -> Self {
+        self.strict_close = enabled;
+        self
+    }
+
+    /// Shares idle keep-alive connections with other clients using the same pool.
+    pub fn with_pool(mut self, pool: Arc<ConnectionPool>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Answers repeated identical requests from `cache` instead of the server (`--response-cache`).
+    pub fn with_response_cache(mut self, cache: Option<Arc<ResponseCache>>) -> Self {
+        self.response_cache = cache;
+        self
+    }
+
+    /// Drops every cached answer, so a request whose answer failed verification goes back to
+    /// the server. Does nothing without a response cache.
+    pub fn clear_response_cache(&self) {
+        if let Some(cache) = &self.response_cache {
+            cache.clear();
+        }
+    }
+
+    /// Shares resolved addresses with other clients using the same cache.
+    pub fn with_address_cache(mut self, addresses: Arc<AddressCache>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    /// Rate-limits retries together with every other client sharing `budget`.
+    pub fn with_retry_budget(mut self, budget: Option<Arc<RetryBudget>>) -> Self {
+        self.retry_budget = budget;
+        self
+    }
+
+    pub fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.retry_budget.as_deref()
+    }
+
+    /// Caps how often new connections are attempted (`--connect-rate`), whatever the reason
+    /// for connecting. Pooled connections are reused without waiting.
+    pub fn with_connect_rate(mut self, limiter: Option<Arc<RetryBudget>>) -> Self {
+        self.connect_rate = limiter;
+        self
+    }
+
+    /// Lets `token` interrupt the pauses a download takes between requests.
+    pub fn with_cancel_token(mut self, token: Option<CancelToken>) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    pub fn cancel_token(&self) -> Option<&CancelToken> {
+        self.cancel.as_ref()
+    }
+
+    /// Sends requests through an HTTP proxy: plain `http://` requests in absolute form,
+    /// `https://` ones through a `CONNECT` tunnel.
+    pub fn with_proxy(mut self, proxy: Option<Url>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Connects directly, ignoring the proxy, when the target host matches one of `hosts`
+    /// (same matching rules as `NO_PROXY`).
+    pub fn with_proxy_bypass(mut self, hosts: Vec<String>) -> Self {
+        self.proxy_bypass_hosts = hosts;
+        self
+    }
+
+    /// Connects through a SOCKS5 proxy; it takes precedence over an HTTP proxy.
+    pub fn with_socks5(mut self, proxy: Option<Socks5Proxy>) -> Self {
+        self.socks5 = proxy;
+        self
+    }
+
+    /// Copies every byte received from the server into `dump` before it is parsed.
+    pub fn with_raw_dump(mut self, dump: Option<Arc<RawDump>>) -> Self {
+        self.raw_dump = dump;
+        self
+    }
+
+    /// Reports connection details such as the negotiated TLS session through `diag`.
+    pub fn with_verbose(mut self, diag: Option<Diagnostics>) -> Self {
+        self.verbose = diag;
+        self
+    }
+
+    /// Stops the client from printing its warnings to stderr, for library callers.
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// The proxy to use for this client's target, or `None` for a direct connection.
+    pub fn effective_proxy(&self) -> Option<&Url> {
+        let bypassed = self
+            .proxy_bypass_hosts
+            .iter()
+            .any(|pattern| proxy::no_proxy_matches(&self.host, pattern));
+        if bypassed || self.socks5.is_some() {
+            None
+        } else {
+            self.proxy.as_ref()
+        }
+    }
+
+    pub fn fetch_range(&self, start_byte: usize) -> Result<HttpResponse, Box<dyn Error>> {
+        self.fetch_range_with_timeouts(start_byte, self.connect_timeout, self.read_write_timeout)
+    }
+
+    /// Like `fetch_range`, but appends the body straight to `buf` instead of allocating one, so
+    /// a caller can reuse a single buffer across ranges. Returns the status and the number of
+    /// bytes appended; on error `buf` is left as it was. The body is not decoded.
+    pub fn fetch_range_into(
+        &self,
+        start_byte: usize,
+        buf: &mut Vec<u8>,
+    ) -> Result<(u16, usize), Box<dyn Error>> {
+        let before = buf.len();
+        let response = self.perform_into(Request::Range(start_byte), self.timeouts(), buf)?;
+        Ok((response.status, buf.len() - before))
+    }
+
+    /// Like `fetch_range`, but with these timeouts instead of the client's for this one call.
+    /// Overridden timeouts always use a fresh connection, since a pooled one keeps the socket
+    /// timeouts it was opened with.
+    pub fn fetch_range_with_timeouts(
+        &self,
+        start_byte: usize,
+        connect_timeout: Duration,
+        read_write_timeout: Duration,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform_with(
+            Request::Range(start_byte),
+            Timeouts {
+                connect: connect_timeout,
+                read_write: read_write_timeout,
+            },
+        )
+    }
+
+    /// Fetches several inclusive byte ranges with one request. Handles a `multipart/byteranges`
+    /// answer, a single-part `206`, and a `200` with the whole body (sliced locally).
+    pub fn fetch_ranges(
+        &self,
+        ranges: &[(usize, usize)],
+    ) -> Result<Vec<RangePart>, Box<dyn Error>> {
+        if ranges.is_empty() || ranges.iter().any(|&(start, end)| end < start) {
+            return Err("fetch_ranges needs at least one range with start <= end".into());
+        }
+        let response = self.perform(Request::Ranges(ranges))?;
+        split_ranges(response, ranges)
+    }
+
+    /// Fetches the last `length` bytes (the whole resource if it is shorter) with
+    /// `Range: bytes=-<length>`, e.g. to read a trailer. Returns the bytes with the range they
+    /// cover, checked against the `Content-Range` of a `206`; a `200` with the whole body is
+    /// sliced locally.
+    pub fn fetch_suffix(&self, length: usize) -> Result<RangePart, Box<dyn Error>> {
+        if length == 0 {
+            return Err("fetch_suffix needs a length of at least 1".into());
+        }
+        let response = self.perform(Request::Suffix(length))?;
+        suffix_part(response, length)
+    }
+
+    /// Sends `Range: bytes=<spec>` exactly as given, even if malformed, to see how the server
+    /// reacts (`--probe-ranges`).
+    pub fn fetch_range_spec(&self, spec: &str) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform(Request::Spec(spec))
+    }
+
+    /// Fetches the whole resource in one request, without a `Range` header.
+    pub fn fetch_whole(&self) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform(Request::Whole)
+    }
+
+    /// Sends a `HEAD` request for the configured path, e.g. to compare size and `ETag`.
+    pub fn head(&self) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform(Request::Head)
+    }
+
+    /// Sends `body` to the configured path with a `POST`, e.g. a `--notify-url` status report.
+    pub fn post(&self, content_type: &str, body: &[u8]) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform(Request::Post(content_type, body))
+    }
+
+    fn perform(&self, request: Request) -> Result<HttpResponse, Box<dyn Error>> {
+        self.perform_with(request, self.timeouts())
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: self.connect_timeout,
+            read_write: self.read_write_timeout,
+        }
+    }
+
+    fn perform_with(
+        &self,
+        request: Request,
+        timeouts: Timeouts,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        // Everything that changes the answer: the scheme and the request head as it would be
+        // sent, so every header (`Range`, `If-Range`, `Authorization`, `Referer`, ...) counts.
+        let cache_key = self.response_cache.as_ref().and_then(|_| {
+            request.cache_key().map(|(method, spec)| {
+                let target_host = format!("{}:{}", self.host, self.port);
+                format!(
+                    "{}://{}",
+                    self.scheme.name(),
+                    self.format_request(&target_host, spec.as_deref(), method, None)
+                )
+            })
+        });
+        if let (Some(cache), Some(key)) = (&self.response_cache, &cache_key) {
+            if let Some(response) = cache.get(key) {
+                return Ok(response);
+            }
+        }
+
+        let mut body = Vec::new();
+        let mut response = self.perform_into(request, timeouts, &mut body)?;
+        response.body = body;
+        if let (Some(cache), Some(key)) = (&self.response_cache, cache_key) {
+            // A short body is requested again rather than served again.
+            let complete = request == Request::Head
+                || response
+                    .header("Content-Length")
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .is_none_or(|length| length == response.body.len());
+            if (200..300).contains(&response.status) && complete {
+                cache.insert(key, &response);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Like `perform_with`, but appends the body to `body` (see `exchange_into`).
+    fn perform_into(
+        &self,
+        request: Request,
+        timeouts: Timeouts,
+        body: &mut Vec<u8>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        if self.scheme == Scheme::Https && !cfg!(feature = "tls") {
+            return Err(TLS_UNAVAILABLE.into());
+        }
+        let target = format!("{}:{}", self.host, self.port);
+        let deadline = self
+            .request_timeout
+            .map(|timeout| (Instant::now() + timeout, timeout));
+        if !self.keep_alive || timeouts != self.timeouts() {
+            let mut connection = self.connect(&target, timeouts)?;
+            let mut stream =
+                DeadlineStream::new(&mut connection.stream, deadline, self.stall_timeout);
+            let (mut response, _) = self
+                .exchange_into(&mut stream, &target, request, body)
+                .map_err(|e| naming_peer(e, connection.peer))?;
+            response.peer = Some(connection.peer);
+            return Ok(response);
+        }
+
+        let pool_key = format!("{}://{}", self.scheme.name(), target);
+        let pooled = self.pool.take(&pool_key);
+        let reused = pooled.is_some();
+        let mut connection = match pooled {
+            Some(pooled) => pooled,
+            None => self.connect(&target, timeouts)?,
+        };
+        let result = self.exchange_into(
+            &mut DeadlineStream::new(&mut connection.stream, deadline, self.stall_timeout),
+            &target,
+            request,
+            body,
+        );
+        // Junk before the status line of a reused connection means it fell out of step with the
+        // server (possibly a smuggled response); retry the request once on a fresh connection.
+        let result = match result {
+            Err(e) if reused && is_desync(e.as_ref()) && !matches!(request, Request::Post(..)) => {
+                if let Some(diag) = self.verbose {
+                    diag.println(format_args!("Warning: {}. Reconnecting.", e));
+                }
+                connection = self.connect(&target, timeouts)?;
+                self.exchange_into(
+                    &mut DeadlineStream::new(&mut connection.stream, deadline, self.stall_timeout),
+                    &target,
+                    request,
+                    body,
+                )
+            }
+            result => result,
+        };
+        let (mut response, reusable) = result.map_err(|e| naming_peer(e, connection.peer))?;
+        response.peer = Some(connection.peer);
+        connection.ranges_served += 1;
+        let recycle = self
+            .recycle_after
+            .is_some_and(|limit| connection.ranges_served >= limit);
+        if reusable && !recycle {
+            self.pool.put(&pool_key, connection);
+        }
+        Ok(response)
+    }
+
+    fn connect(&self, target: &str, timeouts: Timeouts) -> Result<Connection, Box<dyn Error>> {
+        let connect_target = match (&self.socks5, self.effective_proxy()) {
+            (Some(socks5), _) => format!("{}:{}", socks5.host, socks5.port),
+            (None, Some(proxy)) => format!("{}:{}", proxy.host, proxy.port),
+            (None, None) => target.to_string(),
+        };
+        let socket_addr: SocketAddr = self
+            .addresses
+            .resolve(&connect_target)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("Failed to resolve address: {}", connect_target))?;
+        if let Some(limiter) = &self.connect_rate {
+            limiter.acquire();
+        }
+        let connect_timeout = self.capped_by_request_timeout(timeouts.connect);
+        let mut stream = connect_socket(
+            &socket_addr,
+            self.bind_port,
+            self.recv_buffer,
+            connect_timeout,
+        )
+        .map_err(|e| naming_peer(e.into(), socket_addr))?;
+        self.pool.record_opened();
+        if let (Some(requested), Some(diag)) = (self.recv_buffer, self.verbose) {
+            diag.println(format_args!(
+                "Receive buffer for {}: requested {} bytes, kernel granted {} bytes",
+                socket_addr,
+                requested,
+                SockRef::from(&stream).recv_buffer_size()?
+            ));
+        }
+        let io_timeout = self.capped_by_request_timeout(timeouts.read_write);
+        let read_timeout = self
+            .stall_timeout
+            .map_or(io_timeout, |stall| io_timeout.min(stall));
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_write_timeout(Some(io_timeout))?;
+        if let Some(socks5) = &self.socks5 {
+            proxy::socks5_connect(&mut stream, socks5, &self.host, self.port)
+                .map_err(|e| naming_peer(e, socket_addr))?;
+        }
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Stream> = if self.scheme == Scheme::Https {
+            self.wrap_tls(stream, target, io_timeout)
+                .map_err(|e| naming_peer(e, socket_addr))?
+        } else {
+            Box::new(stream)
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Stream> = Box::new(stream);
+        Ok(Connection {
+            stream,
+            peer: socket_addr,
+            ranges_served: 0,
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn wrap_tls(
+        &self,
+        mut stream: TcpStream,
+        target: &str,
+        io_timeout: Duration,
+    ) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+        if self.effective_proxy().is_some() {
+            proxy::establish_tunnel(&mut stream, target)?;
+        }
+        let tls_stream = crate::tls::connect(
+            stream,
+            self.tls_server_name(),
+            self.min_tls_version,
+            self.capped_by_request_timeout(self.tls_handshake_timeout),
+            io_timeout,
+        )?;
+        if let Some(diag) = self.verbose {
+            if let Some(info) = crate::tls::TlsInfo::from_connection(&tls_stream.conn) {
+                diag.println(format_args!(
+                    "TLS session with {}: {}",
+                    self.tls_server_name(),
+                    info
+                ));
+            }
+        }
+        if let Some(days) = self.warn_cert_expiry_days {
+            if let Some(warning) =
+                crate::tls::leaf_not_after(&tls_stream.conn).and_then(|not_after| {
+                    crate::tls::expiry_warning(
+                        self.tls_server_name(),
+                        not_after,
+                        std::time::SystemTime::now(),
+                        days,
+                    )
+                })
+            {
+                if !self
+                    .cert_expiry_warned
+                    .swap(true, std::sync::atomic::Ordering::Relaxed)
+                    && !self.quiet
+                {
+                    eprintln!("\n{}", warning);
+                }
+            }
+        }
+        Ok(Box::new(tls_stream))
+    }
+
+    /// A single blocking step never needs to wait longer than the whole request may take.
+    fn capped_by_request_timeout(&self, timeout: Duration) -> Duration {
+        self.request_timeout
+            .map_or(timeout, |request_timeout| timeout.min(request_timeout))
+    }
+
+    /// The exact request head that would be sent for `method`, with `Range: bytes=<start>-<end>`
+    /// when `start` is given (`end` inclusive, open-ended when `None`). Nothing is sent.
+    pub fn build_request(
+        &self,
+        start: Option<usize>,
+        end: Option<usize>,
+        method: Method,
+    ) -> String {
+        let target_host = format!("{}:{}", self.host, self.port);
+        let spec = start.map(|start| range_spec(&[(start, end)]));
+        self.format_request(&target_host, spec.as_deref(), method, None)
+    }
+
+    /// Formats a request head, with `Range: bytes=<spec>` when a range spec is given and
+    /// `Content-Type`/`Content-Length` for a body of the given type and length.
+    fn format_request(
+        &self,
+        target_host: &str,
+        spec: Option<&str>,
+        method: Method,
+        content: Option<(&str, usize)>,
+    ) -> String {
+        // Plain HTTP through a proxy uses the absolute URL as the request target.
+        let request_target = if self.effective_proxy().is_some() && self.scheme == Scheme::Http {
+            format!("http://{}{}", target_host, self.path)
+        } else {
+            self.path.clone()
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\n\
+             Host: {}\r\n",
+            method.name(),
+            request_target,
+            target_host
+        );
+        if let Some(spec) = spec {
+            request.push_str(&format!("Range: bytes={}\r\n", spec));
+            if let Some(etag) = &self.if_range {
+                request.push_str(&format!("If-Range: {}\r\n", etag));
+            }
+        }
+        request.push_str(&format!(
+            "Accept-Encoding: {}\r\n\
+             Connection: {}\r\n\
+             User-Agent: RustStdNetClient/1.0\r\n",
+            if self.accept_gzip || (self.probe_gzip && method == Method::Head) {
+                "gzip"
+            } else {
+                "identity"
+            },
+            if self.keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            }
+        ));
+        if let Some(referer) = &self.referer {
+            request.push_str(&format!("Referer: {}\r\n", referer));
+        }
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        if let Some((content_type, length)) = content {
+            request.push_str(&format!(
+                "Content-Type: {}\r\n\
+                 Content-Length: {}\r\n",
+                content_type, length
+            ));
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    /// Sends one request and reads the response, also reporting whether the
+    /// connection is left in a clean state for another request.
+    #[cfg(test)]
+    fn exchange<T: Read + Write>(
+        &self,
+        stream: &mut T,
+        target_host: &str,
+        request_kind: Request,
+    ) -> Result<(HttpResponse, bool), Box<dyn Error>> {
+        let mut body = Vec::new();
+        let (mut response, reusable) =
+            self.exchange_into(stream, target_host, request_kind, &mut body)?;
+        response.body = body;
+        Ok((response, reusable))
+    }
+
+    /// Like `exchange`, but appends the body to `body` and leaves the response's own body empty.
+    /// On error `body` is truncated back to its original length.
+    fn exchange_into<T: Read + Write>(
+        &self,
+        stream: &mut T,
+        target_host: &str,
+        request_kind: Request,
+        body: &mut Vec<u8>,
+    ) -> Result<(HttpResponse, bool), Box<dyn Error>> {
+        let mut request_body: &[u8] = &[];
+        let request = match request_kind {
+            Request::Range(start_byte) => self.format_request(
+                target_host,
+                Some(&range_spec(&[(start_byte, None)])),
+                Method::Get,
+                None,
+            ),
+            Request::Ranges(ranges) => {
+                let ranges: Vec<_> = ranges
+                    .iter()
+                    .map(|&(start, end)| (start, Some(end)))
+                    .collect();
+                self.format_request(target_host, Some(&range_spec(&ranges)), Method::Get, None)
+            }
+            Request::Spec(spec) => self.format_request(target_host, Some(spec), Method::Get, None),
+            Request::Suffix(length) => self.format_request(
+                target_host,
+                Some(&format!("-{}", length)),
+                Method::Get,
+                None,
+            ),
+            Request::Whole => self.format_request(target_host, None, Method::Get, None),
+            Request::Head => self.format_request(target_host, None, Method::Head, None),
+            Request::Post(content_type, body) => {
+                request_body = body;
+                self.format_request(
+                    target_host,
+                    None,
+                    Method::Post,
+                    Some((content_type, body.len())),
+                )
+            }
+        };
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(request_body)?;
+        stream.flush()?;
+        let mut reader = BufReader::new(TeeReader::new(stream, self.raw_dump.as_deref()));
+        let mut status_line = String::new();
+        if reader.read_line(&mut status_line)? == 0 {
+            return Err("Connection closed before status line received".into());
+        }
+        if self.keep_alive && !status_line.starts_with("HTTP/") {
+            return Err(format!(
+                "{}: status line starts with {:?} instead of HTTP/",
+                RESPONSE_DESYNC,
+                status_line.chars().take(16).collect::<String>()
+            )
+            .into());
+        }
+        let (status_code, reason) = parse_status_line(&status_line)?;
+        let mut headers = Vec::new();
+        let mut header_line = String::new();
+        let mut header_count = 0;
+        loop {
+            header_line.clear();
+            let bytes_read = reader.read_line(&mut header_line)?;
+            if bytes_read == 0 {
+                return Err("Connection closed during header reading".into());
+            }
+            if header_line == "\r\n" {
+                break;
+            }
+            header_count += 1;
+            if header_count > self.max_headers {
+                return Err(format!(
+                    "Response has more than {} header lines (raise --max-headers to allow more)",
+                    self.max_headers
+                )
+                .into());
+            }
+            if let Some((name, value)) = header_line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        // Transfer codings are undone here and content codings later on the assembled body, so a
+        // transfer coding other than chunked would reach the output still encoded.
+        let transfer_encoding = find_header(&headers, "Transfer-Encoding").unwrap_or_default();
+        if let Some(coding) = transfer_encoding.split(',').map(str::trim).find(|coding| {
+            !coding.is_empty()
+                && !coding.eq_ignore_ascii_case("chunked")
+                && !coding.eq_ignore_ascii_case("identity")
+        }) {
+            return Err(format!("Unsupported Transfer-Encoding: {}", coding).into());
+        }
+        // Only a keep-alive connection needs the body framed by Content-Length; a closed
+        // connection is simply read until EOF, unless `--strict-close` checks for that EOF.
+        // Chunked bodies carry their own framing.
+        let chunked = transfer_encoding.to_ascii_lowercase().contains("chunked");
+        let body_length = if (self.keep_alive || self.strict_close) && !chunked {
+            find_header(&headers, "Content-Length").and_then(|value| value.parse::<usize>().ok())
+        } else {
+            None
+        };
+        // A HEAD response describes the body without sending it.
+        let body_start = body.len();
+        let complete = if request_kind == Request::Head {
+            Ok(true)
+        } else if chunked {
+            read_chunked_body(&mut reader, self.cancel.as_ref(), self.quiet, body)
+        } else {
+            read_body(
+                &mut reader,
+                body_length,
+                self.cancel.as_ref(),
+                self.quiet,
+                body,
+            )
+        };
+        let complete = complete
+            .and_then(|complete| {
+                // The header describes the whole body: after a short read the caller keeps the
+                // bytes that arrived and asks for the rest, so there is nothing to check yet.
+                let declared_length = find_header(&headers, "Content-Length")
+                    .and_then(|value| value.parse::<usize>().ok());
+                let whole_body = complete
+                    || (!chunked
+                        && declared_length.is_none_or(|length| body.len() - body_start >= length));
+                if self.verify_chunk_sha && whole_body {
+                    verify_chunk_sha256(&headers, &body[body_start..])?;
+                }
+                if self.strict_close
+                    && !self.keep_alive
+                    && (request_kind == Request::Head || chunked || body_length.is_some())
+                {
+                    confirm_closed(&mut reader)?;
+                }
+                Ok(complete)
+            })
+            .inspect_err(|_| body.truncate(body_start))?;
+        let mut reusable = complete
+            && (request_kind == Request::Head || chunked || body_length.is_some())
+            && !find_header(&headers, "Connection")
+                .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        if reusable && !reader.buffer().is_empty() {
+            if !self.quiet {
+                eprintln!(
+                    "\nWarning: {} unexpected bytes after response body. Discarding connection instead of reusing it.",
+                    reader.buffer().len()
+                );
+            }
+            reusable = false;
+        }
+        let response = HttpResponse {
+            status: status_code,
+            reason,
+            headers,
+            body: Vec::new(),
+            request_bytes: request.len() + request_body.len(),
+            peer: None,
+        };
+        Ok((response, reusable))
+    }
+}
+
+/// `--strict-close`: after a framed body, the server must close the connection the request
+/// asked it to close. Waits up to the read timeout for the end of the stream.
+fn confirm_closed(reader: &mut impl BufRead) -> Result<(), Box<dyn Error>> {
+    match reader.fill_buf() {
+        Ok([]) => Ok(()),
+        Ok(extra) => Err(format!(
+            "Server sent {} bytes after the body instead of closing the connection (--strict-close)",
+            extra.len()
+        )
+        .into()),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Err(
+            "Server kept the connection open after the body despite Connection: close (--strict-close)"
+                .into(),
+        ),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `start-end` pairs joined by commas; an open end is left empty (`100-`).
+fn range_spec(ranges: &[(usize, Option<usize>)]) -> String {
+    let specs: Vec<_> = ranges
+        .iter()
+        .map(|(start, end)| {
+            let end = end.map(|end| end.to_string()).unwrap_or_default();
+            format!("{}-{}", start, end)
+        })
+        .collect();
+    specs.join(",")
+}
+
+/// Checks the answer to a suffix request of `length` bytes and returns the bytes it carries.
+fn suffix_part(response: HttpResponse, length: usize) -> Result<RangePart, Box<dyn Error>> {
+    match response.status {
+        206 => {
+            let range = response
+                .content_range()
+                .ok_or("206 response to a suffix request without Content-Range")?;
+            let end = range
+                .last
+                .checked_add(1)
+                .ok_or_else(|| format!("Content-Range last byte {} is out of range", range.last))?;
+            let returned = end.checked_sub(range.first);
+            if returned != Some(response.body.len() as u64) {
+                return Err(format!(
+                    "Content-Range bytes {}-{} does not match the {}-byte body",
+                    range.first,
+                    range.last,
+                    response.body.len()
+                )
+                .into());
+            }
+            let expected = match range.total {
+                Some(total) if end != total => {
+                    return Err(format!(
+                        "Server returned bytes {}-{} of {}, not the end of the file",
+                        range.first, range.last, total
+                    )
+                    .into())
+                }
+                Some(total) => total.min(length as u64),
+                None => length as u64,
+            };
+            if response.body.len() as u64 != expected {
+                return Err(format!(
+                    "Server returned {} bytes for the last {} (Content-Range bytes {}-{})",
+                    response.body.len(),
+                    length,
+                    range.first,
+                    range.last
+                )
+                .into());
+            }
+            Ok((range, response.body))
+        }
+        // The server ignored the Range header and sent everything.
+        200 if !response.body.is_empty() => {
+            let total = response.body.len();
+            let first = total.saturating_sub(length);
+            let range = ContentRange {
+                first: first as u64,
+                last: total as u64 - 1,
+                total: Some(total as u64),
+            };
+            Ok((range, response.body[first..].to_vec()))
+        }
+        status => Err(format!(
+            "Server returned status {} for the last {} bytes",
+            status, length
+        )
+        .into()),
+    }
+}
+
+/// Turns the answer to a multi-range request into its parts.
+fn split_ranges(
+    response: HttpResponse,
+    ranges: &[(usize, usize)],
+) -> Result<Vec<RangePart>, Box<dyn Error>> {
+    match response.status {
+        206 => {
+            if let Some(boundary) = response
+                .header("Content-Type")
+                .and_then(multipart::byteranges_boundary)
+            {
+                return multipart::parse_byteranges(&response.body, boundary);
+            }
+            let range = response
+                .content_range()
+                .ok_or("206 response without Content-Range or multipart body")?;
+            Ok(vec![(range, response.body)])
+        }
+        // The server ignored the Range header and sent everything.
+        200 => {
+            let total = response.body.len();
+            ranges
+                .iter()
+                .map(|&(start, end)| {
+                    if end >= total {
+                        return Err(format!(
+                            "range {}-{} is beyond the {}-byte body",
+                            start, end, total
+                        )
+                        .into());
+                    }
+                    let range = ContentRange {
+                        first: start as u64,
+                        last: end as u64,
+                        total: Some(total as u64),
+                    };
+                    Ok((range, response.body[start..=end].to_vec()))
+                })
+                .collect()
+        }
+        status => Err(format!(
+            "Server returned status {} for a multi-range request",
+            status
+        )
+        .into()),
+    }
+}
+
+/// Connects with the socket options that must be set before the handshake: the local `port`
+/// (`--bind-port`), for firewalls that only allow certain source ports, and the receive buffer
+/// size (`--recv-buffer`), which bounds the TCP window that gets negotiated.
+fn connect_socket(
+    addr: &SocketAddr,
+    bind_port: Option<u16>,
+    recv_buffer: Option<usize>,
+    timeout: Duration,
+) -> std::io::Result<TcpStream> {
+    if bind_port.is_none() && recv_buffer.is_none() {
+        return TcpStream::connect_timeout(addr, timeout);
+    }
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if let Some(size) = recv_buffer {
+        apply_recv_buffer(&socket, size)?;
+    }
+    if let Some(port) = bind_port {
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, port).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, port).into()
+        };
+        socket.set_reuse_address(true)?;
+        socket.bind(&local.into()).map_err(|e| match e.kind() {
+            ErrorKind::AddrInUse => std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("local port {} is already in use (--bind-port)", port),
+            ),
+            _ => e,
+        })?;
+    }
+    socket.connect_timeout(&(*addr).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Sets `SO_RCVBUF`, clamping the request to what `setsockopt` accepts, and returns the size
+/// the kernel actually granted. The kernel applies its own ceiling (`net.core.rmem_max` on
+/// Linux) and may report double the value to account for bookkeeping.
+fn apply_recv_buffer(socket: &Socket, requested: usize) -> std::io::Result<usize> {
+    socket.set_recv_buffer_size(requested.clamp(MIN_RECV_BUFFER, MAX_RECV_BUFFER))?;
+    socket.recv_buffer_size()
+}
+
+/// Appends the address a failed attempt used to its error, keeping the `io::ErrorKind` so the
+/// retry logic still classifies it.
+fn naming_peer(e: Box<dyn Error>, peer: SocketAddr) -> Box<dyn Error> {
+    match e.downcast::<std::io::Error>() {
+        Ok(io_error) => Box::new(std::io::Error::new(
+            io_error.kind(),
+            format!("{} (via {})", io_error, peer),
+        )),
+        Err(e) => format!("{} (via {})", e, peer).into(),
+    }
+}
+
+/// Reads a body of `length` bytes, or until EOF when the length is unknown, appending it to
+/// `body`. Returns whether the framing was satisfied; a timeout or EOF mid-body is treated as a
+/// partial read. Every `CANCEL_CHECK_INTERVAL` reads the cancel token is consulted, so a long
+/// body does not hold up cancellation.
+fn read_body<R: Read>(
+    reader: &mut R,
+    length: Option<usize>,
+    cancel: Option<&CancelToken>,
+    quiet: bool,
+    body: &mut Vec<u8>,
+) -> Result<bool, Box<dyn Error>> {
+    let start = body.len();
+    let mut chunk_buffer = [0; 8 * 1024];
+    let mut reads = 0usize;
+    loop {
+        reads += 1;
+        if reads.is_multiple_of(CANCEL_CHECK_INTERVAL)
+            && cancel.is_some_and(CancelToken::is_cancelled)
+        {
+            return Err(CANCELLED.into());
+        }
+        let received = body.len() - start;
+        let to_read = match length {
+            Some(length) => chunk_buffer.len().min(length - received),
+            None => chunk_buffer.len(),
+        };
+        if to_read == 0 {
+            return Ok(true);
+        }
+        match reader.read(&mut chunk_buffer[..to_read]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => body.extend_from_slice(&chunk_buffer[..n]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn_partial_read(e, received, quiet)?;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body into `body`. Chunk extensions (`1a;name=value`)
+/// are ignored and trailers are skipped. Size lines and the terminator are read as whole lines,
+/// so they may arrive split across reads. A timeout or EOF mid-body keeps the bytes decoded so
+/// far.
+fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    cancel: Option<&CancelToken>,
+    quiet: bool,
+    body: &mut Vec<u8>,
+) -> Result<bool, Box<dyn Error>> {
+    let start = body.len();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(false),
+            Ok(_) => {}
+            Err(e) => {
+                warn_partial_read(e, body.len() - start, quiet)?;
+                return Ok(false);
+            }
+        }
+        if !line.ends_with('\n') {
+            return Ok(false);
+        }
+        let size_field = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_field, 16)
+            .map_err(|_| format!("Invalid chunk size line: '{}'", line.trim_end()))?;
+        if size == 0 {
+            loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) => return Ok(false),
+                    Ok(_) if line == "\r\n" || line == "\n" => return Ok(true),
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn_partial_read(e, body.len() - start, quiet)?;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        if !read_body(reader, Some(size), cancel, quiet, body)? {
+            return Ok(false);
+        }
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(_) if line == "\r\n" || line == "\n" => {}
+            Ok(0) => return Ok(false),
+            Ok(_) => {
+                return Err(format!(
+                    "Chunk of {} bytes not followed by CRLF (got '{}')",
+                    size,
+                    line.trim_end()
+                )
+                .into())
+            }
+            Err(e) => {
+                warn_partial_read(e, body.len() - start, quiet)?;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Warns about a timeout or EOF during the body (unless `quiet`), which only cuts the read
+/// short; any other error is returned.
+fn warn_partial_read(
+    e: std::io::Error,
+    received: usize,
+    quiet: bool,
+) -> Result<(), Box<dyn Error>> {
+    let cause = match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            "Read timeout/wouldblock occurred during body read"
+        }
+        ErrorKind::UnexpectedEof => "Unexpected EOF during body read",
+        _ => return Err(Box::new(e)),
+    };
+    if !quiet {
+        eprintln!(
+            "\nWarning: {}. Treating as partial read ({} bytes received this attempt).",
+            cause, received
+        );
+    }
+    Ok(())
+}
+
+/// Fails reads and writes with `TimedOut` once the `--request-timeout` deadline has passed.
+///
+/// Socket timeouts bound each blocking call; this bounds a server that trickles bytes just
+/// fast enough to never trip them. With `--stall-timeout`, a read that times out is tried again
+/// until no byte has arrived for the stall timeout.
+struct DeadlineStream<S> {
+    inner: S,
+    deadline: Option<(Instant, Duration)>,
+    stall_timeout: Option<Duration>,
+    last_byte: Instant,
+}
+
+impl<S> DeadlineStream<S> {
+    fn new(
+        inner: S,
+        deadline: Option<(Instant, Duration)>,
+        stall_timeout: Option<Duration>,
+    ) -> Self {
+        DeadlineStream {
+            inner,
+            deadline,
+            stall_timeout,
+            last_byte: Instant::now(),
+        }
+    }
+
+    fn check(&self) -> std::io::Result<()> {
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Request timed out after {:?} (--request-timeout)", timeout),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports a socket timeout that ran into the deadline as the request timing out.
+    fn map_timeout<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        match result {
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                self.check()?;
+                Err(e)
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: Read> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            self.check()?;
+            let result = self.inner.read(buf);
+            match self.map_timeout(result) {
+                Ok(n) => {
+                    if n > 0 {
+                        self.last_byte = Instant::now();
+                    }
+                    return Ok(n);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let Some(stall_timeout) = self.stall_timeout else {
+                        return Err(e);
+                    };
+                    if self.last_byte.elapsed() >= stall_timeout {
+                        return Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("No data received for {:?} (--stall-timeout)", stall_timeout),
+                        ));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check()?;
+        let result = self.inner.write(buf);
+        self.map_timeout(result)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn verify_chunk_sha256(headers: &[(String, String)], body: &[u8]) -> Result<(), Box<dyn Error>> {
+    let Some(declared) = find_header(headers, CHUNK_SHA256_HEADER) else {
+        return Ok(());
+    };
+    let actual = hex::encode(&Sha256::digest(body));
+    if actual.eq_ignore_ascii_case(declared) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Chunk SHA-256 mismatch: {} declared {}, received {} bytes hashing to {}",
+            CHUNK_SHA256_HEADER,
+            declared,
+            body.len(),
+            actual
+        )
+        .into())
+    }
+}
+
+fn is_desync(e: &(dyn Error + 'static)) -> bool {
+    e.to_string().starts_with(RESPONSE_DESYNC)
+}
+
+/// Why `parse_status_line` rejected a line. Each variant carries the trimmed line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatusLineError {
+    /// Nothing but whitespace.
+    Empty,
+    /// No space separating the version from the code.
+    TooFewParts(String),
+    /// The first part does not start with `HTTP/`.
+    InvalidVersion { version: String, line: String },
+    /// The code is not a number from 0 to 65535.
+    InvalidCode { code: String, line: String },
+}
+
+impl fmt::Display for StatusLineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusLineError::Empty => f.write_str("Status line is empty after trimming"),
+            StatusLineError::TooFewParts(line) => {
+                write!(f, "Malformed status line (too few parts): '{}'", line)
+            }
+            StatusLineError::InvalidVersion { version, line } => write!(
+                f,
+                "Malformed status line (invalid or missing HTTP version part '{}'): '{}'",
+                version, line
+            ),
+            StatusLineError::InvalidCode { code, line } => {
+                write!(f, "Invalid status code '{}' in line '{}'", code, line)
+            }
+        }
+    }
+}
+
+impl Error for StatusLineError {}
+
+/// Parses `HTTP/<version> <code> [reason]` into the code and reason phrase. The reason is
+/// optional (`HTTP/1.1 200` yields an empty one) and extra spaces around the code are tolerated.
+///
+/// It never panics: any input, however malformed, yields either the parts or a
+/// `StatusLineError`. The code is not range-checked beyond fitting in a `u16`.
+///
+/// ```
+/// use client::http_client::{parse_status_line, StatusLineError};
+///
+/// assert_eq!(
+///     parse_status_line("HTTP/1.1 206 Partial Content\r\n"),
+///     Ok((206, "Partial Content".to_string()))
+/// );
+/// assert_eq!(parse_status_line("HTTP/1.0 204"), Ok((204, String::new())));
+/// assert_eq!(parse_status_line("  "), Err(StatusLineError::Empty));
+/// assert!(matches!(
+///     parse_status_line("HTTP/1.1 OK"),
+///     Err(StatusLineError::InvalidCode { .. })
+/// ));
+/// ```
+pub fn parse_status_line(line: &str) -> Result<(u16, String), StatusLineError> {
+    let trimmed_line = line.trim();
+    if trimmed_line.is_empty() {
+        return Err(StatusLineError::Empty);
+    }
+    let Some((version, rest)) = trimmed_line.split_once(' ') else {
+        return Err(StatusLineError::TooFewParts(trimmed_line.to_string()));
+    };
+    if !version.starts_with("HTTP/") {
+        return Err(StatusLineError::InvalidVersion {
+            version: version.to_string(),
+            line: trimmed_line.to_string(),
+        });
+    }
+    let rest = rest.trim_start();
+    let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    let code = code
+        .parse::<u16>()
+        .map_err(|_| StatusLineError::InvalidCode {
+            code: code.to_string(),
+            line: trimmed_line.to_string(),
+        })?;
+    Ok((code, reason.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl HttpClient {
+        fn fetch_range_via_stream<T: Read + Write>(
+            &self,
+            stream: &mut T,
+            target_host: &str,
+            start_byte: usize,
+        ) -> Result<HttpResponse, Box<dyn Error>> {
+            self.exchange(stream, target_host, Request::Range(start_byte))
+                .map(|(response, _)| response)
+        }
+    }
+    use std::collections::VecDeque;
+    use std::io::{Error as IoError, Result as IoResult};
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct MockTcpStream {
+        read_queue: VecDeque<IoResult<Vec<u8>>>,
+        write_buffer: Vec<u8>,
+    }
+
+    impl MockTcpStream {
+        fn new(responses: Vec<IoResult<Vec<u8>>>) -> Self {
+            MockTcpStream {
+                read_queue: responses.into(),
+                write_buffer: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for MockTcpStream {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            match self.read_queue.pop_front() {
+                Some(Ok(mut data)) => {
+                    if data.is_empty() {
+                        self.read_queue.push_front(Ok(Vec::new()));
+                        Ok(0)
+                    } else {
+                        let bytes_to_copy = std::cmp::min(buf.len(), data.len());
+                        buf[..bytes_to_copy].copy_from_slice(&data[..bytes_to_copy]);
+                        if data.len() > bytes_to_copy {
+                            self.read_queue
+                                .push_front(Ok(data.split_off(bytes_to_copy)));
+                        }
+                        Ok(bytes_to_copy)
+                    }
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Write for MockTcpStream {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.write_buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    fn mock_client() -> HttpClient {
+        HttpClient::new(
+            "mock.server".to_string(),
+            8080,
+            Duration::from_secs(5),
+            Duration::from_secs(15),
+        )
+    }
+
+    #[test]
+    fn test_fetch_success_206_partial_content() {
+        let response_body = b"some partial data".to_vec();
+        let response_headers = format!(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Length: {}\r\n\
+             Content-Range: bytes 100-116/1000\r\n\
+             \r\n",
+            response_body.len()
+        );
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_body.clone()),
+        ]);
+        let start_byte = 100;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, response_body);
+        let request_str =
+            String::from_utf8(mock_stream.write_buffer).expect("Request not valid UTF-8");
+        assert!(request_str.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(request_str.contains(&format!("\r\nHost: {}\r\n", target_host)));
+        assert!(request_str.contains(&format!("\r\nRange: bytes={}-\r\n", start_byte)));
+        assert!(request_str.contains("\r\nConnection: close\r\n"));
+        assert!(request_str.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_fetch_success_200_ok() {
+        let response_body = b"complete file data".to_vec();
+        let response_headers = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            response_body.len()
+        );
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_body.clone()),
+        ]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, response_body);
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request_str.contains(&format!("\r\nRange: bytes={}-\r\n", start_byte)));
+    }
+
+    #[test]
+    fn test_fetch_error_404_not_found() {
+        let response_body = b"Not Found Error Page".to_vec();
+        let response_headers = format!(
+            "HTTP/1.1 404 Not Found\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            response_body.len()
+        );
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_body.clone()),
+        ]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, response_body);
+    }
+
+    #[test]
+    fn test_fetch_simulated_timeout_during_body_read() {
+        let response_part1 = b"first chunk".to_vec();
+        let response_headers = "HTTP/1.1 206 Partial Content\r\n\
+             Content-Length: 1000\r\n\
+             \r\n"
+            .to_string();
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_part1.clone()),
+            Err(IoError::new(ErrorKind::TimedOut, "Simulated read timeout")),
+        ]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, response_part1);
+    }
+
+    #[test]
+    fn test_fetch_simulated_unexpected_eof_during_body_read() {
+        let response_part1 = b"partial data before EOF".to_vec();
+        let response_headers = "HTTP/1.1 206 Partial Content\r\n\
+             Content-Length: 1000\r\n\
+             \r\n"
+            .to_string();
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_part1.clone()),
+            Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "Simulated unexpected EOF",
+            )),
+        ]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, response_part1);
+    }
+
+    #[test]
+    fn test_fetch_premature_eof_before_status_line() {
+        let mut mock_stream = MockTcpStream::new(vec![Ok(Vec::new())]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_err());
+        let error_msg = result.err().unwrap().to_string();
+        assert!(error_msg.contains("Connection closed before status line received"));
+    }
+
+    #[test]
+    fn test_fetch_premature_eof_during_headers() {
+        let response_partial = "HTTP/1.1 206 OK\r\nContent-Type: text/p";
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response_partial.as_bytes().to_vec())]);
+        let start_byte = 0;
+        let target_host = "mock.server:8080";
+        let result =
+            mock_client().fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
+        assert!(result.is_err());
+        let error_msg = result.err().unwrap().to_string();
+        assert!(error_msg.contains("Connection closed during header reading"));
+    }
+
+    #[test]
+    fn test_fetch_chunk_sha256_header_matches() {
+        let response_body = b"verified chunk".to_vec();
+        let response_headers = format!(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Length: {}\r\n\
+             X-Content-SHA256: {}\r\n\
+             \r\n",
+            response_body.len(),
+            hex::encode(&Sha256::digest(&response_body))
+        );
+        let mut mock_stream = MockTcpStream::new(vec![
+            Ok(response_headers.into_bytes()),
+            Ok(response_body.clone()),
+        ]);
+        let client = mock_client().with_chunk_sha_verification(true);
+        let result = client.fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0);
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.body, response_body);
+        assert!(response.header("x-content-sha256").is_some());
+    }
+
+    #[test]
+    fn test_fetch_chunk_sha256_header_mismatch() {
+        let response_body = b"corrupted chunk".to_vec();
+        let response_headers = format!(
+            "HTTP/1.1 206 Partial Content\r\n\
+             Content-Length: {}\r\n\
+             X-Content-SHA256: {}\r\n\
+             \r\n",
+            response_body.len(),
+            hex::encode(&Sha256::digest(b"original chunk"))
+        );
+        let responses = vec![Ok(response_headers.into_bytes()), Ok(response_body.clone())];
+
+        let mut mock_stream = MockTcpStream::new(responses);
+        let client = mock_client().with_chunk_sha_verification(true);
+        let result = client.fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0);
+        assert!(result.is_err());
+        let error_msg = result.err().unwrap().to_string();
+        assert!(error_msg.contains("Chunk SHA-256 mismatch"));
+    }
+
+    #[test]
+    fn test_fetch_sends_referer_only_when_set() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response.clone())]);
+        let client = mock_client().with_referer(Some("https://example.com/downloads".to_string()));
+        client
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request_str.contains("\r\nReferer: https://example.com/downloads\r\n"));
+        assert!(request_str.ends_with("\r\n\r\n"));
+
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        mock_client()
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(!request_str.contains("Referer:"));
+    }
+
+    #[test]
+    fn test_authorization_is_dropped_for_another_host() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let client = mock_client().with_authorization(Some("Bearer t0k3n".to_string()));
+
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response.clone())]);
+        client
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request_str.contains("\r\nAuthorization: Bearer t0k3n\r\n"));
+
+        let same_host = Url::parse(&format!("http://{}:9000/other", client.host)).unwrap();
+        assert!(client
+            .clone()
+            .with_target(&same_host)
+            .authorization
+            .is_some());
+        let other_host = Url::parse("http://elsewhere.example/file").unwrap();
+        let redirected = client.with_target(&other_host);
+        assert!(redirected.authorization.is_none());
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        redirected
+            .fetch_range_via_stream(&mut mock_stream, "elsewhere.example:80", 0)
+            .unwrap();
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(!request_str.contains("Authorization:"));
+    }
+
+    #[test]
+    fn test_response_reports_request_bytes_written() {
+        let client = mock_client().with_referer(Some("http://example.com/page".to_string()));
+        let mut mock_stream = MockTcpStream::new(vec![Ok(b"HTTP/1.1 200 OK\r\n\r\nbody".to_vec())]);
+        let response = client
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 12345)
+            .unwrap();
+        assert_eq!(response.request_bytes, mock_stream.write_buffer.len());
+        let request = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_raw_dump_holds_unmodified_response_bytes() {
+        let path = std::env::temp_dir().join(format!("client-raw-{}.bin", std::process::id()));
+        let dump = RawDump::new(Box::new(std::fs::File::create(&path).unwrap()), None);
+        let client = mock_client().with_raw_dump(Some(Arc::new(dump)));
+        let raw = b"HTTP/1.1 200 OK\r\nX-Odd:  spaced value \r\n\r\n\x00binary\xffbody".to_vec();
+        let mut mock_stream =
+            MockTcpStream::new(vec![Ok(raw[..20].to_vec()), Ok(raw[20..].to_vec())]);
+
+        let response = client
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
+
+        assert_eq!(response.header("X-Odd"), Some("spaced value"));
+        assert_eq!(std::fs::read(&path).unwrap(), raw);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_chunked_body_with_extensions() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         5;foo=bar\r\nhello\r\n\
+                         6;name=\"quoted;semi\";flag\r\n world\r\n\
+                         0;last\r\nX-Trailer: t\r\n\r\n"
+            .to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let (response, reusable) = mock_client()
+            .with_keep_alive(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hello world");
+        assert!(reusable);
+    }
+
+    #[test]
+    fn test_chunked_terminator_split_across_reads() {
+        let head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n".to_vec();
+        let terminator = b"0\r\nX-Trailer: t\r\n\r\n";
+        for split in 1..terminator.len() {
+            let mut mock_stream = MockTcpStream::new(vec![
+                Ok(head.clone()),
+                Ok(terminator[..split].to_vec()),
+                Ok(terminator[split..].to_vec()),
+            ]);
+            let (response, reusable) = mock_client()
+                .with_keep_alive(true)
+                .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+                .unwrap();
+            assert_eq!(response.body, b"hello", "split at {}", split);
+            assert!(reusable, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_transfer_codings_other_than_chunked_are_rejected() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let error = mock_client()
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported Transfer-Encoding: gzip");
+    }
+
+    #[test]
+    fn test_chunked_body_cut_short_keeps_decoded_bytes() {
+        let response =
+            b"HTTP/1.1 206 Partial Content\r\nTransfer-Encoding: chunked\r\n\r\n5;x=y\r\nhello\r\n8\r\nwor"
+                .to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let (response, reusable) = mock_client()
+            .with_keep_alive(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hellowor");
+        assert!(!reusable);
+    }
+
+    #[test]
+    fn test_build_request_for_default_client() {
+        assert_eq!(
+            mock_client().build_request(Some(100), None, Method::Get),
+            "GET / HTTP/1.1\r\n\
+             Host: mock.server:8080\r\n\
+             Range: bytes=100-\r\n\
+             Accept-Encoding: identity\r\n\
+             Connection: close\r\n\
+             User-Agent: RustStdNetClient/1.0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_build_request_reflects_configuration() {
+        let client = mock_client()
+            .with_path("/file.bin".to_string())
+            .with_keep_alive(true)
+            .with_gzip(true)
+            .with_referer(Some("http://example.com/".to_string()));
+        assert_eq!(
+            client.build_request(Some(0), Some(99), Method::Get),
+            "GET /file.bin HTTP/1.1\r\n\
+             Host: mock.server:8080\r\n\
+             Range: bytes=0-99\r\n\
+             Accept-Encoding: gzip\r\n\
+             Connection: keep-alive\r\n\
+             User-Agent: RustStdNetClient/1.0\r\n\
+             Referer: http://example.com/\r\n\r\n"
+        );
+        let head = client.build_request(None, None, Method::Head);
+        assert!(head.starts_with("HEAD /file.bin HTTP/1.1\r\n"));
+        assert!(!head.contains("Range:"));
+    }
+
+    #[test]
+    fn test_build_request_uses_absolute_target_through_proxy() {
+        let client = mock_client().with_proxy(Some(Url::parse("http://proxy:3128").unwrap()));
+        assert!(client
+            .build_request(Some(0), None, Method::Get)
+            .starts_with("GET http://mock.server:8080/ HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_ranged_request_advertises_identity_encoding() {
+        let response = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        mock_client()
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 10)
+            .unwrap();
+        let request = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request.contains("Range: bytes=10-\r\n"));
+        assert!(request.contains("Accept-Encoding: identity\r\n"));
+    }
+
+    #[test]
+    fn test_whole_request_with_gzip_sends_no_range() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        mock_client()
+            .with_gzip(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Whole)
+            .unwrap();
+        let request = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(!request.contains("Range:"));
+        assert!(request.contains("Accept-Encoding: gzip\r\n"));
+    }
+
+    fn ranges_client(port: u16) -> HttpClient {
+        HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+    }
+
+    #[test]
+    fn test_fetch_ranges_parses_two_part_multipart_response() {
+        let body = b"--SEP\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-3/20\r\n\r\nabcd\r\n\
+--SEP\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 10-12/20\r\n\r\nklm\r\n\
+--SEP--\r\n";
+        let (port, server) =
+            crate::test_support::spawn_server(vec![crate::test_support::response(
+                "206 Partial Content",
+                "Content-Type: multipart/byteranges; boundary=SEP\r\n",
+                body,
+            )]);
+
+        let parts = ranges_client(port)
+            .fetch_ranges(&[(0, 3), (10, 12)])
+            .unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].0.first, parts[0].0.last), (0, 3));
+        assert_eq!(parts[0].1, b"abcd");
+        assert_eq!((parts[1].0.first, parts[1].0.last), (10, 12));
+        assert_eq!(parts[1].1, b"klm");
+        assert!(server.join().unwrap()[0].contains("Range: bytes=0-3,10-12\r\n"));
+    }
+
+    #[test]
+    fn test_repeated_request_is_answered_from_the_response_cache() {
+        let (port, server) = crate::test_support::spawn_server(vec![
+            crate::test_support::response("206 Partial Content", "", b"abc"),
+            crate::test_support::response("206 Partial Content", "", b""),
+        ]);
+        let cache = Arc::new(ResponseCache::default());
+        let client = ranges_client(port).with_response_cache(Some(cache.clone()));
+
+        assert_eq!(client.fetch_range(0).unwrap().body, b"abc");
+        // The clone shares the cache, so the same range does not reach the server again.
+        assert_eq!(client.clone().fetch_range(0).unwrap().body, b"abc");
+        assert!(client.fetch_range(3).unwrap().body.is_empty());
+        assert_eq!(cache.len(), 2);
+        client.clear_response_cache();
+        assert!(cache.is_empty());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("Range: bytes=0-\r\n"));
+        assert!(requests[1].contains("Range: bytes=3-\r\n"));
+    }
+
+    #[test]
+    fn test_response_cache_keys_on_request_headers() {
+        let (port, server) = crate::test_support::spawn_server(vec![
+            crate::test_support::response("206 Partial Content", "", b"alice"),
+            crate::test_support::response("206 Partial Content", "", b"bob"),
+            crate::test_support::response("206 Partial Content", "", b"referred"),
+        ]);
+        let cache = Arc::new(ResponseCache::default());
+        let client = ranges_client(port).with_response_cache(Some(cache.clone()));
+        let alice = client
+            .clone()
+            .with_authorization(Some("Bearer alice".to_string()));
+        let bob = client
+            .clone()
+            .with_authorization(Some("Bearer bob".to_string()));
+        let referred = alice
+            .clone()
+            .with_referer(Some("http://example.com/".to_string()));
+
+        assert_eq!(alice.fetch_range(0).unwrap().body, b"alice");
+        // Same URL and range, but other credentials or headers: not answered from the cache.
+        assert_eq!(bob.fetch_range(0).unwrap().body, b"bob");
+        assert_eq!(referred.fetch_range(0).unwrap().body, b"referred");
+        assert_eq!(alice.fetch_range(0).unwrap().body, b"alice");
+        assert_eq!(cache.len(), 3);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].contains("Authorization: Bearer bob\r\n"));
+    }
+
+    #[test]
+    fn test_fetch_range_into_appends_to_the_buffer() {
+        let (port, server) = crate::test_support::spawn_server(vec![
+            crate::test_support::response("206 Partial Content", "", b"def"),
+            crate::test_support::response(
+                "206 Partial Content",
+                "Transfer-Encoding: chunked\r\n",
+                b"3\r\nghi\r\n0\r\n\r\n",
+            ),
+            crate::test_support::response(
+                "206 Partial Content",
+                &format!("{}: {}\r\n", CHUNK_SHA256_HEADER, "00".repeat(32)),
+                b"jkl",
+            ),
+        ]);
+        let client = ranges_client(port).with_chunk_sha_verification(true);
+        let mut buf = b"abc".to_vec();
+
+        assert_eq!(client.fetch_range_into(3, &mut buf).unwrap(), (206, 3));
+        assert_eq!(client.fetch_range_into(6, &mut buf).unwrap(), (206, 3));
+        assert_eq!(buf, b"abcdefghi");
+        // A rejected body leaves the buffer as it was.
+        assert!(client.fetch_range_into(9, &mut buf).is_err());
+        assert_eq!(buf, b"abcdefghi");
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("Range: bytes=6-\r\n"));
+    }
+
+    #[test]
+    fn test_fetch_ranges_accepts_single_part_response() {
+        let (port, server) =
+            crate::test_support::spawn_server(vec![crate::test_support::response(
+                "206 Partial Content",
+                "Content-Range: bytes 5-9/20\r\n",
+                b"fghij",
+            )]);
+
+        let parts = ranges_client(port).fetch_ranges(&[(5, 9)]).unwrap();
+
+        assert_eq!(
+            parts,
+            vec![(
+                ContentRange {
+                    first: 5,
+                    last: 9,
+                    total: Some(20)
+                },
+                b"fghij".to_vec()
+            )]
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_suffix_sends_suffix_range() {
+        let (port, server) =
+            crate::test_support::spawn_server(vec![crate::test_support::response(
+                "206 Partial Content",
+                "Content-Range: bytes 16-19/20\r\n",
+                b"qrst",
+            )]);
+
+        let (range, body) = ranges_client(port).fetch_suffix(4).unwrap();
+
+        assert_eq!((range.first, range.last, range.total), (16, 19, Some(20)));
+        assert_eq!(body, b"qrst");
+        assert!(server.join().unwrap()[0].contains("Range: bytes=-4\r\n"));
+    }
+
+    #[test]
+    fn test_suffix_response_is_checked_against_content_range() {
+        let answer = |status: u16, content_range: Option<&str>, body: &[u8]| HttpResponse {
+            status,
+            headers: content_range
+                .map(|value| vec![("Content-Range".to_string(), value.to_string())])
+                .unwrap_or_default(),
+            body: body.to_vec(),
+            reason: String::new(),
+            request_bytes: 0,
+            peer: None,
+        };
+        // A file shorter than the suffix comes back whole.
+        let (range, body) = suffix_part(answer(206, Some("bytes 0-2/3"), b"abc"), 10).unwrap();
+        assert_eq!((range.first, range.last), (0, 2));
+        assert_eq!(body, b"abc");
+        // A 200 is the whole file, so the suffix is cut from it.
+        let (range, body) = suffix_part(answer(200, None, b"abcdef"), 2).unwrap();
+        assert_eq!((range.first, range.last, range.total), (4, 5, Some(6)));
+        assert_eq!(body, b"ef");
+
+        assert!(suffix_part(answer(206, Some("bytes 10-13/20"), b"klmn"), 4).is_err());
+        assert!(suffix_part(answer(206, Some("bytes 15-19/20"), b"pqrst"), 4).is_err());
+        assert!(suffix_part(answer(206, Some("bytes 16-19/20"), b"qrs"), 4).is_err());
+        assert!(suffix_part(answer(206, None, b"qrst"), 4).is_err());
+        assert!(suffix_part(answer(416, None, b""), 4).is_err());
+        let max = format!("bytes {}-{}/*", u64::MAX - 1, u64::MAX);
+        assert!(suffix_part(answer(206, Some(&max), b"yz"), 2).is_err());
+    }
+
+    #[test]
+    fn test_head_request_reads_no_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nETag: \"abc\"\r\n\r\n".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let (response, reusable) = mock_client()
+            .with_keep_alive(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Head)
+            .unwrap();
+        assert!(response.body.is_empty());
+        assert_eq!(response.header("Content-Length"), Some("1234"));
+        assert!(reusable);
+        let request = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request.starts_with("HEAD / HTTP/1.1\r\n"));
+        assert!(!request.contains("Range:"));
+    }
+
+    #[test]
+    fn test_keep_alive_response_framed_by_content_length_is_reusable() {
+        let response = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let client = mock_client().with_keep_alive(true);
+        let (response, reusable) = client
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hello");
+        assert!(reusable);
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request_str.contains("\r\nConnection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn test_keep_alive_trailing_bytes_poison_connection() {
+        let response =
+            b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhelloGARBAGE".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let client = mock_client().with_keep_alive(true);
+        let (response, reusable) = client
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hello");
+        assert!(!reusable);
+    }
+
+    fn response_with_headers(count: usize) -> Vec<u8> {
+        let mut response = "HTTP/1.1 200 OK\r\n".to_string();
+        for index in 0..count {
+            response.push_str(&format!("X-Filler-{}: {}\r\n", index, index));
+        }
+        response.push_str("\r\nbody");
+        response.into_bytes()
+    }
+
+    #[test]
+    fn test_max_headers_allows_exactly_the_limit() {
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response_with_headers(3))]);
+        let (response, _) = mock_client()
+            .with_max_headers(3)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.headers.len(), 3);
+        assert_eq!(response.body, b"body");
+
+        let mut mock_stream =
+            MockTcpStream::new(vec![Ok(response_with_headers(DEFAULT_MAX_HEADERS))]);
+        assert!(mock_client()
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_max_headers_rejects_one_more_than_the_limit() {
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response_with_headers(4))]);
+        let error = mock_client()
+            .with_max_headers(3)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("more than 3 header lines"));
+
+        let mut mock_stream =
+            MockTcpStream::new(vec![Ok(response_with_headers(DEFAULT_MAX_HEADERS + 1))]);
+        assert!(mock_client()
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_strict_close_flags_a_server_that_keeps_the_connection_open() {
+        let response = b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let timed_out = || IoError::new(ErrorKind::WouldBlock, "read timed out");
+
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response.clone()), Err(timed_out())]);
+        let error = mock_client()
+            .with_strict_close(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("kept the connection open"));
+
+        let mut extra = response.clone();
+        extra.extend_from_slice(b"HTTP/1.1");
+        let mut mock_stream = MockTcpStream::new(vec![Ok(extra)]);
+        let error = mock_client()
+            .with_strict_close(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("8 bytes after the body"));
+
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let (response, _) = mock_client()
+            .with_strict_close(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[test]
+    fn test_keep_alive_rejects_leading_junk_before_status_line() {
+        let response = b" HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response.clone())]);
+        let error = mock_client()
+            .with_keep_alive(true)
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .err()
+            .unwrap();
+        assert!(is_desync(error.as_ref()));
+
+        // Without keep-alive there is no earlier response to be out of step with.
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let (response, _) = mock_client()
+            .exchange(&mut mock_stream, "mock.server:8080", Request::Range(0))
+            .unwrap();
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[test]
+    fn test_keep_alive_reconnects_after_leading_junk_on_reuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let read_request = |reader: &mut BufReader<TcpStream>| {
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            };
+            let (mut first, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(first.try_clone().unwrap());
+            read_request(&mut reader);
+            first
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap();
+            // The reused connection answers with junk ahead of the status line.
+            read_request(&mut reader);
+            first
+                .write_all(b"XHTTP/1.1 206 Partial Content\r\nContent-Length: 6\r\n\r\nsmuggl")
+                .unwrap();
+            let (mut second, _) = listener.accept().unwrap();
+            read_request(&mut BufReader::new(second.try_clone().unwrap()));
+            second
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 6\r\n\r\n world")
+                .unwrap();
+            (first, second)
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_keep_alive(true);
+        assert_eq!(client.fetch_range(0).unwrap().body, b"hello");
+        assert_eq!(client.fetch_range(5).unwrap().body, b" world");
+        server.join().unwrap();
+        assert_eq!(client.pool.stats().opened, 2);
+    }
+
+    #[test]
+    fn test_keep_alive_reconnects_after_trailing_garbage() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let responses: [&[u8]; 2] = [
+                b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhelloGARBAGE",
+                b"HTTP/1.1 206 Partial Content\r\nContent-Length: 6\r\n\r\n world",
+            ];
+            let mut connections = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+                stream.write_all(response).unwrap();
+                // Keep the connection open, as a keep-alive server would.
+                connections.push(stream);
+            }
+            connections.len()
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_keep_alive(true);
+        assert_eq!(client.fetch_range(0).unwrap().body, b"hello");
+        assert_eq!(client.fetch_range(5).unwrap().body, b" world");
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_keep_alive_connection_recycled_after_n_ranges() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut ranges_per_connection = Vec::new();
+            let mut total = 0;
+            while total < 5 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut served = 0;
+                while total < 5 {
+                    let mut head = String::new();
+                    while reader.read_line(&mut head).unwrap() > 0 && !head.ends_with("\r\n\r\n") {}
+                    if head.is_empty() {
+                        break;
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 1\r\n\r\nx")
+                        .unwrap();
+                    served += 1;
+                    total += 1;
+                }
+                ranges_per_connection.push(served);
+            }
+            ranges_per_connection
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_keep_alive(true)
+        .with_recycle_after(Some(2));
+        for start in 0..5 {
+            assert_eq!(client.fetch_range(start).unwrap().body, b"x");
         }
+        assert_eq!(server.join().unwrap(), vec![2, 2, 1]);
+        assert_eq!(client.pool.stats().opened, 3);
     }
 
-    pub fn fetch_range(&self, start_byte: usize) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
-        let target = format!("{}:{}", self.host, self.port);
-        let socket_addr: SocketAddr = target
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| format!("Failed to resolve address: {}", target))?;
-        let mut stream = TcpStream::connect_timeout(&socket_addr, self.connect_timeout)?;
-        stream.set_read_timeout(Some(self.read_write_timeout))?;
-        stream.set_write_timeout(Some(self.read_write_timeout))?;
-        Self::fetch_range_via_stream(&mut stream, &target, start_byte)
+    #[test]
+    fn test_request_timeout_stops_a_stalled_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Answer the status line, then stall well past the request timeout.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n").unwrap();
+            thread::sleep(Duration::from_millis(800));
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(15),
+        )
+        .with_request_timeout(Some(Duration::from_millis(200)));
+        let started = Instant::now();
+        let error = client.fetch_range(0).err().unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(700));
+        let io_error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_error.kind(), ErrorKind::TimedOut);
+        server.join().unwrap();
     }
 
-    fn fetch_range_via_stream<T: Read + Write>(
-        stream: &mut T,
-        target_host: &str,
-        start_byte: usize,
-    ) -> Result<(u16, Vec<u8>), Box<dyn Error>> {
-        let request = format!(
-            "GET / HTTP/1.1\r\n\
-             Host: {}\r\n\
-             Range: bytes={}-\r\n\
-             Connection: close\r\n\
-             User-Agent: RustStdNetClient/1.0\r\n\
-             \r\n",
-            target_host, start_byte
+    #[test]
+    fn test_per_call_timeouts_override_the_client_defaults() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accept, then send nothing for longer than the per-call timeout.
+        let server = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(800));
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(15),
         );
-        stream.write_all(request.as_bytes())?;
-        stream.flush()?;
-        let mut reader = BufReader::new(stream);
-        let mut status_line = String::new();
-        if reader.read_line(&mut status_line)? == 0 {
-            return Err("Connection closed before status line received".into());
-        }
-        let status_code = parse_status_line(&status_line)?;
-        let mut header_line = String::new();
-        loop {
-            header_line.clear();
-            let bytes_read = reader.read_line(&mut header_line)?;
-            if bytes_read == 0 {
-                return Err("Connection closed during header reading".into());
-            }
-            if header_line == "\r\n" {
-                break;
-            }
-        }
-        let mut body_bytes = Vec::new();
-        let mut chunk_buffer = [0; 8 * 1024];
-        loop {
-            match reader.read(&mut chunk_buffer) {
-                Ok(0) => break,
-                Ok(n) => body_bytes.extend_from_slice(&chunk_buffer[..n]),
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(ref e)
-                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
-                {
-                    eprintln!("\nWarning: Read timeout/wouldblock occurred during body read. Treating as partial read ({} bytes received this attempt).", body_bytes.len());
-                    break;
-                }
-                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
-                    eprintln!("\nWarning: Unexpected EOF during body read. Treating as partial read ({} bytes received this attempt).", body_bytes.len());
-                    break;
-                }
-                Err(e) => return Err(Box::new(e)),
+        let started = Instant::now();
+        let error = client
+            .fetch_range_with_timeouts(0, Duration::from_secs(1), Duration::from_millis(200))
+            .err()
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_millis(700));
+        let io_error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert!(matches!(
+            io_error.kind(),
+            ErrorKind::TimedOut | ErrorKind::WouldBlock
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancel_stops_a_long_body_read_promptly() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Trickle an endless body until the client hangs up.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000000000\r\n\r\n");
+            let chunk = [b'x'; 8 * 1024];
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_secs(10) && stream.write_all(&chunk).is_ok() {
+                thread::sleep(Duration::from_millis(1));
             }
-        }
-        Ok((status_code, body_bytes))
+        });
+
+        let token = CancelToken::new();
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_cancel_token(Some(token.clone()));
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            token.cancel();
+        });
+        let started = Instant::now();
+        let error = client.fetch_whole().err().unwrap();
+
+        assert!(error.to_string().contains(CANCELLED));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        canceller.join().unwrap();
+        server.join().unwrap();
     }
-}
 
-fn parse_status_line(line: &str) -> Result<u16, Box<dyn Error>> {
-    let trimmed_line = line.trim();
-    if trimmed_line.is_empty() {
-        return Err("Status line is empty after trimming".into());
+    #[test]
+    fn test_response_and_errors_name_the_address_used() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+        });
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            addr.port(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        );
+        assert_eq!(client.fetch_range(0).unwrap().peer, Some(addr));
+        server.join().unwrap();
+
+        // Nothing listens any more, so the connection attempt itself fails.
+        let error = client.fetch_range(0).err().unwrap();
+        assert!(error.to_string().contains(&format!("via {}", addr)));
+        let io_error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io_error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_connect_rate_spaces_out_connection_attempts() {
+        // Nothing listens on the port, so every attempt is refused at once.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_connect_rate(Some(Arc::new(RetryBudget::new(20.0))));
+
+        let started = Instant::now();
+        for _ in 0..5 {
+            assert!(client.fetch_range(0).is_err());
+        }
+        // The first attempt is free; the other four wait 50ms each.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
     }
-    let parts: Vec<&str> = trimmed_line.splitn(3, ' ').collect();
-    if parts.len() < 2 {
-        return Err(format!("Malformed status line (too few parts): '{}'", trimmed_line).into());
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_server_name_overrides_the_host() {
+        let client = mock_client();
+        assert_eq!(client.tls_server_name(), "mock.server");
+        let client = client.with_tls_server_name(Some("cdn.example.test".to_string()));
+        assert_eq!(client.tls_server_name(), "cdn.example.test");
     }
-    if !parts[0].starts_with("HTTP/") {
-        return Err(format!(
-            "Malformed status line (invalid or missing HTTP version part '{}'): '{}'",
-            parts[0], trimmed_line
+
+    #[test]
+    fn test_bind_port_sets_the_local_port() {
+        let local_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+            peer.port()
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
         )
-        .into());
+        .with_bind_port(Some(local_port));
+        assert_eq!(client.fetch_range(0).unwrap().body, b"ok");
+        assert_eq!(server.join().unwrap(), local_port);
     }
-    parts[1].parse::<u16>().map_err(|e| {
-        format!(
-            "Invalid status code '{}' in line '{}': {}",
-            parts[1], trimmed_line, e
+
+    #[test]
+    fn test_bind_port_in_use_is_reported() {
+        let occupied = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_port = occupied.local_addr().unwrap().port();
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            target.local_addr().unwrap().port(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
         )
-        .into()
-    })
-}
+        .with_bind_port(Some(local_port));
+        let error = client.fetch_range(0).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains(&format!("local port {} is already in use", local_port)));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::VecDeque;
-    use std::io::{Error as IoError, Result as IoResult};
+    #[test]
+    fn test_apply_recv_buffer_sets_and_clamps_the_option() {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        let small = apply_recv_buffer(&socket, 8 * 1024).unwrap();
+        let larger = apply_recv_buffer(&socket, 64 * 1024).unwrap();
+        assert_eq!(larger, socket.recv_buffer_size().unwrap());
+        assert!(small >= 8 * 1024);
+        assert!(larger > small);
 
-    struct MockTcpStream {
-        read_queue: VecDeque<IoResult<Vec<u8>>>,
-        write_buffer: Vec<u8>,
+        let tiny = apply_recv_buffer(&socket, 1).unwrap();
+        assert!(tiny >= MIN_RECV_BUFFER);
+        assert!(apply_recv_buffer(&socket, usize::MAX).is_ok());
     }
 
-    impl MockTcpStream {
-        fn new(responses: Vec<IoResult<Vec<u8>>>) -> Self {
-            MockTcpStream {
-                read_queue: responses.into(),
-                write_buffer: Vec::new(),
-            }
-        }
+    #[test]
+    fn test_recv_buffer_connection_still_fetches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+        });
+
+        let client = HttpClient::new(
+            "127.0.0.1".to_string(),
+            port,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_recv_buffer(Some(64 * 1024));
+        assert_eq!(client.fetch_range(0).unwrap().body, b"ok");
+        server.join().unwrap();
     }
 
-    impl Read for MockTcpStream {
-        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-            match self.read_queue.pop_front() {
-                Some(Ok(mut data)) => {
-                    if data.is_empty() {
-                        self.read_queue.push_front(Ok(Vec::new()));
-                        Ok(0)
-                    } else {
-                        let bytes_to_copy = std::cmp::min(buf.len(), data.len());
-                        buf[..bytes_to_copy].copy_from_slice(&data[..bytes_to_copy]);
-                        if data.len() > bytes_to_copy {
-                            self.read_queue
-                                .push_front(Ok(data.split_off(bytes_to_copy)));
-                        }
-                        Ok(bytes_to_copy)
-                    }
-                }
-                Some(Err(e)) => Err(e),
-                None => Ok(0),
-            }
-        }
+    #[test]
+    fn test_deadline_stream_rejects_reads_after_deadline() {
+        let expired = Some((Instant::now(), Duration::from_millis(1)));
+        let mut stream = DeadlineStream::new(&b"late bytes"[..], expired, None);
+        let error = stream.read(&mut [0; 4]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(error.to_string().contains("--request-timeout"));
     }
 
-    impl Write for MockTcpStream {
-        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-            self.write_buffer.extend_from_slice(buf);
-            Ok(buf.len())
-        }
-        fn flush(&mut self) -> IoResult<()> {
-            Ok(())
+    /// Times out every read after `wait`, except those listed in `data_on`, which return a byte.
+    struct SlowReader {
+        wait: Duration,
+        reads: usize,
+        data_on: Vec<usize>,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            std::thread::sleep(self.wait);
+            if self.data_on.contains(&self.reads) {
+                buf[0] = b'x';
+                Ok(1)
+            } else {
+                Err(ErrorKind::WouldBlock.into())
+            }
         }
     }
 
     #[test]
-    fn test_fetch_success_206_partial_content() {
-        let response_body = b"some partial data".to_vec();
-        let response_headers = format!(
-            "HTTP/1.1 206 Partial Content\r\n\
-             Content-Length: {}\r\n\
-             Content-Range: bytes 100-116/1000\r\n\
-             \r\n",
-            response_body.len()
+    fn test_stall_timeout_waits_out_slow_progress_but_not_a_stall() {
+        let slow = SlowReader {
+            wait: Duration::from_millis(20),
+            reads: 0,
+            data_on: vec![3, 6],
+        };
+        let mut stream = DeadlineStream::new(slow, None, Some(Duration::from_millis(100)));
+        assert_eq!(stream.read(&mut [0; 4]).unwrap(), 1);
+        assert_eq!(stream.read(&mut [0; 4]).unwrap(), 1);
+        let started = Instant::now();
+        let error = stream.read(&mut [0; 4]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(error.to_string().contains("--stall-timeout"));
+        assert!(started.elapsed() >= Duration::from_millis(80));
+
+        let stalled = SlowReader {
+            wait: Duration::from_millis(1),
+            reads: 0,
+            data_on: vec![],
+        };
+        let mut stream = DeadlineStream::new(stalled, None, None);
+        assert_eq!(
+            stream.read(&mut [0; 4]).unwrap_err().kind(),
+            ErrorKind::WouldBlock
         );
-        let mut mock_stream = MockTcpStream::new(vec![
-            Ok(response_headers.into_bytes()),
-            Ok(response_body.clone()),
-        ]);
-        let start_byte = 100;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_ok());
-        let (status, body) = result.unwrap();
-        assert_eq!(status, 206);
-        assert_eq!(body, response_body);
-        let request_str =
-            String::from_utf8(mock_stream.write_buffer).expect("Request not valid UTF-8");
-        assert!(request_str.starts_with("GET / HTTP/1.1\r\n"));
-        assert!(request_str.contains(&format!("\r\nHost: {}\r\n", target_host)));
-        assert!(request_str.contains(&format!("\r\nRange: bytes={}-\r\n", start_byte)));
-        assert!(request_str.contains("\r\nConnection: close\r\n"));
-        assert!(request_str.ends_with("\r\n\r\n"));
     }
 
     #[test]
-    fn test_fetch_success_200_ok() {
-        let response_body = b"complete file data".to_vec();
-        let response_headers = format!(
-            "HTTP/1.1 200 OK\r\n\
-             Content-Length: {}\r\n\
-             \r\n",
-            response_body.len()
-        );
-        let mut mock_stream = MockTcpStream::new(vec![
-            Ok(response_headers.into_bytes()),
-            Ok(response_body.clone()),
-        ]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_ok());
-        let (status, body) = result.unwrap();
-        assert_eq!(status, 200);
-        assert_eq!(body, response_body);
+    fn test_fetch_through_proxy_uses_absolute_form() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        let client = mock_client()
+            .with_path("/file.bin".to_string())
+            .with_proxy(Some(Url::parse("http://proxy.local:3128").unwrap()));
+        client
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
         let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
-        assert!(request_str.contains(&format!("\r\nRange: bytes={}-\r\n", start_byte)));
+        assert!(request_str.starts_with("GET http://mock.server:8080/file.bin HTTP/1.1\r\n"));
+        assert!(request_str.contains("\r\nHost: mock.server:8080\r\n"));
     }
 
     #[test]
-    fn test_fetch_error_404_not_found() {
-        let response_body = b"Not Found Error Page".to_vec();
-        let response_headers = format!(
-            "HTTP/1.1 404 Not Found\r\n\
-             Content-Type: text/plain\r\n\
-             Content-Length: {}\r\n\
-             \r\n",
-            response_body.len()
-        );
-        let mut mock_stream = MockTcpStream::new(vec![
-            Ok(response_headers.into_bytes()),
-            Ok(response_body.clone()),
-        ]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_ok());
-        let (status, body) = result.unwrap();
-        assert_eq!(status, 404);
-        assert_eq!(body, response_body);
-    }
+    fn test_proxy_bypass_decision() {
+        let proxy_url = Url::parse("http://proxy.local:3128").unwrap();
+        let client = |host: &str| {
+            HttpClient::new(
+                host.to_string(),
+                80,
+                Duration::from_secs(5),
+                Duration::from_secs(15),
+            )
+            .with_proxy(Some(proxy_url.clone()))
+            .with_proxy_bypass(vec!["mirror.internal".to_string(), "10.0.0.7".to_string()])
+        };
 
-    #[test]
-    fn test_fetch_simulated_timeout_during_body_read() {
-        let response_part1 = b"first chunk".to_vec();
-        let response_headers = format!(
-            "HTTP/1.1 206 Partial Content\r\n\
-             Content-Length: 1000\r\n\
-             \r\n"
-        );
-        let mut mock_stream = MockTcpStream::new(vec![
-            Ok(response_headers.into_bytes()),
-            Ok(response_part1.clone()),
-            Err(IoError::new(ErrorKind::TimedOut, "Simulated read timeout")),
-        ]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_ok());
-        let (status, body) = result.unwrap();
-        assert_eq!(status, 206);
-        assert_eq!(body, response_part1);
+        assert_eq!(client("example.com").effective_proxy(), Some(&proxy_url));
+        assert_eq!(client("mirror.internal").effective_proxy(), None);
+        assert_eq!(client("eu.mirror.internal").effective_proxy(), None);
+        assert_eq!(client("10.0.0.7").effective_proxy(), None);
+        assert_eq!(client("10.0.0.70").effective_proxy(), Some(&proxy_url));
+        assert_eq!(mock_client().effective_proxy(), None);
     }
 
     #[test]
-    fn test_fetch_simulated_unexpected_eof_during_body_read() {
-        let response_part1 = b"partial data before EOF".to_vec();
-        let response_headers = format!(
-            "HTTP/1.1 206 Partial Content\r\n\
-             Content-Length: 1000\r\n\
-             \r\n"
-        );
-        let mut mock_stream = MockTcpStream::new(vec![
-            Ok(response_headers.into_bytes()),
-            Ok(response_part1.clone()),
-            Err(IoError::new(
-                ErrorKind::UnexpectedEof,
-                "Simulated unexpected EOF",
-            )),
-        ]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_ok());
-        let (status, body) = result.unwrap();
-        assert_eq!(status, 206);
-        assert_eq!(body, response_part1);
+    fn test_fetch_through_socks5_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut connect = [0u8; 5];
+            stream.read_exact(&mut connect).unwrap();
+            let mut target = vec![0u8; connect[4] as usize + 2];
+            stream.read_exact(&mut target).unwrap();
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap();
+            (greeting, target, line)
+        });
+
+        let client = HttpClient::new(
+            "files.internal".to_string(),
+            8080,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .with_socks5(Some(
+            Socks5Proxy::parse(&format!("127.0.0.1:{}", port)).unwrap(),
+        ));
+        assert_eq!(client.fetch_range(0).unwrap().body, b"hello");
+
+        let (greeting, target, request) = proxy.join().unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        assert_eq!(&target[..target.len() - 2], b"files.internal");
+        assert_eq!(&target[target.len() - 2..], 8080u16.to_be_bytes());
+        assert!(request.starts_with("GET / HTTP/1.1\r\nHost: files.internal:8080\r\n"));
     }
 
     #[test]
-    fn test_fetch_premature_eof_before_status_line() {
-        let mut mock_stream = MockTcpStream::new(vec![Ok(Vec::new())]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_err());
-        let error_msg = result.err().unwrap().to_string();
-        assert!(error_msg.contains("Connection closed before status line received"));
+    fn test_establish_tunnel_sends_connect() {
+        let response = b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(response)]);
+        proxy::establish_tunnel(&mut mock_stream, "example.com:443").unwrap();
+        let request_str = String::from_utf8(mock_stream.write_buffer).unwrap();
+        assert!(request_str.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(request_str.contains("\r\nHost: example.com:443\r\n"));
+
+        let refused = b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec();
+        let mut mock_stream = MockTcpStream::new(vec![Ok(refused)]);
+        assert!(proxy::establish_tunnel(&mut mock_stream, "example.com:443").is_err());
     }
 
     #[test]
-    fn test_fetch_premature_eof_during_headers() {
-        let response_partial = "HTTP/1.1 206 OK\r\nContent-Type: text/p";
-        let mut mock_stream = MockTcpStream::new(vec![Ok(response_partial.as_bytes().to_vec())]);
-        let start_byte = 0;
-        let target_host = "mock.server:8080";
-        let result = HttpClient::fetch_range_via_stream(&mut mock_stream, target_host, start_byte);
-        assert!(result.is_err());
-        let error_msg = result.err().unwrap().to_string();
-        assert!(error_msg.contains("Connection closed during header reading"));
+    fn test_parse_content_range() {
+        assert_eq!(
+            ContentRange::parse("bytes 100-116/1000"),
+            Some(ContentRange {
+                first: 100,
+                last: 116,
+                total: Some(1000)
+            })
+        );
+        assert_eq!(ContentRange::parse("bytes 0-9/*").unwrap().total, None);
+        assert_eq!(ContentRange::parse("bytes */1000"), None);
+        assert_eq!(ContentRange::parse("items 0-9/10"), None);
+        assert_eq!(ContentRange::parse("bytes 0-9/ten"), None);
     }
 
     #[test]
     fn test_parse_status_line_valid_codes() {
-        assert_eq!(parse_status_line("HTTP/1.1 200 OK\r\n").unwrap(), 200);
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK\r\n").unwrap().0, 200);
         assert_eq!(
             parse_status_line("HTTP/1.0 206 Partial Content").unwrap(),
-            206
+            (206, "Partial Content".to_string())
         );
-        assert_eq!(parse_status_line("HTTP/2 404 Not Found").unwrap(), 404);
+        assert_eq!(parse_status_line("HTTP/2 404 Not Found").unwrap().0, 404);
         assert_eq!(
-            parse_status_line("HTTP/1.1 500 Internal Server Error").unwrap(),
+            parse_status_line("HTTP/1.1 500 Internal Server Error")
+                .unwrap()
+                .0,
             500
         );
         assert_eq!(
             parse_status_line(" HTTP/1.1 302 Found Redirect \r\n").unwrap(),
-            302
+            (302, "Found Redirect".to_string())
         );
     }
 
+    #[test]
+    fn test_parse_status_line_without_reason() {
+        for line in [
+            "HTTP/1.1 200\r\n",
+            "HTTP/1.1 200 \r\n",
+            "HTTP/1.1 200",
+            "HTTP/1.1  200",
+        ] {
+            assert_eq!(
+                parse_status_line(line).unwrap(),
+                (200, String::new()),
+                "line {:?}",
+                line
+            );
+        }
+        let mut mock_stream = MockTcpStream::new(vec![Ok(
+            b"HTTP/1.1 206\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+        )]);
+        let response = mock_client()
+            .fetch_range_via_stream(&mut mock_stream, "mock.server:8080", 0)
+            .unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(response.reason, "");
+        assert_eq!(response.body, b"ok");
+    }
+
     #[test]
     fn test_parse_status_line_invalid_format() {
         assert!(parse_status_line("HTTP/1.1 OK").is_err());
@@ -346,5 +3018,34 @@ mod tests {
         assert!(parse_status_line("HTTP/1.1").is_err());
         assert!(parse_status_line("").is_err());
         assert!(parse_status_line("\r\n").is_err());
+        assert_eq!(
+            parse_status_line("ICY 200 OK"),
+            Err(StatusLineError::InvalidVersion {
+                version: "ICY".to_string(),
+                line: "ICY 200 OK".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_status_line_never_panics() {
+        // Every prefix of some awkward lines, cut at byte and char boundaries alike.
+        let samples = [
+            "HTTP/1.1 200 OK\r\n",
+            "HTTP/1.1  99999 Too Big",
+            "HTTP/ \u{00e9}\u{1f600} \t\u{0} ok",
+            "\u{feff}HTTP/1.1 -1 Negative",
+            "HTTP/1.1 +200 Signed",
+        ];
+        for sample in samples {
+            for end in (0..=sample.len()).filter(|&end| sample.is_char_boundary(end)) {
+                let _ = parse_status_line(&sample[..end]);
+            }
+        }
+        for byte in 0..=u8::MAX {
+            let line =
+                String::from_utf8_lossy(&[b'H', b'T', b'T', b'P', b'/', b' ', byte]).into_owned();
+            let _ = parse_status_line(&line);
+        }
     }
 }
@@ -0,0 +1,40 @@
+pub mod args;
+pub mod auth;
+pub mod base64;
+pub mod batch;
+pub mod cache;
+pub mod cancel;
+pub mod config;
+pub mod config_file;
+pub mod diff;
+pub mod download;
+pub mod downloader;
+pub mod dump;
+pub mod encoding;
+pub mod hashing;
+pub mod hex;
+pub mod histogram;
+pub mod http_client;
+pub mod metrics;
+pub mod multipart;
+pub mod notify;
+pub mod output;
+pub mod pieces;
+pub mod pool;
+pub mod probe;
+pub mod progress;
+pub mod proxy;
+pub mod resolve;
+pub mod retry;
+pub mod sources;
+pub mod template;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trailer;
+pub mod url;
+pub mod verify;
+
+pub use http_client::{parse_status_line, StatusLineError};
+
+#[cfg(test)]
+mod test_support;
@@ -1,128 +1,476 @@
-mod args;
-mod config;
-mod hex;
-mod http_client;
-
-use sha2::{Digest, Sha256};
-use std::error::Error;
-use std::io::{self, ErrorKind};
+use std::fs;
 use std::process;
+use std::time::Instant;
 
-use crate::args::parse_args;
-use crate::config::Config;
-use crate::http_client::HttpClient;
-
-fn download_file(config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
-    let client = HttpClient::new(
-        config.host.clone(),
-        config.port,
-        config.connect_timeout,
-        config.read_write_timeout,
-    );
-
-    let mut data: Vec<u8> = Vec::new();
-    let server_address = format!("{}:{}", config.host, config.port);
-
-    println!(
-        "Starting download from {} using std::net HttpClient...",
-        server_address
-    );
-
-    loop {
-        let start_byte = data.len();
-        let range_header_info = format!("bytes={}-", start_byte);
-
-        print!("Requesting range: {} -> ", range_header_info);
-        match client.fetch_range(start_byte) {
-            Ok((status, received_chunk)) => {
-                println!(
-                    "Status: {}, Received: {} bytes",
-                    status,
-                    received_chunk.len()
-                );
+use client::args::parse_args;
+use client::batch::{parse_manifest, run_manifest};
+use client::config::Config;
+use client::diff::{diff_against, format_diff_report};
+use client::download::DownloadError;
+use client::downloader::Downloader;
+use client::encoding::check_inner_digest;
+use client::hashing::{check_digests, DigestCheck};
+use client::hex;
+use client::metrics::write_metrics_file;
+use client::notify::{send_notification, Notification};
+use client::output::{save_with_template, write_outputs, Diagnostics};
+use client::probe::{format_report, probe_ranges};
+use client::sources::apply_sources_file;
+use client::trailer::TrailerCheck;
+use client::verify::verify_local;
+
+const USAGE: &str = "\
+Usage: --hash=<HASH> [OPTIONS]
+       --manifest=<PATH> [OPTIONS]
+       --sources-file=<PATH> [OPTIONS]
+       --body-only [OPTIONS]
+       --hash=<HASH> --verify-only=<PATH> [--compare-remote] [OPTIONS]
+       --probe-ranges [OPTIONS]
+  --config=<PATH>                Read options from a TOML or JSON file (keys are flag names; flags override it)
+  --config-format=<toml|json>    Format of --config when its extension is not .toml or .json
+  --hash=<ALGO>:<HASH>           Verify a named digest, e.g. sha512:<hex> (repeatable; all must match)
+  --algo=<sha1|sha256|sha512>    Hash algorithm for --hash (default sha256)
+  --checksum-algorithm-auto      Infer the algorithm from the hash length (40/64/128 hex digits)
+  --hash-format=<FORMAT>         Print computed digests as hex (default), HEX, base64 or sri (sha256-<base64>)
+  --url=<URL>                    Download from this http:// or https:// URL
+  --manifest=<PATH>              Download and verify every `<hash> <url>` line of PATH in turn
+  --sources-file=<PATH>          Fetch one file in segments from several mirrors listed in PATH (`hash`, `size` and
+                                 `url` lines), failing over between them; all must serve the same size
+  --report=<PATH>                With --manifest, keep going past failed entries and write a CSV report of every entry
+  --verify-only=<PATH>           Hash an existing local file instead of downloading
+  --compare-remote               With --verify-only, also compare size and ETag from a HEAD request to the URL
+  --probe-ranges                 Send crafted range requests and report how the server handles each
+  --print-config                 Print the resolved configuration (secrets redacted) and exit without downloading
+  --verbose                      Print the address each attempt used, the TLS session, reuse statistics and
+                                 a summary of response times (min/p50/p90/max)
+  --host=<HOST>                  Server host (default 127.0.0.1)
+  --port=<PORT>                  Server port (default 8080)
+  --connect-timeout=<SECONDS>    Timeout for establishing a connection
+  --read-write-timeout=<SECONDS> Timeout for each socket read or write
+  --request-timeout=<SECONDS>    Timeout for one whole range request; retried when exceeded
+  --stall-timeout=<SECONDS>      Give up on a request only after no byte has arrived for SECONDS; retried
+  --tls-handshake-timeout=<SECONDS> Timeout for completing the TLS handshake
+  --tls-servername=<NAME>        Send NAME as the TLS SNI and validate the certificate against it instead of the host
+  --min-tls-version=<VERSION>    Refuse TLS below 1.2 (default) or 1.3
+  --warn-cert-expiry-days=<N>    Warn when the server's TLS certificate expires within N days
+  --bind-port=<PORT>             Connect from this local source port
+  --recv-buffer=<BYTES>          Set the socket receive buffer (SO_RCVBUF); the OS may cap it
+  --address-order=<ORDER>        Order resolved addresses: resolver (default), sorted or random
+  --dns-cache-size=<N>           Remember lookups for at most N hosts, evicting the least recently used
+  --dns-ttl=<SECONDS>            Resolve hosts again after this long; failed lookups are retried after it too (default 1s)
+  --dns-timeout=<SECONDS>        Give up on a DNS lookup after this long and retry; by default lookups are not timed
+  --verify-chunk-sha             Verify each response against its X-Content-SHA256 header
+  --range-hash-url-template=<URL> After each range, fetch its hash from URL with {start} and {end} (inclusive) filled
+                                 in, e.g. http://host/hash?range={start}-{end}, and retry the range on a mismatch
+  --normalize-newlines           Hash the data with CRLF converted to LF (the saved bytes are unchanged)
+  --normalize-output             With --normalize-newlines, also save the LF-normalized bytes
+  --referer=<URL>                Send a Referer header
+  --user=<USER>:<PASSWORD>       Authenticate with HTTP Basic auth
+  --bearer-token=<TOKEN>         Authenticate with an Authorization: Bearer header
+  --allow-plaintext-credentials  Send --user/--bearer-token credentials over http:// anyway (with a warning)
+  --single-request               Fetch the whole resource in one request; a short body is fetched again
+  --suffix=<N>                   Download and verify only the last N bytes (Range: bytes=-N)
+  --start-offset=<N>             Download from byte N onward without reading a local file; --hash covers those bytes
+  --head-first                   Send a HEAD first; use --single-request for small or unrangeable resources
+  --small-threshold=<BYTES>      With --head-first, the size below which one request is used (default 1048576)
+  --head-retries=<N>             With --head-first, retry a failed HEAD up to N times before using ranges (default 2)
+  --compression=<MODE>           auto (default; gzip only with --single-request and HEAD probes), off (always identity) or on
+  --keep-alive                   Reuse one connection across range requests
+  --shared-connection            Same as --keep-alive
+  --connection-per-range         Open a fresh connection for every range (the default)
+  --strict-close                 Read bodies by Content-Length and fail if the server then leaves the connection open
+  --response-cache               Answer repeated identical requests (method, URL, range, headers) from memory for the run
+  --keepalive-idle-timeout=<SECONDS> Close pooled connections idle for longer instead of reusing them
+  --recycle-after=<N>            With --keep-alive, open a fresh connection after every N ranges
+  --output=<PATH>                Write the downloaded bytes to PATH (`-` for stdout)
+                                 A file is written range by range to PATH.part and renamed once complete,
+                                 unless an option needs the whole body in memory
+  --output-mode=<OCTAL>          Create the --output file with this mode, e.g. 0600 (Unix only)
+  --no-verify                    Skip hash verification (no --hash needed)
+  --quiet-on-success             Print no progress or summary; only errors reach stderr
+  --body-only                    Write only the body to stdout: --output=- --no-verify --quiet-on-success
+  --resume                       Continue from the partial --output file instead of starting over; its ETag is kept in
+                                 PATH.etag and a changed ETag restarts from byte 0
+  --expected-prefix-hash=<HASH>  With --resume, restart from byte 0 unless the partial file has this hash
+  --output-template=<TEMPLATE>   Save verified data under a name built from {basename}, {hash}, {hash8}, {timestamp}
+  --max-headers=<N>              Abort a response with more than N header lines (default 100)
+  --dump-raw=<PATH>              Also write the raw bytes received (status line, headers, body) to PATH
+  --max-size=<BYTES>             Stop adding to the --dump-raw file after BYTES
+  --hexdump-on-failure=<N>       On a hash mismatch, print a hexdump of the first N bytes to stderr
+  --hash-only                    Only verify the hash: stream each range through the hasher without keeping the bytes
+  --diff-against=<PATH>          Report which byte ranges of the download differ from the local file PATH
+  --decompress-gzip              Gunzip the verified bytes when writing --output (the hash covers the .gz bytes)
+  --trailer-checksum=<ALGO>:<LEN> Verify the content against the checksum in its last LEN bytes, e.g. crc32:4 or
+                                 sha256:32 (--hash becomes optional)
+  --verify-inner-hash=<HASH>     Also verify the hash of the gunzipped data (--hash becomes optional)
+  --fsync                        Sync saved files and their directory to disk before reporting success
+  --tee-stdout                   Also write the bytes to stdout when --output is a file
+  --notify-url=<URL>             POST a JSON status (success, digest, bytes, elapsed) here when done
+  --metrics-file=<PATH>          Write Prometheus text-format metrics (bytes, retries, duration, success) to PATH when done
+  --fail-on-empty                Treat an empty successful response at byte 0 as an error
+  --pieces-file=<PATH>           Verify each piece against this list of SHA-1/SHA-256 hex digests, one per line,
+                                 fetching a bad piece again instead of failing the file
+  --piece-length=<BYTES>         Size of each --pieces-file piece
+  --stop-on-hash-match           Stop requesting ranges once the bytes so far match --hash (rehashes after every range)
+  --fail-on-html                 Treat a text/html response as an error page and stop before hashing
+  --success-status=<CODE,...>    Statuses whose body is accepted (default 200,206); any other non-redirect is fatal
+  --retry-rate=<PER_SECOND>      Allow at most this many retries per second, shared by all downloads of a run
+  --retry-log=<PATH>             Append a line per retry (time, offset, error kind, attempt, backoff) to PATH
+  --max-retries=<N>              Give up after N network-error retries in a row without progress (default: no limit)
+  --no-reset-retries-on-progress Make --max-retries a cap on all retries of the download, even ones after progress
+  --connect-rate=<PER_SECOND>    Attempt at most this many new connections per second, e.g. while the server is down
+  --inter-range-delay-ms=<N>     Wait N milliseconds after each successful range before requesting the next
+  --max-redirects=<N>            Follow at most N redirects, restarting from byte 0 each time (default 5)
+  --allow-redirect-host=<HOST>   Only follow redirects to this host (repeatable; the original host is always allowed)
+  --proxy=<URL>                  Use this HTTP proxy (default: HTTP_PROXY/HTTPS_PROXY, honouring NO_PROXY)
+  --no-proxy                     Always connect directly, ignoring --proxy and the environment
+  --no-proxy-hosts=<HOST,...>    Connect directly to these hosts (and their subdomains)
+  --socks5=[USER:PASS@]HOST:PORT Connect through this SOCKS5 proxy, which resolves the host name";
+
+fn main() {
+    let config = match parse_args() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Error parsing arguments: {}", e);
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
 
-                if status == 200 || status == 206 {
-                    data.extend_from_slice(&received_chunk);
+    if config.print_config {
+        println!("{:#?}", config.redacted());
+        return;
+    }
+
+    if config.sends_plaintext_credentials() {
+        eprintln!(
+            "WARNING: sending credentials to http://{} without TLS (--allow-plaintext-credentials); anyone on the network path can read them.",
+            config.host
+        );
+    }
 
-                    if status == 206 && received_chunk.is_empty() && start_byte > 0 {
-                        println!("Received status 206 and 0 bytes for range starting at {}, assuming download complete.", start_byte);
-                        return Ok(data);
-                    }
-                } else {
-                    return Err(format!("Server returned non-successful status: {}", status).into());
-                }
+    let config = match &config.sources_file {
+        Some(path) => match apply_sources_file(&config, path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Failed to read sources file {}: {}", path, e);
+                process::exit(1);
             }
+        },
+        None => config,
+    };
 
+    if let Some(manifest) = &config.manifest {
+        run_batch(&config, manifest);
+        return;
+    }
+
+    if config.probe_ranges {
+        match probe_ranges(&config) {
+            Ok(outcomes) => print!("{}", format_report(&outcomes)),
             Err(e) => {
-                let error_string = e.to_string();
-                let io_error_kind = e.downcast_ref::<io::Error>().map(|io_err| io_err.kind());
-
-                let is_retryable = match io_error_kind {
-                    Some(ErrorKind::ConnectionRefused)
-                    | Some(ErrorKind::TimedOut)
-                    | Some(ErrorKind::ConnectionReset)
-                    | Some(ErrorKind::ConnectionAborted)
-                    | Some(ErrorKind::NotConnected)
-                    | Some(ErrorKind::BrokenPipe) => true,
-                    _ => {
-                        error_string.contains("Failed to resolve address")
-                            || error_string.contains("Connection closed before status line")
-                            || error_string.contains("Connection closed during header reading")
-                    }
-                };
-
-                if is_retryable {
-                    eprintln!(
-                        "\nNetwork/Connection Error: {}. Retrying range {}...",
-                        e, range_header_info
-                    );
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
-                } else {
-                    return Err(format!("Fatal download error: {}", e).into());
-                }
+                eprintln!("Failed to probe {}: {}", config.host, e);
+                process::exit(1);
             }
         }
+        return;
+    }
+
+    if let Some(path) = &config.verify_only {
+        run_verify_only(&config, path);
+        return;
+    }
+
+    let status = run_download(&config);
+    if let Some(path) = &config.metrics_file {
+        if let Err(e) = write_metrics_file(path, &status) {
+            eprintln!("Warning: failed to write --metrics-file {}: {}", path, e);
+        }
+    }
+    if let Some(url) = &config.notify_url {
+        if let Err(e) = send_notification(&config, url, &status) {
+            eprintln!("Warning: failed to notify {}: {}", url, e);
+        }
+    }
+    if !status.success {
+        process::exit(1);
     }
 }
 
-fn main() {
-    let config = match parse_args() {
-        Ok(cfg) => cfg,
+/// Downloads and verifies the data, printing progress and errors, and returns the outcome.
+fn run_download(config: &Config) -> Notification {
+    let diag = Diagnostics::for_config(config);
+    let started = Instant::now();
+    let expected = config.expected_digests().unwrap_or_default();
+    let download = match Downloader::from_config(config.clone()).download() {
+        Ok(download) => download,
         Err(e) => {
-            eprintln!("Error parsing arguments: {}", e);
-            eprintln!("Usage: --hash=<HASH> [--host=<HOST>] [--port=<PORT>] [--connect-timeout=<SECONDS>] [--read-write-timeout=<SECONDS>]");
+            let error = format!("Failed to download the data: {}", e);
+            eprintln!("\n--------------------");
+            eprintln!("{}", error);
+            eprintln!("--------------------");
+            let progress = e.downcast_ref::<DownloadError>();
+            return Notification {
+                success: false,
+                digest: None,
+                bytes: progress.map_or(0, |progress| progress.bytes),
+                retries: progress.map_or(0, |progress| progress.retries),
+                elapsed: started.elapsed(),
+                error: Some(error),
+            };
+        }
+    };
+    let outcome = |success: bool, digest: Option<&str>, error: Option<String>| Notification {
+        success,
+        digest: digest.map(str::to_string),
+        bytes: download.bytes,
+        retries: download.retries,
+        elapsed: download.elapsed,
+        error,
+    };
+    let downloaded_data = download.data.as_deref().unwrap_or_default();
+    // A streamed `--output` was written range by range during the download.
+    if download.data.is_some() {
+        if let Err(e) = write_outputs(config, downloaded_data) {
+            let error = format!("Failed to write the downloaded data: {}", e);
+            eprintln!("{}", error);
+            return outcome(false, None, Some(error));
+        }
+    }
+
+    diag.println(format_args!("\n--------------------"));
+    diag.println(format_args!("Download finished."));
+    diag.println(format_args!("Downloaded data length: {}", download.bytes));
+    diag.println(format_args!(
+        "Took {:.2?} with {} retries",
+        download.elapsed, download.retries
+    ));
+    if config.verbose {
+        if let Some(summary) = download.range_times.summary() {
+            diag.println(format_args!("Response times: {}", summary));
+        }
+    }
+    if !download.failed_pieces.is_empty() {
+        let indices: Vec<String> = download
+            .failed_pieces
+            .iter()
+            .map(usize::to_string)
+            .collect();
+        diag.println(format_args!(
+            "Pieces fetched again after failing verification: {}",
+            indices.join(", ")
+        ));
+    }
+    if let Some(path) = &config.diff_against {
+        match diff_against(downloaded_data, path) {
+            Ok(report) if report.identical() => {
+                diag.print(format_args!("{}", format_diff_report(&report)))
+            }
+            Ok(report) => eprint!("{}", format_diff_report(&report)),
+            Err(e) => eprintln!("Warning: failed to compare with {}: {}", path, e),
+        }
+    }
+
+    if config.no_verify {
+        diag.println(format_args!("Verification skipped (--no-verify)."));
+        return outcome(true, None, None);
+    }
+
+    let mut checks = check_digests(&expected, &download.digest);
+    for check in &checks {
+        diag.println(format_args!(
+            "Downloaded data {}: {}",
+            check.algorithm,
+            check.actual_as(config.hash_format)
+        ));
+        diag.println(format_args!(
+            "Expected data {}:   {}",
+            check.algorithm, check.expected
+        ));
+    }
+    if let Some((algorithm, inner_hash)) = config.expected_inner_digest().unwrap_or_default() {
+        match check_inner_digest(downloaded_data, algorithm, &inner_hash) {
+            Ok(check) => {
+                diag.println(format_args!(
+                    "Decompressed data {}: {}",
+                    check.algorithm, check.actual
+                ));
+                diag.println(format_args!(
+                    "Expected inner {}:    {}",
+                    check.algorithm, check.expected
+                ));
+                checks.push(check);
+            }
+            Err(e) => {
+                let error = format!("Failed to verify the decompressed data: {}", e);
+                eprintln!("{}", error);
+                return outcome(false, None, Some(error));
+            }
+        }
+    }
+    let mut trailer_check: Option<TrailerCheck> = None;
+    if let Some(trailer) = &config.trailer_checksum {
+        match trailer.check(downloaded_data) {
+            Ok(check) => {
+                diag.println(format_args!(
+                    "Content {}: {}",
+                    check.algorithm, check.actual
+                ));
+                diag.println(format_args!(
+                    "Trailer {}: {}",
+                    check.algorithm, check.expected
+                ));
+                trailer_check = Some(check);
+            }
+            Err(e) => {
+                let error = format!("Failed to verify the trailer checksum: {}", e);
+                eprintln!("{}", error);
+                return outcome(false, None, Some(error));
+            }
+        }
+    }
+    diag.println(format_args!("--------------------"));
+
+    // Without --hash, the trailer's checksum stands in for the file's.
+    let hash_hex = match (checks.first(), &trailer_check) {
+        (Some(check), _) => check.actual.as_str(),
+        (None, Some(check)) => check.actual.as_str(),
+        (None, None) => "",
+    };
+    if !checks.iter().all(DigestCheck::matches)
+        || !trailer_check.as_ref().is_none_or(TrailerCheck::matches)
+    {
+        let error = "Hashes DO NOT match.".to_string();
+        eprintln!("Failure: Data corruption detected! {}", error);
+        if let Some(warning) = download.html_warning() {
+            eprintln!("{}", warning);
+        }
+        if let Some(limit) = config.hexdump_on_failure {
+            let shown = &downloaded_data[..limit.min(downloaded_data.len())];
+            eprint!("First {} bytes:\n{}", shown.len(), hex::hexdump(shown));
+        }
+        return outcome(false, Some(hash_hex), Some(error));
+    }
+
+    diag.println(format_args!(
+        "Success: Data downloaded correctly! Hashes match."
+    ));
+    if let Some(template) = &config.output_template {
+        match save_with_template(
+            template,
+            &config.path,
+            hash_hex,
+            downloaded_data,
+            config.fsync,
+        ) {
+            Ok(path) => diag.println(format_args!("Saved verified data to {}", path)),
+            Err(e) => {
+                let error = format!("Failed to save the verified data: {}", e);
+                eprintln!("{}", error);
+                return outcome(false, Some(hash_hex), Some(error));
+            }
+        }
+    }
+    outcome(true, Some(hash_hex), None)
+}
+
+fn run_batch(config: &Config, manifest: &str) {
+    let diag = Diagnostics::for_config(config);
+    let entries = match fs::read_to_string(manifest)
+        .map_err(|e| e.into())
+        .and_then(|text| parse_manifest(&text))
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read manifest {}: {}", manifest, e);
             process::exit(1);
         }
     };
 
-    match download_file(&config) {
-        Ok(downloaded_data) => {
-            println!("\n--------------------");
-            println!("Download finished.");
-            println!("Downloaded data length: {}", downloaded_data.len());
-
-            let mut hasher = Sha256::new();
-            hasher.update(&downloaded_data);
-            let hash_result = hasher.finalize();
-            let hash_hex = hex::encode(&hash_result);
-
-            println!("Downloaded data SHA-256: {}", hash_hex);
-            println!("Expected data SHA-256:   {}", config.expected_hash);
-            println!("--------------------");
-
-            if hash_hex == config.expected_hash {
-                println!("Success: Data downloaded correctly! Hashes match.");
-            } else {
-                eprintln!("Failure: Data corruption detected! Hashes DO NOT match.");
+    match run_manifest(config, &entries) {
+        Ok(summary) => {
+            diag.println(format_args!("\n--------------------"));
+            diag.println(format_args!(
+                "Batch finished: {} entries verified.",
+                summary.verified
+            ));
+            if config.verbose {
+                diag.println(format_args!(
+                    "Connections: {} opened, {} reused, {} closed after idling",
+                    summary.connections.opened,
+                    summary.connections.reused,
+                    summary.connections.expired
+                ));
+                diag.println(format_args!(
+                    "Address lookups: {} ({} resolved, {} from cache)",
+                    summary.addresses.lookups,
+                    summary.addresses.resolutions,
+                    summary.addresses.lookups - summary.addresses.resolutions
+                ));
+            }
+            if summary.failed > 0 {
+                eprintln!(
+                    "Batch failed: {} of {} entries did not verify.",
+                    summary.failed,
+                    summary.verified + summary.failed
+                );
                 process::exit(1);
             }
         }
         Err(e) => {
             eprintln!("\n--------------------");
-            eprintln!("Failed to download the data: {}", e);
+            eprintln!("Batch failed: {}", e);
             eprintln!("--------------------");
             process::exit(1);
         }
     }
 }
+
+fn run_verify_only(config: &Config, path: &str) {
+    let diag = Diagnostics::for_config(config);
+    let report = match verify_local(config, path) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to verify {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    diag.println(format_args!(
+        "Local file: {} ({} bytes)",
+        path, report.local_size
+    ));
+    for check in &report.checks {
+        diag.println(format_args!(
+            "Local {}:    {}",
+            check.algorithm,
+            check.actual_as(config.hash_format)
+        ));
+        diag.println(format_args!(
+            "Expected {}: {}",
+            check.algorithm, check.expected
+        ));
+    }
+    if let Some(remote) = &report.remote {
+        match remote.content_length {
+            Some(length) => diag.println(format_args!("Remote Content-Length: {}", length)),
+            None => diag.println(format_args!("Remote Content-Length: not sent")),
+        }
+        if let Some(etag) = &remote.etag {
+            let verdict = match report.etag_matches() {
+                Some(true) => "matches the local digest",
+                Some(false) => "differs from the local digest",
+                None => "not a digest; not compared",
+            };
+            diag.println(format_args!("Remote ETag: {} ({})", etag, verdict));
+        }
+    }
+    diag.println(format_args!("--------------------"));
+
+    if report.passed() {
+        diag.println(format_args!("Success: Local file verified."));
+    } else {
+        eprintln!("Failure: Local file does not match the expected hash or the remote metadata.");
+        process::exit(1);
+    }
+}
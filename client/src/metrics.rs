@@ -0,0 +1,120 @@
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use crate::notify::Notification;
+use crate::output::write_atomically;
+
+/// Renders the outcome of a download in the Prometheus text exposition format, for
+/// `--metrics-file`.
+pub fn render_metrics(status: &Notification) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = write!(
+            text,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        );
+    };
+    metric(
+        "download_bytes_total",
+        "counter",
+        "Bytes of data downloaded.",
+        status.bytes.to_string(),
+    );
+    metric(
+        "download_retries_total",
+        "counter",
+        "Requests repeated after a failure.",
+        status.retries.to_string(),
+    );
+    metric(
+        "download_duration_seconds",
+        "gauge",
+        "Time the download took.",
+        format!("{:.3}", status.elapsed.as_secs_f64()),
+    );
+    metric(
+        "download_success",
+        "gauge",
+        "1 if the data was downloaded and verified, 0 otherwise.",
+        u8::from(status.success).to_string(),
+    );
+    text
+}
+
+/// Writes the metrics to `path` through a temporary file, so a textfile collector scraping the
+/// directory never reads a half-written file.
+pub fn write_metrics_file(path: &str, status: &Notification) -> io::Result<()> {
+    write_atomically(Path::new(path), render_metrics(status).as_bytes(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// Parses exposition text into samples, checking that each one follows its `# TYPE` line.
+    fn parse_exposition(text: &str) -> HashMap<String, (String, f64)> {
+        let mut kinds = HashMap::new();
+        let mut samples = HashMap::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut fields = comment.splitn(3, ' ');
+                let (keyword, name, rest) = (fields.next(), fields.next(), fields.next());
+                match (keyword, name, rest) {
+                    (Some("TYPE"), Some(name), Some(kind)) => {
+                        kinds.insert(name.to_string(), kind.to_string());
+                    }
+                    (Some("HELP"), Some(_), Some(_)) => {}
+                    _ => panic!("bad comment line '{}'", line),
+                }
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'));
+            let kind = kinds
+                .get(name)
+                .unwrap_or_else(|| panic!("{} has no TYPE", name));
+            samples.insert(name.to_string(), (kind.clone(), value.parse().unwrap()));
+        }
+        samples
+    }
+
+    #[test]
+    fn test_metrics_file_has_the_expected_series() {
+        let status = Notification {
+            success: true,
+            digest: None,
+            bytes: 1024,
+            retries: 3,
+            elapsed: Duration::from_millis(2500),
+            error: None,
+        };
+        let path = std::env::temp_dir().join(format!("client-metrics-{}.prom", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        write_metrics_file(path, &status).unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(text.ends_with('\n'));
+        let samples = parse_exposition(&text);
+        assert_eq!(samples.len(), 4);
+        assert_eq!(
+            samples["download_bytes_total"],
+            ("counter".to_string(), 1024.0)
+        );
+        assert_eq!(
+            samples["download_retries_total"],
+            ("counter".to_string(), 3.0)
+        );
+        assert_eq!(
+            samples["download_duration_seconds"],
+            ("gauge".to_string(), 2.5)
+        );
+        assert_eq!(samples["download_success"], ("gauge".to_string(), 1.0));
+    }
+}
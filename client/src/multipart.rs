@@ -0,0 +1,128 @@
+use std::error::Error;
+
+use crate::http_client::ContentRange;
+
+/// One part of a ranged response: the bytes and the range they cover.
+pub type RangePart = (ContentRange, Vec<u8>);
+
+/// The `boundary` parameter of a `multipart/byteranges` content type, or `None` for any other type.
+pub fn byteranges_boundary(content_type: &str) -> Option<&str> {
+    let (media_type, params) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/byteranges")
+    {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Splits a `multipart/byteranges` body into its parts. Each part's length comes from its
+/// `Content-Range`, so binary data that happens to contain the boundary is not misread.
+pub fn parse_byteranges(body: &[u8], boundary: &str) -> Result<Vec<RangePart>, Box<dyn Error>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut rest = body;
+    let mut parts = Vec::new();
+    loop {
+        let start =
+            find(rest, &delimiter).ok_or("multipart body ended without a closing boundary")?;
+        rest = &rest[start + delimiter.len()..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or("malformed multipart boundary line")?;
+
+        let mut range = None;
+        loop {
+            let line_end = find(rest, b"\r\n").ok_or("multipart part headers are truncated")?;
+            let line = std::str::from_utf8(&rest[..line_end])?;
+            rest = &rest[line_end + 2..];
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Content-Range") {
+                    range = ContentRange::parse(value);
+                }
+            }
+        }
+        let range = range.ok_or("multipart part without a valid Content-Range")?;
+        let length = range
+            .last
+            .checked_sub(range.first)
+            .and_then(|length| length.checked_add(1))
+            .and_then(|length| usize::try_from(length).ok())
+            .ok_or_else(|| {
+                format!(
+                    "multipart part range {}-{} has no valid length",
+                    range.first, range.last
+                )
+            })?;
+        if rest.len() < length {
+            return Err(format!(
+                "multipart part {}-{} is truncated: {} of {} bytes",
+                range.first,
+                range.last,
+                rest.len(),
+                length
+            )
+            .into());
+        }
+        parts.push((range, rest[..length].to_vec()));
+        rest = &rest[length..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary_from_content_type() {
+        assert_eq!(
+            byteranges_boundary("multipart/byteranges; boundary=\"3d6b6a416f9b5\""),
+            Some("3d6b6a416f9b5")
+        );
+        assert_eq!(byteranges_boundary("text/plain; charset=utf-8"), None);
+    }
+
+    #[test]
+    fn test_parts_may_contain_the_boundary() {
+        let body = b"--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-6/100\r\n\r\n--XYZ!!\r\n--XYZ--\r\n";
+        let parts = parse_byteranges(body, "XYZ").unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].1, b"--XYZ!!");
+        assert!(
+            parse_byteranges(b"--XYZ\r\nContent-Range: bytes 0-9/10\r\n\r\nshort", "XYZ").is_err()
+        );
+    }
+
+    #[test]
+    fn test_reversed_and_max_value_ranges_are_rejected() {
+        let reversed = b"--XYZ\r\nContent-Range: bytes 9-3/20\r\n\r\nabcdefg\r\n--XYZ--\r\n";
+        assert!(ContentRange::parse("bytes 9-3/20").is_none());
+        assert!(parse_byteranges(reversed, "XYZ").is_err());
+
+        let max = format!(
+            "--XYZ\r\nContent-Range: bytes 0-{}/*\r\n\r\nabc\r\n--XYZ--\r\n",
+            u64::MAX
+        );
+        let error = parse_byteranges(max.as_bytes(), "XYZ")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("no valid length"));
+    }
+}
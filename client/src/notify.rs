@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::http_client::HttpClient;
+use crate::url::Url;
+
+/// The outcome of a download, as reported to `--notify-url`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub success: bool,
+    /// Hex digest of the downloaded data, once one was computed.
+    pub digest: Option<String>,
+    pub bytes: u64,
+    /// Requests that had to be repeated; reported in `--metrics-file`, not the JSON.
+    pub retries: u32,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+impl Notification {
+    /// The JSON body of the `POST`, e.g.
+    /// `{"status":"success","digest":"ab12…","bytes":5,"elapsed_ms":12,"error":null}`.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"status\":\"{}\",\"digest\":{},\"bytes\":{},\"elapsed_ms\":{},\"error\":{}}}",
+            if self.success { "success" } else { "failure" },
+            json_string_or_null(self.digest.as_deref()),
+            self.bytes,
+            self.elapsed.as_millis(),
+            json_string_or_null(self.error.as_deref())
+        )
+    }
+}
+
+fn json_string_or_null(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Posts `notification` to `url` as JSON. Any status other than 2xx is an error; the caller
+/// only logs it, since a failed notification must not change the download's outcome.
+pub fn send_notification(
+    config: &Config,
+    url: &Url,
+    notification: &Notification,
+) -> Result<(), Box<dyn Error>> {
+    let client = HttpClient::new(
+        url.host.clone(),
+        url.port,
+        config.connect_timeout,
+        config.read_write_timeout,
+    )
+    .with_target(url)
+    .with_request_timeout(config.request_timeout)
+    .with_tls_handshake_timeout(config.tls_handshake_timeout)
+    .with_proxy(config.proxy.clone())
+    .with_proxy_bypass(config.no_proxy_hosts.clone())
+    .with_socks5(config.socks5.clone());
+    let response = client.post("application/json", notification.to_json().as_bytes())?;
+    if (200..300).contains(&response.status) {
+        Ok(())
+    } else {
+        Err(format!("Notification rejected with status {}", response.status).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::response;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn notification() -> Notification {
+        Notification {
+            success: false,
+            digest: None,
+            bytes: 42,
+            retries: 0,
+            elapsed: Duration::from_millis(1500),
+            error: Some("Hashes \"differ\"\n".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_json_escapes_strings_and_writes_null() {
+        assert_eq!(
+            notification().to_json(),
+            r#"{"status":"failure","digest":null,"bytes":42,"elapsed_ms":1500,"error":"Hashes \"differ\"\n"}"#
+        );
+    }
+
+    /// Accepts one request, reads its head and `Content-Length` body, and answers with `status`.
+    fn serve_post(status: &'static str) -> (u16, thread::JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream.write_all(&response(status, "", b"")).unwrap();
+            (head, body)
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn test_notification_is_a_well_formed_post() {
+        let (port, server) = serve_post("204 No Content");
+        let url = Url::parse(&format!("http://127.0.0.1:{}/hooks/done", port)).unwrap();
+        let sent = notification();
+
+        send_notification(&Config::default(), &url, &sent).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("POST /hooks/done HTTP/1.1\r\n"));
+        assert!(head.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert_eq!(body, sent.to_json().into_bytes());
+    }
+
+    #[test]
+    fn test_rejected_notification_is_an_error() {
+        let (port, server) = serve_post("500 Internal Server Error");
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        let error = send_notification(&Config::default(), &url, &notification()).unwrap_err();
+        server.join().unwrap();
+        assert!(error.to_string().contains("500"));
+    }
+}
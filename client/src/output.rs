@@ -0,0 +1,373 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::encoding::gunzip_to;
+use crate::template::{basename_from_path, render_output_template, TemplateVars};
+
+/// Where progress and summary messages go: stderr whenever stdout carries the downloaded bytes,
+/// and nowhere with `--quiet-on-success`. Warnings always go to stderr, unless the library
+/// caller asked for silence (`Config::silent`).
+#[derive(Debug, Clone, Copy)]
+pub struct Diagnostics {
+    to_stderr: bool,
+    quiet: bool,
+    silent: bool,
+}
+
+impl Diagnostics {
+    pub fn for_config(config: &Config) -> Self {
+        Diagnostics {
+            to_stderr: config.body_to_stdout(),
+            quiet: config.quiet_on_success || config.silent,
+            silent: config.silent,
+        }
+    }
+
+    pub fn warn(&self, args: fmt::Arguments) {
+        if !self.silent {
+            eprintln!("{}", args);
+        }
+    }
+
+    pub fn print(&self, args: fmt::Arguments) {
+        if self.quiet {
+            return;
+        }
+        if self.to_stderr {
+            eprint!("{}", args);
+        } else {
+            print!("{}", args);
+        }
+    }
+
+    pub fn println(&self, args: fmt::Arguments) {
+        if self.quiet {
+            return;
+        }
+        if self.to_stderr {
+            eprintln!("{}", args);
+        } else {
+            println!("{}", args);
+        }
+    }
+}
+
+/// Writes the same bytes to every sink.
+pub struct MultiWriter<'a> {
+    sinks: Vec<Box<dyn Write + 'a>>,
+}
+
+impl<'a> MultiWriter<'a> {
+    pub fn new(sinks: Vec<Box<dyn Write + 'a>>) -> Self {
+        MultiWriter { sinks }
+    }
+}
+
+impl Write for MultiWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for sink in &mut self.sinks {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A file-like sink that can force its contents to stable storage.
+pub trait Durable: Write {
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl Durable for File {
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// Flushes `sink`, and with `--fsync` also syncs it to disk.
+fn finish_durably<W: Durable>(sink: &mut W, fsync: bool) -> io::Result<()> {
+    sink.flush()?;
+    if fsync {
+        sink.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Syncs the directory containing `path` so a newly created or renamed entry survives a crash.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Writes `data` to `sink`, gunzipping it on the way with `--decompress-gzip`.
+fn write_body<W: Write>(sink: &mut W, data: &[u8], decompress_gzip: bool) -> io::Result<()> {
+    if decompress_gzip {
+        gunzip_to(data, sink)?;
+        Ok(())
+    } else {
+        sink.write_all(data)
+    }
+}
+
+/// Writes the downloaded bytes to the configured `--output` (and stdout with `--tee-stdout`).
+/// The hash covers the bytes as downloaded; only what is written is gunzipped.
+pub fn write_outputs(config: &Config, data: &[u8]) -> io::Result<()> {
+    match config.output.as_deref() {
+        None => Ok(()),
+        Some("-") => {
+            let mut stdout = io::stdout().lock();
+            write_body(&mut stdout, data, config.decompress_gzip)?;
+            stdout.flush()
+        }
+        Some(path) => {
+            let mut file = create_output(Path::new(path), config.output_mode)?;
+            if config.tee_stdout {
+                let mut sinks =
+                    MultiWriter::new(vec![Box::new(&mut file), Box::new(io::stdout().lock())]);
+                write_body(&mut sinks, data, config.decompress_gzip)?;
+                sinks.flush()?;
+            } else {
+                write_body(&mut file, data, config.decompress_gzip)?;
+            }
+            finish_durably(&mut file, config.fsync)?;
+            if config.fsync {
+                sync_parent_dir(Path::new(path))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The temporary `<path>.part` that content is written to until it is complete.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial_name = path.as_os_str().to_owned();
+    partial_name.push(".part");
+    PathBuf::from(partial_name)
+}
+
+/// Creates `<path>.part` for a download that writes each range to `--output` as it arrives;
+/// whatever `path` holds stays untouched until `finish_output_file` renames it into place.
+pub fn create_output_file(config: &Config, path: &str) -> io::Result<File> {
+    create_output(&partial_path(Path::new(path)), config.output_mode)
+}
+
+/// Flushes a streamed `--output` file and renames it over `path`; with `--fsync` also syncs it
+/// before the rename and its directory after it.
+pub fn finish_output_file(mut file: File, path: &str, fsync: bool) -> io::Result<()> {
+    finish_durably(&mut file, fsync)?;
+    drop(file);
+    fs::rename(partial_path(Path::new(path)), path)?;
+    if fsync {
+        sync_parent_dir(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// Deletes the `.part` file of a streamed `--output` that did not complete.
+pub fn discard_output_file(file: File, path: &str) {
+    drop(file);
+    let _ = fs::remove_file(partial_path(Path::new(path)));
+}
+
+/// Creates (or truncates) the `--output` file. With `--output-mode` the file gets exactly that
+/// mode, regardless of the umask or of the mode an existing file had; other platforms ignore it
+/// with a warning.
+fn create_output(path: &Path, mode: Option<u32>) -> io::Result<File> {
+    let Some(mode) = mode else {
+        return File::create(path);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        // Created with the mode so it is never readable more widely, then set again since
+        // the umask narrows the first and an existing file keeps its old mode.
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        eprintln!(
+            "Warning: --output-mode={:o} is ignored on this platform.",
+            mode
+        );
+        File::create(path)
+    }
+}
+
+/// Writes `data` to `path` via a temporary `.part` file renamed into place, so the final
+/// name only ever holds complete content. With `fsync`, the file is synced before the rename
+/// and the directory after it, so the rename is durable too.
+pub fn write_atomically(path: &Path, data: &[u8], fsync: bool) -> io::Result<()> {
+    let partial_path = partial_path(path);
+    let mut file = File::create(&partial_path)?;
+    file.write_all(data)?;
+    finish_durably(&mut file, fsync)?;
+    drop(file);
+    fs::rename(&partial_path, path)?;
+    if fsync {
+        sync_parent_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Saves verified bytes under the name rendered from `--output-template`, returning that name.
+pub fn save_with_template(
+    template: &str,
+    url_path: &str,
+    digest_hex: &str,
+    data: &[u8],
+    fsync: bool,
+) -> Result<String, Box<dyn Error>> {
+    let vars = TemplateVars {
+        basename: basename_from_path(url_path),
+        digest_hex,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+    };
+    let path = render_output_template(template, &vars)?;
+    write_atomically(Path::new(&path), data, fsync)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_writer_sends_full_content_to_every_sink() {
+        let path = std::env::temp_dir().join(format!("client-tee-{}.bin", std::process::id()));
+        let content: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let mut stdout_copy = Vec::new();
+        {
+            let mut writer = MultiWriter::new(vec![
+                Box::new(File::create(&path).unwrap()),
+                Box::new(&mut stdout_copy),
+            ]);
+            for chunk in content.chunks(4096) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        let file_copy = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file_copy, content);
+        assert_eq!(stdout_copy, content);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_mode_sets_the_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("client-mode-{}.bin", std::process::id()));
+        let config = Config {
+            output: Some(path.to_string_lossy().into_owned()),
+            output_mode: Some(0o600),
+            ..Config::default()
+        };
+        write_outputs(&config, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn test_write_atomically_leaves_no_partial_file() {
+        let path = std::env::temp_dir().join(format!("client-atomic-{}.bin", std::process::id()));
+        write_atomically(&path, b"complete", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+        assert!(!partial_path(&path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_decompress_gzip_writes_plain_bytes_but_hashes_the_archive() {
+        use crate::download::download_file;
+        use crate::test_support::{response, spawn_server};
+        use sha2::{Digest, Sha256};
+
+        let archive = crate::test_support::gzip(b"plain text inside");
+        let (port, server) = spawn_server(vec![response("200 OK", "", &archive)]);
+        let path = std::env::temp_dir().join(format!("client-gunzip-{}.txt", std::process::id()));
+        let config = Config {
+            port,
+            expected_hash: "unused".to_string(),
+            output: Some(path.to_string_lossy().into_owned()),
+            decompress_gzip: true,
+            ..Config::default()
+        };
+
+        let download = download_file(&config, Box::new(Sha256::new())).unwrap();
+        write_outputs(&config, download.data.as_deref().unwrap()).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(download.digest, Sha256::digest(&archive).to_vec());
+        assert_eq!(fs::read(&path).unwrap(), b"plain text inside");
+        fs::remove_file(&path).unwrap();
+    }
+
+    struct RecordingSink {
+        data: Vec<u8>,
+        flushed: bool,
+        synced: bool,
+    }
+
+    impl Write for RecordingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    impl Durable for RecordingSink {
+        fn sync_all(&mut self) -> io::Result<()> {
+            assert!(self.flushed, "sync before flush");
+            self.synced = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fsync_path_syncs_only_when_enabled() {
+        for fsync in [false, true] {
+            let mut sink = RecordingSink {
+                data: Vec::new(),
+                flushed: false,
+                synced: false,
+            };
+            sink.write_all(b"verified").unwrap();
+            finish_durably(&mut sink, fsync).unwrap();
+            assert_eq!(sink.data, b"verified");
+            assert!(sink.flushed);
+            assert_eq!(sink.synced, fsync);
+        }
+    }
+}
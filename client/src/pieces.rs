@@ -0,0 +1,253 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+
+use crate::hashing::Algorithm;
+use crate::hex;
+
+/// How many times one piece may fail verification before the download gives up on it.
+pub const MAX_PIECE_ATTEMPTS: u32 = 3;
+
+/// A `--pieces-file`: the digest of every `--piece-length` piece of the file, in order, like a
+/// BitTorrent v1 piece list. The algorithm follows from the digest length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceList {
+    pub algorithm: Algorithm,
+    pub piece_length: u64,
+    pub hashes: Vec<String>,
+}
+
+impl PieceList {
+    /// Parses one hex digest per line; blank lines and `#` comments are skipped. The pieces
+    /// must fit in memory together, so piece offsets never overflow.
+    pub fn parse(text: &str, piece_length: u64) -> Result<Self, Box<dyn Error>> {
+        let hashes: Vec<String> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_ascii_lowercase)
+            .collect();
+        let Some(first) = hashes.first() else {
+            return Err("Pieces file lists no piece hashes".into());
+        };
+        let algorithm = Algorithm::from_hex_len(first)?;
+        if let Some((index, hash)) = hashes.iter().enumerate().find(|(_, hash)| {
+            hash.len() != first.len() || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+        }) {
+            return Err(format!(
+                "Piece {} hash '{}' is not a {}-digit hex {} digest like the first",
+                index,
+                hash,
+                first.len(),
+                algorithm
+            )
+            .into());
+        }
+        if usize::try_from(piece_length)
+            .ok()
+            .and_then(|length| length.checked_mul(hashes.len()))
+            .is_none()
+        {
+            return Err(format!(
+                "{} pieces of {} bytes are more than this platform can address",
+                hashes.len(),
+                piece_length
+            )
+            .into());
+        }
+        Ok(PieceList {
+            algorithm,
+            piece_length,
+            hashes,
+        })
+    }
+
+    pub fn load(path: &str, piece_length: u64) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        Self::parse(&text, piece_length).map_err(|e| format!("Invalid {}: {}", path, e).into())
+    }
+
+    /// The byte range `[start, end)` of piece `index` in a file of `total` bytes.
+    fn bounds(&self, index: usize, total: usize) -> (usize, usize) {
+        let start = index * self.piece_length as usize;
+        let end = (start + self.piece_length as usize).min(total).max(start);
+        (start, end)
+    }
+
+    fn matches(&self, index: usize, piece: &[u8]) -> bool {
+        let mut hasher = self.algorithm.new_hasher();
+        hasher.update(piece);
+        hex::encode(&hasher.finalize()) == self.hashes[index]
+    }
+}
+
+/// A piece that failed verification; bytes `start..end` are fetched again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadPiece {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    /// How many times this piece has failed so far.
+    pub attempts: u32,
+}
+
+/// Checks pieces as the download completes them, so only a bad one is fetched again instead of
+/// failing the whole file at the end.
+#[derive(Debug)]
+pub struct PieceVerifier {
+    pieces: PieceList,
+    /// Pieces before this index have been verified.
+    verified: usize,
+    attempts: Vec<u32>,
+    failed: BTreeSet<usize>,
+}
+
+impl PieceVerifier {
+    pub fn new(pieces: PieceList) -> Self {
+        PieceVerifier {
+            attempts: vec![0; pieces.hashes.len()],
+            pieces,
+            verified: 0,
+            failed: BTreeSet::new(),
+        }
+    }
+
+    /// Verifies every piece `data` now completes; with `finished`, also the short last piece
+    /// and that the length fits the piece list. Returns the first bad piece, which the caller
+    /// replaces before checking again, or an error once a piece has failed `MAX_PIECE_ATTEMPTS`
+    /// times.
+    pub fn check(
+        &mut self,
+        data: &[u8],
+        finished: bool,
+    ) -> Result<Option<BadPiece>, Box<dyn Error>> {
+        let piece_length = self.pieces.piece_length as usize;
+        let count = self.pieces.hashes.len();
+        if data.len() > piece_length * count {
+            return Err(format!(
+                "Downloaded {} bytes, more than the {} pieces of {} bytes in the pieces file",
+                data.len(),
+                count,
+                piece_length
+            )
+            .into());
+        }
+        while self.verified < count {
+            let (start, end) = self.pieces.bounds(self.verified, data.len());
+            let complete = end - start == piece_length || (finished && end > start);
+            if !complete {
+                break;
+            }
+            let index = self.verified;
+            if self.pieces.matches(index, &data[start..end]) {
+                self.verified += 1;
+                continue;
+            }
+            self.attempts[index] += 1;
+            self.failed.insert(index);
+            if self.attempts[index] >= MAX_PIECE_ATTEMPTS {
+                return Err(format!(
+                    "Piece {} (bytes {}-{}) failed {} verification {} times",
+                    index,
+                    start,
+                    end - 1,
+                    self.pieces.algorithm,
+                    self.attempts[index]
+                )
+                .into());
+            }
+            return Ok(Some(BadPiece {
+                index,
+                start,
+                end,
+                attempts: self.attempts[index],
+            }));
+        }
+        if finished && self.verified < count {
+            return Err(format!(
+                "Download ended after {} bytes, but the pieces file lists {} pieces of {} bytes",
+                data.len(),
+                count,
+                piece_length
+            )
+            .into());
+        }
+        Ok(None)
+    }
+
+    /// Starts verifying from the first piece again, after the download restarted from byte 0.
+    pub fn restart(&mut self) {
+        self.verified = 0;
+    }
+
+    /// Indices of the pieces that failed verification at least once, in order.
+    pub fn failed_pieces(&self) -> Vec<usize> {
+        self.failed.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece_list(data: &[u8], piece_length: usize) -> PieceList {
+        let text: String = data
+            .chunks(piece_length)
+            .map(|piece| {
+                let mut hasher = Algorithm::Sha1.new_hasher();
+                hasher.update(piece);
+                hex::encode(&hasher.finalize()) + "\n"
+            })
+            .collect();
+        PieceList::parse(&format!("# pieces\n{}", text), piece_length as u64).unwrap()
+    }
+
+    #[test]
+    fn test_matching_pieces_verify() {
+        let data = b"abcdefghij";
+        let mut verifier = PieceVerifier::new(piece_list(data, 4));
+        assert_eq!(verifier.pieces.algorithm, Algorithm::Sha1);
+        assert_eq!(verifier.check(&data[..6], false).unwrap(), None);
+        assert_eq!(verifier.verified, 1);
+        assert_eq!(verifier.check(data, true).unwrap(), None);
+        assert_eq!(verifier.verified, 3);
+        assert!(verifier.failed_pieces().is_empty());
+    }
+
+    #[test]
+    fn test_single_bad_piece_is_reported() {
+        let data = b"abcdefghij";
+        let mut verifier = PieceVerifier::new(piece_list(data, 4));
+        let bad = verifier.check(b"abcdXfghij", true).unwrap();
+        assert_eq!(
+            bad,
+            Some(BadPiece {
+                index: 1,
+                start: 4,
+                end: 8,
+                attempts: 1
+            })
+        );
+        assert_eq!(verifier.check(data, true).unwrap(), None);
+        assert_eq!(verifier.failed_pieces(), [1]);
+    }
+
+    #[test]
+    fn test_piece_gives_up_after_max_attempts() {
+        let mut verifier = PieceVerifier::new(piece_list(b"abcd", 4));
+        for _ in 1..MAX_PIECE_ATTEMPTS {
+            assert!(verifier.check(b"abcX", false).unwrap().is_some());
+        }
+        assert!(verifier.check(b"abcX", false).is_err());
+        assert!(PieceList::parse("abc\n", 4).is_err());
+    }
+
+    #[test]
+    fn test_piece_length_that_overflows_is_rejected() {
+        let hash = "a".repeat(40);
+        let two_pieces = format!("{}\n{}\n", hash, hash);
+        assert!(PieceList::parse(&two_pieces, u64::MAX).is_err());
+        assert!(PieceList::parse(&two_pieces, (usize::MAX / 2 + 1) as u64).is_err());
+        assert!(PieceList::parse(&hash, usize::MAX as u64).is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub opened: usize,
+    pub reused: usize,
+    pub expired: usize,
+}
+
+/// Holds idle keep-alive connections between requests, keyed by scheme and `host:port`.
+///
+/// Clients share one pool through an `Arc` so batch entries on the same host reuse connections.
+///
+/// With an idle timeout, connections unused for longer are closed instead of reused, since the
+/// server has likely dropped them already.
+#[derive(Default)]
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<String, IdleConnection>>,
+    idle_timeout: Option<Duration>,
+    opened: AtomicUsize,
+    reused: AtomicUsize,
+    expired: AtomicUsize,
+}
+
+/// An open connection with the address it reached and the ranges it has served so far.
+pub struct Connection {
+    pub stream: Box<dyn Stream>,
+    pub peer: SocketAddr,
+    pub ranges_served: usize,
+}
+
+struct IdleConnection {
+    connection: Connection,
+    last_used: Instant,
+}
+
+impl ConnectionPool {
+    pub fn with_idle_timeout(idle_timeout: Option<Duration>) -> Self {
+        ConnectionPool {
+            idle_timeout,
+            ..ConnectionPool::default()
+        }
+    }
+
+    /// Borrows the idle connection for `key`, unless it has idled past the timeout.
+    pub fn take(&self, key: &str) -> Option<Connection> {
+        let idle = self.idle.lock().unwrap().remove(key)?;
+        if self
+            .idle_timeout
+            .is_some_and(|timeout| idle.last_used.elapsed() > timeout)
+        {
+            self.expired.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        Some(idle.connection)
+    }
+
+    pub fn put(&self, key: &str, connection: Connection) {
+        self.idle.lock().unwrap().insert(
+            key.to_string(),
+            IdleConnection {
+                connection,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    pub fn record_opened(&self) {
+        self.opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            opened: self.opened.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let idle = self.idle.lock().map(|idle| idle.len()).unwrap_or(0);
+        f.debug_struct("ConnectionPool")
+            .field("idle", &idle)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::thread;
+
+    fn connection(ranges_served: usize) -> Connection {
+        Connection {
+            stream: Box::new(Cursor::new(Vec::new())),
+            peer: "127.0.0.1:80".parse().unwrap(),
+            ranges_served,
+        }
+    }
+
+    #[test]
+    fn test_connection_idle_beyond_timeout_is_discarded() {
+        let pool = ConnectionPool::with_idle_timeout(Some(Duration::from_millis(50)));
+        pool.put("http://a:80", connection(1));
+        assert_eq!(
+            pool.take("http://a:80").map(|idle| idle.ranges_served),
+            Some(1)
+        );
+
+        pool.put("http://a:80", connection(2));
+        thread::sleep(Duration::from_millis(80));
+        assert!(pool.take("http://a:80").is_none());
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                opened: 0,
+                reused: 1,
+                expired: 1
+            }
+        );
+    }
+}
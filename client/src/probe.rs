@@ -0,0 +1,148 @@
+use std::error::Error;
+use std::fmt::Write as _;
+
+use crate::config::Config;
+use crate::download::build_client;
+
+/// How the server answered one crafted range request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeResponse {
+    pub status: u16,
+    pub content_range: Option<String>,
+    pub bytes: usize,
+}
+
+#[derive(Debug)]
+pub struct ProbeOutcome {
+    pub name: &'static str,
+    pub spec: String,
+    /// The response, or the error that prevented one.
+    pub result: Result<ProbeResponse, String>,
+}
+
+/// The crafted ranges, placed relative to the resource size when `HEAD` reported one.
+fn range_probes(size: Option<u64>) -> Vec<(&'static str, String)> {
+    let (mid, past_eof) = match size {
+        Some(size) => (
+            format!("{}-{}", size / 4, size / 2),
+            format!("{}-", size + 100),
+        ),
+        None => ("100-199".to_string(), format!("{}-", u32::MAX)),
+    };
+    vec![
+        ("mid-file", mid),
+        ("past EOF", past_eof),
+        ("overlapping", "0-9,5-14".to_string()),
+        ("zero-length", "10-9".to_string()),
+    ]
+}
+
+/// Sends each crafted range to the configured URL for `--probe-ranges`. A failing probe is
+/// recorded rather than aborting the rest.
+pub fn probe_ranges(config: &Config) -> Result<Vec<ProbeOutcome>, Box<dyn Error>> {
+    let client = build_client(config);
+    let size = client
+        .head()
+        .ok()
+        .filter(|response| response.status == 200)
+        .and_then(|response| response.resource_length());
+    Ok(range_probes(size)
+        .into_iter()
+        .map(|(name, spec)| {
+            let result = client
+                .fetch_range_spec(&spec)
+                .map(|response| ProbeResponse {
+                    status: response.status,
+                    content_range: response.header("Content-Range").map(str::to_string),
+                    bytes: response.body.len(),
+                })
+                .map_err(|e| e.to_string());
+            ProbeOutcome { name, spec, result }
+        })
+        .collect())
+}
+
+/// Renders the outcomes as a compatibility table.
+pub fn format_report(outcomes: &[ProbeOutcome]) -> String {
+    let mut report = format!(
+        "{:<12} {:<22} {:<6} {:<26} {}\n",
+        "Probe", "Range", "Status", "Content-Range", "Bytes"
+    );
+    for outcome in outcomes {
+        let range = format!("bytes={}", outcome.spec);
+        let _ = match &outcome.result {
+            Ok(response) => writeln!(
+                report,
+                "{:<12} {:<22} {:<6} {:<26} {}",
+                outcome.name,
+                range,
+                response.status,
+                response.content_range.as_deref().unwrap_or("-"),
+                response.bytes
+            ),
+            Err(e) => writeln!(report, "{:<12} {:<22} error: {}", outcome.name, range, e),
+        };
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, spawn_server};
+
+    #[test]
+    fn test_probe_sends_each_crafted_range_and_tabulates() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n".to_vec();
+        let (port, server) = spawn_server(vec![
+            head,
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 250-500/1000\r\n",
+                &[b'x'; 251],
+            ),
+            response(
+                "416 Range Not Satisfiable",
+                "Content-Range: bytes */1000\r\n",
+                b"",
+            ),
+            response("200 OK", "", &[b'x'; 1000]),
+            response("416 Range Not Satisfiable", "", b""),
+        ]);
+        let config = Config {
+            port,
+            ..Config::default()
+        };
+
+        let outcomes = probe_ranges(&config).unwrap();
+
+        let ranges: Vec<_> = server.join().unwrap()[1..]
+            .iter()
+            .map(|request| {
+                request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Range: "))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                "bytes=250-500",
+                "bytes=1100-",
+                "bytes=0-9,5-14",
+                "bytes=10-9"
+            ]
+        );
+        let statuses: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| outcome.result.as_ref().unwrap().status)
+            .collect();
+        assert_eq!(statuses, [206, 416, 200, 416]);
+        let report = format_report(&outcomes);
+        assert!(report.contains("mid-file"));
+        assert!(report.contains("bytes 250-500/1000"));
+        assert_eq!(report.lines().count(), 5);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The shortest time between two progress lines, so a download of many small ranges does not
+/// flood the log.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks how far a download has come. With `--resume` the bytes already in the partial file
+/// count toward the total progress but not toward throughput, which only covers bytes fetched
+/// by this run.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    resumed: u64,
+    started: Instant,
+    last_printed: Option<Instant>,
+}
+
+/// Progress at one moment, as printed after a range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressSnapshot {
+    /// Bytes of the file so far, resumed ones included.
+    pub file_len: u64,
+    pub total: Option<u64>,
+    /// Bytes fetched by this run.
+    pub new_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Starts counting from `resumed` bytes already on disk (0 for a fresh download).
+    pub fn new(resumed: u64) -> Self {
+        Progress {
+            resumed,
+            started: Instant::now(),
+            last_printed: None,
+        }
+    }
+
+    /// Whether a progress line is due: the first one is, then one per `PROGRESS_INTERVAL`.
+    pub fn due(&mut self) -> bool {
+        self.due_at(Instant::now())
+    }
+
+    fn due_at(&mut self, now: Instant) -> bool {
+        if self
+            .last_printed
+            .is_some_and(|last| now.duration_since(last) < PROGRESS_INTERVAL)
+        {
+            return false;
+        }
+        self.last_printed = Some(now);
+        true
+    }
+
+    /// Starts over from byte 0, after the partial data was discarded.
+    pub fn restart(&mut self) {
+        *self = Progress::new(0);
+    }
+
+    pub fn snapshot(&self, file_len: u64, total: Option<u64>) -> ProgressSnapshot {
+        self.snapshot_after(file_len, total, self.started.elapsed())
+    }
+
+    fn snapshot_after(
+        &self,
+        file_len: u64,
+        total: Option<u64>,
+        elapsed: Duration,
+    ) -> ProgressSnapshot {
+        ProgressSnapshot {
+            file_len,
+            total,
+            new_bytes: file_len.saturating_sub(self.resumed),
+            elapsed,
+        }
+    }
+}
+
+impl ProgressSnapshot {
+    /// How much of the whole file is present, resumed bytes included; `None` without a total.
+    pub fn percent(&self) -> Option<f64> {
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| self.file_len.min(total) as f64 * 100.0 / total as f64)
+    }
+
+    /// Bytes per second fetched by this run.
+    pub fn bytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.new_bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// The time left for the rest of the file at this run's throughput.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.file_len);
+        let rate = self.bytes_per_second();
+        (rate > 0.0).then(|| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+impl fmt::Display for ProgressSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.total, self.percent()) {
+            (Some(total), Some(percent)) => {
+                write!(f, "{:.1}% ({} of {} bytes", percent, self.file_len, total)?
+            }
+            _ => write!(f, "{} bytes (", self.file_len)?,
+        }
+        write!(
+            f,
+            "{}{} new at {:.0} B/s",
+            if self.total.is_some() { ", " } else { "" },
+            self.new_bytes,
+            self.bytes_per_second()
+        )?;
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {:.0?}", eta)?;
+        }
+        f.write_str(")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumed_progress_starts_at_the_resume_offset() {
+        let progress = Progress::new(600);
+
+        let start = progress.snapshot_after(600, Some(1000), Duration::ZERO);
+        assert_eq!(start.percent(), Some(60.0));
+        assert_eq!(start.new_bytes, 0);
+        assert_eq!(start.eta(), None);
+
+        let later = progress.snapshot_after(800, Some(1000), Duration::from_secs(2));
+        assert_eq!(later.percent(), Some(80.0));
+        assert_eq!(later.bytes_per_second(), 100.0);
+        assert_eq!(later.eta(), Some(Duration::from_secs(2)));
+        assert_eq!(
+            later.to_string(),
+            "80.0% (800 of 1000 bytes, 200 new at 100 B/s, ETA 2s)"
+        );
+
+        let mut progress = progress;
+        progress.restart();
+        let unknown = progress.snapshot_after(50, None, Duration::from_secs(1));
+        assert_eq!(unknown.to_string(), "50 bytes (50 new at 50 B/s)");
+    }
+
+    #[test]
+    fn test_progress_lines_are_rate_limited() {
+        let mut progress = Progress::new(0);
+        let start = Instant::now();
+        assert!(progress.due_at(start));
+        assert!(!progress.due_at(start + Duration::from_millis(10)));
+        assert!(!progress.due_at(start + PROGRESS_INTERVAL / 2));
+        assert!(progress.due_at(start + PROGRESS_INTERVAL));
+        assert!(!progress.due_at(start + PROGRESS_INTERVAL + Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolveStats {
+    pub lookups: usize,
+    pub resolutions: usize,
+}
+
+/// How resolved addresses are ordered before connecting to the first one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressOrder {
+    /// Keep the order the system resolver returned.
+    #[default]
+    Resolver,
+    /// Sort deterministically (IPv4 before IPv6, then by address and port).
+    Sorted,
+    /// Shuffle on every lookup to spread load across addresses.
+    Random,
+}
+
+impl AddressOrder {
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "resolver" => Ok(AddressOrder::Resolver),
+            "sorted" => Ok(AddressOrder::Sorted),
+            "random" => Ok(AddressOrder::Random),
+            _ => Err(format!(
+                "Invalid --address-order '{}' (expected resolver, sorted or random)",
+                value
+            )
+            .into()),
+        }
+    }
+
+    /// Reorders `addrs` in place; `seed` drives the `Random` shuffle.
+    pub fn apply(self, addrs: &mut [SocketAddr], seed: u64) {
+        match self {
+            AddressOrder::Resolver => {}
+            AddressOrder::Sorted => addrs.sort(),
+            AddressOrder::Random => {
+                // xorshift64 is plenty for spreading connections; no need for a rand dependency.
+                let mut state = seed | 1;
+                for i in (1..addrs.len()).rev() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    addrs.swap(i, (state % (i as u64 + 1)) as usize);
+                }
+            }
+        }
+    }
+}
+
+/// How long a failed lookup is remembered when no `--dns-ttl` is set.
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(1);
+
+/// Caches lookups per `host:port` so repeated requests skip DNS. Failed lookups are cached
+/// too (negative caching), so retries do not hammer a broken resolver.
+///
+/// Successful lookups live for `ttl` (forever when unset) and failures for `ttl` or a short
+/// default. With a capacity, the least recently used entry is evicted to make room.
+#[derive(Debug, Default)]
+pub struct AddressCache {
+    entries: Mutex<HashMap<String, CachedLookup>>,
+    order: AddressOrder,
+    capacity: Option<usize>,
+    ttl: Option<Duration>,
+    timeout: Option<Duration>,
+    lookups: AtomicUsize,
+    resolutions: AtomicUsize,
+    clock: AtomicU64,
+}
+
+#[derive(Debug)]
+struct CachedLookup {
+    /// The addresses, or the failure's kind and message (`io::Error` is not `Clone`).
+    result: Result<Vec<SocketAddr>, (io::ErrorKind, String)>,
+    stored: Instant,
+    last_used: u64,
+}
+
+impl CachedLookup {
+    fn expired(&self, ttl: Option<Duration>) -> bool {
+        let ttl = match (&self.result, ttl) {
+            (Ok(addrs), ttl) if !addrs.is_empty() => ttl,
+            (_, ttl) => Some(ttl.unwrap_or(DEFAULT_NEGATIVE_TTL)),
+        };
+        ttl.is_some_and(|ttl| self.stored.elapsed() > ttl)
+    }
+}
+
+impl AddressCache {
+    pub fn with_order(order: AddressOrder) -> Self {
+        AddressCache {
+            order,
+            ..AddressCache::default()
+        }
+    }
+
+    /// Keeps at most `capacity` targets (`--dns-cache-size`), evicting the least recently used.
+    pub fn with_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Re-resolves targets whose lookup is older than `ttl` (`--dns-ttl`).
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Gives up on a lookup after `timeout` (`--dns-timeout`). Timed-out lookups are not cached.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get_mut(target) {
+                Some(entry) if !entry.expired(self.ttl) => {
+                    entry.last_used = tick;
+                    Some(entry.result.clone())
+                }
+                _ => None,
+            }
+        };
+        let result = match cached {
+            Some(result) => result,
+            None => {
+                self.resolutions.fetch_add(1, Ordering::Relaxed);
+                let result = match self.timeout {
+                    Some(timeout) => resolve_with_timeout(target, timeout, system_lookup),
+                    None => system_lookup(target.to_string()),
+                };
+                let result = result.map_err(|e| (e.kind(), e.to_string()));
+                if !matches!(result, Err((io::ErrorKind::TimedOut, _))) {
+                    self.store(target, result.clone(), tick);
+                }
+                result
+            }
+        };
+        let mut addrs = result.map_err(|(kind, message)| io::Error::new(kind, message))?;
+        self.order
+            .apply(&mut addrs, RandomState::new().hash_one(target));
+        Ok(addrs)
+    }
+
+    fn store(
+        &self,
+        target: &str,
+        result: Result<Vec<SocketAddr>, (io::ErrorKind, String)>,
+        tick: u64,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(capacity) = self.capacity {
+            while !entries.contains_key(target) && entries.len() >= capacity {
+                let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            target.to_string(),
+            CachedLookup {
+                result,
+                stored: Instant::now(),
+                last_used: tick,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> ResolveStats {
+        ResolveStats {
+            lookups: self.lookups.load(Ordering::Relaxed),
+            resolutions: self.resolutions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn system_lookup(target: String) -> io::Result<Vec<SocketAddr>> {
+    target.to_socket_addrs().map(|addrs| addrs.collect())
+}
+
+/// Runs `lookup` on a worker thread and fails with `TimedOut` if it takes longer than `timeout`,
+/// since the system resolver has no timeout of its own. A lookup that times out keeps running
+/// in the background until the resolver returns; its result is dropped.
+pub fn resolve_with_timeout<F>(
+    target: &str,
+    timeout: Duration,
+    lookup: F,
+) -> io::Result<Vec<SocketAddr>>
+where
+    F: FnOnce(String) -> io::Result<Vec<SocketAddr>> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let owned = target.to_string();
+    thread::Builder::new()
+        .name("dns-lookup".to_string())
+        .spawn(move || {
+            let _ = sender.send(lookup(owned));
+        })?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "DNS lookup for {} timed out after {:?} (--dns-timeout)",
+                target, timeout
+            ),
+        )),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::other(format!(
+            "DNS lookup for {} failed",
+            target
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_lookup_times_out() {
+        let slow = |_: String| {
+            thread::sleep(Duration::from_millis(500));
+            Ok(vec![SocketAddr::from(([127, 0, 0, 1], 80))])
+        };
+        let started = Instant::now();
+        let error = resolve_with_timeout("slow.test:80", Duration::from_millis(50), slow)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(error.to_string().contains("--dns-timeout"));
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        let addrs =
+            resolve_with_timeout("127.0.0.1:80", Duration::from_secs(5), system_lookup).unwrap();
+        assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 80))]);
+    }
+
+    #[test]
+    fn test_repeated_lookups_resolve_once() {
+        let cache = AddressCache::default();
+        let first = cache.resolve("127.0.0.1:8080").unwrap();
+        let second = cache.resolve("127.0.0.1:8080").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(
+            cache.stats(),
+            ResolveStats {
+                lookups: 2,
+                resolutions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_lookup_is_cached_until_ttl() {
+        let cache = AddressCache::default().with_ttl(Some(Duration::from_millis(50)));
+        // Not a `host:port` pair, so this fails without touching the network.
+        assert!(cache.resolve("no port here").is_err());
+        assert!(cache.resolve("no port here").is_err());
+        assert_eq!(cache.stats().resolutions, 1);
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(cache.resolve("no port here").is_err());
+        assert_eq!(cache.stats().resolutions, 2);
+    }
+
+    #[test]
+    fn test_least_recently_used_target_is_evicted() {
+        let cache = AddressCache::default().with_capacity(Some(2));
+        cache.resolve("127.0.0.1:1").unwrap();
+        cache.resolve("127.0.0.1:2").unwrap();
+        cache.resolve("127.0.0.1:1").unwrap();
+        cache.resolve("127.0.0.1:3").unwrap();
+        assert_eq!(cache.stats().resolutions, 3);
+
+        cache.resolve("127.0.0.1:1").unwrap();
+        assert_eq!(cache.stats().resolutions, 3);
+        cache.resolve("127.0.0.1:2").unwrap();
+        assert_eq!(cache.stats().resolutions, 4);
+    }
+
+    fn fixed_addresses() -> Vec<SocketAddr> {
+        ["[::1]:80", "10.0.0.2:80", "10.0.0.1:443", "10.0.0.1:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_address_ordering() {
+        let mut resolver = fixed_addresses();
+        AddressOrder::Resolver.apply(&mut resolver, 7);
+        assert_eq!(resolver, fixed_addresses());
+
+        let mut sorted = fixed_addresses();
+        AddressOrder::Sorted.apply(&mut sorted, 7);
+        let sorted: Vec<String> = sorted.iter().map(|addr| addr.to_string()).collect();
+        assert_eq!(
+            sorted,
+            ["10.0.0.1:80", "10.0.0.1:443", "10.0.0.2:80", "[::1]:80"]
+        );
+
+        let mut shuffled = fixed_addresses();
+        AddressOrder::Random.apply(&mut shuffled, 7);
+        let mut again = fixed_addresses();
+        AddressOrder::Random.apply(&mut again, 7);
+        assert_eq!(shuffled, again);
+        shuffled.sort();
+        let mut expected = fixed_addresses();
+        expected.sort();
+        assert_eq!(shuffled, expected);
+
+        assert!(AddressOrder::parse("fastest").is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Pause before retrying after a retryable error under the default policy.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// What to do after a failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Give up; the download fails with the error.
+    Stop,
+    /// Repeat the request after this pause.
+    RetryAfter(Duration),
+}
+
+type Classifier = dyn Fn(&(dyn Error + 'static), u32) -> RetryDecision + Send + Sync;
+
+/// Decides, from the error and the number of retries made so far, whether a failed request is
+/// retried. Library users with unusual servers can plug in their own classification and backoff;
+/// the default retries network errors after `DEFAULT_RETRY_DELAY`, without limit.
+#[derive(Clone)]
+pub struct RetryPolicy(Arc<Classifier>);
+
+impl RetryPolicy {
+    pub fn new<F>(classify: F) -> Self
+    where
+        F: Fn(&(dyn Error + 'static), u32) -> RetryDecision + Send + Sync + 'static,
+    {
+        RetryPolicy(Arc::new(classify))
+    }
+
+    pub fn decide(&self, error: &(dyn Error + 'static), retries: u32) -> RetryDecision {
+        (self.0)(error, retries)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(|error, _| {
+            if is_retryable(error) {
+                RetryDecision::RetryAfter(DEFAULT_RETRY_DELAY)
+            } else {
+                RetryDecision::Stop
+            }
+        })
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RetryPolicy(..)")
+    }
+}
+
+/// Policies compare equal only when they are the same closure.
+impl PartialEq for RetryPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The retries of one download. `--max-retries` counts those since the last range that made
+/// progress, or every retry of the download with `--no-reset-retries-on-progress`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryCount {
+    total: u32,
+    since_progress: u32,
+}
+
+impl RetryCount {
+    pub fn record(&mut self) {
+        self.total += 1;
+        self.since_progress += 1;
+    }
+
+    /// A range added bytes to the download.
+    pub fn progressed(&mut self) {
+        self.since_progress = 0;
+    }
+
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// The retries that count toward `--max-retries`.
+    pub fn counted(&self, reset_on_progress: bool) -> u32 {
+        if reset_on_progress {
+            self.since_progress
+        } else {
+            self.total
+        }
+    }
+}
+
+/// Whether an error is a transient network failure worth retrying.
+pub fn is_retryable(e: &(dyn Error + 'static)) -> bool {
+    let error_string = e.to_string();
+    let io_error_kind = e.downcast_ref::<io::Error>().map(|io_err| io_err.kind());
+
+    match io_error_kind {
+        Some(ErrorKind::ConnectionRefused)
+        | Some(ErrorKind::TimedOut)
+        | Some(ErrorKind::ConnectionReset)
+        | Some(ErrorKind::ConnectionAborted)
+        | Some(ErrorKind::NotConnected)
+        | Some(ErrorKind::BrokenPipe) => true,
+        _ => {
+            error_string.contains("Failed to resolve address")
+                || error_string.contains("Connection closed before status line")
+                || error_string.contains("Connection closed during header reading")
+                || error_string.contains("Chunk SHA-256 mismatch")
+                || error_string.contains("Range hash mismatch")
+                || error_string.starts_with("Short read")
+                || (error_string.starts_with("Piece ")
+                    && error_string.contains("failed verification (attempt"))
+        }
+    }
+}
+
+/// A token bucket that caps how often retries may happen, shared (through an `Arc`) by every
+/// download that retries against the same server so their combined retries stay under
+/// `--retry-rate` per second. The bucket holds a single token, so retries never burst.
+/// `--connect-rate` uses a second bucket to cap connection attempts the same way.
+#[derive(Debug)]
+pub struct RetryBudget {
+    per_second: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(per_second: f64) -> Self {
+        RetryBudget {
+            per_second,
+            state: Mutex::new(BucketState {
+                tokens: 1.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Blocks until a retry token is available and takes it.
+    pub fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(state.refilled).as_secs_f64() * self.per_second;
+                state.tokens = (state.tokens + refill).min(1.0);
+                state.refilled = now;
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / self.per_second)
+            };
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// One retry, as appended to the `--retry-log` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryEvent {
+    pub timestamp: SystemTime,
+    /// The byte the failed request started at; `None` for a suffix request.
+    pub offset: Option<u64>,
+    /// The `io::ErrorKind` of a network error, `other` for protocol errors.
+    pub kind: String,
+    /// The retry this is, counting from 1.
+    pub attempt: u32,
+    pub backoff: Duration,
+    pub error: String,
+}
+
+impl RetryEvent {
+    pub fn new(
+        offset: Option<u64>,
+        error: &(dyn Error + 'static),
+        attempt: u32,
+        backoff: Duration,
+    ) -> Self {
+        let kind = error
+            .downcast_ref::<io::Error>()
+            .map_or_else(|| "other".to_string(), |e| format!("{:?}", e.kind()));
+        RetryEvent {
+            timestamp: SystemTime::now(),
+            offset,
+            kind,
+            attempt,
+            backoff,
+            error: error.to_string(),
+        }
+    }
+
+    /// A `key=value` line, e.g.
+    /// `1760612345.120 offset=1024 kind=ConnectionReset attempt=2 backoff_ms=100 error="..."`.
+    pub fn to_line(&self) -> String {
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "{}.{:03} offset={} kind={} attempt={} backoff_ms={} error={:?}\n",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            self.offset
+                .map_or_else(|| "-".to_string(), |offset| offset.to_string()),
+            self.kind,
+            self.attempt,
+            self.backoff.as_millis(),
+            self.error
+        )
+    }
+}
+
+/// Appends `event` to the `--retry-log` file at `path`, creating it if needed.
+pub fn append_retry_log(path: &str, event: &RetryEvent) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(event.to_line().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_retries_share_the_rate() {
+        let budget = Arc::new(RetryBudget::new(100.0));
+        let started = Instant::now();
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let budget = Arc::clone(&budget);
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        budget.acquire();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        // 20 retries at 100 per second: the first is free, the other 19 wait 10ms each.
+        assert!(started.elapsed() >= Duration::from_millis(180));
+    }
+}
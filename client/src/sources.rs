@@ -0,0 +1,568 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::download::{build_client, wait_to_retry, DownloadError, DownloadResult};
+use crate::hashing::{Algorithm, Hasher};
+use crate::histogram::LatencyHistogram;
+use crate::http_client::HttpClient;
+use crate::output::Diagnostics;
+use crate::retry::{RetryBudget, RetryCount};
+use crate::url::Url;
+
+/// The mirrors of one file from a `--sources-file`, with the size every one of them must serve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceList {
+    pub urls: Vec<Url>,
+    pub size: u64,
+}
+
+/// A parsed `--sources-file`: `hash`, `size` and one or more `url` lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcesDescriptor {
+    /// `<hex>`, or `<algo>:<hex>` like `--hash`.
+    pub hash: String,
+    pub sources: SourceList,
+}
+
+/// Parses a sources descriptor; blank lines and `#` comments are skipped:
+///
+/// ```text
+/// hash sha256:<hex>
+/// size 1048576
+/// url http://a.example/file.bin
+/// url http://b.example/file.bin
+/// ```
+pub fn parse_sources(text: &str) -> Result<SourcesDescriptor, Box<dyn Error>> {
+    let mut hash = None;
+    let mut size = None;
+    let mut urls = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| format!("Sources line {}: {}", index + 1, message);
+        let (key, value) = line
+            .split_once(char::is_whitespace)
+            .map(|(key, value)| (key, value.trim()))
+            .ok_or_else(|| error(format!("expected '<key> <value>', got '{}'", line)))?;
+        match key {
+            "hash" => hash = Some(value.to_string()),
+            "size" => {
+                size = Some(
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| error(format!("invalid size '{}'", value)))?,
+                )
+            }
+            "url" => urls.push(Url::parse(value).map_err(|e| error(e.to_string()))?),
+            _ => return Err(error(format!("unknown key '{}'", key)).into()),
+        }
+    }
+    match (hash, size) {
+        (Some(hash), Some(size)) if !urls.is_empty() => Ok(SourcesDescriptor {
+            hash,
+            sources: SourceList { urls, size },
+        }),
+        _ => Err("A sources file needs a hash line, a size line and at least one url line".into()),
+    }
+}
+
+/// For `--sources-file`: reads the descriptor and returns `config` set up to download from its
+/// sources and verify against its hash.
+pub fn apply_sources_file(config: &Config, path: &str) -> Result<Config, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let descriptor = parse_sources(&text)?;
+    let first = &descriptor.sources.urls[0];
+    let mut config = Config {
+        scheme: first.scheme,
+        host: first.host.clone(),
+        port: first.port,
+        path: first.path.clone(),
+        sources: Some(descriptor.sources.clone()),
+        ..config.clone()
+    };
+    match descriptor.hash.split_once(':') {
+        Some((algorithm, hex)) => config
+            .prefixed_hashes
+            .push((Algorithm::parse(algorithm)?, hex.to_ascii_lowercase())),
+        None => config.expected_hash = descriptor.hash.to_ascii_lowercase(),
+    }
+    Ok(config)
+}
+
+/// Segments waiting for a source, and how many are being fetched right now.
+struct Schedule {
+    pending: VecDeque<(u64, u64)>,
+    in_flight: usize,
+}
+
+/// How often a source is asked again after a retryable error before its segment goes to the
+/// other sources, unless `--max-retries` says otherwise.
+pub const SOURCE_MAX_RETRIES: u32 = 3;
+
+/// What the source workers have produced between them.
+#[derive(Default)]
+struct Fetched {
+    segments: Vec<(u64, Vec<u8>)>,
+    retries: u32,
+    range_times: LatencyHistogram,
+    last_error: Option<String>,
+}
+
+/// Downloads `sources.size` bytes split into one segment per source, every source fetching in
+/// parallel. A source that keeps failing (retryable errors are retried up to `--max-retries`, or
+/// `SOURCE_MAX_RETRIES`, times) is dropped and the rest of its segment goes back to the queue for
+/// the remaining sources, so the download survives as long as one source does. Before any data
+/// is fetched, every source must report the descriptor's size in a `HEAD`. All sources draw
+/// their retries from one `--retry-rate` bucket and their connections from one `--connect-rate`
+/// bucket.
+pub fn download_from_sources(
+    config: &Config,
+    sources: &SourceList,
+    mut hasher: Box<dyn Hasher>,
+) -> Result<DownloadResult, Box<dyn Error>> {
+    let diag = Diagnostics::for_config(config);
+    let started = Instant::now();
+    let retry_budget = config
+        .retry_rate
+        .map(|rate| Arc::new(RetryBudget::new(rate)));
+    let connect_rate = config
+        .connect_rate
+        .map(|rate| Arc::new(RetryBudget::new(rate)));
+    let clients: Vec<(&Url, HttpClient)> = sources
+        .urls
+        .iter()
+        .map(|url| {
+            let client = build_client(&Config {
+                scheme: url.scheme,
+                host: url.host.clone(),
+                port: url.port,
+                path: url.path.clone(),
+                ..config.clone()
+            })
+            .with_retry_budget(retry_budget.clone())
+            .with_connect_rate(connect_rate.clone());
+            (url, client)
+        })
+        .collect();
+    for (url, client) in &clients {
+        let length = client
+            .head()
+            .map_err(|e| format!("Source {}: HEAD failed: {}", url, e))?
+            .resource_length();
+        if length != Some(sources.size) {
+            return Err(format!(
+                "Source {} reports {} bytes, but the sources file says {}",
+                url,
+                length.map_or_else(|| "an unknown number of".to_string(), |l| l.to_string()),
+                sources.size
+            )
+            .into());
+        }
+    }
+    diag.println(format_args!(
+        "All {} sources serve {} bytes; downloading a segment from each.",
+        clients.len(),
+        sources.size
+    ));
+
+    let schedule = Mutex::new(Schedule {
+        pending: segments(sources.size, clients.len() as u64).collect(),
+        in_flight: 0,
+    });
+    let changed = Condvar::new();
+    let fetched = Mutex::new(Fetched::default());
+    let retry_config = Config {
+        max_retries: Some(config.max_retries.unwrap_or(SOURCE_MAX_RETRIES)),
+        ..config.clone()
+    };
+    thread::scope(|scope| {
+        for (url, client) in &clients {
+            let (schedule, changed, fetched, diag) = (&schedule, &changed, &fetched, &diag);
+            let retry_config = &retry_config;
+            scope.spawn(move || {
+                let mut retries = RetryCount::default();
+                while let Some((start, end)) = next_segment(schedule, changed) {
+                    let (done, error) =
+                        fetch_segment(retry_config, client, start, end, &mut retries, fetched);
+                    let mut schedule = schedule.lock().unwrap();
+                    schedule.in_flight -= 1;
+                    if let Some(e) = error {
+                        diag.println(format_args!(
+                            "Source {} failed at byte {} ({}); handing bytes {}-{} to the other sources.",
+                            url, done, e, done, end
+                        ));
+                        schedule.pending.push_back((done, end));
+                        let mut fetched = fetched.lock().unwrap();
+                        fetched.retries += 1;
+                        fetched.last_error = Some(format!("{}: {}", url, e));
+                        changed.notify_all();
+                        return;
+                    }
+                    diag.println(format_args!(
+                        "Source {} delivered bytes {}-{}",
+                        url, start, end
+                    ));
+                    changed.notify_all();
+                }
+            });
+        }
+    });
+
+    let fetched = fetched.into_inner().unwrap();
+    if !schedule.into_inner().unwrap().pending.is_empty() {
+        return Err(Box::new(DownloadError {
+            retries: fetched.retries,
+            bytes: fetched
+                .segments
+                .iter()
+                .map(|(_, bytes)| bytes.len() as u64)
+                .sum(),
+            source: format!(
+                "Every source failed; last error from {}",
+                fetched.last_error.unwrap_or_default()
+            )
+            .into(),
+        }));
+    }
+    let mut segments = fetched.segments;
+    segments.sort_by_key(|(start, _)| *start);
+    let mut data = Vec::with_capacity(sources.size as usize);
+    for (_, bytes) in segments {
+        data.extend_from_slice(&bytes);
+    }
+    if data.len() as u64 != sources.size {
+        return Err(format!(
+            "Reassembled {} bytes from the sources instead of {}",
+            data.len(),
+            sources.size
+        )
+        .into());
+    }
+    hasher.update(&data);
+    Ok(DownloadResult {
+        bytes: data.len() as u64,
+        data: Some(data),
+        retries: fetched.retries,
+        elapsed: started.elapsed(),
+        digest: hasher.finalize(),
+        content_type: None,
+        failed_pieces: Vec::new(),
+        range_times: fetched.range_times,
+    })
+}
+
+/// Splits `size` bytes into `count` inclusive ranges of nearly equal length.
+fn segments(size: u64, count: u64) -> impl Iterator<Item = (u64, u64)> {
+    let count = count.clamp(1, size.max(1));
+    (0..count)
+        .map(move |index| (size * index / count, size * (index + 1) / count))
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| (start, end - 1))
+}
+
+/// Waits for a segment to fetch; `None` once nothing is left to fetch or retry.
+fn next_segment(schedule: &Mutex<Schedule>, changed: &Condvar) -> Option<(u64, u64)> {
+    let mut schedule = schedule.lock().unwrap();
+    loop {
+        if let Some(segment) = schedule.pending.pop_front() {
+            schedule.in_flight += 1;
+            return Some(segment);
+        }
+        if schedule.in_flight == 0 {
+            return None;
+        }
+        schedule = changed.wait(schedule).unwrap();
+    }
+}
+
+/// Fetches bytes `start..=end` from one source, asking again after short reads and, as the
+/// retry policy allows, after retryable errors. Returns the offset reached and, if the source
+/// gave up, why; whatever arrived before a failure is kept.
+fn fetch_segment(
+    config: &Config,
+    client: &HttpClient,
+    start: u64,
+    end: u64,
+    retries: &mut RetryCount,
+    fetched: &Mutex<Fetched>,
+) -> (u64, Option<Box<dyn Error>>) {
+    let mut offset = start;
+    while offset <= end {
+        let requested = Instant::now();
+        let result = client.fetch_ranges(&[(offset as usize, end as usize)]);
+        fetched
+            .lock()
+            .unwrap()
+            .range_times
+            .record(requested.elapsed());
+        let body = match result {
+            Ok(mut parts) if parts.len() == 1 => {
+                let (range, body) = parts.remove(0);
+                if range.first != offset {
+                    let error = format!(
+                        "answered with bytes starting at {} for a range starting at {}",
+                        range.first, offset
+                    );
+                    return (offset, Some(error.into()));
+                }
+                body
+            }
+            Ok(parts) => {
+                let error = format!("answered one range with {} parts", parts.len());
+                return (offset, Some(error.into()));
+            }
+            Err(e) => {
+                let what = format!("from byte {}", offset);
+                if let Err(e) = wait_to_retry(config, client, e, retries, Some(offset), &what) {
+                    return (offset, Some(e));
+                }
+                retries.record();
+                fetched.lock().unwrap().retries += 1;
+                continue;
+            }
+        };
+        if body.is_empty() {
+            return (offset, Some("returned no bytes".into()));
+        }
+        retries.progressed();
+        let body = &body[..body.len().min((end + 1 - offset) as usize)];
+        fetched
+            .lock()
+            .unwrap()
+            .segments
+            .push((offset, body.to_vec()));
+        offset += body.len() as u64;
+    }
+    (offset, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::{RetryDecision, RetryPolicy};
+    use sha2::{Digest, Sha256};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_sources() {
+        let descriptor = parse_sources(
+            "# two mirrors\n\
+             hash sha256:ABCD\n\
+             size 10\n\
+             url http://a.test/f.bin\n\
+             url http://b.test:8080/f.bin\n",
+        )
+        .unwrap();
+        assert_eq!(descriptor.hash, "sha256:ABCD");
+        assert_eq!(descriptor.sources.size, 10);
+        assert_eq!(descriptor.sources.urls[1].port, 8080);
+        assert!(parse_sources("hash abc\nsize 10\n").is_err());
+        assert!(parse_sources("hash abc\nsize ten\nurl http://a.test/\n").is_err());
+        assert!(parse_sources("mirror http://a.test/\n").is_err());
+    }
+
+    #[test]
+    fn test_segments_cover_the_file() {
+        assert_eq!(segments(10, 2).collect::<Vec<_>>(), [(0, 4), (5, 9)]);
+        assert_eq!(segments(2, 3).collect::<Vec<_>>(), [(0, 0), (1, 1)]);
+        assert_eq!(segments(0, 2).count(), 0);
+    }
+
+    /// Serves `file` for `connections` requests: a `HEAD` gets its length, a ranged `GET` the
+    /// requested bytes. Returns the request lines.
+    fn range_server(file: &'static [u8], connections: usize) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some(spec) = line.trim_end().strip_prefix("Range: bytes=") {
+                        let (start, end) = spec.split_once('-').unwrap();
+                        range = Some((
+                            start.parse::<usize>().unwrap(),
+                            end.parse::<usize>().unwrap(),
+                        ));
+                    }
+                    request.push_str(&line);
+                }
+                let response = match range {
+                    Some((start, end)) => {
+                        let mut response = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                            start,
+                            end,
+                            file.len(),
+                            end + 1 - start
+                        )
+                        .into_bytes();
+                        response.extend_from_slice(&file[start..=end]);
+                        response
+                    }
+                    None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", file.len())
+                        .into_bytes(),
+                };
+                stream.write_all(&response).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (port, handle)
+    }
+
+    fn sources(ports: &[u16], size: u64) -> SourceList {
+        SourceList {
+            urls: ports
+                .iter()
+                .map(|port| Url::parse(&format!("http://127.0.0.1:{}/file.bin", port)).unwrap())
+                .collect(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_two_sources_each_serve_half_of_the_file() {
+        const FILE: &[u8] = b"0123456789abcdefghij";
+        let (port_a, server_a) = range_server(FILE, 2);
+        let (port_b, server_b) = range_server(FILE, 2);
+        let config = Config::default();
+
+        let download = download_from_sources(
+            &config,
+            &sources(&[port_a, port_b], FILE.len() as u64),
+            Box::new(Sha256::new()),
+        )
+        .unwrap();
+
+        assert_eq!(download.data.unwrap(), FILE);
+        assert_eq!(download.digest, Sha256::digest(FILE).to_vec());
+        let (a, b) = (server_a.join().unwrap(), server_b.join().unwrap());
+        assert!(a[0].starts_with("HEAD ") && b[0].starts_with("HEAD "));
+        assert!(a[1].contains("Range: bytes=0-9\r\n"));
+        assert!(b[1].contains("Range: bytes=10-19\r\n"));
+    }
+
+    #[test]
+    fn test_a_failing_source_hands_its_segment_over() {
+        const FILE: &[u8] = b"0123456789abcdefghij";
+        let (port_a, server_a) = range_server(FILE, 3);
+        // Answers the HEAD, then closes the connection of its GET without a response.
+        let (port_b, server_b) = crate::test_support::spawn_server(vec![
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", FILE.len()).into_bytes(),
+            Vec::new(),
+        ]);
+
+        let download = download_from_sources(
+            &Config::default(),
+            &sources(&[port_a, port_b], FILE.len() as u64),
+            Box::new(Sha256::new()),
+        )
+        .unwrap();
+
+        assert_eq!(download.data.unwrap(), FILE);
+        // Retried SOURCE_MAX_RETRIES times against the closed listener, then handed over.
+        assert_eq!(download.retries, SOURCE_MAX_RETRIES + 1);
+        assert_eq!(server_a.join().unwrap().len(), 3);
+        server_b.join().unwrap();
+    }
+
+    #[test]
+    fn test_failing_sources_share_one_retry_rate() {
+        const FILE: &[u8] = b"0123456789abcdefghij";
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", FILE.len());
+        // Both answer the HEAD, then close the connection of their GET without a response.
+        let (port_a, server_a) =
+            crate::test_support::spawn_server(vec![head.clone().into_bytes(), Vec::new()]);
+        let (port_b, server_b) =
+            crate::test_support::spawn_server(vec![head.into_bytes(), Vec::new()]);
+        let config = Config {
+            retry_rate: Some(10.0),
+            retry_policy: Some(RetryPolicy::new(|error, _| {
+                if crate::retry::is_retryable(error) {
+                    RetryDecision::RetryAfter(Duration::ZERO)
+                } else {
+                    RetryDecision::Stop
+                }
+            })),
+            ..Config::default()
+        };
+        let started = Instant::now();
+
+        let error = download_from_sources(
+            &config,
+            &sources(&[port_a, port_b], FILE.len() as u64),
+            Box::new(Sha256::new()),
+        )
+        .err()
+        .unwrap();
+
+        assert!(error.to_string().contains("Every source failed"));
+        // 2 * SOURCE_MAX_RETRIES retries at 10 per second from one bucket: the first is free,
+        // the other 5 wait 100ms each. A bucket per source would let them finish in 200ms.
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        server_a.join().unwrap();
+        server_b.join().unwrap();
+    }
+
+    #[test]
+    fn test_sources_share_one_connect_rate() {
+        const FILE: &[u8] = b"0123456789abcdefghij";
+        let (port_a, server_a) = range_server(FILE, 2);
+        let (port_b, server_b) = range_server(FILE, 2);
+        let config = Config {
+            connect_rate: Some(10.0),
+            ..Config::default()
+        };
+        let started = Instant::now();
+
+        let download = download_from_sources(
+            &config,
+            &sources(&[port_a, port_b], FILE.len() as u64),
+            Box::new(Sha256::new()),
+        )
+        .unwrap();
+
+        assert_eq!(download.data.unwrap(), FILE);
+        // Four connections (a HEAD and a GET per source) at 10 per second from one bucket: the
+        // first is free, the other 3 wait 100ms each. A bucket per source would take 100ms.
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        server_a.join().unwrap();
+        server_b.join().unwrap();
+    }
+
+    #[test]
+    fn test_sources_must_agree_on_size() {
+        let (port_a, server_a) = range_server(b"0123456789", 1);
+        let (port_b, server_b) = range_server(b"012345678", 1);
+
+        let error = download_from_sources(
+            &Config::default(),
+            &sources(&[port_a, port_b], 10),
+            Box::new(Sha256::new()),
+        )
+        .err()
+        .unwrap();
+
+        assert!(error.to_string().contains("reports 9 bytes"));
+        server_a.join().unwrap();
+        server_b.join().unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::error::Error;
+
+/// Values available to `--output-template`.
+pub struct TemplateVars<'a> {
+    pub basename: &'a str,
+    pub digest_hex: &'a str,
+    pub timestamp: u64,
+}
+
+/// Renders `{basename}`, `{hash}`, `{hash8}` and `{timestamp}` in an output template.
+pub fn render_output_template(
+    template: &str,
+    vars: &TemplateVars,
+) -> Result<String, Box<dyn Error>> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after_open = &rest[open + 1..];
+        let close = after_open
+            .find('}')
+            .ok_or_else(|| format!("Unterminated '{{' in output template '{}'", template))?;
+        match &after_open[..close] {
+            "basename" => rendered.push_str(vars.basename),
+            "hash" => rendered.push_str(vars.digest_hex),
+            "hash8" => rendered.push_str(&vars.digest_hex[..vars.digest_hex.len().min(8)]),
+            "timestamp" => rendered.push_str(&vars.timestamp.to_string()),
+            other => {
+                return Err(format!(
+                    "Unknown variable '{{{}}}' in output template (expected basename, hash, hash8 or timestamp)",
+                    other
+                )
+                .into())
+            }
+        }
+        rest = &after_open[close + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// The last path segment of a URL path, without query string; `download` if there is none.
+pub fn basename_from_path(path: &str) -> &str {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() => name,
+        _ => "download",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: TemplateVars = TemplateVars {
+        basename: "data.bin",
+        digest_hex: "0123456789abcdef",
+        timestamp: 1_700_000_000,
+    };
+
+    #[test]
+    fn test_render_basename() {
+        assert_eq!(
+            render_output_template("out/{basename}", &VARS).unwrap(),
+            "out/data.bin"
+        );
+    }
+
+    #[test]
+    fn test_render_hash_and_hash8() {
+        assert_eq!(
+            render_output_template("{hash}.bin", &VARS).unwrap(),
+            "0123456789abcdef.bin"
+        );
+        assert_eq!(
+            render_output_template("{basename}-{hash8}.bin", &VARS).unwrap(),
+            "data.bin-01234567.bin"
+        );
+    }
+
+    #[test]
+    fn test_render_timestamp() {
+        assert_eq!(
+            render_output_template("backup-{timestamp}", &VARS).unwrap(),
+            "backup-1700000000"
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_unknown_or_unterminated_variables() {
+        assert!(render_output_template("{size}.bin", &VARS).is_err());
+        assert!(render_output_template("{hash", &VARS).is_err());
+        assert_eq!(
+            render_output_template("plain.bin", &VARS).unwrap(),
+            "plain.bin"
+        );
+    }
+
+    #[test]
+    fn test_basename_from_path() {
+        assert_eq!(basename_from_path("/files/data.bin?v=2"), "data.bin");
+        assert_eq!(basename_from_path("/files/"), "download");
+        assert_eq!(basename_from_path("/"), "download");
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+/// Serves each canned response on its own connection and returns the received request heads.
+pub fn spawn_server(responses: Vec<Vec<u8>>) -> (u16, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            requests.push(request);
+            stream.write_all(&response).unwrap();
+        }
+        requests
+    });
+    (port, handle)
+}
+
+/// Serves the canned responses in order, several per connection while the client keeps it
+/// open, and returns how many connections were accepted.
+pub fn spawn_keep_alive_server(responses: Vec<Vec<u8>>) -> (u16, JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let mut responses = responses.into_iter().peekable();
+        let mut connections = 0;
+        while responses.peek().is_some() {
+            let (mut stream, _) = listener.accept().unwrap();
+            connections += 1;
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            'requests: while let Some(response) = responses.peek() {
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break 'requests;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                stream.write_all(response).unwrap();
+                responses.next();
+                if request.contains("Connection: close\r\n") {
+                    break;
+                }
+            }
+        }
+        connections
+    });
+    (port, handle)
+}
+
+/// Gzip-compresses `data` as a server would for `Content-Encoding: gzip`.
+#[cfg(feature = "gzip")]
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn response(status: &str, extra_headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut bytes = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}\r\n",
+        status,
+        body.len(),
+        extra_headers
+    )
+    .into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
@@ -0,0 +1,535 @@
+use rustls::pki_types::ServerName;
+use rustls::{
+    AlertDescription, ClientConfig, ClientConnection, ProtocolVersion, RootCertStore, StreamOwned,
+    SupportedProtocolVersion,
+};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http_client::TlsVersion;
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// The protocol versions a handshake may settle on for `--min-tls-version`.
+fn protocol_versions(min_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_version {
+        TlsVersion::Tls12 => rustls::DEFAULT_VERSIONS,
+        TlsVersion::Tls13 => {
+            static TLS13_ONLY: [&SupportedProtocolVersion; 1] = [&rustls::version::TLS13];
+            &TLS13_ONLY
+        }
+    }
+}
+
+fn client_config(min_version: TlsVersion) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(protocol_versions(min_version))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// The negotiated session parameters, reported in verbose mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub protocol: String,
+    pub cipher_suite: String,
+}
+
+impl TlsInfo {
+    /// Reads the parameters from a connection whose handshake has completed.
+    pub fn from_connection(connection: &ClientConnection) -> Option<Self> {
+        let protocol = match connection.protocol_version()? {
+            ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+            other => format!("{:?}", other),
+        };
+        let cipher_suite = format!("{:?}", connection.negotiated_cipher_suite()?.suite());
+        Some(TlsInfo {
+            protocol,
+            cipher_suite,
+        })
+    }
+}
+
+impl fmt::Display for TlsInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} with {}", self.protocol, self.cipher_suite)
+    }
+}
+
+/// Wraps `stream` in TLS, completing the handshake within `handshake_timeout`.
+///
+/// The socket's read/write timeouts are narrowed to the remaining handshake time while it runs
+/// and restored to `read_write_timeout` afterwards. A server that only speaks versions older
+/// than `min_version` fails the handshake with an error naming `--min-tls-version`.
+pub fn connect(
+    stream: TcpStream,
+    host: &str,
+    min_version: TlsVersion,
+    handshake_timeout: Duration,
+    read_write_timeout: Duration,
+) -> Result<TlsStream, Box<dyn Error>> {
+    connect_with_config(
+        stream,
+        host,
+        client_config(min_version)?,
+        handshake_timeout,
+        read_write_timeout,
+    )
+    .map_err(|e| {
+        if is_version_mismatch(e.as_ref()) {
+            format!(
+                "TLS handshake with {} failed: the server offers nothing at or above {} (--min-tls-version): {}",
+                host, min_version, e
+            )
+            .into()
+        } else {
+            e
+        }
+    })
+}
+
+/// Whether a handshake failed because client and server share no protocol version.
+fn is_version_mismatch(error: &(dyn Error + 'static)) -> bool {
+    let rustls_error = error
+        .downcast_ref::<io::Error>()
+        .and_then(|e| e.get_ref())
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+    matches!(
+        rustls_error,
+        Some(rustls::Error::AlertReceived(
+            AlertDescription::ProtocolVersion
+        )) | Some(rustls::Error::PeerIncompatible(_))
+    )
+}
+
+fn connect_with_config(
+    mut stream: TcpStream,
+    host: &str,
+    config: Arc<ClientConfig>,
+    handshake_timeout: Duration,
+    read_write_timeout: Duration,
+) -> Result<TlsStream, Box<dyn Error>> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| format!("Invalid TLS server name '{}': {}", host, e))?;
+    let mut connection = ClientConnection::new(config, server_name)?;
+
+    let deadline = Instant::now() + handshake_timeout;
+    while connection.is_handshaking() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(handshake_timed_out(handshake_timeout).into());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        stream.set_write_timeout(Some(remaining))?;
+        match connection.complete_io(&mut stream) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(handshake_timed_out(handshake_timeout).into());
+            }
+            // Overloaded TLS terminators often drop connections mid-handshake; report that as a
+            // reset so the retry loop connects again.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe
+                ) =>
+            {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    format!("TLS handshake with {} was cut off: {}", host, e),
+                )
+                .into());
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    stream.set_read_timeout(Some(read_write_timeout))?;
+    stream.set_write_timeout(Some(read_write_timeout))?;
+
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// When the server's leaf certificate expires, read from its `notAfter`.
+pub fn leaf_not_after(connection: &ClientConnection) -> Option<SystemTime> {
+    cert_not_after(connection.peer_certificates()?.first()?)
+}
+
+/// For `--warn-cert-expiry-days`: a warning when `not_after` is less than `days` days past `now`.
+pub fn expiry_warning(
+    host: &str,
+    not_after: SystemTime,
+    now: SystemTime,
+    days: u64,
+) -> Option<String> {
+    let Ok(left) = not_after.duration_since(now) else {
+        return Some(format!(
+            "Warning: the TLS certificate of {} has expired",
+            host
+        ));
+    };
+    let days_left = left.as_secs() / 86_400;
+    (days_left < days).then(|| {
+        format!(
+            "Warning: the TLS certificate of {} expires in {} day{} (--warn-cert-expiry-days={})",
+            host,
+            days_left,
+            if days_left == 1 { "" } else { "s" },
+            days
+        )
+    })
+}
+
+/// Reads `tbsCertificate.validity.notAfter` from a DER certificate. Only the few elements in
+/// front of it are walked, which is not worth an X.509 dependency.
+fn cert_not_after(der: &[u8]) -> Option<SystemTime> {
+    let (_, certificate, _) = der_element(der)?;
+    let (_, tbs, _) = der_element(certificate)?;
+    let (tag, _, mut rest) = der_element(tbs)?;
+    // The version is optional and explicitly tagged [0]; the serial number follows either way.
+    if tag == 0xa0 {
+        rest = der_element(rest)?.2;
+    }
+    let rest = der_element(rest)?.2; // signature algorithm
+    let rest = der_element(rest)?.2; // issuer
+    let (_, validity, _) = der_element(rest)?;
+    let (_, _, validity) = der_element(validity)?; // notBefore
+    let (tag, time, _) = der_element(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let (year, digits) = match tag {
+        // UTCTime: YYMMDDHHMMSSZ, with two-digit years from 1950 to 2049.
+        0x17 => {
+            let year: u64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        // GeneralizedTime: YYYYMMDDHHMMSSZ.
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<u64> { digits.get(i..i + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?)?;
+    let seconds = days * 86_400 + field(4)? * 3_600 + field(6)? * 60 + field(8)?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Splits one DER element off `input`: its tag, its contents and what follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count]
+            .iter()
+            .fold(0usize, |length, &byte| (length << 8) | byte as usize);
+        (length, &rest[count..])
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+/// Days from 1970-01-01 to a date on or after it (Howard Hinnant's `days_from_civil`).
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+fn handshake_timed_out(handshake_timeout: Duration) -> io::Error {
+    io::Error::new(
+        ErrorKind::TimedOut,
+        format!("TLS handshake timed out after {:?}", handshake_timeout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_stalled_handshake_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accept the connection but never answer the ClientHello.
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        let result = connect(
+            stream,
+            "localhost",
+            TlsVersion::Tls12,
+            Duration::from_millis(200),
+            Duration::from_secs(15),
+        );
+
+        let error = result.err().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let io_error = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_error.kind(), ErrorKind::TimedOut);
+        assert!(io_error.to_string().contains("TLS handshake timed out"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_handshake_cut_off_by_the_server_is_retryable() {
+        // Closing normally gives EOF mid-handshake; a zero linger sends a reset instead.
+        for reset in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                // Read the whole ClientHello record, so closing sends no reset by itself.
+                let mut header = [0u8; 5];
+                stream.read_exact(&mut header).unwrap();
+                let mut client_hello =
+                    vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+                stream.read_exact(&mut client_hello).unwrap();
+                if reset {
+                    socket2::SockRef::from(&stream)
+                        .set_linger(Some(Duration::ZERO))
+                        .unwrap();
+                }
+            });
+
+            let error = connect(
+                TcpStream::connect(addr).unwrap(),
+                "localhost",
+                TlsVersion::Tls12,
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+            )
+            .err()
+            .unwrap();
+
+            let io_error = error.downcast_ref::<io::Error>().unwrap();
+            assert_eq!(
+                io_error.kind(),
+                ErrorKind::ConnectionReset,
+                "reset: {}",
+                reset
+            );
+            assert!(
+                crate::retry::is_retryable(error.as_ref()),
+                "reset: {}",
+                reset
+            );
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sni_and_validation_use_the_given_name() {
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::{ServerConfig, ServerConnection};
+
+        // The certificate only names the logical host, never the address connected to.
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["cdn.example.test".to_string()]).unwrap();
+        let cert_der = certified.cert.der().clone();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = Arc::new(
+            ServerConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert_der.clone()], key)
+                .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut server_names = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut connection = ServerConnection::new(server_config.clone()).unwrap();
+                while connection.is_handshaking() {
+                    if connection.complete_io(&mut stream).is_err() {
+                        break;
+                    }
+                }
+                server_names.push(connection.server_name().map(str::to_string));
+            }
+            server_names
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client_config = Arc::new(
+            ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let connect_as = |name: &str| {
+            connect_with_config(
+                TcpStream::connect(addr).unwrap(),
+                name,
+                client_config.clone(),
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+            )
+        };
+
+        assert!(connect_as("cdn.example.test").is_ok());
+        let error = connect_as("other.example.test").err().unwrap();
+        assert!(error.to_string().contains("certificate"), "{}", error);
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                Some("cdn.example.test".to_string()),
+                Some("other.example.test".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_soon_to_expire_certificate_warns() {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        let cert = params
+            .self_signed(&rcgen::KeyPair::generate().unwrap())
+            .unwrap();
+
+        let not_after = cert_not_after(cert.der()).unwrap();
+        assert_eq!(not_after, UNIX_EPOCH + Duration::from_secs(1_893_456_000));
+
+        let three_days_before = not_after - Duration::from_secs(3 * 86_400);
+        let warning = expiry_warning("localhost", not_after, three_days_before, 7).unwrap();
+        assert!(warning.contains("expires in 3 days"), "{}", warning);
+        assert_eq!(
+            expiry_warning("localhost", not_after, three_days_before, 3),
+            None
+        );
+        let expired = expiry_warning(
+            "localhost",
+            not_after,
+            not_after + Duration::from_secs(1),
+            7,
+        );
+        assert!(expired.unwrap().contains("has expired"));
+    }
+
+    #[test]
+    fn test_min_tls_version_rejects_an_older_server() {
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::{ServerConfig, ServerConnection};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let server_config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS12])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key)
+                .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connection = ServerConnection::new(Arc::new(server_config)).unwrap();
+            while connection.is_handshaking() {
+                if connection.complete_io(&mut stream).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let error = connect(
+            TcpStream::connect(addr).unwrap(),
+            "localhost",
+            TlsVersion::Tls13,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .err()
+        .unwrap();
+
+        assert!(error.to_string().contains("--min-tls-version"), "{}", error);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_negotiated_parameters_are_captured() {
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::{ServerConfig, ServerConnection};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = certified.cert.der().clone();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connection = ServerConnection::new(Arc::new(server_config)).unwrap();
+            while connection.is_handshaking() {
+                connection.complete_io(&mut stream).unwrap();
+            }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let tls = connect_with_config(
+            TcpStream::connect(addr).unwrap(),
+            "localhost",
+            Arc::new(client_config),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        let info = TlsInfo::from_connection(&tls.conn).unwrap();
+        assert_eq!(info.protocol, "TLS 1.3");
+        assert!(info.cipher_suite.starts_with("TLS13_"));
+        assert!(info.to_string().starts_with("TLS 1.3 with TLS13_"));
+        server.join().unwrap();
+    }
+}
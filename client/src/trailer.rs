@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::hashing::Algorithm;
+use crate::hex;
+
+/// The checksum a `--trailer-checksum` file ends with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailerAlgorithm {
+    /// CRC-32 (IEEE, as in zip and PNG), stored big-endian.
+    Crc32,
+    /// The raw digest bytes of one of the hash algorithms.
+    Digest(Algorithm),
+}
+
+impl TrailerAlgorithm {
+    fn output_len(self) -> usize {
+        match self {
+            TrailerAlgorithm::Crc32 => 4,
+            TrailerAlgorithm::Digest(algorithm) => algorithm.output_len(),
+        }
+    }
+
+    fn checksum(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            TrailerAlgorithm::Crc32 => crc32(data).to_be_bytes().to_vec(),
+            TrailerAlgorithm::Digest(algorithm) => {
+                let mut hasher = algorithm.new_hasher();
+                hasher.update(data);
+                hasher.finalize()
+            }
+        }
+    }
+}
+
+impl fmt::Display for TrailerAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrailerAlgorithm::Crc32 => f.write_str("CRC-32"),
+            TrailerAlgorithm::Digest(algorithm) => algorithm.fmt(f),
+        }
+    }
+}
+
+/// `--trailer-checksum=<ALGO>:<LENGTH>`: the last `length` bytes of the file are a checksum of
+/// everything before them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrailerChecksum {
+    pub algorithm: TrailerAlgorithm,
+    pub length: usize,
+}
+
+/// The trailer's stated checksum next to the one computed over the content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrailerCheck {
+    pub algorithm: TrailerAlgorithm,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl TrailerCheck {
+    pub fn matches(&self) -> bool {
+        self.actual == self.expected
+    }
+}
+
+impl TrailerChecksum {
+    /// Parses `crc32:4`, `sha256:32` and the like; the length must be the checksum's size.
+    pub fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        let (name, length) = value.split_once(':').ok_or_else(|| {
+            format!(
+                "Invalid --trailer-checksum '{}' (expected <ALGO>:<LENGTH>, e.g. crc32:4)",
+                value
+            )
+        })?;
+        let algorithm = if name.eq_ignore_ascii_case("crc32") {
+            TrailerAlgorithm::Crc32
+        } else {
+            TrailerAlgorithm::Digest(Algorithm::parse(name)?)
+        };
+        let length: usize = length
+            .parse()
+            .map_err(|_| format!("Invalid --trailer-checksum length '{}'", length))?;
+        if length != algorithm.output_len() {
+            return Err(format!(
+                "A {} trailer is {} bytes long, not {}",
+                algorithm,
+                algorithm.output_len(),
+                length
+            )
+            .into());
+        }
+        Ok(TrailerChecksum { algorithm, length })
+    }
+
+    /// Splits the trailer off `data` and checks the content in front of it.
+    pub fn check(&self, data: &[u8]) -> Result<TrailerCheck, Box<dyn Error>> {
+        let split = data.len().checked_sub(self.length).ok_or_else(|| {
+            format!(
+                "The download is {} bytes, shorter than its {}-byte {} trailer",
+                data.len(),
+                self.length,
+                self.algorithm
+            )
+        })?;
+        let (content, trailer) = data.split_at(split);
+        Ok(TrailerCheck {
+            algorithm: self.algorithm,
+            expected: hex::encode(trailer),
+            actual: hex::encode(&self.algorithm.checksum(content)),
+        })
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 with the IEEE polynomial; small enough not to need a crate.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_trailer_verifies_the_content() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let trailer = TrailerChecksum::parse("crc32:4").unwrap();
+        let mut file = b"self-verifying payload".to_vec();
+        file.extend_from_slice(&crc32(b"self-verifying payload").to_be_bytes());
+
+        assert!(trailer.check(&file).unwrap().matches());
+        file[0] ^= 1;
+        let check = trailer.check(&file).unwrap();
+        assert!(!check.matches());
+        assert_eq!(check.expected.len(), 8);
+        assert!(trailer.check(b"abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_checks_the_length() {
+        assert_eq!(
+            TrailerChecksum::parse("SHA256:32").unwrap().algorithm,
+            TrailerAlgorithm::Digest(Algorithm::Sha256)
+        );
+        assert!(TrailerChecksum::parse("crc32:8").is_err());
+        assert!(TrailerChecksum::parse("crc32").is_err());
+        assert!(TrailerChecksum::parse("md5:16").is_err());
+    }
+}
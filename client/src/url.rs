@@ -0,0 +1,201 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+
+    pub fn default_port(self) -> u16 {
+        match self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(input: &str) -> Result<Url, Box<dyn Error>> {
+        let (scheme, rest) = if let Some(rest) = input.strip_prefix("http://") {
+            (Scheme::Http, rest)
+        } else if let Some(rest) = input.strip_prefix("https://") {
+            (Scheme::Https, rest)
+        } else {
+            return Err(format!(
+                "Unsupported URL (expected http:// or https://): '{}'",
+                input
+            )
+            .into());
+        };
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(idx) if rest[idx..].starts_with('/') => (&rest[..idx], rest[idx..].to_string()),
+            Some(idx) => (&rest[..idx], format!("/{}", &rest[idx..])),
+            None => (rest, "/".to_string()),
+        };
+        let path = match path.find('#') {
+            Some(idx) => path[..idx].to_string(),
+            None => path,
+        };
+
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, after) = bracketed
+                .split_once(']')
+                .ok_or_else(|| format!("Unterminated IPv6 address in URL: '{}'", input))?;
+            (host, after.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("URL has no host: '{}'", input).into());
+        }
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port '{}' in URL '{}': {}", port, input, e))?,
+            None => scheme.default_port(),
+        };
+
+        Ok(Url {
+            scheme,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    /// Resolves a `Location`-style reference (absolute, scheme-relative, absolute-path or
+    /// relative-path) against this URL.
+    pub fn join(&self, reference: &str) -> Result<Url, Box<dyn Error>> {
+        if reference.contains("://") {
+            return Url::parse(reference);
+        }
+        if let Some(rest) = reference.strip_prefix("//") {
+            return Url::parse(&format!("{}://{}", self.scheme.name(), rest));
+        }
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            let base = self.path.split(['?', '#']).next().unwrap_or("/");
+            let directory = &base[..base.rfind('/').map_or(0, |idx| idx + 1)];
+            format!("{}{}", directory, reference)
+        };
+        let path = match path.find('#') {
+            Some(idx) => path[..idx].to_string(),
+            None => path,
+        };
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(
+                f,
+                "{}://[{}]:{}{}",
+                self.scheme.name(),
+                self.host,
+                self.port,
+                self.path
+            )
+        } else {
+            write!(
+                f,
+                "{}://{}:{}{}",
+                self.scheme.name(),
+                self.host,
+                self.port,
+                self.path
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_with_port_and_path() {
+        let url = Url::parse("http://example.com:8080/files/data.bin?v=1").unwrap();
+        assert_eq!(url.scheme, Scheme::Http);
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/files/data.bin?v=1");
+    }
+
+    #[test]
+    fn test_parse_default_ports_and_root_path() {
+        let url = Url::parse("https://example.com").unwrap();
+        assert_eq!(url.scheme, Scheme::Https);
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/");
+        assert_eq!(Url::parse("http://example.com/").unwrap().port, 80);
+    }
+
+    #[test]
+    fn test_parse_ipv6_host() {
+        let url = Url::parse("http://[::1]:9000/x").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 9000);
+        assert_eq!(url.path, "/x");
+    }
+
+    #[test]
+    fn test_join_location_references() {
+        let base = Url::parse("http://example.com:8080/files/old.bin?x=1").unwrap();
+        assert_eq!(
+            base.join("https://mirror.example/new.bin").unwrap(),
+            Url::parse("https://mirror.example/new.bin").unwrap()
+        );
+        assert_eq!(
+            base.join("//cdn.example/a").unwrap(),
+            Url::parse("http://cdn.example/a").unwrap()
+        );
+        assert_eq!(
+            base.join("/other").unwrap().to_string(),
+            "http://example.com:8080/other"
+        );
+        assert_eq!(base.join("new.bin#frag").unwrap().path, "/files/new.bin");
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for input in ["http://example.com:8080/a?b", "https://[::1]:443/"] {
+            assert_eq!(Url::parse(input).unwrap().to_string(), input);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_urls() {
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("example.com").is_err());
+        assert!(Url::parse("http:///path").is_err());
+        assert!(Url::parse("http://example.com:99999/").is_err());
+        assert!(Url::parse("http://[::1/").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+
+use crate::config::Config;
+use crate::download::build_client;
+use crate::hashing::{check_digests, DigestCheck, Hasher, MultiHasher};
+
+/// What a `HEAD` request says about the remote copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteMetadata {
+    pub content_length: Option<u64>,
+    pub etag: Option<String>,
+}
+
+/// Outcome of `--verify-only`: the local digests, plus the remote metadata with `--compare-remote`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub checks: Vec<DigestCheck>,
+    pub local_size: u64,
+    pub remote: Option<RemoteMetadata>,
+}
+
+impl VerifyReport {
+    /// Whether the local size equals the remote `Content-Length`, when the server sent one.
+    pub fn size_matches(&self) -> Option<bool> {
+        let remote_length = self.remote.as_ref()?.content_length?;
+        Some(remote_length == self.local_size)
+    }
+
+    /// Whether the `ETag` equals one of the local digests. Only an `ETag` that looks like a hex
+    /// digest of a checked algorithm is compared; any other `ETag` is opaque and yields `None`.
+    pub fn etag_matches(&self) -> Option<bool> {
+        let etag = self.remote.as_ref()?.etag.as_deref()?;
+        let etag = etag.strip_prefix("W/").unwrap_or(etag).trim_matches('"');
+        let check = self.checks.iter().find(|check| {
+            etag.len() == check.algorithm.output_len() * 2
+                && etag.bytes().all(|b| b.is_ascii_hexdigit())
+        })?;
+        Some(etag.eq_ignore_ascii_case(&check.actual))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(DigestCheck::matches)
+            && self.size_matches() != Some(false)
+            && self.etag_matches() != Some(false)
+    }
+}
+
+/// Hashes the `--verify-only` file against the expected digests and, with `--compare-remote`,
+/// fetches the URL's headers to compare size and `ETag` without downloading the body.
+pub fn verify_local(config: &Config, path: &str) -> Result<VerifyReport, Box<dyn Error>> {
+    let expected = config.expected_digests()?;
+    let algorithms: Vec<_> = expected.iter().map(|(algorithm, _)| *algorithm).collect();
+    let mut hasher: Box<dyn Hasher> = Box::new(MultiHasher::new(&algorithms));
+    let mut file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    let mut local_size = 0u64;
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                local_size += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("Cannot read {}: {}", path, e).into()),
+        }
+    }
+
+    let remote = if config.compare_remote {
+        let response = build_client(config).head()?;
+        if response.status != 200 {
+            return Err(format!("HEAD request failed with status {}", response.status).into());
+        }
+        Some(RemoteMetadata {
+            content_length: response.resource_length(),
+            etag: response.header("ETag").map(str::to_string),
+        })
+    } else {
+        None
+    };
+
+    Ok(VerifyReport {
+        checks: check_digests(&expected, &hasher.finalize()),
+        local_size,
+        remote,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::spawn_server;
+    use std::fs;
+
+    // SHA-256 of "hello".
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn local_file(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("client-verify-{}-{}", name, std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn head_response(headers: &str) -> Vec<u8> {
+        format!("HTTP/1.1 200 OK\r\n{}\r\n", headers).into_bytes()
+    }
+
+    #[test]
+    fn test_local_hash_matches_without_network() {
+        let path = local_file("local");
+        let config = Config {
+            expected_hash: HELLO_SHA256.to_string(),
+            ..Config::default()
+        };
+        let report = verify_local(&config, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(report.checks[0].matches());
+        assert_eq!(report.local_size, 5);
+        assert_eq!(report.remote, None);
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_remote_size_and_hex_etag_match() {
+        let (port, server) = spawn_server(vec![head_response(&format!(
+            "Content-Length: 5\r\nETag: \"{}\"\r\n",
+            HELLO_SHA256
+        ))]);
+        let path = local_file("size-match");
+        let config = Config {
+            port,
+            expected_hash: HELLO_SHA256.to_string(),
+            compare_remote: true,
+            ..Config::default()
+        };
+        let report = verify_local(&config, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(report.size_matches(), Some(true));
+        assert_eq!(report.etag_matches(), Some(true));
+        assert!(report.passed());
+        assert!(server.join().unwrap()[0].starts_with("HEAD / HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_remote_size_mismatch_fails_despite_matching_hash() {
+        let (port, server) = spawn_server(vec![head_response(
+            "Content-Length: 6\r\nETag: \"opaque-version-7\"\r\n",
+        )]);
+        let path = local_file("size-mismatch");
+        let config = Config {
+            port,
+            expected_hash: HELLO_SHA256.to_string(),
+            compare_remote: true,
+            ..Config::default()
+        };
+        let report = verify_local(&config, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        server.join().unwrap();
+        assert!(report.checks[0].matches());
+        assert_eq!(report.size_matches(), Some(false));
+        assert_eq!(report.etag_matches(), None);
+        assert!(!report.passed());
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::Command;
+use std::thread;
+
+/// Serves the whole body on a single request; its `Content-Length` tells the client it is done.
+fn spawn_server(body: &'static [u8]) -> (u16, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+    });
+    (port, handle)
+}
+
+#[test]
+fn body_only_stdout_contains_only_the_body() {
+    let body = b"\x00binary payload\r\nwith no trailing newline\xff";
+    let (port, server) = spawn_server(body);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .arg("--body-only")
+        .arg(format!("--url=http://127.0.0.1:{}/file.bin", port))
+        .env_remove("http_proxy")
+        .env_remove("HTTP_PROXY")
+        .output()
+        .unwrap();
+
+    server.join().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, body);
+    assert!(output.stderr.is_empty());
+}
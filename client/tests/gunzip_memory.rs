@@ -0,0 +1,114 @@
+//! Holds the counting allocator, so only this test binary pays for it.
+#![cfg(feature = "gzip")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use client::config::Config;
+use client::download::download_file;
+use sha2::{Digest, Sha256};
+
+/// The system allocator, counting each thread's live bytes for `peak_allocation`.
+struct CountingAllocator;
+
+thread_local! {
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+    static PEAK_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+fn count_allocation(delta: isize) {
+    let _ = LIVE_BYTES.try_with(|live| {
+        live.set(live.get() + delta);
+        let _ = PEAK_BYTES.try_with(|peak| peak.set(peak.get().max(live.get())));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            count_allocation(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        count_allocation(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            count_allocation(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns its result with the most heap it held at once on this thread, in bytes.
+fn peak_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = LIVE_BYTES.with(Cell::get);
+    PEAK_BYTES.with(|peak| peak.set(start));
+    let result = f();
+    let peak = PEAK_BYTES.with(Cell::get);
+    (result, (peak - start).max(0) as usize)
+}
+
+/// Answers `requests` requests, each on its own connection, with the gzipped `archive`.
+fn spawn_server(archive: Vec<u8>, requests: usize) -> (u16, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        for _ in 0..requests {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && !line.ends_with("\r\n\r\n") {}
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                archive.len()
+            )
+            .unwrap();
+            stream.write_all(&archive).unwrap();
+        }
+    });
+    (port, handle)
+}
+
+#[test]
+fn hash_only_gunzips_a_compressed_body_with_bounded_memory() {
+    const PLAIN_LEN: usize = 32 * 1024 * 1024;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    let block = vec![b'x'; 64 * 1024];
+    let mut expected = Sha256::new();
+    for _ in 0..PLAIN_LEN / block.len() {
+        encoder.write_all(&block).unwrap();
+        expected.update(&block);
+    }
+    let archive = encoder.finish().unwrap();
+    // The range request that finds the encoding, then the buffered request that decodes it.
+    let (port, server) = spawn_server(archive, 2);
+    let config = Config {
+        port,
+        hash_only: true,
+        silent: true,
+        ..Config::default()
+    };
+
+    let (download, peak) =
+        peak_allocation(|| download_file(&config, Box::new(Sha256::new())).unwrap());
+
+    server.join().unwrap();
+    assert!(download.data.is_none());
+    assert_eq!(download.bytes, PLAIN_LEN as u64);
+    assert_eq!(download.digest, expected.finalize().to_vec());
+    assert!(peak < 1024 * 1024, "peak allocation {} bytes", peak);
+}
@@ -0,0 +1,99 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use client::config::Config;
+use client::sources::{download_from_sources, SourceList};
+use client::url::Url;
+use sha2::{Digest, Sha256};
+
+const FILE: &[u8] = b"0123456789abcdefghij";
+
+/// Serves ranges of `FILE`, one request per connection, closing the connections of the ranged
+/// requests listed in `drop_gets` (counted from 0) without answering. Returns the request lines.
+fn spawn_source(
+    connections: usize,
+    drop_gets: &'static [usize],
+) -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        let mut gets = 0;
+        for _ in 0..connections {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some(spec) = line.trim_end().strip_prefix("Range: bytes=") {
+                    let (start, end) = spec.split_once('-').unwrap();
+                    range = Some((
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    ));
+                }
+                request.push_str(&line);
+            }
+            requests.push(request);
+            let Some((start, end)) = range else {
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                    FILE.len()
+                )
+                .unwrap();
+                continue;
+            };
+            gets += 1;
+            if drop_gets.contains(&(gets - 1)) {
+                continue;
+            }
+            write!(
+                stream,
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                start,
+                end,
+                FILE.len(),
+                end + 1 - start
+            )
+            .unwrap();
+            stream.write_all(&FILE[start..=end]).unwrap();
+        }
+        requests
+    });
+    (port, handle)
+}
+
+fn url(port: u16) -> Url {
+    Url::parse(&format!("http://127.0.0.1:{}/file.bin", port)).unwrap()
+}
+
+#[test]
+fn a_source_is_retried_before_its_segment_is_handed_over() {
+    let (port_a, source_a) = spawn_source(2, &[]);
+    // Drops its first GET; the retry from the same source gets the bytes.
+    let (port_b, source_b) = spawn_source(3, &[0]);
+    let download = download_from_sources(
+        &Config::default(),
+        &SourceList {
+            urls: vec![url(port_a), url(port_b)],
+            size: FILE.len() as u64,
+        },
+        Box::new(Sha256::new()),
+    )
+    .unwrap();
+
+    assert_eq!(download.data.unwrap(), FILE);
+    assert_eq!(download.digest, Sha256::digest(FILE).to_vec());
+    assert_eq!(download.retries, 1);
+    let (a, b) = (source_a.join().unwrap(), source_b.join().unwrap());
+    assert_eq!(a.len(), 2);
+    assert!(a[1].contains("Range: bytes=0-9\r\n"));
+    assert!(b[1].contains("Range: bytes=10-19\r\n"));
+    assert!(b[2].contains("Range: bytes=10-19\r\n"));
+}
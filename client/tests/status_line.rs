@@ -0,0 +1,16 @@
+use client::{parse_status_line, StatusLineError};
+
+#[test]
+fn status_line_parser_is_usable_from_the_crate_root() {
+    assert_eq!(
+        parse_status_line("HTTP/1.1 416 Range Not Satisfiable\r\n"),
+        Ok((416, "Range Not Satisfiable".to_string()))
+    );
+    let error = parse_status_line("HTTP/1.1").unwrap_err();
+    assert_eq!(error, StatusLineError::TooFewParts("HTTP/1.1".to_string()));
+    let boxed: Box<dyn std::error::Error> = error.into();
+    assert_eq!(
+        boxed.to_string(),
+        "Malformed status line (too few parts): 'HTTP/1.1'"
+    );
+}